@@ -1,15 +1,316 @@
-use rusqlite::{params, Connection};
+use base64::prelude::*;
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension, Transaction};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::sync::Mutex;
-use tauri::{Manager, State};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, TryLockError};
+use std::cell::RefCell;
+use std::time::{Duration, Instant};
+use tauri::{AppHandle, Emitter, Manager, State};
 use uuid::Uuid;
 // Data
 
+// Set to true to ask an in-flight reconstruction to stop
+type CancellationToken = Arc<AtomicBool>;
+
+type CacheKey = (String, String);
+
+// Reconstructed content by (doc_uuid, patch_uuid). Anything that removes or
+// re-encodes a document's patches calls `invalidate_document_cache` for it.
+#[derive(Default)]
+struct ContentCache {
+    entries: Mutex<HashMap<CacheKey, CachedContent>>,
+    // Store new entries zstd-compressed, trading CPU on every hit for memory
+    compress: AtomicBool,
+    // Lookups since startup, reported by `cache_stats`
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+enum CachedContent {
+    Plain(Vec<u8>),
+    Compressed { bytes: Vec<u8>, len: usize },
+}
+
+// zstd level for cache entries; low, since every hit pays for decompression
+const CACHE_ZSTD_LEVEL: i32 = 1;
+
+impl CachedContent {
+    fn stored_len(&self) -> usize {
+        match self {
+            CachedContent::Plain(content) => content.len(),
+            CachedContent::Compressed { bytes, .. } => bytes.len(),
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            CachedContent::Plain(content) => content.len(),
+            CachedContent::Compressed { len, .. } => *len,
+        }
+    }
+}
+
+impl ContentCache {
+    fn entries(&self) -> MutexGuard<'_, HashMap<CacheKey, CachedContent>> {
+        lock(&self.entries)
+    }
+
+    fn get(&self, key: &CacheKey) -> Option<Vec<u8>> {
+        let content = self.lookup(key);
+        let counter = if content.is_some() { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+        content
+    }
+
+    fn lookup(&self, key: &CacheKey) -> Option<Vec<u8>> {
+        let entries = self.entries();
+        match entries.get(key)? {
+            CachedContent::Plain(content) => Some(content.clone()),
+            CachedContent::Compressed { bytes, len } => {
+                let bytes = bytes.clone();
+                let len = *len;
+                drop(entries);
+                // An entry that fails to decompress is just a miss
+                zstd::bulk::decompress(&bytes, len).ok()
+            }
+        }
+    }
+
+    fn insert(&self, key: CacheKey, content: Vec<u8>) {
+        let compressed = self
+            .compress
+            .load(Ordering::Relaxed)
+            .then(|| zstd::bulk::compress(&content, CACHE_ZSTD_LEVEL).ok())
+            .flatten();
+        let entry = match compressed {
+            // Only worth keeping when it actually saves memory
+            Some(bytes) if bytes.len() < content.len() => CachedContent::Compressed {
+                bytes,
+                len: content.len(),
+            },
+            _ => CachedContent::Plain(content),
+        };
+        self.entries().insert(key, entry);
+    }
+}
+
+// A panic while a lock is held poisons it, but connections and the cache are
+// still structurally valid, so recover the guard instead of panicking forever
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+// Read-only connections opened alongside the writer for file-backed databases
+const READ_CONNECTIONS: usize = 4;
+
+// How long a connection waits on a locked database before giving up
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Connections to the database: a single writer plus read-only connections
+/// that, under WAL, run alongside it and each other. Without readers (the
+/// in-memory tier) reads share the writer.
+pub struct DbPool {
+    writer: Mutex<Connection>,
+    // Always READ_CONNECTIONS slots so `replace` can swap databases in place;
+    // only the first `active_readers` are connected to the database
+    readers: Vec<Mutex<Connection>>,
+    active_readers: AtomicUsize,
+    next_reader: AtomicUsize,
+}
+
+// Fills reader slots that have no database behind them
+fn idle_reader() -> rusqlite::Result<Mutex<Connection>> {
+    Ok(Mutex::new(Connection::open_in_memory()?))
+}
+
+impl DbPool {
+    pub fn single(conn: Connection) -> Self {
+        let readers = (0..READ_CONNECTIONS)
+            .map(|_| idle_reader())
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .expect("in-memory connections cannot fail to open");
+        DbPool {
+            writer: Mutex::new(conn),
+            readers,
+            active_readers: AtomicUsize::new(0),
+            next_reader: AtomicUsize::new(0),
+        }
+    }
+
+    /// Switches `writer` to WAL and opens the read connections to `path`.
+    pub fn open(writer: Connection, path: &Path) -> rusqlite::Result<Self> {
+        writer.busy_timeout(BUSY_TIMEOUT)?;
+        writer.pragma_update(None, "journal_mode", "WAL")?;
+
+        let readers = (0..READ_CONNECTIONS)
+            .map(|_| {
+                let flags = OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX;
+                let conn = Connection::open_with_flags(path, flags)?;
+                conn.busy_timeout(BUSY_TIMEOUT)?;
+                Ok(Mutex::new(conn))
+            })
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        Ok(DbPool {
+            writer: Mutex::new(writer),
+            readers,
+            active_readers: AtomicUsize::new(READ_CONNECTIONS),
+            next_reader: AtomicUsize::new(0),
+        })
+    }
+
+    /// Points the pool at another database, waiting for every connection to go
+    /// idle first. The old connections are closed.
+    fn replace(&self, other: DbPool) {
+        let (mut readers, mut writer) = self.exclusive();
+
+        *writer = other.writer.into_inner().unwrap_or_else(PoisonError::into_inner);
+        for (slot, reader) in readers.iter_mut().zip(other.readers) {
+            **slot = reader.into_inner().unwrap_or_else(PoisonError::into_inner);
+        }
+        // Set while every reader is held, so `read()` never sees a stale count
+        self.active_readers
+            .store(other.active_readers.into_inner(), Ordering::Relaxed);
+    }
+
+    fn write(&self) -> MutexGuard<'_, Connection> {
+        lock(&self.writer)
+    }
+
+    // Holds every reader idle too, for operations that need the file to themselves.
+    // Readers are locked before the writer so this never deadlocks with `read()`.
+    fn exclusive(&self) -> (Vec<MutexGuard<'_, Connection>>, MutexGuard<'_, Connection>) {
+        let readers = self.readers.iter().map(lock).collect();
+        (readers, self.write())
+    }
+
+    // Takes the first idle reader, starting from a rotating offset, and only
+    // waits when every reader is busy
+    fn read(&self) -> MutexGuard<'_, Connection> {
+        loop {
+            let active = self.active_readers.load(Ordering::Relaxed);
+            if active == 0 {
+                return self.write();
+            }
+
+            let start = self.next_reader.fetch_add(1, Ordering::Relaxed);
+            let idle = (0..active).find_map(|offset| {
+                let index = (start + offset) % active;
+                match self.readers[index].try_lock() {
+                    Ok(guard) => Some((index, guard)),
+                    Err(TryLockError::Poisoned(poisoned)) => Some((index, poisoned.into_inner())),
+                    Err(TryLockError::WouldBlock) => None,
+                }
+            });
+            let (index, guard) =
+                idle.unwrap_or_else(|| (start % active, lock(&self.readers[start % active])));
+
+            // A `replace` may have retired this slot between reading the count and locking it
+            if index < self.active_readers.load(Ordering::Relaxed) {
+                return guard;
+            }
+        }
+    }
+}
+
 pub struct AppState {
-    db: Mutex<Connection>,
-    // Cache: (doc_uuid, patch_uuid) -> reconstructed content
-    cache: Mutex<HashMap<(String, String), Vec<u8>>>,
+    db: DbPool,
+    cache: ContentCache,
+    // In-flight reconstructions: request_id -> cancellation token
+    reconstructions: Mutex<HashMap<String, CancellationToken>>,
+    // Open version streams: stream handle -> content still to be read
+    streams: Mutex<HashMap<String, VersionStream>>,
+    // Documents a background prefetch is currently warming
+    prefetching: Mutex<HashSet<String>>,
+    // Changes when `open_database` switches files
+    db_location: Mutex<DatabaseLocation>,
+    // Size cap for documents without their own `max_content_bytes`
+    default_max_content_bytes: Option<i64>,
+    // Decode each new delta against its base before storing it, so a bad encode
+    // fails the save instead of a later load. Costs one decode per save.
+    verify_encodes: bool,
+    // How far ahead of the clock a document's creation time may be set
+    created_at_tolerance_ms: i64,
+}
+
+// A reconstructed version handed out in chunks by `read_version_chunk`
+struct VersionStream {
+    content: Vec<u8>,
+    offset: usize,
+}
+
+impl AppState {
+    pub fn new(db: DbPool, db_location: DatabaseLocation) -> Self {
+        AppState {
+            db,
+            db_location: Mutex::new(db_location),
+            cache: ContentCache::default(),
+            reconstructions: Mutex::new(HashMap::new()),
+            streams: Mutex::new(HashMap::new()),
+            prefetching: Mutex::new(HashSet::new()),
+            default_max_content_bytes: None,
+            verify_encodes: true,
+            created_at_tolerance_ms: DEFAULT_CREATED_AT_TOLERANCE_MS,
+        }
+    }
+
+    // For commands that only query
+    fn read_db(&self) -> MutexGuard<'_, Connection> {
+        self.db.read()
+    }
+
+    // For commands that modify the database
+    fn write_db(&self) -> MutexGuard<'_, Connection> {
+        self.db.write()
+    }
+
+    fn lock_cache(&self) -> MutexGuard<'_, HashMap<CacheKey, CachedContent>> {
+        self.cache.entries()
+    }
+
+    fn lock_reconstructions(&self) -> MutexGuard<'_, HashMap<String, CancellationToken>> {
+        lock(&self.reconstructions)
+    }
+
+    fn lock_streams(&self) -> MutexGuard<'_, HashMap<String, VersionStream>> {
+        lock(&self.streams)
+    }
+
+    fn lock_prefetching(&self) -> MutexGuard<'_, HashSet<String>> {
+        lock(&self.prefetching)
+    }
+
+    fn db_location(&self) -> DatabaseLocation {
+        lock(&self.db_location).clone()
+    }
+}
+
+/// Drops every cached version of a document, returning how many were dropped.
+fn invalidate_document_cache(cache: &ContentCache, doc_uuid: &str) -> usize {
+    let mut entries = cache.entries();
+    let before = entries.len();
+    entries.retain(|(cached_doc, _), _| cached_doc != doc_uuid);
+    before - entries.len()
+}
+
+/// Which tier of the startup fallback chain the database was opened from.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum DatabaseTier {
+    AppData,
+    Temp,
+    InMemory,
+    // An explicit path from XPATCH_DB_PATH or `open_database`
+    Custom,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DatabaseLocation {
+    pub tier: DatabaseTier,
+    // None for the in-memory tier
+    pub path: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -17,6 +318,7 @@ pub struct Document {
     pub uuid: String,
     pub name: String,
     pub created_at: i64,
+    pub content_type: String,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -35,14 +337,473 @@ pub struct DocumentStats {
     pub compression_ratio: f64,
 }
 
+/// Which side of a timestamp `load_nearest` looks for a version on.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum SeekDirection {
+    /// Whichever bracketing version is closer in time, the earlier one on a tie
+    Nearest,
+    /// The version at or before the timestamp
+    Before,
+    /// The version at or after the timestamp
+    After,
+}
+
+/// What happened to a document, as reported by `document-changed` events.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum DocumentChangeKind {
+    Created,
+    /// New versions were saved
+    Edited,
+    /// Versions were removed, moved or regrouped (truncate, prune, merge, split)
+    HistoryRewritten,
+    /// Patches were re-encoded; every version's content is unchanged
+    Compacted,
+    /// Strategy, content type, creation date, size or version caps
+    SettingsChanged,
+    /// Bookmarks, metadata or attachments
+    AnnotationsChanged,
+    Trashed,
+    Restored,
+    /// Permanently deleted
+    Deleted,
+}
+
+/// Line endings `create_patch` can normalize content to before encoding.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum EolStyle {
+    Lf,
+    Crlf,
+}
+
+/// How `create_patch` picks the base a new version is encoded against.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum BaseStrategy {
+    /// Always encode against the immediately previous version
+    PreviousOnly,
+    /// Try up to `n` previous versions and keep the smallest delta
+    OptimalDepth(usize),
+    /// Store every `n`th version as a standalone snapshot, optimal depth otherwise
+    SnapshotEvery(usize),
+}
+
+impl Default for BaseStrategy {
+    fn default() -> Self {
+        BaseStrategy::OptimalDepth(MAX_BASE_DEPTH)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DocumentSummary {
+    pub document: Document,
+    pub total_patches: i64,
+    pub total_delta_bytes: i64,
+}
+
+/// One page of `get_documents_paged`; `total` counts every match, not just this page.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DocumentPage {
+    pub documents: Vec<Document>,
+    pub total: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SavedVersion {
+    pub patch_uuid: String,
+    pub timestamp: i64,
+    pub content: String,
+}
+
+/// An attachment's details; `get_attachment` returns the bytes.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Attachment {
+    pub uuid: String,
+    pub document_uuid: String,
+    pub name: String,
+    pub size: i64,
+    pub created_at: i64,
+}
+
+/// `manifest.json` inside an `export_all_versions` archive.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ArchiveManifest {
+    pub doc_uuid: String,
+    pub content_type: String,
+    pub versions: Vec<ArchiveEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ArchiveEntry {
+    pub file: String,
+    pub timestamp: i64,
+    pub bytes: usize,
+}
+
+/// What `export_sync_bundle` serializes (as zstd-compressed JSON): everything a
+/// peer with nothing needs to rebuild the document with `apply_delta_stream`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SyncBundle {
+    pub format_version: u32,
+    pub document: Document,
+    // Older bundles lack some or all settings and import with the defaults
+    #[serde(default)]
+    pub base_strategy: BaseStrategy,
+    pub max_versions: Option<i64>,
+    pub max_chain_depth: Option<i64>,
+    pub max_content_bytes: Option<i64>,
+    pub eol_style: Option<EolStyle>,
+    pub metadata: BTreeMap<String, String>,
+    pub patches: Vec<SyncPatch>,
+}
+
+/// One version in a `SyncBundle`. `delta` is base64 and always decodes against
+/// the version before it (empty for the first).
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SyncPatch {
+    pub timestamp: i64,
+    pub delta: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Bookmark {
+    pub name: String,
+    pub timestamp: i64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum LineEventKind {
+    Added,
+    Removed,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LineEvent {
+    pub timestamp: i64,
+    pub kind: LineEventKind,
+}
+
+/// A run of whole lines, including their line endings. Concatenating the
+/// `Equal` and `Delete` runs gives the old text, `Equal` and `Insert` the new.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum DiffOp {
+    Equal(String),
+    Insert(String),
+    Delete(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StorageReport {
+    // None for the in-memory tier
+    pub file_bytes: Option<i64>,
+    // Trashed documents included
+    pub document_count: i64,
+    pub patch_count: i64,
+    pub total_delta_bytes: i64,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct SchemaStatus {
+    pub current_version: i64,
+    // The version the last entry in MIGRATIONS brings a database to
+    pub latest_version: i64,
+    // Also true for a database written by a newer build
+    pub up_to_date: bool,
+}
+
+// Unlike StorageReport, only what the user sees: trashed documents are left out
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LibraryCounts {
+    pub documents: i64,
+    pub patches: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CacheStats {
+    pub entries: usize,
+    pub logical_bytes: usize,
+    pub stored_bytes: usize,
+    pub compression_enabled: bool,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// Where the time goes when loading one version, in microseconds.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ProfileReport {
+    pub query_us: u64,
+    pub decode_us: u64,
+    pub utf8_us: u64,
+    pub patch_count: usize,
+    pub cache_hits: usize,
+    pub cache_misses: usize,
+}
+
+/// One encoding `create_patch` tried while choosing a base.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BaseCandidate {
+    pub tag: usize,
+    // Timestamp of the version encoded against; None for snapshots and empty bases
+    pub base_timestamp: Option<i64>,
+    pub delta_bytes: usize,
+    pub chosen: bool,
+}
+
+/// One delta applied while reconstructing a version.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChainStep {
+    pub patch_uuid: String,
+    pub timestamp: i64,
+    pub tag: usize,
+    // None when the delta decodes against empty content
+    pub base_timestamp: Option<i64>,
+}
+
+/// A version with what rebuilding it from nothing costs: the deltas on its
+/// chain back to a snapshot (or the first patch) and the base bytes they read.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VersionWithCost {
+    pub content: String,
+    pub patches_replayed: usize,
+    pub total_base_bytes_read: usize,
+}
+
+/// A version with the lines it added relative to the previous one, as
+/// `(start, end)` byte ranges into `content`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VersionWithChanges {
+    pub content: String,
+    pub changed_ranges: Vec<(usize, usize)>,
+}
+
+/// A patch row as stored, for bug reports.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PatchInspection {
+    pub uuid: String,
+    pub document_uuid: String,
+    pub timestamp: i64,
+    pub delta_len: usize,
+    // None when there is no delta or its header does not parse
+    pub tag: Option<usize>,
+    // Hex of the delta's first INSPECT_PREFIX_BYTES bytes
+    pub first_bytes_hex: String,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct VersionPreview {
+    pub timestamp: i64,
+    // The first `preview_len` characters, or the first `preview_len` bytes in hex
+    // when the version is not UTF-8
+    pub preview: String,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct VersionMetrics {
+    pub bytes: usize,
+    // Unicode scalar values; invalid UTF-8 counts one per replacement character
+    pub chars: usize,
+    pub lines: usize,
+    // Runs of non-whitespace
+    pub words: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CacheWarmup {
+    pub versions_warmed: usize,
+    pub bytes_cached: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CompactionPreview {
+    pub delta_bytes_before: i64,
+    pub delta_bytes_after: i64,
+    // Snapshots the document's strategy requires that are not stored yet
+    pub snapshots_added: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PruneReport {
+    pub removed_patches: i64,
+    pub bytes_reclaimed: i64,
+}
+
+const DEFAULT_CONTENT_TYPE: &str = "text/plain";
+
+// Columns read by `document_from_row`, in order
+const DOCUMENT_COLUMNS: &str = "uuid, name, created_at, content_type";
+
+fn document_from_row(row: &rusqlite::Row) -> rusqlite::Result<Document> {
+    Ok(Document {
+        uuid: row.get(0)?,
+        name: row.get(1)?,
+        created_at: row.get(2)?,
+        content_type: row.get(3)?,
+    })
+}
+
 // Setup
 
-pub fn init_database(app: &tauri::App) -> Result<Connection, Box<dyn std::error::Error>> {
-    let app_dir = app.path().app_data_dir()?;
-    std::fs::create_dir_all(&app_dir)?;
-    let db_path = app_dir.join("xpatch.db");
+// Overrides where the database lives, e.g. a temp file in tests or one file per
+// profile; `:memory:` gives a throwaway database
+const DB_PATH_ENV: &str = "XPATCH_DB_PATH";
+
+pub fn init_database(
+    app: &tauri::App,
+) -> Result<(DbPool, DatabaseLocation), Box<dyn std::error::Error>> {
+    // An explicit path is not silently swapped for a fallback
+    if let Ok(path) = std::env::var(DB_PATH_ENV) {
+        return Ok(open_custom(&path)?);
+    }
+
+    let app_dir = app.path().app_data_dir().map_err(|e| e.to_string());
+    let temp_dir = std::env::temp_dir().join("xpatch_demo_editor");
+    Ok(open_with_fallback(app_dir, temp_dir)?)
+}
+
+/// Opens the database at `path`, or an in-memory one for `:memory:`.
+fn open_custom(path: &str) -> Result<(DbPool, DatabaseLocation), String> {
+    if path == ":memory:" {
+        let conn = Connection::open_in_memory().map_err(|e| e.to_string())?;
+        init_schema(&conn).map_err(|e| e.to_string())?;
+        let location = DatabaseLocation {
+            tier: DatabaseTier::InMemory,
+            path: None,
+        };
+        return Ok((DbPool::single(conn), location));
+    }
+
+    let db_path = Path::new(path);
+    if let Some(dir) = db_path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+    }
+    let db = open_file(db_path)?;
+    let location = DatabaseLocation {
+        tier: DatabaseTier::Custom,
+        path: Some(path.to_string()),
+    };
+    Ok((db, location))
+}
+
+/// Opens the database in `app_dir`, falling back to `temp_dir` and, as a last
+/// resort, an in-memory database so the app can at least run for a session.
+fn open_with_fallback(
+    app_dir: Result<PathBuf, String>,
+    temp_dir: PathBuf,
+) -> Result<(DbPool, DatabaseLocation), rusqlite::Error> {
+    let candidates = [(DatabaseTier::AppData, app_dir), (DatabaseTier::Temp, Ok(temp_dir))];
+
+    for (tier, dir) in candidates {
+        match dir.and_then(|dir| open_in_dir(&dir)) {
+            Ok((db, db_path)) => {
+                log::info!("Using {:?} database at {}", tier, db_path.display());
+                let path = Some(db_path.to_string_lossy().into_owned());
+                return Ok((db, DatabaseLocation { tier, path }));
+            }
+            Err(e) => log::warn!("Could not open {:?} database: {}", tier, e),
+        }
+    }
+
+    log::warn!("Falling back to an in-memory database; nothing will be saved");
+    let conn = Connection::open_in_memory()?;
+    init_schema(&conn)?;
+
+    Ok((
+        DbPool::single(conn),
+        DatabaseLocation {
+            tier: DatabaseTier::InMemory,
+            path: None,
+        },
+    ))
+}
+
+fn open_in_dir(dir: &Path) -> Result<(DbPool, PathBuf), String> {
+    std::fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+    let db_path = dir.join("xpatch.db");
+    let db = open_file(&db_path)?;
+    Ok((db, db_path))
+}
 
-    let conn = Connection::open(db_path)?;
+fn open_file(db_path: &Path) -> Result<DbPool, String> {
+    let conn = Connection::open(db_path).map_err(|e| e.to_string())?;
+    init_schema(&conn).map_err(|e| e.to_string())?;
+    DbPool::open(conn, db_path).map_err(|e| e.to_string())
+}
+
+// Schema changes applied in order after the base tables exist. A migration's
+// position in this list plus one is the schema version it brings the database to.
+const MIGRATIONS: &[&str] = &[
+    // 1: per-document base selection strategy (JSON-encoded BaseStrategy)
+    "ALTER TABLE documents ADD COLUMN base_strategy TEXT",
+    // 2: hash of each version's uncompressed content, used to detect reverts
+    "ALTER TABLE patches ADD COLUMN content_hash TEXT;
+     CREATE INDEX IF NOT EXISTS idx_patches_doc_hash ON patches(document_uuid, content_hash);",
+    // 3: MIME type telling the UI how to render a document
+    "ALTER TABLE documents ADD COLUMN content_type TEXT NOT NULL DEFAULT 'text/plain'",
+    // 4: CRC32 of each delta, verified before decoding
+    "ALTER TABLE patches ADD COLUMN delta_checksum INTEGER",
+    // 5: soft-delete marker; trashed documents are hidden until restored or purged
+    "ALTER TABLE documents ADD COLUMN deleted_at INTEGER",
+    // 6: named points on a document's timeline, unique per document
+    "CREATE TABLE bookmarks (
+        doc_uuid TEXT NOT NULL,
+        name TEXT NOT NULL,
+        timestamp INTEGER NOT NULL,
+        PRIMARY KEY (doc_uuid, name),
+        FOREIGN KEY (doc_uuid) REFERENCES documents(uuid)
+     )",
+    // 7: optional cap on stored versions; older ones are pruned on each new patch
+    "ALTER TABLE documents ADD COLUMN max_versions INTEGER",
+    // 8: denormalized latest version, kept in step by `refresh_head`
+    "ALTER TABLE documents ADD COLUMN head_timestamp INTEGER;
+     ALTER TABLE documents ADD COLUMN head_hash TEXT;
+     UPDATE documents SET
+        head_timestamp = (SELECT timestamp FROM patches WHERE document_uuid = documents.uuid
+                          ORDER BY timestamp DESC LIMIT 1),
+        head_hash = (SELECT content_hash FROM patches WHERE document_uuid = documents.uuid
+                     ORDER BY timestamp DESC LIMIT 1);",
+    // 9: free-form key/value pairs apps attach to a document (tags, color, folder)
+    "CREATE TABLE document_metadata (
+        doc_uuid TEXT NOT NULL,
+        key TEXT NOT NULL,
+        value TEXT NOT NULL,
+        PRIMARY KEY (doc_uuid, key),
+        FOREIGN KEY (doc_uuid) REFERENCES documents(uuid)
+     )",
+    // 10: optional cap on a single version's size, checked before encoding
+    "ALTER TABLE documents ADD COLUMN max_content_bytes INTEGER",
+    // 11: files kept alongside a document (e.g. images its markdown references);
+    // `content` is zstd-compressed when `compressed` is set, `size` is the original
+    "CREATE TABLE attachments (
+        uuid TEXT PRIMARY KEY,
+        document_uuid TEXT NOT NULL,
+        name TEXT NOT NULL,
+        content BLOB NOT NULL,
+        compressed INTEGER NOT NULL,
+        size INTEGER NOT NULL,
+        created_at INTEGER NOT NULL,
+        FOREIGN KEY (document_uuid) REFERENCES documents(uuid)
+     );
+     CREATE INDEX idx_attachments_doc ON attachments(document_uuid);",
+    // 12: line endings (JSON-encoded EolStyle) the latest save normalized to
+    "ALTER TABLE documents ADD COLUMN eol_style TEXT",
+    // 13: zstd dictionaries trained on a document's history. A document uses its
+    // `dictionary_id` for new saves; a patch whose delta body was compressed with
+    // one records it, and keeps it even after the document retrains
+    "CREATE TABLE zstd_dictionaries (
+        id INTEGER PRIMARY KEY,
+        content BLOB NOT NULL,
+        created_at INTEGER NOT NULL
+     );
+     ALTER TABLE documents ADD COLUMN dictionary_id INTEGER;
+     ALTER TABLE patches ADD COLUMN dictionary_id INTEGER;",
+    // 14: optional cap on how many deltas a new version may depend on; a save that
+    // would go deeper is stored as a snapshot instead
+    "ALTER TABLE documents ADD COLUMN max_chain_depth INTEGER",
+];
+
+pub fn init_schema(conn: &Connection) -> rusqlite::Result<()> {
+    // SQLite leaves foreign keys unenforced unless asked, per connection. Enabling
+    // them does not check existing rows, so a database that already has orphans
+    // still opens; `find_orphan_patches` reports those.
+    conn.pragma_update(None, "foreign_keys", "ON")?;
 
     conn.execute(
         "CREATE TABLE IF NOT EXISTS documents (
@@ -70,373 +831,10230 @@ pub fn init_database(app: &tauri::App) -> Result<Connection, Box<dyn std::error:
         [],
     )?;
 
-    Ok(conn)
-}
+    apply_migrations(conn)?;
+
+    Ok(())
+}
+
+/// Brings the database up to the latest schema version, returning that version.
+fn apply_migrations(conn: &Connection) -> rusqlite::Result<i64> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS schema_version (version INTEGER NOT NULL)",
+        [],
+    )?;
+
+    let current = schema_version(conn)?;
+
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(current as usize) {
+        let version = index as i64 + 1;
+        let tx = conn.unchecked_transaction()?;
+        tx.execute_batch(migration)?;
+        tx.execute("DELETE FROM schema_version", [])?;
+        tx.execute("INSERT INTO schema_version (version) VALUES (?)", params![version])?;
+        tx.commit()?;
+    }
+
+    Ok(current.max(MIGRATIONS.len() as i64))
+}
+
+fn schema_version(conn: &Connection) -> rusqlite::Result<i64> {
+    conn.query_row(
+        "SELECT COALESCE(MAX(version), 0) FROM schema_version",
+        [],
+        |row| row.get(0),
+    )
+}
+
+// Reconstruction
+
+// Tries up to this many previous versions when choosing a base to encode against
+const MAX_BASE_DEPTH: usize = 16;
+// Lets xpatch try zstd on top of each delta. It keeps the compressed form only
+// when that is smaller and records which one it chose in the delta header, so
+// incompressible content is never bloated and decoding needs no flag.
+const ENABLE_ZSTD: bool = true;
+
+// A tag reaching past the start of the chain decodes against empty content, so
+// snapshots are stored with a tag no real chain position can reach
+const SNAPSHOT_TAG: usize = u32::MAX as usize;
+
+// Knobs for a single reconstruction; the default replays with no extras
+#[derive(Default)]
+struct ReconstructOptions<'a> {
+    // Checked between patches; aborts with an error once set
+    cancel: Option<&'a AtomicBool>,
+    // Checked between patches; aborts with a timeout error once passed
+    deadline: Option<Instant>,
+    // Skip delta checksum verification to salvage what decodes
+    repair_mode: bool,
+    // Called with (done, total) every PROGRESS_EVERY patches and at the end
+    progress: Option<&'a dyn Fn(usize, usize)>,
+    // Still read cached versions but insert none, so whole-history scans don't
+    // fill the cache with versions nobody is viewing
+    skip_cache: bool,
+    // When loading text, replace invalid UTF-8 with U+FFFD instead of failing
+    lossy: bool,
+    // Receives query and decode timings plus cache hit counts once replay ends
+    profile: Option<&'a RefCell<ProfileReport>>,
+}
+
+const PROGRESS_EVERY: usize = 64;
+
+#[derive(Debug, Clone, Serialize)]
+struct ReconstructionProgress<'a> {
+    doc_uuid: &'a str,
+    done: usize,
+    total: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct DocumentChanged<'a> {
+    doc_uuid: &'a str,
+    kind: DocumentChangeKind,
+    // The newest saved version for `Edited`, otherwise None
+    timestamp: Option<i64>,
+}
+
+/// Tells every window that a document changed, so open views can refresh.
+fn emit_document_changed(
+    app: &AppHandle,
+    doc_uuid: &str,
+    kind: DocumentChangeKind,
+    timestamp: Option<i64>,
+) {
+    emit_best_effort(
+        app,
+        "document-changed",
+        DocumentChanged {
+            doc_uuid,
+            kind,
+            timestamp,
+        },
+    );
+}
+
+// Events are notifications; a missing listener must not fail the operation behind them
+fn emit_best_effort<S: Serialize + Clone>(app: &AppHandle, event: &str, payload: S) {
+    let _ = app.emit(event, payload);
+}
+
+/// Progress callback that forwards to the frontend as `reconstruction-progress`.
+fn emit_progress<'a>(app: &'a AppHandle, doc_uuid: &'a str) -> impl Fn(usize, usize) + 'a {
+    move |done, total| {
+        emit_best_effort(
+            app,
+            "reconstruction-progress",
+            ReconstructionProgress {
+                doc_uuid,
+                done,
+                total,
+            },
+        );
+    }
+}
+
+/// Replays a document's patches up to `timestamp` and returns every version
+/// in timestamp order alongside its reconstructed content.
+fn reconstruct_versions(
+    db: &Connection,
+    cache: &ContentCache,
+    doc_uuid: &str,
+    timestamp: i64,
+) -> Result<Vec<(Patch, Vec<u8>)>, String> {
+    reconstruct_versions_with(db, cache, doc_uuid, timestamp, &ReconstructOptions::default())
+}
+
+fn reconstruct_versions_with(
+    db: &Connection,
+    cache: &ContentCache,
+    doc_uuid: &str,
+    timestamp: i64,
+    options: &ReconstructOptions,
+) -> Result<Vec<(Patch, Vec<u8>)>, String> {
+    let query_started = Instant::now();
+    let mut stmt = db
+        .prepare(
+            "SELECT uuid, timestamp, delta, delta_checksum, dictionary_id
+             FROM patches
+             WHERE document_uuid = ? AND timestamp <= ?
+             ORDER BY timestamp ASC",
+        )
+        .map_err(|e| e.to_string())?;
+
+    let patches: Vec<(Patch, Option<i64>, Option<i64>)> = stmt
+        .query_map(params![doc_uuid, timestamp], |row| {
+            let patch = Patch {
+                uuid: row.get(0)?,
+                document_uuid: doc_uuid.to_string(),
+                timestamp: row.get(1)?,
+                delta: row.get(2)?,
+            };
+            Ok((patch, row.get(3)?, row.get(4)?))
+        })
+        .map_err(|e| e.to_string())?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| e.to_string())?;
+    let query_time = query_started.elapsed();
+
+    let decode_started = Instant::now();
+    let mut cache_hits = 0;
+    let total = patches.len();
+    let mut versions: Vec<(Patch, Vec<u8>)> = Vec::with_capacity(total);
+    let mut dictionaries: HashMap<i64, Vec<u8>> = HashMap::new();
+    let report_progress = |done: usize| {
+        if let Some(progress) = options.progress {
+            if done.is_multiple_of(PROGRESS_EVERY) || done == total {
+                progress(done, total);
+            }
+        }
+    };
+
+    for (patch, checksum, dictionary_id) in patches {
+        if options.cancel.is_some_and(|cancel| cancel.load(Ordering::Relaxed)) {
+            return Err("Reconstruction cancelled".to_string());
+        }
+        if options.deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return Err(RECONSTRUCTION_TIMED_OUT.to_string());
+        }
+
+        let cache_key = (doc_uuid.to_string(), patch.uuid.clone());
+
+        // Check cache first; it is locked per lookup so other documents keep going
+        if let Some(content) = cache.get(&cache_key) {
+            cache_hits += 1;
+            versions.push((patch, content));
+            report_progress(versions.len());
+            continue;
+        }
+
+        // tag N means use version N+1 steps back (tag 0 is the previous version)
+        let pos = versions.len();
+        let base_content = match base_position(&patch, pos) {
+            Some(base) => versions[base].1.as_slice(),
+            None => &[],
+        };
+
+        let dictionary = match dictionary_id {
+            Some(id) => {
+                if let std::collections::hash_map::Entry::Vacant(entry) = dictionaries.entry(id) {
+                    entry.insert(load_dictionary(db, id)?);
+                }
+                dictionaries.get(&id).map(Vec::as_slice)
+            }
+            None => None,
+        };
+
+        let current_content =
+            decode_patch(&patch, checksum, dictionary, base_content, options.repair_mode)?;
+
+        if !options.skip_cache {
+            cache.insert(cache_key, current_content.clone());
+        }
+        versions.push((patch, current_content));
+        report_progress(versions.len());
+    }
+
+    if let Some(profile) = options.profile {
+        let mut profile = profile.borrow_mut();
+        profile.query_us = query_time.as_micros() as u64;
+        profile.decode_us = decode_started.elapsed().as_micros() as u64;
+        profile.patch_count = total;
+        profile.cache_hits = cache_hits;
+        profile.cache_misses = total - cache_hits;
+    }
+
+    Ok(versions)
+}
+
+/// Position of the version the patch at `pos` decodes against, or None when it
+/// decodes against empty content.
+fn base_position(patch: &Patch, pos: usize) -> Option<usize> {
+    // Extract the tag from the delta to know which base to use
+    let tag = match &patch.delta {
+        Some(delta) => xpatch::get_tag(delta).unwrap_or(0),
+        None => 0,
+    };
+    (pos > tag).then(|| pos - tag - 1)
+}
+
+/// Applies a patch's delta to `base`, first checking it against its stored
+/// checksum unless `repair_mode` is set. `dictionary` is the one the patch's
+/// delta body was compressed with, if any.
+fn decode_patch(
+    patch: &Patch,
+    checksum: Option<i64>,
+    dictionary: Option<&[u8]>,
+    base: &[u8],
+    repair_mode: bool,
+) -> Result<Vec<u8>, String> {
+    let Some(delta) = &patch.delta else {
+        return Ok(base.to_vec());
+    };
+
+    // Refuse to decode a delta that no longer matches its checksum
+    if let (Some(checksum), false) = (checksum, repair_mode) {
+        if delta_checksum(delta) != checksum {
+            return Err(format!("Corrupt delta in patch {}", patch.uuid));
+        }
+    }
+
+    match dictionary {
+        Some(dictionary) => safe_decode(base, &expand_delta(delta, dictionary)?),
+        None => safe_decode(base, delta),
+    }
+}
+
+fn load_dictionary(db: &Connection, dictionary_id: i64) -> Result<Vec<u8>, String> {
+    db.query_row(
+        "SELECT content FROM zstd_dictionaries WHERE id = ?",
+        params![dictionary_id],
+        |row| row.get(0),
+    )
+    .optional()
+    .map_err(|e| e.to_string())?
+    .ok_or_else(|| format!("Dictionary not found: {}", dictionary_id))
+}
+
+// Dictionary frames drop what the patch row already records, to save bytes
+fn dictionary_compressor(dictionary: &[u8]) -> std::io::Result<zstd::bulk::Compressor<'_>> {
+    let mut compressor =
+        zstd::bulk::Compressor::with_dictionary(DICTIONARY_ZSTD_LEVEL, dictionary)?;
+    compressor.include_checksum(false)?;
+    compressor.include_dictid(false)?;
+    Ok(compressor)
+}
+
+/// The delta with its body (everything after the xpatch header) compressed with
+/// `dictionary`, or None when that would not make it smaller. The header stays
+/// as is, so the tag can still be read without the dictionary.
+fn compress_delta(delta: &[u8], dictionary: &[u8]) -> Result<Option<Vec<u8>>, String> {
+    let (_, _, header_len) =
+        xpatch::delta::decode_header(delta).map_err(|e| format!("Delta decode error: {}", e))?;
+    let body = dictionary_compressor(dictionary)
+        .and_then(|mut compressor| compressor.compress(&delta[header_len..]))
+        .map_err(|e| e.to_string())?;
+
+    if header_len + body.len() >= delta.len() {
+        return Ok(None);
+    }
+    let mut compressed = delta[..header_len].to_vec();
+    compressed.extend_from_slice(&body);
+    Ok(Some(compressed))
+}
+
+/// Undoes `compress_delta`, refusing bodies over `MAX_DECODED_BYTES`.
+fn expand_delta(delta: &[u8], dictionary: &[u8]) -> Result<Vec<u8>, String> {
+    use std::io::Read;
+
+    let (_, _, header_len) =
+        xpatch::delta::decode_header(delta).map_err(|e| format!("Delta decode error: {}", e))?;
+    let decoder = zstd::stream::Decoder::with_dictionary(&delta[header_len..], dictionary)
+        .map_err(|e| format!("Delta decode error: {}", e))?;
+
+    let mut expanded = delta[..header_len].to_vec();
+    decoder
+        .take(MAX_DECODED_BYTES as u64 + 1)
+        .read_to_end(&mut expanded)
+        .map_err(|e| format!("Delta decode error: {}", e))?;
+    if expanded.len() - header_len > MAX_DECODED_BYTES {
+        return Err(DECODED_TOO_LARGE.to_string());
+    }
+    Ok(expanded)
+}
+
+// Largest version a single delta may decode to; anything bigger is treated as corrupt
+const MAX_DECODED_BYTES: usize = 256 * 1024 * 1024;
+const DECODED_TOO_LARGE: &str = "Delta decode error: output exceeds size limit";
+
+/// `xpatch::decode` for deltas that may be crafted or corrupt: a panic inside
+/// xpatch becomes an error and no result over `MAX_DECODED_BYTES` is returned.
+/// Counts that xpatch allocates for up front are checked before decoding;
+/// zstd-compressed deltas can only be measured once decompressed.
+fn safe_decode(base: &[u8], delta: &[u8]) -> Result<Vec<u8>, String> {
+    let decoded = std::panic::catch_unwind(|| {
+        check_delta_counts(base, delta)?;
+        xpatch::decode(base, delta).map_err(|e| format!("Delta decode error: {:?}", e))
+    })
+    .map_err(|_| "Delta decode error: malformed delta".to_string())??;
+
+    if decoded.len() > MAX_DECODED_BYTES {
+        return Err(DECODED_TOO_LARGE.to_string());
+    }
+    Ok(decoded)
+}
+
+/// Rejects token and repeat deltas whose counts would make xpatch reserve more
+/// than the delta could possibly describe, or more than `MAX_DECODED_BYTES`.
+fn check_delta_counts(base: &[u8], delta: &[u8]) -> Result<(), String> {
+    // Anything else wrong with the header is left for xpatch to report
+    let Ok((algorithm, _, header_len)) = xpatch::delta::decode_header(delta) else {
+        return Ok(());
+    };
+    let body = &delta[header_len..];
+    let malformed = || "Delta decode error: malformed delta".to_string();
+
+    // Every counted layout starts with the insert position
+    let mut offset = 0;
+    read_varint(body, &mut offset).ok_or_else(malformed)?;
+
+    let inserted = match algorithm {
+        // Each token index takes at least one byte
+        xpatch::Algorithm::Tokens => {
+            let token_count = read_varint(body, &mut offset).ok_or_else(malformed)?;
+            if token_count > body.len() - offset {
+                return Err(malformed());
+            }
+            return Ok(());
+        }
+        xpatch::Algorithm::RepeatChars => {
+            let repeat_count = read_varint(body, &mut offset).ok_or_else(malformed)?;
+            repeat_count.checked_mul(body.len() - offset)
+        }
+        xpatch::Algorithm::RepeatTokens => {
+            let repeat_count = read_varint(body, &mut offset).ok_or_else(malformed)?;
+            let token_count = read_varint(body, &mut offset).ok_or_else(malformed)?;
+            if token_count > body.len() - offset {
+                return Err(malformed());
+            }
+            let tokens = (0..token_count)
+                .map(|_| read_varint(body, &mut offset))
+                .collect::<Option<Vec<_>>>()
+                .ok_or_else(malformed)?;
+            let pattern = xpatch::tokenizer::decode(&tokens).map_err(|_| malformed())?;
+            repeat_count.checked_mul(pattern.len())
+        }
+        _ => return Ok(()),
+    };
+
+    match inserted.and_then(|inserted| inserted.checked_add(base.len())) {
+        Some(total) if total <= MAX_DECODED_BYTES => Ok(()),
+        _ => Err(DECODED_TOO_LARGE.to_string()),
+    }
+}
+
+/// Reads the varint at `offset`, moving past it, or None if the bytes ran out.
+fn read_varint(bytes: &[u8], offset: &mut usize) -> Option<usize> {
+    let rest = bytes.get(*offset..).filter(|rest| !rest.is_empty())?;
+    let (value, len) = xpatch::varint::decode_varint(rest);
+    *offset += len;
+    Some(value)
+}
+
+// Stop searching once a delta is at most 1% of the content (never below this floor)
+const GOOD_ENOUGH_DIVISOR: usize = 100;
+const GOOD_ENOUGH_MIN_BYTES: usize = 4;
+
+fn good_enough_bytes(content_len: usize) -> usize {
+    (content_len / GOOD_ENOUGH_DIVISOR).max(GOOD_ENOUGH_MIN_BYTES)
+}
+
+/// Content of the version at or before `timestamp`, or None when there is none.
+fn content_at(
+    db: &Connection,
+    cache: &ContentCache,
+    doc_uuid: &str,
+    timestamp: i64,
+) -> Result<Option<Vec<u8>>, String> {
+    let versions = reconstruct_versions(db, cache, doc_uuid, timestamp)?;
+    Ok(versions.into_iter().next_back().map(|(_, content)| content))
+}
+
+/// Encodes `new_content` against each candidate base (newest first, so the
+/// index is the tag) and returns the tag and delta of the smallest result.
+/// Bases are pulled lazily and the search stops at the first delta no larger
+/// than `good_enough_bytes`, so later candidates are never loaded.
+/// `on_candidate` sees the tag and delta size of every encoding tried.
+fn select_base<B: AsRef<[u8]>>(
+    bases: impl IntoIterator<Item = Result<B, String>>,
+    new_content: &[u8],
+    enable_zstd: bool,
+    good_enough_bytes: usize,
+    mut on_candidate: impl FnMut(usize, usize),
+) -> Result<(usize, Vec<u8>), String> {
+    let mut best_tag = 0;
+    let mut best_delta: Option<Vec<u8>> = None;
+
+    for (tag, base) in bases.into_iter().enumerate() {
+        let delta = xpatch::encode(tag, base?.as_ref(), new_content, enable_zstd);
+        on_candidate(tag, delta.len());
+
+        // Check if this is the best so far
+        if best_delta.as_ref().is_none_or(|best| delta.len() < best.len()) {
+            best_delta = Some(delta);
+            best_tag = tag;
+        }
+
+        if best_delta.as_ref().is_some_and(|best| best.len() <= good_enough_bytes) {
+            break;
+        }
+    }
+
+    match best_delta {
+        Some(delta) => Ok((best_tag, delta)),
+        // No previous versions, encode against empty
+        None => {
+            let delta = xpatch::encode(0, &[], new_content, enable_zstd);
+            on_candidate(0, delta.len());
+            Ok((0, delta))
+        }
+    }
+}
+
+/// How many previous versions `strategy` searches for the version at `pos`, or
+/// None when that version must be stored as a standalone snapshot.
+fn strategy_depth(strategy: BaseStrategy, pos: usize) -> Option<usize> {
+    match strategy {
+        BaseStrategy::PreviousOnly => Some(1),
+        BaseStrategy::OptimalDepth(depth) => Some(depth),
+        BaseStrategy::SnapshotEvery(every) if pos.is_multiple_of(every) => None,
+        BaseStrategy::SnapshotEvery(_) => Some(MAX_BASE_DEPTH),
+    }
+}
+
+// Whether a delta at `pos` decodes against empty content rather than a version
+fn is_snapshot(tag: usize, pos: usize) -> bool {
+    tag >= pos
+}
+
+/// Encodes the version at `pos` against the versions before it per `strategy`.
+/// `depths` holds the chain depth of every earlier version; a base that would
+/// take this one past `max_chain_depth` gives way to a snapshot.
+fn encode_version(
+    versions: &[(Patch, Vec<u8>)],
+    depths: &[usize],
+    pos: usize,
+    strategy: BaseStrategy,
+    max_chain_depth: Option<usize>,
+    good_enough_bytes: usize,
+) -> (usize, Vec<u8>) {
+    let content = &versions[pos].1;
+    let snapshot = || (SNAPSHOT_TAG, xpatch::encode(SNAPSHOT_TAG, &[], content, ENABLE_ZSTD));
+
+    let Some(depth) = strategy_depth(strategy, pos) else {
+        return snapshot();
+    };
+
+    let bases = versions[pos.saturating_sub(depth)..pos]
+        .iter()
+        .rev()
+        .map(|(_, base)| Ok(base.as_slice()));
+    let (tag, delta) = select_base(bases, content, ENABLE_ZSTD, good_enough_bytes, |_, _| {})
+        .expect("in-memory bases cannot fail to load");
+    if max_chain_depth.is_some_and(|max| depth_with_tag(depths, pos, tag) > max) {
+        return snapshot();
+    }
+    (tag, delta)
+}
+
+/// Chain depth of every version as its stored delta leaves it, counted the way
+/// `chain_depth` does.
+fn stored_chain_depths(versions: &[(Patch, Vec<u8>)]) -> Vec<usize> {
+    let mut depths: Vec<usize> = Vec::with_capacity(versions.len());
+    for (pos, (patch, _)) in versions.iter().enumerate() {
+        let base_depth = base_position(patch, pos).map_or(0, |base| depths[base]);
+        depths.push(base_depth + 1);
+    }
+    depths
+}
+
+// Chain depth of the version at `pos` once encoded with `tag`
+fn depth_with_tag(depths: &[usize], pos: usize, tag: usize) -> usize {
+    if is_snapshot(tag, pos) {
+        1
+    } else {
+        depths[pos - tag - 1] + 1
+    }
+}
+
+/// Re-encodes every version from `from` onward against the versions that now
+/// precede it, so the chain still reconstructs after earlier patches were removed.
+/// Returns the new `(patch_uuid, delta)` pairs.
+fn reencode_chain(
+    versions: &[(Patch, Vec<u8>)],
+    from: usize,
+    strategy: BaseStrategy,
+    max_chain_depth: Option<usize>,
+) -> Vec<(String, Vec<u8>)> {
+    let mut depths = stored_chain_depths(&versions[..from]);
+    let mut rewritten = Vec::with_capacity(versions.len() - from);
+    for pos in from..versions.len() {
+        let good_enough = good_enough_bytes(versions[pos].1.len());
+        let (tag, delta) =
+            encode_version(versions, &depths, pos, strategy, max_chain_depth, good_enough);
+        depths.push(depth_with_tag(&depths, pos, tag));
+        rewritten.push((versions[pos].0.uuid.clone(), delta));
+    }
+    rewritten
+}
+
+/// Works out which patches compaction would rewrite. Every version keeps its
+/// content, so each patch is re-encoded independently with an exhaustive search
+/// and only replaced when smaller, except where the strategy demands a snapshot
+/// or earlier rewrites left the patch deeper than `max_chain_depth`.
+fn plan_compaction(
+    versions: &[(Patch, Vec<u8>)],
+    strategy: BaseStrategy,
+    max_chain_depth: Option<usize>,
+) -> (Vec<(String, Vec<u8>)>, CompactionPreview) {
+    let mut rewritten = Vec::new();
+    let mut depths = Vec::with_capacity(versions.len());
+    let mut preview = CompactionPreview {
+        delta_bytes_before: 0,
+        delta_bytes_after: 0,
+        snapshots_added: 0,
+    };
+
+    for (pos, (patch, _)) in versions.iter().enumerate() {
+        let old_delta = patch.delta.as_deref().unwrap_or_default();
+        let old_tag = xpatch::get_tag(old_delta).unwrap_or(0);
+        let (tag, delta) = encode_version(versions, &depths, pos, strategy, max_chain_depth, 0);
+
+        let needs_snapshot = is_snapshot(tag, pos) && !is_snapshot(old_tag, pos);
+        let too_deep =
+            max_chain_depth.is_some_and(|max| depth_with_tag(&depths, pos, old_tag) > max);
+        let replace = needs_snapshot || too_deep || delta.len() < old_delta.len();
+        depths.push(depth_with_tag(&depths, pos, if replace { tag } else { old_tag }));
+
+        preview.delta_bytes_before += old_delta.len() as i64;
+        if replace {
+            preview.delta_bytes_after += delta.len() as i64;
+            if needs_snapshot && pos > 0 {
+                preview.snapshots_added += 1;
+            }
+            rewritten.push((patch.uuid.clone(), delta));
+        } else {
+            preview.delta_bytes_after += old_delta.len() as i64;
+        }
+    }
+
+    (rewritten, preview)
+}
+
+/// Runs `f` inside a deferred read transaction so every query it makes sees the
+/// same snapshot of the database.
+fn with_read_transaction<T>(
+    db: &Connection,
+    f: impl FnOnce(&Connection) -> Result<T, String>,
+) -> Result<T, String> {
+    let tx = db.unchecked_transaction().map_err(|e| e.to_string())?;
+    let result = f(&tx)?;
+    tx.commit().map_err(|e| e.to_string())?;
+    Ok(result)
+}
+
+// Diff
+
+// Cap on the LCS table `diff_lines` builds, 64 MiB of u32 cells
+const MAX_DIFF_CELLS: usize = 16 * 1024 * 1024;
+
+/// Line diff of `old` against `new`: common leading and trailing lines are
+/// trimmed, then the middle is aligned by longest common subsequence. A middle
+/// too large for `MAX_DIFF_CELLS` is reported as replaced wholesale instead.
+fn diff_lines(old: &str, new: &str) -> Vec<DiffOp> {
+    let old: Vec<&str> = old.split_inclusive('\n').collect();
+    let new: Vec<&str> = new.split_inclusive('\n').collect();
+
+    let prefix = old.iter().zip(&new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let old_mid = &old[prefix..old.len() - suffix];
+    let new_mid = &new[prefix..new.len() - suffix];
+
+    let mut ops: Vec<DiffOp> = Vec::new();
+    let mut push = |op: DiffOp| match (ops.last_mut(), op) {
+        (Some(DiffOp::Equal(run)), DiffOp::Equal(line))
+        | (Some(DiffOp::Insert(run)), DiffOp::Insert(line))
+        | (Some(DiffOp::Delete(run)), DiffOp::Delete(line)) => run.push_str(&line),
+        (_, op) => ops.push(op),
+    };
+
+    for line in &old[..prefix] {
+        push(DiffOp::Equal(line.to_string()));
+    }
+    let width = new_mid.len() + 1;
+    if (old_mid.len() + 1).saturating_mul(width) > MAX_DIFF_CELLS {
+        // The order an alignment without common lines would give
+        for line in new_mid {
+            push(DiffOp::Insert(line.to_string()));
+        }
+        for line in old_mid {
+            push(DiffOp::Delete(line.to_string()));
+        }
+    } else {
+        let lcs = lcs_table(old_mid, new_mid);
+        let (mut i, mut j) = (0, 0);
+        while i < old_mid.len() || j < new_mid.len() {
+            if i < old_mid.len() && j < new_mid.len() && old_mid[i] == new_mid[j] {
+                push(DiffOp::Equal(old_mid[i].to_string()));
+                i += 1;
+                j += 1;
+            } else if j < new_mid.len()
+                && (i == old_mid.len() || lcs[i * width + j + 1] >= lcs[(i + 1) * width + j])
+            {
+                push(DiffOp::Insert(new_mid[j].to_string()));
+                j += 1;
+            } else {
+                push(DiffOp::Delete(old_mid[i].to_string()));
+                i += 1;
+            }
+        }
+    }
+    for line in &old[old.len() - suffix..] {
+        push(DiffOp::Equal(line.to_string()));
+    }
+
+    ops
+}
+
+// lcs[i * (new.len() + 1) + j] = length of the LCS of old[i..] and new[j..]
+fn lcs_table(old: &[&str], new: &[&str]) -> Vec<u32> {
+    let width = new.len() + 1;
+    let mut lcs = vec![0u32; (old.len() + 1) * width];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i * width + j] = if old[i] == new[j] {
+                lcs[(i + 1) * width + j + 1] + 1
+            } else {
+                lcs[(i + 1) * width + j].max(lcs[i * width + j + 1])
+            };
+        }
+    }
+    lcs
+}
+
+// Commands
+
+// Runs off the main thread so `cancel_reconstruction` can be handled meanwhile.
+// Passing a `request_id` makes the reconstruction cancellable under that id;
+// `repair_mode` skips checksum verification for emergency recovery, and `lossy`
+// shows invalid UTF-8 as U+FFFD rather than failing the load. Any timestamp is
+// accepted: one before the first patch (zero or negative included) loads as the
+// empty document, since no version exists at or before it yet.
+#[tauri::command(async)]
+fn load_document_at_timestamp(
+    state: State<AppState>,
+    doc_uuid: String,
+    timestamp: i64,
+    request_id: Option<String>,
+    repair_mode: Option<bool>,
+    lossy: Option<bool>,
+) -> Result<String, String> {
+    let token = request_id
+        .as_ref()
+        .map(|request_id| register_reconstruction(&state, request_id));
+
+    let content = {
+        let db = state.read_db();
+        let options = ReconstructOptions {
+            cancel: token.as_deref(),
+            repair_mode: repair_mode.unwrap_or(false),
+            lossy: lossy.unwrap_or(false),
+            ..Default::default()
+        };
+        load_content(&db, &state.cache, &doc_uuid, timestamp, &options)
+    };
+
+    if let (Some(request_id), Some(token)) = (&request_id, &token) {
+        unregister_reconstruction(&state, request_id, token);
+    }
+    content
+}
+
+/// Makes a reconstruction cancellable under `request_id`. A later load reusing
+/// the id takes it over; `cancel_reconstruction` then stops that one.
+fn register_reconstruction(state: &AppState, request_id: &str) -> CancellationToken {
+    let token = CancellationToken::default();
+    state
+        .lock_reconstructions()
+        .insert(request_id.to_string(), token.clone());
+    token
+}
+
+/// Forgets `token` once its load is done, unless a later load reusing the id
+/// has taken the entry over.
+fn unregister_reconstruction(state: &AppState, request_id: &str, token: &CancellationToken) {
+    let mut reconstructions = state.lock_reconstructions();
+    if reconstructions
+        .get(request_id)
+        .is_some_and(|current| Arc::ptr_eq(current, token))
+    {
+        reconstructions.remove(request_id);
+    }
+}
+
+const RECONSTRUCTION_TIMED_OUT: &str = "Reconstruction timed out";
+
+/// Like `load_document_at_timestamp`, but gives up with "Reconstruction timed
+/// out" once `budget_ms` has passed, so a pathologically long chain cannot
+/// freeze the app. Versions decoded before the timeout stay cached, so a retry
+/// picks up where this one stopped.
+#[tauri::command(async)]
+fn load_with_timeout(
+    state: State<AppState>,
+    doc_uuid: String,
+    timestamp: i64,
+    budget_ms: u64,
+) -> Result<String, String> {
+    let db = state.read_db();
+    let options = ReconstructOptions {
+        deadline: deadline_after(budget_ms),
+        ..Default::default()
+    };
+    load_content(&db, &state.cache, &doc_uuid, timestamp, &options)
+}
+
+// No deadline when the budget reaches past what `Instant` can represent
+fn deadline_after(budget_ms: u64) -> Option<Instant> {
+    Instant::now().checked_add(Duration::from_millis(budget_ms))
+}
+
+/// Text of the version at or before `timestamp`, empty when there is none.
+fn load_content(
+    db: &Connection,
+    cache: &ContentCache,
+    doc_uuid: &str,
+    timestamp: i64,
+    options: &ReconstructOptions,
+) -> Result<String, String> {
+    let versions = reconstruct_versions_with(db, cache, doc_uuid, timestamp, options)?;
+
+    // Return the content at the requested timestamp
+    let final_content = match versions.into_iter().next_back() {
+        Some((_, content)) => content,
+        None => return Ok("".to_string()),
+    };
+
+    if options.lossy {
+        return Ok(String::from_utf8_lossy(&final_content).into_owned());
+    }
+    String::from_utf8(final_content).map_err(|e| format!("UTF-8 conversion error: {}", e))
+}
+
+/// Loads the version current at an RFC 3339 date such as
+/// `2024-05-01T09:30:00+02:00`; the offset is honoured, so the same instant in
+/// any timezone gives the same version. Dates before the first patch yield "".
+#[tauri::command(async)]
+fn load_document_at_date(
+    state: State<AppState>,
+    doc_uuid: String,
+    rfc3339: String,
+) -> Result<String, String> {
+    let timestamp = date_to_timestamp(&rfc3339)?;
+    load_document_at_timestamp(state, doc_uuid, timestamp, None, None, None)
+}
+
+fn date_to_timestamp(rfc3339: &str) -> Result<i64, String> {
+    let date = chrono::DateTime::parse_from_rfc3339(rfc3339)
+        .map_err(|e| format!("Invalid date '{}': {}", rfc3339, e))?;
+    Ok(date.timestamp_millis())
+}
+
+/// Steps forward to the first version after `current_timestamp`. When the
+/// version its delta was encoded against is cached (it usually is, having just
+/// been viewed) only that one delta is decoded; otherwise the chain is replayed.
+#[tauri::command]
+fn load_next_version(
+    state: State<AppState>,
+    doc_uuid: String,
+    current_timestamp: i64,
+) -> Result<Option<(i64, String)>, String> {
+    next_version(&state.read_db(), &state.cache, &doc_uuid, current_timestamp)
+}
+
+fn next_version(
+    db: &Connection,
+    cache: &ContentCache,
+    doc_uuid: &str,
+    current_timestamp: i64,
+) -> Result<Option<(i64, String)>, String> {
+    let next = db
+        .query_row(
+            "SELECT uuid, timestamp, delta, delta_checksum, dictionary_id,
+                    (SELECT COUNT(*) FROM patches WHERE document_uuid = ?1 AND timestamp <= ?2)
+             FROM patches
+             WHERE document_uuid = ?1 AND timestamp > ?2
+             ORDER BY timestamp ASC
+             LIMIT 1",
+            params![doc_uuid, current_timestamp],
+            |row| {
+                let patch = Patch {
+                    uuid: row.get(0)?,
+                    document_uuid: doc_uuid.to_string(),
+                    timestamp: row.get(1)?,
+                    delta: row.get(2)?,
+                };
+                let checksum: Option<i64> = row.get(3)?;
+                let dictionary_id: Option<i64> = row.get(4)?;
+                let pos: i64 = row.get(5)?;
+                Ok((patch, checksum, dictionary_id, pos as usize))
+            },
+        )
+        .optional()
+        .map_err(|e| e.to_string())?;
+    let Some((patch, checksum, dictionary_id, pos)) = next else {
+        return Ok(None);
+    };
+
+    let cache_key = (doc_uuid.to_string(), patch.uuid.clone());
+    let cached = cache.get(&cache_key);
+    let content = match cached {
+        Some(content) => content,
+        None => {
+            let base = match base_position(&patch, pos) {
+                None => Some(Vec::new()),
+                Some(base_pos) => {
+                    let base_uuid: String = db
+                        .query_row(
+                            "SELECT uuid FROM patches
+                             WHERE document_uuid = ?
+                             ORDER BY timestamp ASC
+                             LIMIT 1 OFFSET ?",
+                            params![doc_uuid, base_pos as i64],
+                            |row| row.get(0),
+                        )
+                        .map_err(|e| e.to_string())?;
+                    cache.get(&(doc_uuid.to_string(), base_uuid))
+                }
+            };
+
+            match base {
+                Some(base) => {
+                    let dictionary = dictionary_id.map(|id| load_dictionary(db, id)).transpose()?;
+                    let content =
+                        decode_patch(&patch, checksum, dictionary.as_deref(), &base, false)?;
+                    cache.insert(cache_key, content.clone());
+                    content
+                }
+                None => content_at(db, cache, doc_uuid, patch.timestamp)?
+                    .unwrap_or_default(),
+            }
+        }
+    };
+
+    let content =
+        String::from_utf8(content).map_err(|e| format!("UTF-8 conversion error: {}", e))?;
+    Ok(Some((patch.timestamp, content)))
+}
+
+/// Steps back to the last version before `current_timestamp`.
+#[tauri::command]
+fn load_previous_version(
+    state: State<AppState>,
+    doc_uuid: String,
+    current_timestamp: i64,
+) -> Result<Option<(i64, String)>, String> {
+    previous_version(&state.read_db(), &state.cache, &doc_uuid, current_timestamp)
+}
+
+fn previous_version(
+    db: &Connection,
+    cache: &ContentCache,
+    doc_uuid: &str,
+    current_timestamp: i64,
+) -> Result<Option<(i64, String)>, String> {
+    let previous: Option<i64> = db
+        .query_row(
+            "SELECT MAX(timestamp) FROM patches WHERE document_uuid = ? AND timestamp < ?",
+            params![doc_uuid, current_timestamp],
+            |row| row.get(0),
+        )
+        .map_err(|e| e.to_string())?;
+    let Some(timestamp) = previous else {
+        return Ok(None);
+    };
+
+    let content = load_content(db, cache, doc_uuid, timestamp, &Default::default())?;
+    Ok(Some((timestamp, content)))
+}
+
+/// Loads the version at or around `timestamp` in `direction`, returning its
+/// actual timestamp with the content. An exact match wins in every direction.
+#[tauri::command]
+fn load_nearest(
+    state: State<AppState>,
+    doc_uuid: String,
+    timestamp: i64,
+    direction: SeekDirection,
+) -> Result<(i64, String), String> {
+    nearest_version(&state.read_db(), &state.cache, &doc_uuid, timestamp, direction)
+}
+
+fn nearest_version(
+    db: &Connection,
+    cache: &ContentCache,
+    doc_uuid: &str,
+    timestamp: i64,
+    direction: SeekDirection,
+) -> Result<(i64, String), String> {
+    let (before, after): (Option<i64>, Option<i64>) = db
+        .query_row(
+            "SELECT
+                (SELECT MAX(timestamp) FROM patches WHERE document_uuid = ?1 AND timestamp <= ?2),
+                (SELECT MIN(timestamp) FROM patches WHERE document_uuid = ?1 AND timestamp >= ?2)",
+            params![doc_uuid, timestamp],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .map_err(|e| e.to_string())?;
+
+    let target = match direction {
+        SeekDirection::Before => before,
+        SeekDirection::After => after,
+        SeekDirection::Nearest => match (before, after) {
+            (Some(before), Some(after))
+                if after.abs_diff(timestamp) < timestamp.abs_diff(before) =>
+            {
+                Some(after)
+            }
+            (Some(before), _) => Some(before),
+            (None, after) => after,
+        },
+    };
+    let target = target.ok_or("Version not found")?;
+
+    let content = load_content(db, cache, doc_uuid, target, &Default::default())?;
+    Ok((target, content))
+}
+
+/// Returns the head timestamp and its content in one call. A document without
+/// patches yields `(0, "")`, matching what `load_document_at_timestamp` returns.
+#[tauri::command]
+fn load_latest(state: State<AppState>, doc_uuid: String) -> Result<(i64, String), String> {
+    head_version(&state.read_db(), &state.cache, &doc_uuid)
+}
+
+fn head_version(
+    db: &Connection,
+    cache: &ContentCache,
+    doc_uuid: &str,
+) -> Result<(i64, String), String> {
+    let Some((timestamp, content)) = version_at(db, cache, doc_uuid, i64::MAX)? else {
+        return Ok((0, "".to_string()));
+    };
+
+    let content =
+        String::from_utf8(content).map_err(|e| format!("UTF-8 conversion error: {}", e))?;
+
+    Ok((timestamp, content))
+}
+
+/// Timestamp and content of the version at or before `timestamp`, or None when
+/// there is none.
+fn version_at(
+    db: &Connection,
+    cache: &ContentCache,
+    doc_uuid: &str,
+    timestamp: i64,
+) -> Result<Option<(i64, Vec<u8>)>, String> {
+    let version: Option<(i64, String)> = db
+        .query_row(
+            "SELECT timestamp, uuid FROM patches
+             WHERE document_uuid = ? AND timestamp <= ?
+             ORDER BY timestamp DESC
+             LIMIT 1",
+            params![doc_uuid, timestamp],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()
+        .map_err(|e| e.to_string())?;
+    let Some((timestamp, patch_uuid)) = version else {
+        return Ok(None);
+    };
+
+    // A cached version (e.g. the head just saved) is returned without walking
+    // the chain, which would decode any earlier versions that are not cached
+    let content = match cache.get(&(doc_uuid.to_string(), patch_uuid)) {
+        Some(content) => content,
+        None => content_at(db, cache, doc_uuid, timestamp)?.unwrap_or_default(),
+    };
+    Ok(Some((timestamp, content)))
+}
+
+/// Loads the version `versions_back` saves before the head (0 is the head),
+/// returning its timestamp with the content. Asking past the first version
+/// gives the first; a document without patches yields `(0, "")`.
+#[tauri::command]
+fn load_relative_to_head(
+    state: State<AppState>,
+    doc_uuid: String,
+    versions_back: usize,
+) -> Result<(i64, String), String> {
+    version_before_head(&state.read_db(), &state.cache, &doc_uuid, versions_back)
+}
+
+fn version_before_head(
+    db: &Connection,
+    cache: &ContentCache,
+    doc_uuid: &str,
+    versions_back: usize,
+) -> Result<(i64, String), String> {
+    let target: Option<i64> = db
+        .query_row(
+            "SELECT timestamp FROM patches
+             WHERE document_uuid = ?1
+             ORDER BY timestamp DESC
+             LIMIT 1 OFFSET MIN(?2, (SELECT COUNT(*) - 1 FROM patches WHERE document_uuid = ?1))",
+            params![doc_uuid, i64::try_from(versions_back).unwrap_or(i64::MAX)],
+            |row| row.get(0),
+        )
+        .optional()
+        .map_err(|e| e.to_string())?;
+    let Some(target) = target else {
+        return Ok((0, "".to_string()));
+    };
+
+    let content = load_content(db, cache, doc_uuid, target, &Default::default())?;
+    Ok((target, content))
+}
+
+/// Loads the version at `timestamp` with its reconstruction cost, to find
+/// versions deep in long chains that a snapshot would speed up. The cost is the
+/// same whether or not the versions happen to be cached.
+#[tauri::command]
+fn load_with_cost(
+    state: State<AppState>,
+    doc_uuid: String,
+    timestamp: i64,
+) -> Result<VersionWithCost, String> {
+    let versions = {
+        let db = state.read_db();
+        reconstruct_versions(&db, &state.cache, &doc_uuid, timestamp)?
+    };
+    last_version_cost(versions)
+}
+
+/// The last of `versions` with what rebuilding it from its chain took.
+fn last_version_cost(mut versions: Vec<(Patch, Vec<u8>)>) -> Result<VersionWithCost, String> {
+    let mut patches_replayed = 0;
+    let mut total_base_bytes_read = 0;
+    let mut next = versions.len().checked_sub(1);
+    while let Some(pos) = next {
+        patches_replayed += 1;
+        next = base_position(&versions[pos].0, pos);
+        if let Some(base) = next {
+            total_base_bytes_read += versions[base].1.len();
+        }
+    }
+
+    let content = versions.pop().map(|(_, content)| content).unwrap_or_default();
+    let content =
+        String::from_utf8(content).map_err(|e| format!("UTF-8 conversion error: {}", e))?;
+
+    Ok(VersionWithCost {
+        content,
+        patches_replayed,
+        total_base_bytes_read,
+    })
+}
+
+/// Loads the version at `timestamp` along with which of its lines are new since
+/// the version before, for highlighting each step of a timeline. Everything in
+/// the first version counts as new.
+#[tauri::command]
+fn load_with_changes(
+    state: State<AppState>,
+    doc_uuid: String,
+    timestamp: i64,
+) -> Result<VersionWithChanges, String> {
+    let versions = {
+        let db = state.read_db();
+        reconstruct_versions(&db, &state.cache, &doc_uuid, timestamp)?
+    };
+    last_version_changes(versions)
+}
+
+/// The last of `versions` with the byte ranges it added to the one before.
+fn last_version_changes(
+    mut versions: Vec<(Patch, Vec<u8>)>,
+) -> Result<VersionWithChanges, String> {
+    let content = versions.pop().map(|(_, content)| content).unwrap_or_default();
+    let content =
+        String::from_utf8(content).map_err(|e| format!("UTF-8 conversion error: {}", e))?;
+    let previous = versions.pop().map(|(_, content)| content).unwrap_or_default();
+
+    let mut changed_ranges = Vec::new();
+    let mut offset = 0;
+    for op in diff_lines(&String::from_utf8_lossy(&previous), &content) {
+        match op {
+            DiffOp::Equal(run) => offset += run.len(),
+            DiffOp::Insert(run) => {
+                changed_ranges.push((offset, offset + run.len()));
+                offset += run.len();
+            }
+            DiffOp::Delete(_) => {}
+        }
+    }
+
+    Ok(VersionWithChanges {
+        content,
+        changed_ranges,
+    })
+}
+
+/// Loads the version at `timestamp` exactly like `load_document_at_timestamp`,
+/// cache included, and reports how long each stage took.
+#[tauri::command]
+fn profile_reconstruction(
+    state: State<AppState>,
+    doc_uuid: String,
+    timestamp: i64,
+) -> Result<ProfileReport, String> {
+    profile_load(&state, &doc_uuid, timestamp)
+}
+
+fn profile_load(state: &AppState, doc_uuid: &str, timestamp: i64) -> Result<ProfileReport, String> {
+    let profile = RefCell::new(ProfileReport::default());
+    let options = ReconstructOptions {
+        profile: Some(&profile),
+        ..Default::default()
+    };
+
+    let versions = {
+        let db = state.read_db();
+        reconstruct_versions_with(&db, &state.cache, doc_uuid, timestamp, &options)?
+    };
+
+    let utf8_started = Instant::now();
+    if let Some((_, content)) = versions.into_iter().next_back() {
+        String::from_utf8(content).map_err(|e| format!("UTF-8 conversion error: {}", e))?;
+    }
+    let mut profile = profile.into_inner();
+    profile.utf8_us = utf8_started.elapsed().as_micros() as u64;
+
+    Ok(profile)
+}
+
+/// `(doc_uuid, latest_content)` for every document outside the trash, for
+/// whole-library backups. Nothing is cached, so memory stays bounded by the
+/// largest single history rather than the whole library.
+#[tauri::command(async)]
+fn load_all_latest(state: State<AppState>) -> Result<Vec<(String, String)>, String> {
+    latest_contents(&state.read_db(), &state.cache)
+}
+
+fn latest_contents(db: &Connection, cache: &ContentCache) -> Result<Vec<(String, String)>, String> {
+    let mut stmt = db
+        .prepare("SELECT uuid FROM documents WHERE deleted_at IS NULL ORDER BY created_at DESC")
+        .map_err(|e| e.to_string())?;
+    let doc_uuids = stmt
+        .query_map([], |row| row.get::<_, String>(0))
+        .map_err(|e| e.to_string())?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| e.to_string())?;
+    drop(stmt);
+
+    let options = ReconstructOptions {
+        skip_cache: true,
+        ..Default::default()
+    };
+    doc_uuids
+        .into_iter()
+        .map(|doc_uuid| {
+            let content = load_content(db, cache, &doc_uuid, i64::MAX, &options)?;
+            Ok((doc_uuid, content))
+        })
+        .collect()
+}
+
+/// Writes the raw bytes of the version at `timestamp` to `path`, creating parent
+/// directories as needed, and returns the number of bytes written. A timestamp
+/// before the first patch writes an empty file.
+#[tauri::command]
+fn export_version(
+    state: State<AppState>,
+    doc_uuid: String,
+    timestamp: i64,
+    path: String,
+) -> Result<usize, String> {
+    write_version(&state, &doc_uuid, timestamp, &path)
+}
+
+fn write_version(
+    state: &AppState,
+    doc_uuid: &str,
+    timestamp: i64,
+    path: &str,
+) -> Result<usize, String> {
+    let content = {
+        let db = state.read_db();
+        content_at(&db, &state.cache, doc_uuid, timestamp)?.unwrap_or_default()
+    };
+
+    let mut file = create_export_file(path)?;
+    std::io::Write::write_all(&mut file, &content).map_err(|e| export_error(path, e))?;
+
+    Ok(content.len())
+}
+
+/// Creates (or truncates) the file at `path` for an export, along with any
+/// missing parent directories.
+fn create_export_file(path: &str) -> Result<std::fs::File, String> {
+    let path_ref = Path::new(path);
+    if let Some(parent) = path_ref.parent() {
+        std::fs::create_dir_all(parent).map_err(|e| export_error(path, e))?;
+    }
+    std::fs::File::create(path_ref).map_err(|e| export_error(path, e))
+}
+
+fn export_error(path: &str, e: std::io::Error) -> String {
+    format!("IO error writing {}: {}", path, e)
+}
+
+fn document_content_type(db: &Connection, doc_uuid: &str) -> Result<String, String> {
+    db.query_row(
+        "SELECT content_type FROM documents WHERE uuid = ?",
+        params![doc_uuid],
+        |row| row.get(0),
+    )
+    .optional()
+    .map_err(|e| e.to_string())?
+    .ok_or_else(|| "Document not found".to_string())
+}
+
+// File extension for archive entries; unknown binary types get `bin`
+fn content_type_extension(content_type: &str) -> &'static str {
+    match content_type {
+        "text/markdown" => "md",
+        "text/html" => "html",
+        "text/csv" => "csv",
+        "application/json" => "json",
+        "application/pdf" => "pdf",
+        "image/png" => "png",
+        "image/jpeg" => "jpg",
+        _ if content_type.starts_with("text/") => "txt",
+        _ => "bin",
+    }
+}
+
+/// Writes every version of the document into a tar archive at `path`, named
+/// `v0001_<timestamp>.<ext>` in order, plus a `manifest.json` listing them.
+/// Returns the number of versions written.
+#[tauri::command]
+fn export_all_versions(
+    state: State<AppState>,
+    doc_uuid: String,
+    path: String,
+) -> Result<usize, String> {
+    write_version_archive(&state, &doc_uuid, &path)
+}
+
+fn write_version_archive(state: &AppState, doc_uuid: &str, path: &str) -> Result<usize, String> {
+    let (content_type, versions) = {
+        let db = state.read_db();
+        let content_type = document_content_type(&db, doc_uuid)?;
+        (content_type, reconstruct_versions(&db, &state.cache, doc_uuid, i64::MAX)?)
+    };
+    let extension = content_type_extension(&content_type);
+
+    let io_error = |e| export_error(path, e);
+    let file = create_export_file(path)?;
+    let mut archive = tar::Builder::new(std::io::BufWriter::new(file));
+
+    let append = |archive: &mut tar::Builder<_>, name: &str, timestamp: i64, data: &[u8]| {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime((timestamp / 1000).max(0) as u64);
+        header.set_cksum();
+        archive.append_data(&mut header, name, data)
+    };
+
+    let mut entries = Vec::with_capacity(versions.len());
+    for (index, (patch, content)) in versions.iter().enumerate() {
+        let file = format!("v{:04}_{}.{}", index + 1, patch.timestamp, extension);
+        append(&mut archive, &file, patch.timestamp, content).map_err(io_error)?;
+        entries.push(ArchiveEntry {
+            file,
+            timestamp: patch.timestamp,
+            bytes: content.len(),
+        });
+    }
+
+    let manifest = ArchiveManifest {
+        doc_uuid: doc_uuid.to_string(),
+        content_type,
+        versions: entries,
+    };
+    let manifest = serde_json::to_vec_pretty(&manifest).map_err(|e| e.to_string())?;
+    let newest = versions.last().map_or(0, |(patch, _)| patch.timestamp);
+    append(&mut archive, "manifest.json", newest, &manifest).map_err(io_error)?;
+    archive
+        .into_inner()
+        .and_then(|mut writer| std::io::Write::flush(&mut writer))
+        .map_err(io_error)?;
+
+    Ok(versions.len())
+}
+
+// Bumped whenever `SyncBundle` changes shape; newer bundles are refused
+const SYNC_BUNDLE_VERSION: u32 = 3;
+const SYNC_BUNDLE_ZSTD_LEVEL: i32 = 19;
+
+/// The whole document as a `SyncBundle` for replication: settings, metadata and
+/// every version as a delta against the one before it. Stored deltas that already
+/// are one are sent as is; snapshots, deltas against older versions and
+/// dictionary-compressed ones are re-encoded, since the peer has neither the
+/// same chain nor the dictionary.
+#[tauri::command]
+fn export_sync_bundle(state: State<AppState>, doc_uuid: String) -> Result<Vec<u8>, String> {
+    sync_bundle(&state, &doc_uuid)
+}
+
+fn sync_bundle(state: &AppState, doc_uuid: &str) -> Result<Vec<u8>, String> {
+    let (document, settings, metadata, dictionary_patches, versions) = {
+        let db = state.read_db();
+        let document = db
+            .query_row(
+                &format!("SELECT {} FROM documents WHERE uuid = ?", DOCUMENT_COLUMNS),
+                params![doc_uuid],
+                document_from_row,
+            )
+            .optional()
+            .map_err(|e| e.to_string())?
+            .ok_or("Document not found")?;
+        let limits: (Option<i64>, Option<i64>, Option<i64>) = db
+            .query_row(
+                "SELECT max_versions, max_chain_depth, max_content_bytes
+                 FROM documents WHERE uuid = ?",
+                params![doc_uuid],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .map_err(|e| e.to_string())?;
+        let settings = (get_base_strategy(&db, doc_uuid)?, limits, eol_style(&db, doc_uuid)?);
+
+        let mut stmt = db
+            .prepare("SELECT key, value FROM document_metadata WHERE doc_uuid = ?")
+            .map_err(|e| e.to_string())?;
+        let metadata = stmt
+            .query_map(params![doc_uuid], |row| Ok((row.get(0)?, row.get(1)?)))
+            .map_err(|e| e.to_string())?
+            .collect::<Result<BTreeMap<String, String>, _>>()
+            .map_err(|e| e.to_string())?;
+
+        let mut stmt = db
+            .prepare(
+                "SELECT uuid FROM patches WHERE document_uuid = ? AND dictionary_id IS NOT NULL",
+            )
+            .map_err(|e| e.to_string())?;
+        let dictionary_patches = stmt
+            .query_map(params![doc_uuid], |row| row.get(0))
+            .map_err(|e| e.to_string())?
+            .collect::<Result<HashSet<String>, _>>()
+            .map_err(|e| e.to_string())?;
+
+        let versions = reconstruct_versions(&db, &state.cache, doc_uuid, i64::MAX)?;
+        (document, settings, metadata, dictionary_patches, versions)
+    };
+
+    let mut patches = Vec::with_capacity(versions.len());
+    let mut previous: &[u8] = &[];
+    for (patch, content) in &versions {
+        let stored = patch.delta.as_deref().filter(|delta| {
+            !dictionary_patches.contains(&patch.uuid)
+                && xpatch::get_tag(delta).is_ok_and(|tag| tag == 0)
+        });
+        let delta = match stored {
+            Some(delta) => delta.to_vec(),
+            None => xpatch::encode(0, previous, content, ENABLE_ZSTD),
+        };
+        patches.push(SyncPatch {
+            timestamp: patch.timestamp,
+            delta: BASE64_STANDARD.encode(&delta),
+        });
+        previous = content;
+    }
+
+    let (base_strategy, (max_versions, max_chain_depth, max_content_bytes), eol_style) = settings;
+    let bundle = SyncBundle {
+        format_version: SYNC_BUNDLE_VERSION,
+        document,
+        base_strategy,
+        max_versions,
+        max_chain_depth,
+        max_content_bytes,
+        eol_style,
+        metadata,
+        patches,
+    };
+    let json = serde_json::to_vec(&bundle).map_err(|e| e.to_string())?;
+    zstd::bulk::compress(&json, SYNC_BUNDLE_ZSTD_LEVEL).map_err(|e| e.to_string())
+}
+
+/// Recreates a document from `export_sync_bundle` output under its original
+/// uuid and settings, replaying the versions as `apply_delta_stream` does. It
+/// all happens in one transaction, so nothing is left behind if the uuid is
+/// taken or any delta does not apply.
+#[tauri::command]
+fn import_sync_bundle(
+    app: AppHandle,
+    state: State<AppState>,
+    bundle: Vec<u8>,
+) -> Result<String, String> {
+    let doc_uuid = restore_sync_bundle(&state, &bundle)?;
+    emit_document_changed(&app, &doc_uuid, DocumentChangeKind::Created, None);
+    Ok(doc_uuid)
+}
+
+fn restore_sync_bundle(state: &AppState, bundle: &[u8]) -> Result<String, String> {
+    use std::io::Read;
+
+    let invalid = |e: &dyn std::fmt::Display| format!("Invalid sync bundle: {}", e);
+    let mut json = Vec::new();
+    zstd::stream::Decoder::new(bundle)
+        .map_err(|e| invalid(&e))?
+        .take(MAX_DECODED_BYTES as u64 + 1)
+        .read_to_end(&mut json)
+        .map_err(|e| invalid(&e))?;
+    if json.len() > MAX_DECODED_BYTES {
+        return Err(invalid(&"exceeds size limit"));
+    }
+    let bundle: SyncBundle = serde_json::from_slice(&json).map_err(|e| invalid(&e))?;
+    if bundle.format_version > SYNC_BUNDLE_VERSION {
+        return Err(format!("Unsupported sync bundle version {}", bundle.format_version));
+    }
+    let deltas = bundle
+        .patches
+        .iter()
+        .map(|patch| {
+            let delta = BASE64_STANDARD.decode(&patch.delta).map_err(|e| invalid(&e))?;
+            Ok((patch.timestamp, delta))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+
+    let document = bundle.document;
+    let created_at = validate_created_at(document.created_at, state.created_at_tolerance_ms)?;
+
+    let mut db = state.write_db();
+    let tx = db.transaction().map_err(|e| e.to_string())?;
+    let exists: bool = tx
+        .query_row(
+            "SELECT EXISTS(SELECT 1 FROM documents WHERE uuid = ?)",
+            params![&document.uuid],
+            |row| row.get(0),
+        )
+        .map_err(|e| e.to_string())?;
+    if exists {
+        return Err(format!("Document already exists: {}", document.uuid));
+    }
+    tx.execute(
+        "INSERT INTO documents (uuid, name, created_at, content_type) VALUES (?, ?, ?, ?)",
+        params![&document.uuid, &document.name, created_at, &document.content_type],
+    )
+    .map_err(|e| e.to_string())?;
+    // Set before the replay, which stores versions per the strategy and depth cap
+    write_base_strategy(&tx, &document.uuid, bundle.base_strategy)?;
+    write_max_versions(&tx, &document.uuid, bundle.max_versions)?;
+    write_max_chain_depth(&tx, &document.uuid, bundle.max_chain_depth)?;
+    write_max_content_bytes(&tx, &document.uuid, bundle.max_content_bytes)?;
+    if let Some(style) = bundle.eol_style {
+        write_eol_style(&tx, &document.uuid, style)?;
+    }
+    for (key, value) in &bundle.metadata {
+        tx.execute(
+            "INSERT INTO document_metadata (doc_uuid, key, value) VALUES (?, ?, ?)",
+            params![&document.uuid, key, value],
+        )
+        .map_err(|e| e.to_string())?;
+    }
+    let limit = content_limit(&tx, &document.uuid, state.default_max_content_bytes)?;
+    let versions = replay_deltas(&tx, &state.cache, &document.uuid, limit, deltas)?;
+    tx.commit().map_err(|e| e.to_string())?;
+
+    enforce_max_versions(&mut db, &state.cache, &document.uuid)?;
+    cache_replayed(&state.cache, &document.uuid, versions);
+    Ok(document.uuid)
+}
+
+/// SHA-256 (hex) of the version at `timestamp`, so clients can tell whether
+/// content changed without transferring it. Uses the stored hash when the patch
+/// has one and only reconstructs for older rows.
+#[tauri::command]
+fn version_hash(
+    state: State<AppState>,
+    doc_uuid: String,
+    timestamp: i64,
+) -> Result<String, String> {
+    content_hash_at(&state.read_db(), &state.cache, &doc_uuid, timestamp)
+}
+
+fn content_hash_at(
+    db: &Connection,
+    cache: &ContentCache,
+    doc_uuid: &str,
+    timestamp: i64,
+) -> Result<String, String> {
+    let stored: Option<Option<String>> = db
+        .query_row(
+            "SELECT content_hash FROM patches
+             WHERE document_uuid = ? AND timestamp <= ?
+             ORDER BY timestamp DESC
+             LIMIT 1",
+            params![doc_uuid, timestamp],
+            |row| row.get(0),
+        )
+        .optional()
+        .map_err(|e| e.to_string())?;
+
+    match stored {
+        Some(Some(hash)) => Ok(hash),
+        // Before the first patch the document is empty
+        None => Ok(content_hash(&[])),
+        Some(None) => {
+            let versions = reconstruct_versions(db, cache, doc_uuid, timestamp)?;
+            let content = versions.last().map(|(_, content)| content.as_slice());
+            Ok(content_hash(content.unwrap_or_default()))
+        }
+    }
+}
+
+/// True when the document has no patches or its latest version is empty or
+/// only whitespace, e.g. to hide documents that were never really written.
+#[tauri::command]
+fn is_document_blank(state: State<AppState>, doc_uuid: String) -> Result<bool, String> {
+    document_is_blank(&state.read_db(), &state.cache, &doc_uuid)
+}
+
+fn document_is_blank(
+    db: &Connection,
+    cache: &ContentCache,
+    doc_uuid: &str,
+) -> Result<bool, String> {
+    let options = ReconstructOptions {
+        lossy: true,
+        ..Default::default()
+    };
+    let head = load_content(db, cache, doc_uuid, i64::MAX, &options)?;
+    Ok(head.trim().is_empty())
+}
+
+/// Size and counts of the version at `timestamp`, for status bars that would
+/// otherwise pull the whole content over IPC.
+#[tauri::command]
+fn version_metrics(
+    state: State<AppState>,
+    doc_uuid: String,
+    timestamp: i64,
+) -> Result<VersionMetrics, String> {
+    let content = {
+        let db = state.read_db();
+        content_at(&db, &state.cache, &doc_uuid, timestamp)?.unwrap_or_default()
+    };
+    Ok(metrics(&content))
+}
+
+fn metrics(content: &[u8]) -> VersionMetrics {
+    let text = String::from_utf8_lossy(content);
+    VersionMetrics {
+        bytes: content.len(),
+        chars: text.chars().count(),
+        lines: text.lines().count(),
+        words: text.split_whitespace().count(),
+    }
+}
+
+// Largest version `version_as_data_url` encodes; base64 adds another third
+const MAX_DATA_URL_BYTES: usize = 16 * 1024 * 1024;
+
+/// Whether the version at `timestamp` is valid UTF-8, so the frontend can pick
+/// a text or a hex viewer.
+#[tauri::command]
+fn version_is_utf8(
+    state: State<AppState>,
+    doc_uuid: String,
+    timestamp: i64,
+) -> Result<bool, String> {
+    is_utf8_at(&state.read_db(), &state.cache, &doc_uuid, timestamp)
+}
+
+fn is_utf8_at(
+    db: &Connection,
+    cache: &ContentCache,
+    doc_uuid: &str,
+    timestamp: i64,
+) -> Result<bool, String> {
+    let content = content_at(db, cache, doc_uuid, timestamp)?.unwrap_or_default();
+    Ok(std::str::from_utf8(&content).is_ok())
+}
+
+/// The start of each version, oldest first, `limit` versions from `offset` on,
+/// for a history sidebar. One pass reconstructs everything up to the end of
+/// the page; only the previews are returned.
+#[tauri::command]
+fn version_previews(
+    state: State<AppState>,
+    doc_uuid: String,
+    offset: usize,
+    limit: usize,
+    preview_len: usize,
+) -> Result<Vec<VersionPreview>, String> {
+    preview_page(&state.read_db(), &state.cache, &doc_uuid, offset, limit, preview_len)
+}
+
+fn preview_page(
+    db: &Connection,
+    cache: &ContentCache,
+    doc_uuid: &str,
+    offset: usize,
+    limit: usize,
+    preview_len: usize,
+) -> Result<Vec<VersionPreview>, String> {
+    if limit == 0 {
+        return Ok(Vec::new());
+    }
+
+    let last = offset.saturating_add(limit - 1).min(i64::MAX as usize);
+    let page_end: Option<i64> = db
+        .query_row(
+            "SELECT timestamp FROM patches
+             WHERE document_uuid = ?
+             ORDER BY timestamp ASC
+             LIMIT 1 OFFSET ?",
+            params![doc_uuid, last as i64],
+            |row| row.get(0),
+        )
+        .optional()
+        .map_err(|e| e.to_string())?;
+    // A short last page ends at the newest version
+    let page_end = page_end.unwrap_or(i64::MAX);
+
+    let versions = reconstruct_versions(db, cache, doc_uuid, page_end)?;
+    Ok(versions
+        .iter()
+        .skip(offset)
+        .map(|(patch, content)| VersionPreview {
+            timestamp: patch.timestamp,
+            preview: match std::str::from_utf8(content) {
+                Ok(text) => text.chars().take(preview_len).collect(),
+                Err(_) => content.iter().take(preview_len).map(|b| format!("{:02x}", b)).collect(),
+            },
+        })
+        .collect())
+}
+
+/// The version at `timestamp` as a `data:` URL, so a webview can offer it as a
+/// download without touching the filesystem. `mime` defaults to the document's
+/// content type.
+#[tauri::command]
+fn version_as_data_url(
+    state: State<AppState>,
+    doc_uuid: String,
+    timestamp: i64,
+    mime: Option<String>,
+) -> Result<String, String> {
+    data_url(&state.read_db(), &state.cache, &doc_uuid, timestamp, mime)
+}
+
+fn data_url(
+    db: &Connection,
+    cache: &ContentCache,
+    doc_uuid: &str,
+    timestamp: i64,
+    mime: Option<String>,
+) -> Result<String, String> {
+    let content_type = document_content_type(db, doc_uuid)?;
+    let content = content_at(db, cache, doc_uuid, timestamp)?.unwrap_or_default();
+    if content.len() > MAX_DATA_URL_BYTES {
+        return Err(format!(
+            "Version is too large for a data URL: {} bytes (limit {})",
+            content.len(),
+            MAX_DATA_URL_BYTES
+        ));
+    }
+
+    Ok(format!(
+        "data:{};base64,{}",
+        mime.unwrap_or(content_type),
+        BASE64_STANDARD.encode(&content)
+    ))
+}
+
+/// Bytes `start..start + len` of the version at `timestamp`, clamped to its
+/// length, for rendering just a viewport of a huge document. The whole version
+/// is still reconstructed.
+#[tauri::command]
+fn load_version_range(
+    state: State<AppState>,
+    doc_uuid: String,
+    timestamp: i64,
+    start: usize,
+    len: usize,
+) -> Result<Vec<u8>, String> {
+    version_range(&state.read_db(), &state.cache, &doc_uuid, timestamp, start, len)
+}
+
+fn version_range(
+    db: &Connection,
+    cache: &ContentCache,
+    doc_uuid: &str,
+    timestamp: i64,
+    start: usize,
+    len: usize,
+) -> Result<Vec<u8>, String> {
+    let content = content_at(db, cache, doc_uuid, timestamp)?.unwrap_or_default();
+
+    let start = start.min(content.len());
+    let end = start.saturating_add(len).min(content.len());
+    Ok(content[start..end].to_vec())
+}
+
+/// Reconstructs the version at `timestamp` once and returns a handle for reading
+/// it in chunks, keeping each IPC payload small for huge documents.
+#[tauri::command]
+fn open_version_stream(
+    state: State<AppState>,
+    doc_uuid: String,
+    timestamp: i64,
+) -> Result<String, String> {
+    open_stream(&state, &doc_uuid, timestamp)
+}
+
+fn open_stream(state: &AppState, doc_uuid: &str, timestamp: i64) -> Result<String, String> {
+    let content = {
+        let db = state.read_db();
+        content_at(&db, &state.cache, doc_uuid, timestamp)?.unwrap_or_default()
+    };
+
+    let handle = Uuid::new_v4().to_string();
+    state
+        .lock_streams()
+        .insert(handle.clone(), VersionStream { content, offset: 0 });
+
+    Ok(handle)
+}
+
+/// Returns the next chunk of at most `max_bytes`, or None once the stream is
+/// exhausted, at which point the handle is closed.
+#[tauri::command]
+fn read_version_chunk(
+    state: State<AppState>,
+    handle: String,
+    max_bytes: usize,
+) -> Result<Option<Vec<u8>>, String> {
+    next_chunk(&state, &handle, max_bytes)
+}
+
+fn next_chunk(state: &AppState, handle: &str, max_bytes: usize) -> Result<Option<Vec<u8>>, String> {
+    if max_bytes == 0 {
+        return Err("max_bytes must be at least 1".to_string());
+    }
+
+    let mut streams = state.lock_streams();
+    let stream = streams.get_mut(handle).ok_or("Stream not found")?;
+
+    if stream.offset == stream.content.len() {
+        streams.remove(handle);
+        return Ok(None);
+    }
+
+    let end = (stream.offset + max_bytes).min(stream.content.len());
+    let chunk = stream.content[stream.offset..end].to_vec();
+    stream.offset = end;
+
+    Ok(Some(chunk))
+}
+
+/// Frees a stream the caller stopped reading early.
+#[tauri::command]
+fn close_version_stream(state: State<AppState>, handle: String) -> Result<(), String> {
+    state.lock_streams().remove(&handle);
+    Ok(())
+}
+
+#[cfg(test)]
+thread_local! {
+    // Calls to `find_optimal_base` on this thread, so tests can see it skipped
+    static BASE_SEARCHES: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+fn find_optimal_base(
+    state: &AppState,
+    doc_uuid: &str,
+    current_timestamp: i64,
+    new_content: &[u8],
+    max_depth: usize,
+    good_enough_bytes: usize,
+    mut trace: Option<&mut Vec<BaseCandidate>>,
+) -> Result<(usize, Vec<u8>), String> {
+    #[cfg(test)]
+    BASE_SEARCHES.with(|calls| calls.set(calls.get() + 1));
+
+    let db = state.read_db();
+
+    // Get timestamps of previous versions
+    let mut stmt = db
+        .prepare(
+            "SELECT DISTINCT timestamp
+             FROM patches
+             WHERE document_uuid = ? AND timestamp < ?
+             ORDER BY timestamp DESC
+             LIMIT ?",
+        )
+        .map_err(|e| e.to_string())?;
+
+    let previous_timestamps: Vec<i64> = stmt
+        .query_map(params![doc_uuid, current_timestamp, max_depth], |row| {
+            row.get(0)
+        })
+        .map_err(|e| e.to_string())?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| e.to_string())?;
+
+    drop(stmt);
+
+    // Every candidate is a prefix of the chain up to the newest one, so a single
+    // pass reconstructs them all instead of replaying the chain per candidate
+    let versions = match previous_timestamps.first() {
+        Some(&newest) => reconstruct_versions(&db, &state.cache, doc_uuid, newest)?,
+        None => Vec::new(),
+    };
+    drop(db);
+
+    let bases = previous_timestamps.iter().map(|&timestamp| {
+        // Same version `load_document_at_timestamp` picks: the last at or before it
+        let end = versions.partition_point(|(patch, _)| patch.timestamp <= timestamp);
+        Ok(end.checked_sub(1).map_or(&[][..], |pos| versions[pos].1.as_slice()))
+    });
+
+    select_base(bases, new_content, ENABLE_ZSTD, good_enough_bytes, |tag, delta_bytes| {
+        if let Some(trace) = trace.as_deref_mut() {
+            trace.push(BaseCandidate {
+                tag,
+                base_timestamp: previous_timestamps.get(tag).copied(),
+                delta_bytes,
+                chosen: false,
+            });
+        }
+    })
+}
+
+fn content_hash(content: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(content))
+}
+
+// Guards each stored delta against on-disk corruption
+fn delta_checksum(delta: &[u8]) -> i64 {
+    crc32fast::hash(delta) as i64
+}
+
+fn insert_patch(
+    db: &Connection,
+    patch_uuid: &str,
+    doc_uuid: &str,
+    timestamp: i64,
+    delta: &[u8],
+    hash: &str,
+) -> Result<(), String> {
+    db.execute(
+        "INSERT INTO patches (uuid, document_uuid, timestamp, delta, content_hash, delta_checksum)
+         VALUES (?, ?, ?, ?, ?, ?)",
+        params![patch_uuid, doc_uuid, timestamp, delta, hash, delta_checksum(delta)],
+    )
+    .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+/// Points the document's head columns at its latest patch. Call in the same
+/// transaction as anything that adds or removes patches.
+fn refresh_head(db: &Connection, doc_uuid: &str) -> Result<(), String> {
+    db.execute(
+        "UPDATE documents SET
+            head_timestamp = (SELECT timestamp FROM patches WHERE document_uuid = ?1
+                              ORDER BY timestamp DESC LIMIT 1),
+            head_hash = (SELECT content_hash FROM patches WHERE document_uuid = ?1
+                         ORDER BY timestamp DESC LIMIT 1)
+         WHERE uuid = ?1",
+        params![doc_uuid],
+    )
+    .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+/// Whether `hash` matches the version current at `timestamp`, answered from the
+/// head columns when `timestamp` is at or past the head. None means the caller
+/// has to reconstruct (a timestamp before the head, or a head without a stored
+/// hash).
+fn matches_head(
+    db: &Connection,
+    doc_uuid: &str,
+    timestamp: i64,
+    hash: &str,
+) -> Result<Option<bool>, String> {
+    let head: Option<(Option<i64>, Option<String>)> = db
+        .query_row(
+            "SELECT head_timestamp, head_hash FROM documents WHERE uuid = ?",
+            params![doc_uuid],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()
+        .map_err(|e| e.to_string())?;
+
+    Ok(match head {
+        // No patches yet: the current version is empty
+        Some((None, _)) => Some(hash == content_hash(&[])),
+        Some((Some(head_timestamp), Some(head_hash))) if timestamp >= head_timestamp => {
+            Some(hash == head_hash)
+        }
+        _ => None,
+    })
+}
+
+/// Rejects a new version's timestamp unless it is positive epoch milliseconds.
+/// Loads take any timestamp; one before the first patch is the empty document.
+fn check_timestamp(timestamp: i64) -> Result<(), String> {
+    if timestamp <= 0 {
+        return Err(format!(
+            "Invalid timestamp {}: must be positive milliseconds since the epoch",
+            timestamp
+        ));
+    }
+    Ok(())
+}
+
+/// Rejects a new patch at or before the document's latest one.
+fn check_after_head(db: &Connection, doc_uuid: &str, timestamp: i64) -> Result<(), String> {
+    let head_timestamp: Option<i64> = db
+        .query_row(
+            "SELECT head_timestamp FROM documents WHERE uuid = ?",
+            params![doc_uuid],
+            |row| row.get(0),
+        )
+        .optional()
+        .map_err(|e| e.to_string())?
+        .flatten();
+
+    match head_timestamp {
+        Some(head_timestamp) if timestamp <= head_timestamp => Err(format!(
+            "Timestamp {} is not after the latest version ({})",
+            timestamp, head_timestamp
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Replaces a patch's delta; its reconstructed content (and hash) must not change.
+fn update_patch_delta(db: &Connection, patch_uuid: &str, delta: &[u8]) -> Result<(), String> {
+    db.execute(
+        "UPDATE patches SET delta = ?, delta_checksum = ?, dictionary_id = NULL WHERE uuid = ?",
+        params![delta, delta_checksum(delta), patch_uuid],
+    )
+    .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+/// If `new_content` matches an earlier version exactly, returns a tag pointing
+/// straight at it and the (tiny) delta against it. Such patches are reverts: their
+/// `content_hash` equals that of an earlier patch in the same document.
+fn encode_revert(
+    db: &Connection,
+    doc_uuid: &str,
+    timestamp: i64,
+    new_content: &[u8],
+    hash: &str,
+) -> Result<Option<(usize, Vec<u8>, i64)>, String> {
+    let matched: Option<i64> = db
+        .query_row(
+            "SELECT timestamp FROM patches
+             WHERE document_uuid = ? AND content_hash = ? AND timestamp < ?
+             ORDER BY timestamp DESC
+             LIMIT 1",
+            params![doc_uuid, hash, timestamp],
+            |row| row.get(0),
+        )
+        .optional()
+        .map_err(|e| e.to_string())?;
+
+    let Some(matched) = matched else {
+        return Ok(None);
+    };
+
+    // The tag counts the versions sitting between the match and the new patch
+    let between: i64 = db
+        .query_row(
+            "SELECT COUNT(*) FROM patches
+             WHERE document_uuid = ? AND timestamp > ? AND timestamp < ?",
+            params![doc_uuid, matched, timestamp],
+            |row| row.get(0),
+        )
+        .map_err(|e| e.to_string())?;
+
+    let tag = between as usize;
+    let delta = xpatch::encode(tag, new_content, new_content, ENABLE_ZSTD);
+    Ok(Some((tag, delta, matched)))
+}
+
+fn get_base_strategy(db: &Connection, doc_uuid: &str) -> Result<BaseStrategy, String> {
+    let strategy: Option<String> = db
+        .query_row(
+            "SELECT base_strategy FROM documents WHERE uuid = ?",
+            params![doc_uuid],
+            |row| row.get(0),
+        )
+        .optional()
+        .map_err(|e| e.to_string())?
+        .flatten();
+
+    match strategy {
+        Some(json) => serde_json::from_str(&json).map_err(|e| e.to_string()),
+        None => Ok(BaseStrategy::default()),
+    }
+}
+
+/// Encodes a new version following the document's base strategy. Reverts to an
+/// earlier version reference it directly unless the strategy is `PreviousOnly`.
+/// Every encoding tried is appended to `trace` when one is given.
+fn encode_with_strategy(
+    state: &AppState,
+    doc_uuid: &str,
+    timestamp: i64,
+    new_content: &[u8],
+    hash: &str,
+    mut trace: Option<&mut Vec<BaseCandidate>>,
+) -> Result<(usize, Vec<u8>), String> {
+    let mut record = |tag, base_timestamp, delta: &[u8]| {
+        if let Some(trace) = trace.as_deref_mut() {
+            trace.push(BaseCandidate {
+                tag,
+                base_timestamp,
+                delta_bytes: delta.len(),
+                chosen: false,
+            });
+        }
+    };
+
+    let (strategy, previous_count) = {
+        let db = state.read_db();
+        let strategy = get_base_strategy(&db, doc_uuid)?;
+        let previous_count: i64 = db
+            .query_row(
+                "SELECT COUNT(*) FROM patches WHERE document_uuid = ? AND timestamp < ?",
+                params![doc_uuid, timestamp],
+                |row| row.get(0),
+            )
+            .map_err(|e| e.to_string())?;
+        (strategy, previous_count as usize)
+    };
+
+    let Some(max_depth) = strategy_depth(strategy, previous_count) else {
+        let delta = xpatch::encode(SNAPSHOT_TAG, &[], new_content, ENABLE_ZSTD);
+        record(SNAPSHOT_TAG, None, &delta);
+        return Ok((SNAPSHOT_TAG, delta));
+    };
+
+    // A revert points straight at the matching version, as long as the strategy
+    // would have searched back that far
+    if strategy != BaseStrategy::PreviousOnly {
+        let revert = encode_revert(&state.read_db(), doc_uuid, timestamp, new_content, hash)?;
+        if let Some((tag, delta, matched)) = revert.filter(|(tag, _, _)| *tag < max_depth) {
+            record(tag, Some(matched), &delta);
+            return Ok((tag, delta));
+        }
+    }
+
+    // Pure appends, e.g. logs, always encode best against the previous version,
+    // so there is nothing to search for
+    let previous = version_at(&state.read_db(), &state.cache, doc_uuid, timestamp - 1)?;
+    let append_base = previous.filter(|(_, content)| new_content.starts_with(content));
+    if let Some((base_timestamp, content)) = append_base {
+        let delta = xpatch::encode(0, &content, new_content, ENABLE_ZSTD);
+        record(0, Some(base_timestamp), &delta);
+        return Ok((0, delta));
+    }
+
+    find_optimal_base(
+        state,
+        doc_uuid,
+        timestamp,
+        new_content,
+        max_depth,
+        good_enough_bytes(new_content.len()),
+        trace,
+    )
+}
+
+/// Lists every base `create_patch` would try for `content` at `timestamp` and the
+/// delta size each gives, flagging the one it would store. Nothing is written.
+#[tauri::command]
+fn explain_optimal_base(
+    state: State<AppState>,
+    doc_uuid: String,
+    content: String,
+    timestamp: i64,
+) -> Result<Vec<BaseCandidate>, String> {
+    base_trace(&state, &doc_uuid, content, timestamp)
+}
+
+fn base_trace(
+    state: &AppState,
+    doc_uuid: &str,
+    content: String,
+    timestamp: i64,
+) -> Result<Vec<BaseCandidate>, String> {
+    let new_content = content.into_bytes();
+    let hash = content_hash(&new_content);
+
+    let mut trace = Vec::new();
+    let (chosen_tag, _delta) =
+        encode_with_strategy(state, doc_uuid, timestamp, &new_content, &hash, Some(&mut trace))?;
+
+    if let Some(chosen) = trace.iter_mut().find(|candidate| candidate.tag == chosen_tag) {
+        chosen.chosen = true;
+    }
+
+    Ok(trace)
+}
+
+/// Saves a new latest version. `timestamp` must be after every existing patch:
+/// later patches' tags count back from their own position, so slotting one into
+/// the past would change what they decode against. Use `resequence_patches` to
+/// correct timestamps already stored.
+///
+/// With `normalize_eol`, line endings are converted first so a save from another
+/// platform does not rewrite every line; the style is recorded on the document
+/// (see `get_eol_style`).
+#[tauri::command]
+fn create_patch(
+    app: AppHandle,
+    state: State<AppState>,
+    doc_uuid: String,
+    current_content: String,
+    timestamp: i64,
+    normalize_eol: Option<EolStyle>,
+) -> Result<String, String> {
+    let patch_uuid = save_version(&state, &doc_uuid, current_content, timestamp, normalize_eol)?;
+    emit_document_changed(&app, &doc_uuid, DocumentChangeKind::Edited, Some(timestamp));
+    Ok(patch_uuid)
+}
+
+/// Everything `create_patch` does short of telling the frontend.
+fn save_version(
+    state: &AppState,
+    doc_uuid: &str,
+    current_content: String,
+    timestamp: i64,
+    normalize_eol: Option<EolStyle>,
+) -> Result<String, String> {
+    let current_content = match normalize_eol {
+        Some(style) => normalize_line_endings(&current_content, style),
+        None => current_content,
+    };
+
+    check_timestamp(timestamp)?;
+    check_after_head(&state.read_db(), doc_uuid, timestamp)?;
+    let limit = content_limit(&state.read_db(), doc_uuid, state.default_max_content_bytes)?;
+    check_content_size(limit, current_content.len())?;
+
+    let new_content = current_content.as_bytes().to_vec();
+    let hash = content_hash(&new_content);
+
+    // Compare against the last version, reconstructing it only if the head can't tell
+    let head_match = matches_head(&state.read_db(), doc_uuid, timestamp, &hash)?;
+    let identical = match head_match {
+        Some(identical) => identical,
+        None => {
+            let options = ReconstructOptions::default();
+            let last_content =
+                load_content(&state.read_db(), &state.cache, doc_uuid, timestamp, &options)?;
+            last_content.as_bytes() == new_content
+        }
+    };
+
+    // If content is identical, return early without creating a patch
+    if identical {
+        // The style still applies to the next save, which is checked against it
+        if let Some(style) = normalize_eol {
+            write_eol_style(&state.write_db(), doc_uuid, style)?;
+        }
+        return Err("Content identical to last version - patch not created".to_string());
+    }
+
+    // Find the optimal base version to encode against
+    let (best_tag, delta) =
+        encode_with_strategy(state, doc_uuid, timestamp, &new_content, &hash, None)?;
+    let too_deep = exceeds_max_chain_depth(&state.read_db(), doc_uuid, timestamp, best_tag)?;
+    let (best_tag, delta) = if too_deep {
+        (SNAPSHOT_TAG, xpatch::encode(SNAPSHOT_TAG, &[], &new_content, ENABLE_ZSTD))
+    } else {
+        (best_tag, delta)
+    };
+
+    if state.verify_encodes {
+        let base = encoded_base(state, doc_uuid, timestamp, best_tag)?;
+        verify_delta(&delta, &base, &new_content)?;
+    }
+
+    store_patch(state, doc_uuid, timestamp, &delta, &hash, new_content, normalize_eol)
+}
+
+fn write_eol_style(db: &Connection, doc_uuid: &str, style: EolStyle) -> Result<(), String> {
+    let json = serde_json::to_string(&style).map_err(|e| e.to_string())?;
+    db.execute(
+        "UPDATE documents SET eol_style = ? WHERE uuid = ?",
+        params![&json, doc_uuid],
+    )
+    .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+/// Rewrites every CRLF or LF line ending in `content` as `style`. Lone CRs are
+/// left alone.
+fn normalize_line_endings(content: &str, style: EolStyle) -> String {
+    let lf = content.replace("\r\n", "\n");
+    match style {
+        EolStyle::Lf => lf,
+        EolStyle::Crlf => lf.replace('\n', "\r\n"),
+    }
+}
+
+/// The line endings the document's latest normalized save used, so a reader can
+/// convert back to the platform's own; None if it was never normalized.
+#[tauri::command]
+fn get_eol_style(state: State<AppState>, doc_uuid: String) -> Result<Option<EolStyle>, String> {
+    eol_style(&state.read_db(), &doc_uuid)
+}
+
+fn eol_style(db: &Connection, doc_uuid: &str) -> Result<Option<EolStyle>, String> {
+    let style: Option<String> = db
+        .query_row(
+            "SELECT eol_style FROM documents WHERE uuid = ?",
+            params![doc_uuid],
+            |row| row.get(0),
+        )
+        .optional()
+        .map_err(|e| e.to_string())?
+        .ok_or("Document not found")?;
+
+    style
+        .map(|json| serde_json::from_str(&json).map_err(|e| e.to_string()))
+        .transpose()
+}
+
+/// `create_patch` that also hands back the saved content, which is now the
+/// cached head, so the editor can refresh without a separate `load_latest`.
+#[tauri::command]
+fn create_patch_returning_content(
+    app: AppHandle,
+    state: State<AppState>,
+    doc_uuid: String,
+    current_content: String,
+    timestamp: i64,
+) -> Result<SavedVersion, String> {
+    let patch_uuid = create_patch(app, state, doc_uuid, current_content.clone(), timestamp, None)?;
+    Ok(SavedVersion {
+        patch_uuid,
+        timestamp,
+        content: current_content,
+    })
+}
+
+/// Like `create_patch`, but encodes against the version at `base_timestamp`
+/// instead of searching for the best base, e.g. for reproducible encodings.
+/// Fails if that base's chain would put the new version past `max_chain_depth`.
+/// The stored delta is always verified.
+#[tauri::command]
+fn create_patch_with_base(
+    app: AppHandle,
+    state: State<AppState>,
+    doc_uuid: String,
+    current_content: String,
+    timestamp: i64,
+    base_timestamp: i64,
+) -> Result<String, String> {
+    let patch_uuid = save_with_base(&state, &doc_uuid, current_content, timestamp, base_timestamp)?;
+    emit_document_changed(&app, &doc_uuid, DocumentChangeKind::Edited, Some(timestamp));
+    Ok(patch_uuid)
+}
+
+fn save_with_base(
+    state: &AppState,
+    doc_uuid: &str,
+    current_content: String,
+    timestamp: i64,
+    base_timestamp: i64,
+) -> Result<String, String> {
+    if base_timestamp >= timestamp {
+        return Err("Base version must be older than the new patch".to_string());
+    }
+    check_timestamp(timestamp)?;
+    check_after_head(&state.read_db(), doc_uuid, timestamp)?;
+    let limit = content_limit(&state.read_db(), doc_uuid, state.default_max_content_bytes)?;
+    check_content_size(limit, current_content.len())?;
+
+    let new_content = current_content.into_bytes();
+    let hash = content_hash(&new_content);
+
+    let tag = {
+        let db = state.read_db();
+        let exists: bool = db
+            .query_row(
+                "SELECT EXISTS(SELECT 1 FROM patches WHERE document_uuid = ? AND timestamp = ?)",
+                params![doc_uuid, base_timestamp],
+                |row| row.get(0),
+            )
+            .map_err(|e| e.to_string())?;
+        if !exists {
+            return Err("Version not found".to_string());
+        }
+
+        // Same counting as `encode_revert`: the versions between base and new patch
+        let between: i64 = db
+            .query_row(
+                "SELECT COUNT(*) FROM patches
+                 WHERE document_uuid = ? AND timestamp > ? AND timestamp < ?",
+                params![doc_uuid, base_timestamp, timestamp],
+                |row| row.get(0),
+            )
+            .map_err(|e| e.to_string())?;
+        between as usize
+    };
+
+    // The caller chose the base, so it is not swapped for a snapshot as in `create_patch`
+    if exceeds_max_chain_depth(&state.read_db(), doc_uuid, timestamp, tag)? {
+        return Err("Base chain exceeds max_chain_depth".to_string());
+    }
+    let base = content_at(&state.read_db(), &state.cache, doc_uuid, base_timestamp)?
+        .unwrap_or_default();
+    let delta = xpatch::encode(tag, &base, &new_content, ENABLE_ZSTD);
+    verify_delta(&delta, &base, &new_content)?;
+
+    store_patch(state, doc_uuid, timestamp, &delta, &hash, new_content, None)
+}
+
+/// Inserts an already encoded version, updating the head, cache and version cap.
+/// `eol_style` is recorded on the document in the same transaction.
+fn store_patch(
+    state: &AppState,
+    doc_uuid: &str,
+    timestamp: i64,
+    delta: &[u8],
+    hash: &str,
+    content: Vec<u8>,
+    eol_style: Option<EolStyle>,
+) -> Result<String, String> {
+    let compressed = dictionary_delta(state, doc_uuid, timestamp, delta, &content)?;
+
+    let mut db = state.write_db();
+
+    let patch_uuid = Uuid::new_v4().to_string();
+
+    let tx = db.transaction().map_err(|e| e.to_string())?;
+    // Checked again under the write lock in case another save got in first
+    check_after_head(&tx, doc_uuid, timestamp)?;
+    // A dictionary replaced in the meantime may already be deleted
+    let compressed = match compressed {
+        Some((id, compressed)) if document_dictionary(&tx, doc_uuid)? == Some(id) => {
+            Some((id, compressed))
+        }
+        _ => None,
+    };
+    match &compressed {
+        Some((id, compressed)) => {
+            insert_patch(&tx, &patch_uuid, doc_uuid, timestamp, compressed, hash)?;
+            tx.execute(
+                "UPDATE patches SET dictionary_id = ? WHERE uuid = ?",
+                params![id, &patch_uuid],
+            )
+            .map_err(|e| e.to_string())?;
+        }
+        None => insert_patch(&tx, &patch_uuid, doc_uuid, timestamp, delta, hash)?,
+    }
+    if let Some(style) = eol_style {
+        write_eol_style(&tx, doc_uuid, style)?;
+    }
+    refresh_head(&tx, doc_uuid)?;
+    tx.commit().map_err(|e| e.to_string())?;
+
+    // Pruning clears the document's cache, so the new head is cached after it
+    enforce_max_versions(&mut db, &state.cache, doc_uuid)?;
+
+    state
+        .cache
+        .insert((doc_uuid.to_string(), patch_uuid.clone()), content);
+
+    Ok(patch_uuid)
+}
+
+fn document_dictionary(db: &Connection, doc_uuid: &str) -> Result<Option<i64>, String> {
+    Ok(db
+        .query_row(
+            "SELECT dictionary_id FROM documents WHERE uuid = ?",
+            params![doc_uuid],
+            |row| row.get(0),
+        )
+        .optional()
+        .map_err(|e| e.to_string())?
+        .flatten())
+}
+
+/// When the document has a dictionary, the new version re-encoded against the
+/// same base without xpatch's own zstd and with its body compressed with the
+/// dictionary instead, if that beats `delta` and decodes back to `content`.
+/// Returns the dictionary id with it.
+fn dictionary_delta(
+    state: &AppState,
+    doc_uuid: &str,
+    timestamp: i64,
+    delta: &[u8],
+    content: &[u8],
+) -> Result<Option<(i64, Vec<u8>)>, String> {
+    let Some(dictionary_id) = document_dictionary(&state.read_db(), doc_uuid)? else {
+        return Ok(None);
+    };
+    let dictionary = load_dictionary(&state.read_db(), dictionary_id)?;
+
+    let tag = xpatch::get_tag(delta).map_err(|e| format!("Delta decode error: {}", e))?;
+    let base = encoded_base(state, doc_uuid, timestamp, tag)?;
+    let plain = xpatch::encode(tag, &base, content, false);
+
+    Ok(compress_delta(&plain, &dictionary)?
+        .filter(|compressed| compressed.len() < delta.len())
+        .filter(|compressed| expands_to(compressed, &dictionary, &base, content))
+        .map(|compressed| (dictionary_id, compressed)))
+}
+
+// What gets stored replaces a verified delta, so it must decode to the same content
+fn expands_to(compressed: &[u8], dictionary: &[u8], base: &[u8], content: &[u8]) -> bool {
+    expand_delta(compressed, dictionary)
+        .and_then(|delta| safe_decode(base, &delta))
+        .is_ok_and(|decoded| decoded == content)
+}
+
+/// Content the new version at `timestamp` was encoded against with `tag`: the
+/// tag-th previous version, newest first, or empty past the start of the chain.
+fn encoded_base(
+    state: &AppState,
+    doc_uuid: &str,
+    timestamp: i64,
+    tag: usize,
+) -> Result<Vec<u8>, String> {
+    let db = state.read_db();
+    let base = match encoded_base_timestamp(&db, doc_uuid, timestamp, tag)? {
+        Some(base_timestamp) => version_at(&db, &state.cache, doc_uuid, base_timestamp)?,
+        None => None,
+    };
+    Ok(base.map(|(_, content)| content).unwrap_or_default())
+}
+
+/// Timestamp of the version `encoded_base` returns, or None for empty content.
+fn encoded_base_timestamp(
+    db: &Connection,
+    doc_uuid: &str,
+    timestamp: i64,
+    tag: usize,
+) -> Result<Option<i64>, String> {
+    if tag == SNAPSHOT_TAG {
+        return Ok(None);
+    }
+
+    db.query_row(
+        "SELECT DISTINCT timestamp
+         FROM patches
+         WHERE document_uuid = ? AND timestamp < ?
+         ORDER BY timestamp DESC
+         LIMIT 1 OFFSET ?",
+        params![doc_uuid, timestamp, tag as i64],
+        |row| row.get(0),
+    )
+    .optional()
+    .map_err(|e| e.to_string())
+}
+
+/// Whether the new version at `timestamp`, encoded against its `tag` base, would
+/// depend on more deltas than the document's `max_chain_depth` allows.
+fn exceeds_max_chain_depth(
+    db: &Connection,
+    doc_uuid: &str,
+    timestamp: i64,
+    tag: usize,
+) -> Result<bool, String> {
+    let Some(max_depth) = read_max_chain_depth(db, doc_uuid)? else {
+        return Ok(false);
+    };
+    Ok(chain_depth_with_tag(db, doc_uuid, timestamp, tag)? > max_depth)
+}
+
+fn read_max_chain_depth(db: &Connection, doc_uuid: &str) -> Result<Option<usize>, String> {
+    let max_depth: Option<i64> = db
+        .query_row(
+            "SELECT max_chain_depth FROM documents WHERE uuid = ?",
+            params![doc_uuid],
+            |row| row.get(0),
+        )
+        .optional()
+        .map_err(|e| e.to_string())?
+        .flatten();
+    Ok(max_depth.map(|max| max as usize))
+}
+
+/// Chain depth the version at `timestamp` has when encoded against its `tag` base.
+fn chain_depth_with_tag(
+    db: &Connection,
+    doc_uuid: &str,
+    timestamp: i64,
+    tag: usize,
+) -> Result<usize, String> {
+    let base_depth = match encoded_base_timestamp(db, doc_uuid, timestamp, tag)? {
+        Some(base_timestamp) => chain_steps(db, doc_uuid, base_timestamp)?.len(),
+        None => 0,
+    };
+    Ok(base_depth + 1)
+}
+
+/// Checks that `delta` applied to `base` gives back exactly `expected`.
+fn verify_delta(delta: &[u8], base: &[u8], expected: &[u8]) -> Result<(), String> {
+    match safe_decode(base, delta) {
+        Ok(decoded) if decoded == expected => Ok(()),
+        Ok(_) => Err("Encode verification failed: delta does not reproduce the content".into()),
+        Err(e) => Err(format!("Encode verification failed: {}", e)),
+    }
+}
+
+/// Prunes the oldest versions of a document beyond its `max_versions`, if set.
+fn enforce_max_versions(
+    db: &mut Connection,
+    cache: &ContentCache,
+    doc_uuid: &str,
+) -> Result<(), String> {
+    let (max_versions, count): (Option<i64>, i64) = db
+        .query_row(
+            "SELECT max_versions, (SELECT COUNT(*) FROM patches WHERE document_uuid = ?1)
+             FROM documents WHERE uuid = ?1",
+            params![doc_uuid],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .map_err(|e| e.to_string())?;
+
+    let excess = match max_versions {
+        Some(max) if count > max => (count - max) as usize,
+        _ => return Ok(()),
+    };
+
+    let versions = reconstruct_versions(db, cache, doc_uuid, i64::MAX)?;
+    remove_versions(db, cache, doc_uuid, versions, |pos, _| pos < excess)?;
+
+    Ok(())
+}
+
+/// Returns the size of the delta `create_patch` would store for `content`,
+/// without inserting anything.
+#[tauri::command]
+fn estimate_patch_size(
+    state: State<AppState>,
+    doc_uuid: String,
+    content: String,
+    timestamp: i64,
+) -> Result<usize, String> {
+    let hash = content_hash(content.as_bytes());
+    let (_best_tag, delta) =
+        encode_with_strategy(&state, &doc_uuid, timestamp, content.as_bytes(), &hash, None)?;
+
+    Ok(delta.len())
+}
+
+#[tauri::command]
+fn create_document(
+    app: AppHandle,
+    state: State<AppState>,
+    name: String,
+    content_type: Option<String>,
+    created_at: Option<i64>,
+) -> Result<String, String> {
+    let tolerance_ms = state.created_at_tolerance_ms;
+    let doc_uuid =
+        insert_document(&state.write_db(), &name, content_type, created_at, tolerance_ms)?;
+    emit_document_changed(&app, &doc_uuid, DocumentChangeKind::Created, None);
+    Ok(doc_uuid)
+}
+
+fn insert_document(
+    db: &Connection,
+    name: &str,
+    content_type: Option<String>,
+    created_at: Option<i64>,
+    tolerance_ms: i64,
+) -> Result<String, String> {
+    let created_at = match created_at {
+        Some(created_at) => validate_created_at(created_at, tolerance_ms)?,
+        None => chrono::Utc::now().timestamp_millis(),
+    };
+
+    let doc_uuid = Uuid::new_v4().to_string();
+    let content_type = content_type.unwrap_or_else(|| DEFAULT_CONTENT_TYPE.to_string());
+
+    db.execute(
+        "INSERT INTO documents (uuid, name, created_at, content_type) VALUES (?, ?, ?, ?)",
+        params![&doc_uuid, name, created_at, &content_type],
+    )
+    .map_err(|e| e.to_string())?;
+    Ok(doc_uuid)
+}
+
+// How far ahead of the clock an imported creation time may be, to allow for
+// skew, unless overridden at startup
+const DEFAULT_CREATED_AT_TOLERANCE_MS: i64 = 24 * 60 * 60 * 1000;
+const CREATED_AT_TOLERANCE_ENV: &str = "XPATCH_CREATED_AT_TOLERANCE_MS";
+
+fn created_at_tolerance_ms() -> Result<i64, String> {
+    match std::env::var(CREATED_AT_TOLERANCE_ENV) {
+        Ok(value) => match value.parse::<i64>() {
+            Ok(tolerance) if tolerance >= 0 => Ok(tolerance),
+            _ => Err(format!("{} must be milliseconds: {}", CREATED_AT_TOLERANCE_ENV, value)),
+        },
+        Err(_) => Ok(DEFAULT_CREATED_AT_TOLERANCE_MS),
+    }
+}
+
+/// Accepts any creation time up to `tolerance_ms` from now.
+fn validate_created_at(created_at: i64, tolerance_ms: i64) -> Result<i64, String> {
+    let latest_allowed = chrono::Utc::now().timestamp_millis().saturating_add(tolerance_ms);
+    if created_at > latest_allowed {
+        return Err(format!("created_at is too far in the future: {}", created_at));
+    }
+    Ok(created_at)
+}
+
+/// Overrides when the document was created, e.g. to keep an imported
+/// document's original date. This moves it within `get_documents`.
+#[tauri::command]
+fn set_created_at(
+    app: AppHandle,
+    state: State<AppState>,
+    doc_uuid: String,
+    created_at: i64,
+) -> Result<(), String> {
+    let tolerance_ms = state.created_at_tolerance_ms;
+    write_created_at(&state.write_db(), &doc_uuid, created_at, tolerance_ms)?;
+    emit_document_changed(&app, &doc_uuid, DocumentChangeKind::SettingsChanged, None);
+    Ok(())
+}
+
+fn write_created_at(
+    db: &Connection,
+    doc_uuid: &str,
+    created_at: i64,
+    tolerance_ms: i64,
+) -> Result<(), String> {
+    validate_created_at(created_at, tolerance_ms)?;
+
+    let updated = db
+        .execute(
+            "UPDATE documents SET created_at = ? WHERE uuid = ?",
+            params![created_at, doc_uuid],
+        )
+        .map_err(|e| e.to_string())?;
+
+    if updated == 0 {
+        return Err(format!("Document not found: {}", doc_uuid));
+    }
+    Ok(())
+}
+
+/// Creates one document per name inside a single transaction, returning the new
+/// uuids in the same order. Nothing is inserted if any row fails.
+#[tauri::command]
+fn create_documents(
+    app: AppHandle,
+    state: State<AppState>,
+    names: Vec<String>,
+) -> Result<Vec<String>, String> {
+    let created_at = chrono::Utc::now().timestamp_millis();
+    let doc_uuids = insert_documents(&mut state.write_db(), &names, created_at)?;
+
+    for doc_uuid in &doc_uuids {
+        emit_document_changed(&app, doc_uuid, DocumentChangeKind::Created, None);
+    }
+    Ok(doc_uuids)
+}
+
+fn insert_documents(
+    db: &mut Connection,
+    names: &[String],
+    created_at: i64,
+) -> Result<Vec<String>, String> {
+    let tx = db.transaction().map_err(|e| e.to_string())?;
+    let mut doc_uuids = Vec::with_capacity(names.len());
+    {
+        let mut stmt = tx
+            .prepare("INSERT INTO documents (uuid, name, created_at) VALUES (?, ?, ?)")
+            .map_err(|e| e.to_string())?;
+
+        for name in names {
+            let doc_uuid = Uuid::new_v4().to_string();
+            stmt.execute(params![&doc_uuid, name, created_at])
+                .map_err(|e| e.to_string())?;
+            doc_uuids.push(doc_uuid);
+        }
+    }
+    tx.commit().map_err(|e| e.to_string())?;
+    Ok(doc_uuids)
+}
+
+/// Creates a document whose first version is `content`, in one transaction.
+#[tauri::command]
+fn create_document_with_content(
+    app: AppHandle,
+    state: State<AppState>,
+    name: String,
+    content: String,
+) -> Result<String, String> {
+    let doc_uuid =
+        create_document_from_bytes(&state, &name, DEFAULT_CONTENT_TYPE, content.into_bytes())?;
+
+    emit_document_changed(&app, &doc_uuid, DocumentChangeKind::Created, None);
+    Ok(doc_uuid)
+}
+
+/// Creates a document whose first version is the file at `path`, byte for
+/// byte. The content type follows the file extension, falling back to plain
+/// text for UTF-8 files and `application/octet-stream` for anything else.
+#[tauri::command]
+fn import_file_as_document(
+    app: AppHandle,
+    state: State<AppState>,
+    name: String,
+    path: String,
+) -> Result<String, String> {
+    let (content_type, content) = read_import_file(&path)?;
+    let doc_uuid = create_document_from_bytes(&state, &name, content_type, content)?;
+
+    emit_document_changed(&app, &doc_uuid, DocumentChangeKind::Created, None);
+    Ok(doc_uuid)
+}
+
+/// Bytes of the file at `path` and the content type to import them as.
+fn read_import_file(path: &str) -> Result<(&'static str, Vec<u8>), String> {
+    let content = std::fs::read(path).map_err(|e| format!("IO error reading {}: {}", path, e))?;
+    let extension = Path::new(path)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    let content_type = match extension_content_type(&extension) {
+        Some(content_type) => content_type,
+        None if std::str::from_utf8(&content).is_ok() => DEFAULT_CONTENT_TYPE,
+        None => "application/octet-stream",
+    };
+
+    Ok((content_type, content))
+}
+
+// Inverse of `content_type_extension` for the types it names
+fn extension_content_type(extension: &str) -> Option<&'static str> {
+    match extension {
+        "md" => Some("text/markdown"),
+        "html" | "htm" => Some("text/html"),
+        "csv" => Some("text/csv"),
+        "json" => Some("application/json"),
+        "pdf" => Some("application/pdf"),
+        "png" => Some("image/png"),
+        "jpg" | "jpeg" => Some("image/jpeg"),
+        "txt" => Some("text/plain"),
+        _ => None,
+    }
+}
+
+/// Inserts a document and its first version together, so a failure leaves no
+/// empty document behind. Empty content creates the document without a patch,
+/// as `create_patch` would refuse it as identical.
+fn create_document_from_bytes(
+    state: &AppState,
+    name: &str,
+    content_type: &str,
+    content: Vec<u8>,
+) -> Result<String, String> {
+    check_content_size(state.default_max_content_bytes, content.len())?;
+
+    let now = chrono::Utc::now().timestamp_millis();
+    let doc_uuid = Uuid::new_v4().to_string();
+    let patch_uuid = Uuid::new_v4().to_string();
+
+    let mut db = state.write_db();
+    let tx = db.transaction().map_err(|e| e.to_string())?;
+    tx.execute(
+        "INSERT INTO documents (uuid, name, created_at, content_type) VALUES (?, ?, ?, ?)",
+        params![&doc_uuid, name, now, content_type],
+    )
+    .map_err(|e| e.to_string())?;
+    if !content.is_empty() {
+        let delta = xpatch::encode(0, &[], &content, ENABLE_ZSTD);
+        insert_patch(&tx, &patch_uuid, &doc_uuid, now, &delta, &content_hash(&content))?;
+        refresh_head(&tx, &doc_uuid)?;
+    }
+    tx.commit().map_err(|e| e.to_string())?;
+
+    if !content.is_empty() {
+        state.cache.insert((doc_uuid.clone(), patch_uuid), content);
+    }
+
+    Ok(doc_uuid)
+}
+
+/// Returns `desired` if no document (trashed ones included) has that name yet,
+/// otherwise the first free `desired (2)`, `desired (3)`, ...
+#[tauri::command]
+fn suggest_document_name(state: State<AppState>, desired: String) -> Result<String, String> {
+    free_document_name(&state.read_db(), &desired)
+}
+
+fn free_document_name(db: &Connection, desired: &str) -> Result<String, String> {
+    let mut stmt = db
+        .prepare(
+            "SELECT name FROM documents
+             WHERE name = ?1 OR substr(name, 1, length(?1) + 2) = ?1 || ' ('",
+        )
+        .map_err(|e| e.to_string())?;
+
+    let taken: HashSet<String> = stmt
+        .query_map(params![desired], |row| row.get(0))
+        .map_err(|e| e.to_string())?
+        .collect::<Result<_, _>>()
+        .map_err(|e| e.to_string())?;
+
+    if !taken.contains(desired) {
+        return Ok(desired.to_string());
+    }
+    let suggestion = (2..)
+        .map(|n| format!("{} ({})", desired, n))
+        .find(|name| !taken.contains(name))
+        .expect("only finitely many names are taken");
+    Ok(suggestion)
+}
+
+#[tauri::command]
+fn set_base_strategy(
+    app: AppHandle,
+    state: State<AppState>,
+    doc_uuid: String,
+    strategy: BaseStrategy,
+) -> Result<(), String> {
+    write_base_strategy(&state.write_db(), &doc_uuid, strategy)?;
+    emit_document_changed(&app, &doc_uuid, DocumentChangeKind::SettingsChanged, None);
+    Ok(())
+}
+
+fn write_base_strategy(
+    db: &Connection,
+    doc_uuid: &str,
+    strategy: BaseStrategy,
+) -> Result<(), String> {
+    match strategy {
+        BaseStrategy::OptimalDepth(0) | BaseStrategy::SnapshotEvery(0) => {
+            return Err("Strategy parameter must be at least 1".to_string());
+        }
+        _ => {}
+    }
+
+    let json = serde_json::to_string(&strategy).map_err(|e| e.to_string())?;
+    let updated = db
+        .execute(
+            "UPDATE documents SET base_strategy = ? WHERE uuid = ?",
+            params![&json, doc_uuid],
+        )
+        .map_err(|e| e.to_string())?;
+
+    if updated == 0 {
+        return Err("Document not found".to_string());
+    }
+    Ok(())
+}
+
+#[tauri::command]
+fn set_content_type(
+    app: AppHandle,
+    state: State<AppState>,
+    doc_uuid: String,
+    content_type: String,
+) -> Result<(), String> {
+    write_content_type(&state.write_db(), &doc_uuid, &content_type)?;
+    emit_document_changed(&app, &doc_uuid, DocumentChangeKind::SettingsChanged, None);
+    Ok(())
+}
+
+fn write_content_type(db: &Connection, doc_uuid: &str, content_type: &str) -> Result<(), String> {
+    let updated = db
+        .execute(
+            "UPDATE documents SET content_type = ? WHERE uuid = ?",
+            params![content_type, doc_uuid],
+        )
+        .map_err(|e| e.to_string())?;
+
+    if updated == 0 {
+        return Err("Document not found".to_string());
+    }
+    Ok(())
+}
+
+/// Caps how many versions the document keeps (None for unlimited). The oldest
+/// versions beyond the cap are pruned when the next patch is created.
+#[tauri::command]
+fn set_max_versions(
+    app: AppHandle,
+    state: State<AppState>,
+    doc_uuid: String,
+    max: Option<i64>,
+) -> Result<(), String> {
+    write_max_versions(&state.write_db(), &doc_uuid, max)?;
+    emit_document_changed(&app, &doc_uuid, DocumentChangeKind::SettingsChanged, None);
+    Ok(())
+}
+
+fn write_max_versions(db: &Connection, doc_uuid: &str, max: Option<i64>) -> Result<(), String> {
+    if max.is_some_and(|max| max < 1) {
+        return Err("max_versions must be at least 1".to_string());
+    }
+
+    let updated = db
+        .execute(
+            "UPDATE documents SET max_versions = ? WHERE uuid = ?",
+            params![max, doc_uuid],
+        )
+        .map_err(|e| e.to_string())?;
+
+    if updated == 0 {
+        return Err("Document not found".to_string());
+    }
+    Ok(())
+}
+
+/// Caps how many deltas a new version may depend on (None for unlimited). A
+/// `create_patch` that would exceed it stores a snapshot instead, as do
+/// compaction and the rewrites behind pruning, merging and splitting. Existing
+/// versions are left as they are until one of those touches them.
+#[tauri::command]
+fn set_max_chain_depth(
+    app: AppHandle,
+    state: State<AppState>,
+    doc_uuid: String,
+    max: Option<i64>,
+) -> Result<(), String> {
+    write_max_chain_depth(&state.write_db(), &doc_uuid, max)?;
+    emit_document_changed(&app, &doc_uuid, DocumentChangeKind::SettingsChanged, None);
+    Ok(())
+}
+
+fn write_max_chain_depth(db: &Connection, doc_uuid: &str, max: Option<i64>) -> Result<(), String> {
+    if max.is_some_and(|max| max < 1) {
+        return Err("max_chain_depth must be at least 1".to_string());
+    }
+
+    let updated = db
+        .execute(
+            "UPDATE documents SET max_chain_depth = ? WHERE uuid = ?",
+            params![max, doc_uuid],
+        )
+        .map_err(|e| e.to_string())?;
+
+    if updated == 0 {
+        return Err("Document not found".to_string());
+    }
+    Ok(())
+}
+
+// Size cap applied to every document that has none of its own, e.g. for kiosk
+// deployments; unset means unlimited
+const MAX_CONTENT_BYTES_ENV: &str = "XPATCH_MAX_CONTENT_BYTES";
+
+fn default_max_content_bytes() -> Result<Option<i64>, String> {
+    match std::env::var(MAX_CONTENT_BYTES_ENV) {
+        Ok(value) => match value.parse::<i64>() {
+            Ok(max) if max >= 0 => Ok(Some(max)),
+            _ => Err(format!("{} must be a byte count: {}", MAX_CONTENT_BYTES_ENV, value)),
+        },
+        Err(_) => Ok(None),
+    }
+}
+
+// Set to 0 to skip decoding each new delta before it is stored, trading the
+// write-time check for faster saves
+const VERIFY_ENCODES_ENV: &str = "XPATCH_VERIFY_ENCODES";
+
+fn verify_encodes() -> Result<bool, String> {
+    match std::env::var(VERIFY_ENCODES_ENV).as_deref() {
+        Ok("1") | Err(_) => Ok(true),
+        Ok("0") => Ok(false),
+        Ok(value) => Err(format!("{} must be 0 or 1: {}", VERIFY_ENCODES_ENV, value)),
+    }
+}
+
+/// Caps the size of any single version of the document (None falls back to the
+/// startup default). Existing versions are kept even if they are larger.
+#[tauri::command]
+fn set_max_content_bytes(
+    app: AppHandle,
+    state: State<AppState>,
+    doc_uuid: String,
+    max: Option<i64>,
+) -> Result<(), String> {
+    write_max_content_bytes(&state.write_db(), &doc_uuid, max)?;
+    emit_document_changed(&app, &doc_uuid, DocumentChangeKind::SettingsChanged, None);
+    Ok(())
+}
+
+fn write_max_content_bytes(
+    db: &Connection,
+    doc_uuid: &str,
+    max: Option<i64>,
+) -> Result<(), String> {
+    if max.is_some_and(|max| max < 0) {
+        return Err("max_content_bytes must not be negative".to_string());
+    }
+
+    let updated = db
+        .execute(
+            "UPDATE documents SET max_content_bytes = ? WHERE uuid = ?",
+            params![max, doc_uuid],
+        )
+        .map_err(|e| e.to_string())?;
+
+    if updated == 0 {
+        return Err("Document not found".to_string());
+    }
+    Ok(())
+}
+
+/// The size cap for new versions of the document, if any.
+fn content_limit(
+    db: &Connection,
+    doc_uuid: &str,
+    default: Option<i64>,
+) -> Result<Option<i64>, String> {
+    let max: Option<i64> = db
+        .query_row(
+            "SELECT max_content_bytes FROM documents WHERE uuid = ?",
+            params![doc_uuid],
+            |row| row.get(0),
+        )
+        .optional()
+        .map_err(|e| e.to_string())?
+        .flatten();
+    Ok(max.or(default))
+}
+
+fn check_content_size(limit: Option<i64>, len: usize) -> Result<(), String> {
+    match limit {
+        Some(max) if len as i64 > max => {
+            Err(format!("Content too large: {} bytes (limit {})", len, max))
+        }
+        _ => Ok(()),
+    }
+}
+
+#[tauri::command]
+fn get_documents(state: State<AppState>) -> Result<Vec<Document>, String> {
+    list_documents(&state.read_db())
+}
+
+fn list_documents(db: &Connection) -> Result<Vec<Document>, String> {
+    let mut stmt = db
+        .prepare(&format!(
+            "SELECT {} FROM documents WHERE deleted_at IS NULL ORDER BY created_at DESC",
+            DOCUMENT_COLUMNS
+        ))
+        .map_err(|e| e.to_string())?;
+
+    let docs = stmt
+        .query_map([], document_from_row)
+        .map_err(|e| e.to_string())?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| e.to_string())?;
+
+    Ok(docs)
+}
+
+/// `get_documents` ordered most recently edited first: by the newest patch,
+/// or by `created_at` for documents that have none.
+#[tauri::command]
+fn get_documents_by_activity(state: State<AppState>) -> Result<Vec<Document>, String> {
+    documents_by_activity(&state.read_db())
+}
+
+fn documents_by_activity(db: &Connection) -> Result<Vec<Document>, String> {
+    let mut stmt = db
+        .prepare(&format!(
+            "SELECT {} FROM documents d
+             LEFT JOIN (
+                SELECT document_uuid, MAX(timestamp) AS last_edited
+                FROM patches
+                GROUP BY document_uuid
+             ) p ON p.document_uuid = d.uuid
+             WHERE d.deleted_at IS NULL
+             ORDER BY COALESCE(p.last_edited, d.created_at) DESC, d.created_at DESC",
+            DOCUMENT_COLUMNS
+        ))
+        .map_err(|e| e.to_string())?;
+
+    let docs = stmt
+        .query_map([], document_from_row)
+        .map_err(|e| e.to_string())?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| e.to_string())?;
+
+    Ok(docs)
+}
+
+/// `get_documents` a page at a time, optionally keeping only names containing
+/// `search` (case-insensitive for ASCII, as SQLite's LIKE is).
+#[tauri::command]
+fn get_documents_paged(
+    state: State<AppState>,
+    offset: i64,
+    limit: i64,
+    search: Option<String>,
+) -> Result<DocumentPage, String> {
+    document_page(&state.read_db(), offset, limit, search.as_deref())
+}
+
+fn document_page(
+    db: &Connection,
+    offset: i64,
+    limit: i64,
+    search: Option<&str>,
+) -> Result<DocumentPage, String> {
+    if offset < 0 || limit < 0 {
+        return Err("offset and limit must not be negative".to_string());
+    }
+
+    // Wildcards in the search text match themselves
+    let pattern = search.map(|search| {
+        let escaped = search
+            .replace('\\', "\\\\")
+            .replace('%', "\\%")
+            .replace('_', "\\_");
+        format!("%{}%", escaped)
+    });
+    let filter = "WHERE deleted_at IS NULL AND (?1 IS NULL OR name LIKE ?1 ESCAPE '\\')";
+
+    with_read_transaction(db, |tx| {
+        let total: i64 = tx
+            .query_row(
+                &format!("SELECT COUNT(*) FROM documents {}", filter),
+                params![&pattern],
+                |row| row.get(0),
+            )
+            .map_err(|e| e.to_string())?;
+
+        let mut stmt = tx
+            .prepare(&format!(
+                "SELECT {} FROM documents {} ORDER BY created_at DESC LIMIT ?2 OFFSET ?3",
+                DOCUMENT_COLUMNS, filter
+            ))
+            .map_err(|e| e.to_string())?;
+
+        let documents = stmt
+            .query_map(params![&pattern, limit, offset], document_from_row)
+            .map_err(|e| e.to_string())?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| e.to_string())?;
+
+        Ok(DocumentPage { documents, total })
+    })
+}
+
+/// Documents with at least one patch in `[from_ms, to_ms]`, most recently
+/// active (within the window) first. Trashed documents are left out.
+#[tauri::command]
+fn documents_active_between(
+    state: State<AppState>,
+    from_ms: i64,
+    to_ms: i64,
+) -> Result<Vec<Document>, String> {
+    active_documents(&state.read_db(), from_ms, to_ms)
+}
+
+fn active_documents(db: &Connection, from_ms: i64, to_ms: i64) -> Result<Vec<Document>, String> {
+    if from_ms > to_ms {
+        return Err("from_ms must not be after to_ms".to_string());
+    }
+
+    let mut stmt = db
+        .prepare(&format!(
+            "SELECT {}
+             FROM documents
+             JOIN (
+                SELECT document_uuid, MAX(timestamp) AS last_active
+                FROM patches
+                WHERE timestamp BETWEEN ? AND ?
+                GROUP BY document_uuid
+             ) ON document_uuid = uuid
+             WHERE deleted_at IS NULL
+             ORDER BY last_active DESC",
+            DOCUMENT_COLUMNS
+        ))
+        .map_err(|e| e.to_string())?;
+
+    let docs = stmt
+        .query_map(params![from_ms, to_ms], document_from_row)
+        .map_err(|e| e.to_string())?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| e.to_string())?;
+
+    Ok(docs)
+}
+
+#[tauri::command]
+fn get_documents_with_stats(state: State<AppState>) -> Result<Vec<DocumentSummary>, String> {
+    document_summaries(&state.read_db())
+}
+
+fn document_summaries(db: &Connection) -> Result<Vec<DocumentSummary>, String> {
+    let mut stmt = db
+        .prepare(
+            "SELECT d.uuid, d.name, d.created_at, d.content_type,
+                    COUNT(p.uuid), COALESCE(SUM(LENGTH(p.delta)), 0)
+             FROM documents d
+             LEFT JOIN patches p ON p.document_uuid = d.uuid
+             WHERE d.deleted_at IS NULL
+             GROUP BY d.uuid
+             ORDER BY d.created_at DESC",
+        )
+        .map_err(|e| e.to_string())?;
+
+    let summaries = stmt
+        .query_map([], |row| {
+            Ok(DocumentSummary {
+                document: document_from_row(row)?,
+                total_patches: row.get(4)?,
+                total_delta_bytes: row.get(5)?,
+            })
+        })
+        .map_err(|e| e.to_string())?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| e.to_string())?;
+
+    Ok(summaries)
+}
+
+#[tauri::command]
+fn get_patch_timestamps(
+    state: State<AppState>,
+    doc_uuid: String,
+) -> Result<Vec<i64>, String> {
+    let db = state.read_db();
+    let mut stmt = db
+        .prepare("SELECT timestamp FROM patches WHERE document_uuid = ? ORDER BY timestamp ASC")
+        .map_err(|e| e.to_string())?;
+
+    let timestamps = stmt
+        .query_map(params![&doc_uuid], |row| row.get(0))
+        .map_err(|e| e.to_string())?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| e.to_string())?;
+
+    Ok(timestamps)
+}
+
+/// `get_patch_timestamps` for several documents in one query. Every requested
+/// uuid gets an entry, empty when it has no patches.
+#[tauri::command]
+fn get_patch_timestamps_multi(
+    state: State<AppState>,
+    doc_uuids: Vec<String>,
+) -> Result<HashMap<String, Vec<i64>>, String> {
+    timestamps_by_document(&state.read_db(), &doc_uuids)
+}
+
+fn timestamps_by_document(
+    db: &Connection,
+    doc_uuids: &[String],
+) -> Result<HashMap<String, Vec<i64>>, String> {
+    let mut timestamps: HashMap<String, Vec<i64>> =
+        doc_uuids.iter().map(|doc_uuid| (doc_uuid.clone(), Vec::new())).collect();
+    if doc_uuids.is_empty() {
+        return Ok(timestamps);
+    }
+
+    let placeholders = vec!["?"; doc_uuids.len()].join(", ");
+    let mut stmt = db
+        .prepare(&format!(
+            "SELECT document_uuid, timestamp FROM patches
+             WHERE document_uuid IN ({placeholders})
+             ORDER BY timestamp ASC"
+        ))
+        .map_err(|e| e.to_string())?;
+    let rows = stmt
+        .query_map(rusqlite::params_from_iter(doc_uuids), |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?))
+        })
+        .map_err(|e| e.to_string())?;
+
+    for row in rows {
+        let (doc_uuid, timestamp) = row.map_err(|e| e.to_string())?;
+        timestamps.entry(doc_uuid).or_default().push(timestamp);
+    }
+
+    Ok(timestamps)
+}
+
+fn latest_timestamp(db: &Connection, doc_uuid: &str) -> Result<Option<i64>, String> {
+    db.query_row(
+        "SELECT MAX(timestamp) FROM patches WHERE document_uuid = ?",
+        params![doc_uuid],
+        |row| row.get(0),
+    )
+    .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn get_latest_timestamp(
+    state: State<AppState>,
+    doc_uuid: String,
+) -> Result<Option<i64>, String> {
+    let db = state.read_db();
+    latest_timestamp(&db, &doc_uuid)
+}
+
+/// Asks the reconstruction started with `request_id` to stop. Returns false if
+/// no such reconstruction is running.
+#[tauri::command]
+fn cancel_reconstruction(state: State<AppState>, request_id: String) -> Result<bool, String> {
+    Ok(request_cancellation(&state, &request_id))
+}
+
+fn request_cancellation(state: &AppState, request_id: &str) -> bool {
+    match state.lock_reconstructions().get(request_id) {
+        Some(token) => {
+            token.store(true, Ordering::Relaxed);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Switches to the database at `path` (created if missing, `:memory:` for a
+/// throwaway one), e.g. to change profile. Waits for in-flight queries, then
+/// drops everything cached from the previous database.
+#[tauri::command]
+fn open_database(state: State<AppState>, path: String) -> Result<DatabaseLocation, String> {
+    switch_database(&state, &path)
+}
+
+fn switch_database(state: &AppState, path: &str) -> Result<DatabaseLocation, String> {
+    let (db, location) = open_custom(path)?;
+
+    state.db.replace(db);
+    *lock(&state.db_location) = location.clone();
+    state.lock_cache().clear();
+
+    Ok(location)
+}
+
+#[tauri::command]
+fn database_location(state: State<AppState>) -> Result<DatabaseLocation, String> {
+    Ok(state.db_location())
+}
+
+/// Database size on disk and row totals, without reconstructing anything.
+#[tauri::command]
+fn storage_report(state: State<AppState>) -> Result<StorageReport, String> {
+    storage_usage(&state)
+}
+
+fn storage_usage(state: &AppState) -> Result<StorageReport, String> {
+    // The WAL holds writes not yet checkpointed, so it counts towards the size
+    let file_bytes = match &state.db_location().path {
+        Some(path) => {
+            let main = std::fs::metadata(path).map_err(|e| e.to_string())?.len();
+            let wal = std::fs::metadata(format!("{}-wal", path)).map_or(0, |m| m.len());
+            Some((main + wal) as i64)
+        }
+        None => None,
+    };
+
+    let db = state.read_db();
+    let (document_count, patch_count, total_delta_bytes) = db
+        .query_row(
+            "SELECT (SELECT COUNT(*) FROM documents),
+                    COUNT(*),
+                    COALESCE(SUM(LENGTH(delta)), 0)
+             FROM patches",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )
+        .map_err(|e| e.to_string())?;
+
+    Ok(StorageReport {
+        file_bytes,
+        document_count,
+        patch_count,
+        total_delta_bytes,
+    })
+}
+
+/// Document and patch totals outside the trash, for a dashboard.
+#[tauri::command]
+fn library_counts(state: State<AppState>) -> Result<LibraryCounts, String> {
+    count_library(&state.read_db())
+}
+
+fn count_library(db: &Connection) -> Result<LibraryCounts, String> {
+    db.query_row(
+        "SELECT (SELECT COUNT(*) FROM documents WHERE deleted_at IS NULL),
+                (SELECT COUNT(*) FROM patches p
+                 JOIN documents d ON d.uuid = p.document_uuid
+                 WHERE d.deleted_at IS NULL)",
+        [],
+        |row| {
+            Ok(LibraryCounts {
+                documents: row.get(0)?,
+                patches: row.get(1)?,
+            })
+        },
+    )
+    .map_err(|e| e.to_string())
+}
+
+/// Uncompressed bytes of every version of every document, trashed ones
+/// included since they still take up space, over the delta bytes storing them.
+/// 1.0 when nothing is stored. Every document is reconstructed, as there is no
+/// stored uncompressed length.
+#[tauri::command]
+fn library_compression_ratio(state: State<AppState>) -> Result<f64, String> {
+    library_ratio(&state.read_db(), &state.cache)
+}
+
+fn library_ratio(db: &Connection, cache: &ContentCache) -> Result<f64, String> {
+    let mut stmt = db.prepare("SELECT uuid FROM documents").map_err(|e| e.to_string())?;
+    let doc_uuids = stmt
+        .query_map([], |row| row.get::<_, String>(0))
+        .map_err(|e| e.to_string())?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| e.to_string())?;
+
+    let options = ReconstructOptions {
+        skip_cache: true,
+        ..Default::default()
+    };
+    let mut total_delta_bytes = 0;
+    let mut total_uncompressed_bytes = 0;
+    for doc_uuid in &doc_uuids {
+        let stats = document_stats(db, cache, doc_uuid, &options)?;
+        total_delta_bytes += stats.total_delta_bytes;
+        total_uncompressed_bytes += stats.total_uncompressed_bytes;
+    }
+
+    if total_delta_bytes == 0 {
+        return Ok(1.0);
+    }
+    Ok(total_uncompressed_bytes as f64 / total_delta_bytes as f64)
+}
+
+/// First and last edit timestamps of a document. A document with no patches
+/// has never been edited, so both bounds are its `created_at`.
+#[tauri::command]
+fn document_time_bounds(state: State<AppState>, doc_uuid: String) -> Result<(i64, i64), String> {
+    time_bounds(&state.read_db(), &doc_uuid)
+}
+
+fn time_bounds(db: &Connection, doc_uuid: &str) -> Result<(i64, i64), String> {
+    db.query_row(
+        "SELECT COALESCE(MIN(p.timestamp), d.created_at),
+                COALESCE(MAX(p.timestamp), d.created_at)
+         FROM documents d
+         LEFT JOIN patches p ON p.document_uuid = d.uuid
+         WHERE d.uuid = ?
+         GROUP BY d.uuid",
+        params![doc_uuid],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )
+    .optional()
+    .map_err(|e| e.to_string())?
+    .ok_or_else(|| "Document not found".to_string())
+}
+
+/// Rebuilds the database file to release space freed by deletions, returning
+/// the bytes reclaimed. Always 0 for the in-memory tier.
+#[tauri::command]
+fn vacuum_database(state: State<AppState>) -> Result<i64, String> {
+    vacuum(&state)
+}
+
+fn vacuum(state: &AppState) -> Result<i64, String> {
+    // VACUUM cannot run while another connection is mid-read
+    let (_readers, db) = state.db.exclusive();
+
+    let file_size = || -> Result<i64, String> {
+        let Some(path) = &state.db_location().path else {
+            return Ok(0);
+        };
+        // Fold the WAL back into the main file so its size is what's on disk
+        db.execute_batch("PRAGMA wal_checkpoint(TRUNCATE)")
+            .map_err(|e| e.to_string())?;
+        let metadata = std::fs::metadata(path).map_err(|e| e.to_string())?;
+        Ok(metadata.len() as i64)
+    };
+
+    let size_before = file_size()?;
+    db.execute_batch("VACUUM").map_err(|e| e.to_string())?;
+    let size_after = file_size()?;
+
+    // Content is unaffected, but this is a natural point to drop what's cached
+    state.lock_cache().clear();
+
+    Ok(size_before - size_after)
+}
+
+/// How far the database's schema is behind this build. Databases are migrated
+/// when opened, so this only reports a gap for one changed since.
+#[tauri::command]
+fn schema_status(state: State<AppState>) -> Result<SchemaStatus, String> {
+    read_schema_status(&state.read_db())
+}
+
+fn read_schema_status(db: &Connection) -> Result<SchemaStatus, String> {
+    let current_version = schema_version(db).map_err(|e| e.to_string())?;
+    let latest_version = MIGRATIONS.len() as i64;
+    Ok(SchemaStatus {
+        current_version,
+        latest_version,
+        up_to_date: current_version >= latest_version,
+    })
+}
+
+/// Applies any pending migrations now rather than at the next start, returning
+/// the schema version the database is at afterwards.
+#[tauri::command]
+fn run_migrations(state: State<AppState>) -> Result<i64, String> {
+    apply_migrations(&state.write_db()).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn clear_cache(state: State<AppState>) -> Result<(), String> {
+    let mut cache = state.lock_cache();
+    cache.clear();
+    Ok(())
+}
+
+/// Drops only `doc_uuid`'s cached versions, e.g. after it was edited outside
+/// the app. Returns the number of entries removed.
+#[tauri::command]
+fn clear_document_cache(state: State<AppState>, doc_uuid: String) -> Result<usize, String> {
+    Ok(invalidate_document_cache(&state.cache, &doc_uuid))
+}
+
+/// Compresses cache entries stored from now on, so highly compressible text
+/// takes a fraction of the memory. Entries already cached keep their form.
+#[tauri::command]
+fn set_cache_compression(state: State<AppState>, enabled: bool) -> Result<(), String> {
+    state.cache.compress.store(enabled, Ordering::Relaxed);
+    Ok(())
+}
+
+/// What the cache holds: `logical_bytes` of content in `stored_bytes` of memory.
+#[tauri::command]
+fn cache_stats(state: State<AppState>) -> Result<CacheStats, String> {
+    Ok(cache_statistics(&state.cache))
+}
+
+fn cache_statistics(cache: &ContentCache) -> CacheStats {
+    let entries = cache.entries();
+    CacheStats {
+        entries: entries.len(),
+        logical_bytes: entries.values().map(CachedContent::len).sum(),
+        stored_bytes: entries.values().map(CachedContent::stored_len).sum(),
+        compression_enabled: cache.compress.load(Ordering::Relaxed),
+        hits: cache.hits.load(Ordering::Relaxed),
+        misses: cache.misses.load(Ordering::Relaxed),
+    }
+}
+
+/// Reconstructs every version of a document in one pass so later loads are all
+/// cache hits, e.g. before scrubbing through its timeline.
+#[tauri::command]
+fn warm_cache(state: State<AppState>, doc_uuid: String) -> Result<CacheWarmup, String> {
+    warm_document_cache(&state.read_db(), &state.cache, &doc_uuid)
+}
+
+fn warm_document_cache(
+    db: &Connection,
+    cache: &ContentCache,
+    doc_uuid: &str,
+) -> Result<CacheWarmup, String> {
+    let versions = reconstruct_versions(db, cache, doc_uuid, i64::MAX)?;
+
+    Ok(CacheWarmup {
+        versions_warmed: versions.len(),
+        bytes_cached: versions.iter().map(|(_, content)| content.len()).sum(),
+    })
+}
+
+/// Starts warming the cache for a document in the background, e.g. on hover, so
+/// opening it is instant. Returns false if a prefetch for it is already running.
+#[tauri::command]
+fn prefetch_document(
+    app: AppHandle,
+    state: State<AppState>,
+    doc_uuid: String,
+) -> Result<bool, String> {
+    if !state.lock_prefetching().insert(doc_uuid.clone()) {
+        return Ok(false);
+    }
+
+    std::thread::spawn(move || prefetch(&app.state::<AppState>(), &doc_uuid));
+    Ok(true)
+}
+
+/// Warms the cache for a document `prefetch_document` claimed, then releases it.
+fn prefetch(state: &AppState, doc_uuid: &str) {
+    // Best-effort; a failed prefetch only means the first load does the work
+    let _ = reconstruct_versions(&state.read_db(), &state.cache, doc_uuid, i64::MAX);
+    state.lock_prefetching().remove(doc_uuid);
+}
+
+#[tauri::command]
+fn get_document_stats(
+    app: AppHandle,
+    state: State<AppState>,
+    doc_uuid: String,
+) -> Result<DocumentStats, String> {
+    let db = state.read_db();
+
+    let progress = emit_progress(&app, &doc_uuid);
+    let options = ReconstructOptions {
+        progress: Some(&progress),
+        skip_cache: true,
+        ..Default::default()
+    };
+    document_stats(&db, &state.cache, &doc_uuid, &options)
+}
+
+fn document_stats(
+    db: &Connection,
+    cache: &ContentCache,
+    doc_uuid: &str,
+    options: &ReconstructOptions,
+) -> Result<DocumentStats, String> {
+    let (total_patches, total_delta_bytes, total_uncompressed_bytes) =
+        with_read_transaction(db, |tx| {
+            // Get total patches and delta size
+            let (total_patches, total_delta_bytes): (i64, i64) = tx
+                .query_row(
+                    "SELECT COUNT(*), COALESCE(SUM(LENGTH(delta)), 0)
+                     FROM patches
+                     WHERE document_uuid = ?",
+                    params![doc_uuid],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )
+                .map_err(|e| e.to_string())?;
+
+            // Calculate actual uncompressed size by reconstructing each version
+            let versions = reconstruct_versions_with(tx, cache, doc_uuid, i64::MAX, options)?;
+            let total_uncompressed_bytes: i64 =
+                versions.iter().map(|(_, content)| content.len() as i64).sum();
+
+            Ok((total_patches, total_delta_bytes, total_uncompressed_bytes))
+        })?;
+
+    // Calculate true compression ratio
+    let compression_ratio = if total_delta_bytes > 0 {
+        total_uncompressed_bytes as f64 / total_delta_bytes as f64
+    } else {
+        1.0
+    };
+
+    Ok(DocumentStats {
+        total_patches,
+        total_delta_bytes,
+        total_uncompressed_bytes,
+        compression_ratio,
+    })
+}
+
+// Quotes a CSV field when it contains a separator, quote or line break
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Writes `get_document_stats` for every document outside the trash to a CSV
+/// file at `path`, oldest document first. Returns the number of rows written.
+/// There is no stored uncompressed length, so each document is reconstructed.
+#[tauri::command]
+fn export_stats_csv(state: State<AppState>, path: String) -> Result<usize, String> {
+    write_stats_csv(&state, &path)
+}
+
+fn write_stats_csv(state: &AppState, path: &str) -> Result<usize, String> {
+    let mut csv = String::from(
+        "uuid,name,created_at,total_patches,total_delta_bytes,\
+         total_uncompressed_bytes,compression_ratio\n",
+    );
+    let rows = {
+        let db = state.read_db();
+        let mut stmt = db
+            .prepare(
+                "SELECT uuid, name, created_at FROM documents
+                 WHERE deleted_at IS NULL
+                 ORDER BY created_at ASC, rowid ASC",
+            )
+            .map_err(|e| e.to_string())?;
+        let documents = stmt
+            .query_map([], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get::<_, i64>(2)?))
+            })
+            .map_err(|e| e.to_string())?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| e.to_string())?;
+
+        let options = ReconstructOptions {
+            skip_cache: true,
+            ..Default::default()
+        };
+        for (doc_uuid, name, created_at) in &documents {
+            let stats = document_stats(&db, &state.cache, doc_uuid, &options)?;
+            csv.push_str(&format!(
+                "{},{},{},{},{},{},{}\n",
+                csv_field(doc_uuid),
+                csv_field(name),
+                created_at,
+                stats.total_patches,
+                stats.total_delta_bytes,
+                stats.total_uncompressed_bytes,
+                stats.compression_ratio,
+            ));
+        }
+        documents.len()
+    };
+
+    let mut file = create_export_file(path)?;
+    std::io::Write::write_all(&mut file, csv.as_bytes()).map_err(|e| export_error(path, e))?;
+
+    Ok(rows)
+}
+
+/// Returns the raw xpatch delta turning the version at `from_timestamp` into the
+/// one at `to_timestamp`; decode it against your copy of the former.
+#[tauri::command]
+fn delta_between(
+    state: State<AppState>,
+    doc_uuid: String,
+    from_timestamp: i64,
+    to_timestamp: i64,
+    enable_zstd: bool,
+) -> Result<Vec<u8>, String> {
+    version_delta(
+        &state.read_db(),
+        &state.cache,
+        &doc_uuid,
+        from_timestamp,
+        to_timestamp,
+        enable_zstd,
+    )
+}
+
+fn version_delta(
+    db: &Connection,
+    cache: &ContentCache,
+    doc_uuid: &str,
+    from_timestamp: i64,
+    to_timestamp: i64,
+    enable_zstd: bool,
+) -> Result<Vec<u8>, String> {
+    let from = content_at(db, cache, doc_uuid, from_timestamp)?
+        .ok_or("Version not found")?;
+    let to = if to_timestamp == from_timestamp {
+        from.clone()
+    } else {
+        content_at(db, cache, doc_uuid, to_timestamp)?.ok_or("Version not found")?
+    };
+
+    Ok(xpatch::encode(0, &from, &to, enable_zstd))
+}
+
+/// Returns a delta turning whatever the client already holds into the version at
+/// `target_timestamp`; decode it against `client_content`. A timestamp before the
+/// first patch targets the empty document.
+#[tauri::command]
+fn delta_from_client_base(
+    state: State<AppState>,
+    doc_uuid: String,
+    target_timestamp: i64,
+    client_content: Vec<u8>,
+) -> Result<Vec<u8>, String> {
+    client_delta(&state, &doc_uuid, target_timestamp, &client_content)
+}
+
+fn client_delta(
+    state: &AppState,
+    doc_uuid: &str,
+    target_timestamp: i64,
+    client_content: &[u8],
+) -> Result<Vec<u8>, String> {
+    let target = {
+        let db = state.read_db();
+        content_at(&db, &state.cache, doc_uuid, target_timestamp)?.unwrap_or_default()
+    };
+
+    Ok(xpatch::encode(0, client_content, &target, ENABLE_ZSTD))
+}
+
+/// Appends versions received as a stream of `(timestamp, delta)`. Each delta's
+/// base is always the version before it (as `delta_between` and
+/// `delta_from_client_base` produce), whatever its tag says. Versions are stored
+/// re-encoded per the document's base strategy, keeping the incoming delta when
+/// the strategy picks that previous version too, or as a snapshot when the
+/// chain would exceed `max_chain_depth`. Timestamps must be increasing
+/// and after the document's latest version. The whole stream is stored in one
+/// transaction, so a delta that fails to decode leaves the document untouched.
+#[tauri::command]
+fn apply_delta_stream(
+    app: AppHandle,
+    state: State<AppState>,
+    doc_uuid: String,
+    deltas: Vec<(i64, Vec<u8>)>,
+) -> Result<Vec<String>, String> {
+    let last_timestamp = deltas.last().map(|(timestamp, _)| *timestamp);
+    let patch_uuids = append_deltas(&state, &doc_uuid, deltas)?;
+    if !patch_uuids.is_empty() {
+        emit_document_changed(&app, &doc_uuid, DocumentChangeKind::Edited, last_timestamp);
+    }
+    Ok(patch_uuids)
+}
+
+fn append_deltas(
+    state: &AppState,
+    doc_uuid: &str,
+    deltas: Vec<(i64, Vec<u8>)>,
+) -> Result<Vec<String>, String> {
+    let mut db = state.write_db();
+    let tx = db.transaction().map_err(|e| e.to_string())?;
+    let limit = content_limit(&tx, doc_uuid, state.default_max_content_bytes)?;
+    let versions = replay_deltas(&tx, &state.cache, doc_uuid, limit, deltas)?;
+    tx.commit().map_err(|e| e.to_string())?;
+
+    enforce_max_versions(&mut db, &state.cache, doc_uuid)?;
+    Ok(cache_replayed(&state.cache, doc_uuid, versions))
+}
+
+/// Decodes and stores `deltas` after the document's latest version within `tx`,
+/// each at most `limit` bytes. Returns the new versions for the caller to cache
+/// once `tx` commits.
+fn replay_deltas(
+    tx: &Transaction,
+    cache: &ContentCache,
+    doc_uuid: &str,
+    limit: Option<i64>,
+    deltas: Vec<(i64, Vec<u8>)>,
+) -> Result<Vec<(Patch, Vec<u8>)>, String> {
+    let head_timestamp: Option<i64> = tx
+        .query_row(
+            "SELECT head_timestamp FROM documents WHERE uuid = ?",
+            params![doc_uuid],
+            |row| row.get(0),
+        )
+        .optional()
+        .map_err(|e| e.to_string())?
+        .ok_or_else(|| format!("Document not found: {}", doc_uuid))?;
+
+    let strategy = get_base_strategy(tx, doc_uuid)?;
+
+    let max_chain_depth = read_max_chain_depth(tx, doc_uuid)?;
+
+    let mut versions = reconstruct_versions(tx, cache, doc_uuid, i64::MAX)?;
+    let mut depths = stored_chain_depths(&versions);
+    let first_new = versions.len();
+    let mut last_timestamp = head_timestamp;
+
+    for (timestamp, delta) in deltas {
+        check_timestamp(timestamp)?;
+        if last_timestamp.is_some_and(|last| timestamp <= last) {
+            return Err(format!("Stream timestamp {} is not after the previous version", timestamp));
+        }
+
+        let previous = versions.last().map(|(_, content)| content.as_slice());
+        let next = safe_decode(previous.unwrap_or_default(), &delta)?;
+        check_content_size(limit, next.len())?;
+
+        let pos = versions.len();
+        let patch = Patch {
+            uuid: Uuid::new_v4().to_string(),
+            document_uuid: doc_uuid.to_string(),
+            timestamp,
+            delta: None,
+        };
+        versions.push((patch, next));
+
+        // Store what the document's strategy would have saved; the incoming delta
+        // is against the previous version, so it only fits when that is the pick
+        let content = &versions[pos].1;
+        let good_enough = good_enough_bytes(content.len());
+        let (tag, encoded) =
+            encode_version(&versions, &depths, pos, strategy, max_chain_depth, good_enough);
+        depths.push(depth_with_tag(&depths, pos, tag));
+        let delta = if tag == 0 && matches!(xpatch::get_tag(&delta), Ok(0)) {
+            delta
+        } else {
+            encoded
+        };
+
+        let patch_uuid = &versions[pos].0.uuid;
+        insert_patch(tx, patch_uuid, doc_uuid, timestamp, &delta, &content_hash(content))?;
+        last_timestamp = Some(timestamp);
+    }
+    refresh_head(tx, doc_uuid)?;
+
+    Ok(versions.split_off(first_new))
+}
+
+/// Caches versions stored by `replay_deltas`, returning their patch uuids.
+fn cache_replayed(
+    cache: &ContentCache,
+    doc_uuid: &str,
+    versions: Vec<(Patch, Vec<u8>)>,
+) -> Vec<String> {
+    let mut patch_uuids = Vec::with_capacity(versions.len());
+    for (patch, content) in versions {
+        cache.insert((doc_uuid.to_string(), patch.uuid.clone()), content);
+        patch_uuids.push(patch.uuid);
+    }
+    patch_uuids
+}
+
+/// Line diff between two versions of a document. A timestamp before the first
+/// patch counts as the empty document.
+#[tauri::command]
+fn diff_versions(
+    state: State<AppState>,
+    doc_uuid: String,
+    from_timestamp: i64,
+    to_timestamp: i64,
+) -> Result<Vec<DiffOp>, String> {
+    version_diff(&state.read_db(), &state.cache, &doc_uuid, from_timestamp, to_timestamp)
+}
+
+fn version_diff(
+    db: &Connection,
+    cache: &ContentCache,
+    doc_uuid: &str,
+    from_timestamp: i64,
+    to_timestamp: i64,
+) -> Result<Vec<DiffOp>, String> {
+    let from = load_content(db, cache, doc_uuid, from_timestamp, &Default::default())?;
+    let to = load_content(db, cache, doc_uuid, to_timestamp, &Default::default())?;
+    Ok(diff_lines(&from, &to))
+}
+
+/// Line diff from the version at `timestamp` to the latest version. When that
+/// version is the head the whole content comes back as a single `Equal` run.
+#[tauri::command]
+fn diff_against_head(
+    state: State<AppState>,
+    doc_uuid: String,
+    timestamp: i64,
+) -> Result<Vec<DiffOp>, String> {
+    head_diff(&state.read_db(), &state.cache, &doc_uuid, timestamp)
+}
+
+fn head_diff(
+    db: &Connection,
+    cache: &ContentCache,
+    doc_uuid: &str,
+    timestamp: i64,
+) -> Result<Vec<DiffOp>, String> {
+    let version = load_content(db, cache, doc_uuid, timestamp, &Default::default())?;
+    if latest_timestamp(db, doc_uuid)?.is_none_or(|head| timestamp >= head) {
+        return Ok(vec![DiffOp::Equal(version)]);
+    }
+    let head = load_content(db, cache, doc_uuid, i64::MAX, &Default::default())?;
+    Ok(diff_lines(&version, &head))
+}
+
+/// Line diff between the latest versions of two documents; a document without
+/// patches counts as empty.
+#[tauri::command]
+fn diff_documents(
+    state: State<AppState>,
+    doc_a: String,
+    doc_b: String,
+) -> Result<Vec<DiffOp>, String> {
+    document_diff(&state.read_db(), &state.cache, &doc_a, &doc_b)
+}
+
+fn document_diff(
+    db: &Connection,
+    cache: &ContentCache,
+    doc_a: &str,
+    doc_b: &str,
+) -> Result<Vec<DiffOp>, String> {
+    let a = load_content(db, cache, doc_a, i64::MAX, &Default::default())?;
+    let b = load_content(db, cache, doc_b, i64::MAX, &Default::default())?;
+    Ok(diff_lines(&a, &b))
+}
+
+/// Line diff from the stored latest version to unsaved editor content, i.e.
+/// what `create_patch` would record. Nothing is written.
+#[tauri::command]
+fn pending_diff(
+    state: State<AppState>,
+    doc_uuid: String,
+    draft_content: String,
+) -> Result<Vec<DiffOp>, String> {
+    draft_diff(&state.read_db(), &state.cache, &doc_uuid, &draft_content)
+}
+
+fn draft_diff(
+    db: &Connection,
+    cache: &ContentCache,
+    doc_uuid: &str,
+    draft_content: &str,
+) -> Result<Vec<DiffOp>, String> {
+    let head = load_content(db, cache, doc_uuid, i64::MAX, &Default::default())?;
+    Ok(diff_lines(&head, draft_content))
+}
+
+// Each pair is a full line diff, so the matrix is limited to the newest versions
+const MAX_SIMILARITY_VERSIONS: usize = 64;
+
+/// Share of bytes in lines common to both texts, from 0 (nothing shared) to 1
+/// (identical).
+fn similarity(a: &str, b: &str) -> f64 {
+    if a.is_empty() && b.is_empty() {
+        return 1.0;
+    }
+    let common: usize = diff_lines(a, b)
+        .iter()
+        .map(|op| match op {
+            DiffOp::Equal(run) => run.len(),
+            _ => 0,
+        })
+        .sum();
+    (2 * common) as f64 / (a.len() + b.len()) as f64
+}
+
+/// Pairwise `similarity` of the document's versions, oldest first, to spot
+/// near-duplicates. Only the newest `MAX_SIMILARITY_VERSIONS` are included.
+#[tauri::command]
+fn similarity_matrix(state: State<AppState>, doc_uuid: String) -> Result<Vec<Vec<f64>>, String> {
+    let versions = {
+        let db = state.read_db();
+        reconstruct_versions(&db, &state.cache, &doc_uuid, i64::MAX)?
+    };
+    Ok(version_similarities(&versions))
+}
+
+fn version_similarities(versions: &[(Patch, Vec<u8>)]) -> Vec<Vec<f64>> {
+    let skip = versions.len().saturating_sub(MAX_SIMILARITY_VERSIONS);
+    let texts: Vec<String> = versions[skip..]
+        .iter()
+        .map(|(_, content)| String::from_utf8_lossy(content).into_owned())
+        .collect();
+
+    let mut matrix = vec![vec![1.0; texts.len()]; texts.len()];
+    for i in 0..texts.len() {
+        for j in i + 1..texts.len() {
+            // Computed once per pair so the matrix is exactly symmetric
+            let score = similarity(&texts[i], &texts[j]);
+            matrix[i][j] = score;
+            matrix[j][i] = score;
+        }
+    }
+
+    matrix
+}
+
+/// Lengths of the byte prefix and suffix shared by every version, revealing
+/// boilerplate such as headers or license blocks that never changed. Each is
+/// measured on its own, so they overlap when all versions are identical.
+/// `(0, 0)` for a document without versions.
+#[tauri::command]
+fn stable_regions(state: State<AppState>, doc_uuid: String) -> Result<(usize, usize), String> {
+    let versions = {
+        let db = state.read_db();
+        reconstruct_versions(&db, &state.cache, &doc_uuid, i64::MAX)?
+    };
+    Ok(common_affixes(&versions))
+}
+
+/// Lengths of the byte prefix and suffix every one of `versions` shares.
+fn common_affixes(versions: &[(Patch, Vec<u8>)]) -> (usize, usize) {
+    let Some((_, first)) = versions.first() else {
+        return (0, 0);
+    };
+
+    let mut prefix = first.len();
+    let mut suffix = first.len();
+    for (_, content) in &versions[1..] {
+        prefix = first[..prefix]
+            .iter()
+            .zip(content)
+            .take_while(|(a, b)| a == b)
+            .count();
+        suffix = first[first.len() - suffix..]
+            .iter()
+            .rev()
+            .zip(content.iter().rev())
+            .take_while(|(a, b)| a == b)
+            .count();
+    }
+
+    (prefix, suffix)
+}
+
+const INSPECT_PREFIX_BYTES: usize = 32;
+
+// Enough of a delta to hold its header, however large the tag
+const DELTA_HEADER_BYTES: i64 = 16;
+
+/// The deltas the version at `timestamp` is built from, following each one's
+/// base back to the one decoded against empty content, in decode order.
+#[tauri::command]
+fn dependency_chain(
+    state: State<AppState>,
+    doc_uuid: String,
+    timestamp: i64,
+) -> Result<Vec<ChainStep>, String> {
+    chain_steps(&state.read_db(), &doc_uuid, timestamp)
+}
+
+fn chain_steps(db: &Connection, doc_uuid: &str, timestamp: i64) -> Result<Vec<ChainStep>, String> {
+    // Only the headers are needed to resolve tags
+    let mut stmt = db
+        .prepare(
+            "SELECT uuid, timestamp, substr(delta, 1, ?)
+             FROM patches
+             WHERE document_uuid = ? AND timestamp <= ?
+             ORDER BY timestamp ASC",
+        )
+        .map_err(|e| e.to_string())?;
+    let patches = stmt
+        .query_map(params![DELTA_HEADER_BYTES, doc_uuid, timestamp], |row| {
+            Ok(Patch {
+                uuid: row.get(0)?,
+                document_uuid: doc_uuid.to_string(),
+                timestamp: row.get(1)?,
+                delta: row.get(2)?,
+            })
+        })
+        .map_err(|e| e.to_string())?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| e.to_string())?;
+
+    let mut chain = Vec::new();
+    let mut next = patches.len().checked_sub(1);
+    while let Some(pos) = next {
+        let patch = &patches[pos];
+        let base = base_position(patch, pos);
+        chain.push(ChainStep {
+            patch_uuid: patch.uuid.clone(),
+            timestamp: patch.timestamp,
+            tag: patch.delta.as_deref().map_or(0, |delta| xpatch::get_tag(delta).unwrap_or(0)),
+            base_timestamp: base.map(|base| patches[base].timestamp),
+        });
+        next = base;
+    }
+    chain.reverse();
+
+    Ok(chain)
+}
+
+/// How many deltas must be decoded to rebuild the version at `timestamp`: its
+/// dependency chain back to the nearest snapshot or the start, the version's own
+/// delta included. 0 before the first patch.
+#[tauri::command]
+fn chain_depth(state: State<AppState>, doc_uuid: String, timestamp: i64) -> Result<usize, String> {
+    chain_steps(&state.read_db(), &doc_uuid, timestamp).map(|chain| chain.len())
+}
+
+/// Dumps a single patch row by uuid without reconstructing anything.
+#[tauri::command]
+fn inspect_patch(state: State<AppState>, patch_uuid: String) -> Result<PatchInspection, String> {
+    patch_inspection(&state.read_db(), &patch_uuid)
+}
+
+fn patch_inspection(db: &Connection, patch_uuid: &str) -> Result<PatchInspection, String> {
+    let (document_uuid, timestamp, delta): (String, i64, Option<Vec<u8>>) = db
+        .query_row(
+            "SELECT document_uuid, timestamp, delta FROM patches WHERE uuid = ?",
+            params![patch_uuid],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )
+        .optional()
+        .map_err(|e| e.to_string())?
+        .ok_or_else(|| format!("Patch not found: {}", patch_uuid))?;
+
+    let delta = delta.unwrap_or_default();
+    let tag = if delta.is_empty() {
+        None
+    } else {
+        xpatch::get_tag(&delta).ok()
+    };
+    let first_bytes_hex = delta
+        .iter()
+        .take(INSPECT_PREFIX_BYTES)
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+
+    Ok(PatchInspection {
+        uuid: patch_uuid.to_string(),
+        document_uuid,
+        timestamp,
+        delta_len: delta.len(),
+        tag,
+        first_bytes_hex,
+    })
+}
+
+/// Returns `(tag, patch_count, total_delta_bytes)` for each tag in use, sorted by tag.
+#[tauri::command]
+fn tag_histogram(
+    state: State<AppState>,
+    doc_uuid: String,
+) -> Result<Vec<(usize, i64, i64)>, String> {
+    tag_counts(&state.read_db(), &doc_uuid)
+}
+
+fn tag_counts(db: &Connection, doc_uuid: &str) -> Result<Vec<(usize, i64, i64)>, String> {
+    let mut stmt = db
+        .prepare("SELECT delta FROM patches WHERE document_uuid = ?")
+        .map_err(|e| e.to_string())?;
+
+    let deltas: Vec<Option<Vec<u8>>> = stmt
+        .query_map(params![doc_uuid], |row| row.get(0))
+        .map_err(|e| e.to_string())?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| e.to_string())?;
+
+    let mut histogram: BTreeMap<usize, (i64, i64)> = BTreeMap::new();
+    for delta in deltas {
+        let (tag, len) = match delta {
+            Some(delta) => (xpatch::get_tag(&delta).unwrap_or(0), delta.len() as i64),
+            None => (0, 0),
+        };
+        let entry = histogram.entry(tag).or_default();
+        entry.0 += 1;
+        entry.1 += len;
+    }
+
+    Ok(histogram
+        .into_iter()
+        .map(|(tag, (count, bytes))| (tag, count, bytes))
+        .collect())
+}
+
+/// Returns `(timestamp, cumulative_delta_bytes)` for each patch in order, for
+/// charting how storage grew. Reads sizes only, nothing is reconstructed.
+#[tauri::command]
+fn storage_timeline(state: State<AppState>, doc_uuid: String) -> Result<Vec<(i64, i64)>, String> {
+    delta_byte_timeline(&state.read_db(), &doc_uuid)
+}
+
+fn delta_byte_timeline(db: &Connection, doc_uuid: &str) -> Result<Vec<(i64, i64)>, String> {
+    let mut stmt = db
+        .prepare(
+            "SELECT timestamp, COALESCE(LENGTH(delta), 0)
+             FROM patches
+             WHERE document_uuid = ?
+             ORDER BY timestamp ASC",
+        )
+        .map_err(|e| e.to_string())?;
+
+    let sizes: Vec<(i64, i64)> = stmt
+        .query_map(params![doc_uuid], |row| Ok((row.get(0)?, row.get(1)?)))
+        .map_err(|e| e.to_string())?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| e.to_string())?;
+
+    let mut total = 0;
+    Ok(sizes
+        .into_iter()
+        .map(|(timestamp, bytes)| {
+            total += bytes;
+            (timestamp, total)
+        })
+        .collect())
+}
+
+/// Timestamps of versions whose size differs from the version before by more
+/// than `min_delta_ratio` of that size (0.5 = grew or shrank by over half), to
+/// pick out rewrites among typo fixes. Growing from empty always counts.
+#[tauri::command]
+fn significant_edits(
+    state: State<AppState>,
+    doc_uuid: String,
+    min_delta_ratio: f64,
+) -> Result<Vec<i64>, String> {
+    large_edits(&state.read_db(), &state.cache, &doc_uuid, min_delta_ratio)
+}
+
+fn large_edits(
+    db: &Connection,
+    cache: &ContentCache,
+    doc_uuid: &str,
+    min_delta_ratio: f64,
+) -> Result<Vec<i64>, String> {
+    if !(min_delta_ratio >= 0.0 && min_delta_ratio.is_finite()) {
+        return Err("min_delta_ratio must be a non-negative number".to_string());
+    }
+
+    let versions = reconstruct_versions(db, cache, doc_uuid, i64::MAX)?;
+
+    let mut previous_len = 0;
+    let mut timestamps = Vec::new();
+    for (patch, content) in &versions {
+        let significant = if previous_len == 0 {
+            !content.is_empty()
+        } else {
+            let change = content.len().abs_diff(previous_len) as f64;
+            change / previous_len as f64 > min_delta_ratio
+        };
+        if significant {
+            timestamps.push(patch.timestamp);
+        }
+        previous_len = content.len();
+    }
+
+    Ok(timestamps)
+}
+
+/// Returns `(bucket_start, patch_count)` for every `bucket_ms`-wide window that
+/// has at least one patch, oldest first.
+#[tauri::command]
+fn edit_histogram(
+    state: State<AppState>,
+    doc_uuid: String,
+    bucket_ms: i64,
+) -> Result<Vec<(i64, i64)>, String> {
+    patch_histogram(&state.read_db(), &doc_uuid, bucket_ms)
+}
+
+fn patch_histogram(
+    db: &Connection,
+    doc_uuid: &str,
+    bucket_ms: i64,
+) -> Result<Vec<(i64, i64)>, String> {
+    if bucket_ms <= 0 {
+        return Err("bucket_ms must be positive".to_string());
+    }
+
+    // SQLite's % truncates toward zero; flooring keeps negative timestamps in
+    // the bucket that starts at or before them
+    let mut stmt = db
+        .prepare(
+            "SELECT timestamp - ((timestamp % ?1) + ?1) % ?1 AS bucket_start, COUNT(*)
+             FROM patches
+             WHERE document_uuid = ?2
+             GROUP BY bucket_start
+             ORDER BY bucket_start ASC",
+        )
+        .map_err(|e| e.to_string())?;
+
+    let histogram = stmt
+        .query_map(params![bucket_ms, doc_uuid], |row| Ok((row.get(0)?, row.get(1)?)))
+        .map_err(|e| e.to_string())?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| e.to_string())?;
+
+    Ok(histogram)
+}
+
+/// Names `timestamp` on the document's timeline. The bookmark resolves to
+/// whichever version is current at that time, so it survives pruning.
+#[tauri::command]
+fn add_bookmark(
+    app: AppHandle,
+    state: State<AppState>,
+    doc_uuid: String,
+    name: String,
+    timestamp: i64,
+) -> Result<(), String> {
+    insert_bookmark(&state.write_db(), &doc_uuid, &name, timestamp)?;
+    emit_document_changed(&app, &doc_uuid, DocumentChangeKind::AnnotationsChanged, None);
+    Ok(())
+}
+
+fn insert_bookmark(
+    db: &Connection,
+    doc_uuid: &str,
+    name: &str,
+    timestamp: i64,
+) -> Result<(), String> {
+    let (doc_exists, name_taken): (bool, bool) = db
+        .query_row(
+            "SELECT EXISTS(SELECT 1 FROM documents WHERE uuid = ?1),
+                    EXISTS(SELECT 1 FROM bookmarks WHERE doc_uuid = ?1 AND name = ?2)",
+            params![doc_uuid, name],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .map_err(|e| e.to_string())?;
+    if !doc_exists {
+        return Err(format!("Document not found: {}", doc_uuid));
+    }
+    if name_taken {
+        return Err(format!("Bookmark already exists: {}", name));
+    }
+
+    db.execute(
+        "INSERT INTO bookmarks (doc_uuid, name, timestamp) VALUES (?, ?, ?)",
+        params![doc_uuid, name, timestamp],
+    )
+    .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+#[tauri::command]
+fn list_bookmarks(state: State<AppState>, doc_uuid: String) -> Result<Vec<Bookmark>, String> {
+    bookmarks(&state.read_db(), &doc_uuid)
+}
+
+fn bookmarks(db: &Connection, doc_uuid: &str) -> Result<Vec<Bookmark>, String> {
+    let mut stmt = db
+        .prepare(
+            "SELECT name, timestamp FROM bookmarks
+             WHERE doc_uuid = ?
+             ORDER BY timestamp ASC, name ASC",
+        )
+        .map_err(|e| e.to_string())?;
+
+    let bookmarks = stmt
+        .query_map(params![doc_uuid], |row| {
+            Ok(Bookmark {
+                name: row.get(0)?,
+                timestamp: row.get(1)?,
+            })
+        })
+        .map_err(|e| e.to_string())?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| e.to_string())?;
+
+    Ok(bookmarks)
+}
+
+#[tauri::command]
+fn delete_bookmark(
+    app: AppHandle,
+    state: State<AppState>,
+    doc_uuid: String,
+    name: String,
+) -> Result<(), String> {
+    remove_bookmark(&state.write_db(), &doc_uuid, &name)?;
+    emit_document_changed(&app, &doc_uuid, DocumentChangeKind::AnnotationsChanged, None);
+    Ok(())
+}
+
+fn remove_bookmark(db: &Connection, doc_uuid: &str, name: &str) -> Result<(), String> {
+    let deleted = db
+        .execute(
+            "DELETE FROM bookmarks WHERE doc_uuid = ? AND name = ?",
+            params![doc_uuid, name],
+        )
+        .map_err(|e| e.to_string())?;
+
+    if deleted == 0 {
+        return Err(format!("Bookmark not found: {}", name));
+    }
+    Ok(())
+}
+
+/// Content of the document at the bookmarked timestamp.
+#[tauri::command]
+fn load_bookmark(state: State<AppState>, doc_uuid: String, name: String) -> Result<String, String> {
+    bookmark_content(&state.read_db(), &state.cache, &doc_uuid, &name)
+}
+
+fn bookmark_content(
+    db: &Connection,
+    cache: &ContentCache,
+    doc_uuid: &str,
+    name: &str,
+) -> Result<String, String> {
+    let timestamp: i64 = db
+        .query_row(
+            "SELECT timestamp FROM bookmarks WHERE doc_uuid = ? AND name = ?",
+            params![doc_uuid, name],
+            |row| row.get(0),
+        )
+        .optional()
+        .map_err(|e| e.to_string())?
+        .ok_or_else(|| format!("Bookmark not found: {}", name))?;
+
+    load_content(db, cache, doc_uuid, timestamp, &ReconstructOptions::default())
+}
+
+/// Stores `value` under `key` for the document, replacing any earlier value.
+#[tauri::command]
+fn set_metadata(
+    app: AppHandle,
+    state: State<AppState>,
+    doc_uuid: String,
+    key: String,
+    value: String,
+) -> Result<(), String> {
+    write_metadata(&state.write_db(), &doc_uuid, &key, &value)?;
+    emit_document_changed(&app, &doc_uuid, DocumentChangeKind::AnnotationsChanged, None);
+    Ok(())
+}
+
+fn write_metadata(db: &Connection, doc_uuid: &str, key: &str, value: &str) -> Result<(), String> {
+    let doc_exists: bool = db
+        .query_row(
+            "SELECT EXISTS(SELECT 1 FROM documents WHERE uuid = ?)",
+            params![doc_uuid],
+            |row| row.get(0),
+        )
+        .map_err(|e| e.to_string())?;
+    if !doc_exists {
+        return Err(format!("Document not found: {}", doc_uuid));
+    }
+
+    db.execute(
+        "INSERT INTO document_metadata (doc_uuid, key, value) VALUES (?, ?, ?)
+         ON CONFLICT (doc_uuid, key) DO UPDATE SET value = excluded.value",
+        params![doc_uuid, key, value],
+    )
+    .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+#[tauri::command]
+fn get_metadata(
+    state: State<AppState>,
+    doc_uuid: String,
+    key: String,
+) -> Result<Option<String>, String> {
+    metadata_value(&state.read_db(), &doc_uuid, &key)
+}
+
+fn metadata_value(db: &Connection, doc_uuid: &str, key: &str) -> Result<Option<String>, String> {
+    db.query_row(
+        "SELECT value FROM document_metadata WHERE doc_uuid = ? AND key = ?",
+        params![doc_uuid, key],
+        |row| row.get(0),
+    )
+    .optional()
+    .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn list_metadata(
+    state: State<AppState>,
+    doc_uuid: String,
+) -> Result<HashMap<String, String>, String> {
+    metadata_entries(&state.read_db(), &doc_uuid)
+}
+
+fn metadata_entries(db: &Connection, doc_uuid: &str) -> Result<HashMap<String, String>, String> {
+    let mut stmt = db
+        .prepare("SELECT key, value FROM document_metadata WHERE doc_uuid = ?")
+        .map_err(|e| e.to_string())?;
+
+    let metadata = stmt
+        .query_map(params![doc_uuid], |row| Ok((row.get(0)?, row.get(1)?)))
+        .map_err(|e| e.to_string())?
+        .collect::<Result<HashMap<_, _>, _>>()
+        .map_err(|e| e.to_string())?;
+
+    Ok(metadata)
+}
+
+// zstd level for attachment blobs, which are written once and read rarely
+const ATTACHMENT_ZSTD_LEVEL: i32 = 3;
+
+/// Stores a file alongside the document, returning the attachment's uuid.
+/// Names need not be unique. Attachments go when the document is purged.
+#[tauri::command]
+fn add_attachment(
+    app: AppHandle,
+    state: State<AppState>,
+    doc_uuid: String,
+    name: String,
+    data: Vec<u8>,
+) -> Result<String, String> {
+    let attachment_uuid = insert_attachment(&state.write_db(), &doc_uuid, &name, data)?;
+    emit_document_changed(&app, &doc_uuid, DocumentChangeKind::AnnotationsChanged, None);
+    Ok(attachment_uuid)
+}
+
+fn insert_attachment(
+    db: &Connection,
+    doc_uuid: &str,
+    name: &str,
+    data: Vec<u8>,
+) -> Result<String, String> {
+    let size = data.len() as i64;
+    // Already-compressed formats (PNG, JPEG, ...) are kept as they are
+    let (content, compressed) = match zstd::bulk::compress(&data, ATTACHMENT_ZSTD_LEVEL) {
+        Ok(bytes) if bytes.len() < data.len() => (bytes, true),
+        _ => (data, false),
+    };
+
+    let doc_exists: bool = db
+        .query_row(
+            "SELECT EXISTS(SELECT 1 FROM documents WHERE uuid = ?)",
+            params![doc_uuid],
+            |row| row.get(0),
+        )
+        .map_err(|e| e.to_string())?;
+    if !doc_exists {
+        return Err(format!("Document not found: {}", doc_uuid));
+    }
+
+    let attachment_uuid = Uuid::new_v4().to_string();
+    db.execute(
+        "INSERT INTO attachments (uuid, document_uuid, name, content, compressed, size, created_at)
+         VALUES (?, ?, ?, ?, ?, ?, ?)",
+        params![
+            &attachment_uuid,
+            doc_uuid,
+            name,
+            &content,
+            compressed,
+            size,
+            chrono::Utc::now().timestamp_millis()
+        ],
+    )
+    .map_err(|e| e.to_string())?;
+
+    Ok(attachment_uuid)
+}
+
+#[tauri::command]
+fn get_attachment(state: State<AppState>, attachment_uuid: String) -> Result<Vec<u8>, String> {
+    attachment_content(&state.read_db(), &attachment_uuid)
+}
+
+fn attachment_content(db: &Connection, attachment_uuid: &str) -> Result<Vec<u8>, String> {
+    let (content, compressed, size): (Vec<u8>, bool, i64) = db
+        .query_row(
+            "SELECT content, compressed, size FROM attachments WHERE uuid = ?",
+            params![attachment_uuid],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )
+        .optional()
+        .map_err(|e| e.to_string())?
+        .ok_or("Attachment not found")?;
+
+    if !compressed {
+        return Ok(content);
+    }
+    zstd::bulk::decompress(&content, size as usize)
+        .map_err(|e| format!("Corrupt attachment {}: {}", attachment_uuid, e))
+}
+
+/// The document's attachments, oldest first, without their content.
+#[tauri::command]
+fn list_attachments(state: State<AppState>, doc_uuid: String) -> Result<Vec<Attachment>, String> {
+    attachments(&state.read_db(), &doc_uuid)
+}
+
+fn attachments(db: &Connection, doc_uuid: &str) -> Result<Vec<Attachment>, String> {
+    let mut stmt = db
+        .prepare(
+            "SELECT uuid, document_uuid, name, size, created_at
+             FROM attachments
+             WHERE document_uuid = ?
+             ORDER BY created_at ASC, rowid ASC",
+        )
+        .map_err(|e| e.to_string())?;
+
+    let attachments = stmt
+        .query_map(params![doc_uuid], |row| {
+            Ok(Attachment {
+                uuid: row.get(0)?,
+                document_uuid: row.get(1)?,
+                name: row.get(2)?,
+                size: row.get(3)?,
+                created_at: row.get(4)?,
+            })
+        })
+        .map_err(|e| e.to_string())?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| e.to_string())?;
+
+    Ok(attachments)
+}
+
+#[tauri::command]
+fn delete_attachment(
+    app: AppHandle,
+    state: State<AppState>,
+    attachment_uuid: String,
+) -> Result<(), String> {
+    let doc_uuid = remove_attachment(&state.write_db(), &attachment_uuid)?;
+    emit_document_changed(&app, &doc_uuid, DocumentChangeKind::AnnotationsChanged, None);
+    Ok(())
+}
+
+/// Deletes the attachment, returning the uuid of the document it belonged to.
+fn remove_attachment(db: &Connection, attachment_uuid: &str) -> Result<String, String> {
+    db.query_row(
+        "DELETE FROM attachments WHERE uuid = ? RETURNING document_uuid",
+        params![attachment_uuid],
+        |row| row.get(0),
+    )
+    .optional()
+    .map_err(|e| e.to_string())?
+    .ok_or_else(|| "Attachment not found".to_string())
+}
+
+/// Lists every version where `line_text` appeared as a whole line or vanished
+/// again, oldest first.
+#[tauri::command]
+fn blame_line(
+    state: State<AppState>,
+    doc_uuid: String,
+    line_text: String,
+) -> Result<Vec<LineEvent>, String> {
+    line_events(&state.read_db(), &state.cache, &doc_uuid, &line_text)
+}
+
+fn line_events(
+    db: &Connection,
+    cache: &ContentCache,
+    doc_uuid: &str,
+    line_text: &str,
+) -> Result<Vec<LineEvent>, String> {
+    let options = ReconstructOptions {
+        skip_cache: true,
+        ..Default::default()
+    };
+    let versions = reconstruct_versions_with(db, cache, doc_uuid, i64::MAX, &options)?;
+
+    let mut events = Vec::new();
+    let mut present = false;
+    for (patch, content) in &versions {
+        let now_present = String::from_utf8_lossy(content)
+            .lines()
+            .any(|line| line == line_text);
+
+        if now_present != present {
+            let kind = if now_present {
+                LineEventKind::Added
+            } else {
+                LineEventKind::Removed
+            };
+            events.push(LineEvent {
+                timestamp: patch.timestamp,
+                kind,
+            });
+            present = now_present;
+        }
+    }
+
+    Ok(events)
+}
+
+/// Timestamps of the versions whose byte at `offset` differs from the previous
+/// version's, oldest first. A version too short to reach `offset` counts as
+/// having no byte there, so growing past it or shrinking below it is a change.
+#[tauri::command]
+fn offset_history(
+    state: State<AppState>,
+    doc_uuid: String,
+    offset: usize,
+) -> Result<Vec<i64>, String> {
+    let db = state.read_db();
+    let options = ReconstructOptions {
+        skip_cache: true,
+        ..Default::default()
+    };
+    let versions = reconstruct_versions_with(&db, &state.cache, &doc_uuid, i64::MAX, &options)?;
+
+    Ok(offset_changes(&versions, offset))
+}
+
+fn offset_changes(versions: &[(Patch, Vec<u8>)], offset: usize) -> Vec<i64> {
+    let mut previous = None;
+    let mut timestamps = Vec::new();
+    for (patch, content) in versions {
+        let byte = content.get(offset).copied();
+        if byte != previous {
+            timestamps.push(patch.timestamp);
+            previous = byte;
+        }
+    }
+
+    timestamps
+}
+
+/// Reports what `compact_document` would save, without writing anything.
+#[tauri::command]
+fn compaction_preview(
+    app: AppHandle,
+    state: State<AppState>,
+    doc_uuid: String,
+) -> Result<CompactionPreview, String> {
+    let db = state.read_db();
+
+    let progress = emit_progress(&app, &doc_uuid);
+    let options = ReconstructOptions {
+        progress: Some(&progress),
+        skip_cache: true,
+        ..Default::default()
+    };
+    let versions = reconstruct_versions_with(&db, &state.cache, &doc_uuid, i64::MAX, &options)?;
+    let strategy = get_base_strategy(&db, &doc_uuid)?;
+    let max_chain_depth = read_max_chain_depth(&db, &doc_uuid)?;
+
+    let (_rewritten, preview) = plan_compaction(&versions, strategy, max_chain_depth);
+    Ok(preview)
+}
+
+// Documents whose deltas total less than this are not worth compacting
+const COMPACTION_MIN_DELTA_BYTES: i64 = 4 * 1024;
+
+/// Library-wide advisory for `compact_document`: uuids of documents outside
+/// the trash whose projected savings exceed `min_savings_ratio` of their
+/// current delta bytes, largest documents first. Single-patch and tiny
+/// documents are skipped without being reconstructed.
+#[tauri::command]
+fn compaction_candidates(
+    state: State<AppState>,
+    min_savings_ratio: f64,
+) -> Result<Vec<String>, String> {
+    fragmented_documents(&state.read_db(), &state.cache, min_savings_ratio)
+}
+
+fn fragmented_documents(
+    db: &Connection,
+    cache: &ContentCache,
+    min_savings_ratio: f64,
+) -> Result<Vec<String>, String> {
+    if !(min_savings_ratio >= 0.0 && min_savings_ratio.is_finite()) {
+        return Err("min_savings_ratio must be a non-negative number".to_string());
+    }
+
+    let mut stmt = db
+        .prepare(
+            "SELECT d.uuid
+             FROM documents d
+             JOIN patches p ON p.document_uuid = d.uuid
+             WHERE d.deleted_at IS NULL
+             GROUP BY d.uuid
+             HAVING COUNT(*) > 1 AND SUM(LENGTH(p.delta)) >= ?
+             ORDER BY SUM(LENGTH(p.delta)) DESC",
+        )
+        .map_err(|e| e.to_string())?;
+    let doc_uuids = stmt
+        .query_map(params![COMPACTION_MIN_DELTA_BYTES], |row| row.get::<_, String>(0))
+        .map_err(|e| e.to_string())?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| e.to_string())?;
+
+    let options = ReconstructOptions {
+        skip_cache: true,
+        ..Default::default()
+    };
+    let mut candidates = Vec::new();
+    for doc_uuid in doc_uuids {
+        let versions = reconstruct_versions_with(db, cache, &doc_uuid, i64::MAX, &options)?;
+        let strategy = get_base_strategy(db, &doc_uuid)?;
+        let max_chain_depth = read_max_chain_depth(db, &doc_uuid)?;
+        let (_rewritten, preview) = plan_compaction(&versions, strategy, max_chain_depth);
+
+        let savings = (preview.delta_bytes_before - preview.delta_bytes_after) as f64;
+        if savings / preview.delta_bytes_before as f64 > min_savings_ratio {
+            candidates.push(doc_uuid);
+        }
+    }
+
+    Ok(candidates)
+}
+
+/// Re-encodes every patch against its best available base (and inserts the
+/// snapshots the document's strategy asks for), returning the achieved sizes.
+#[tauri::command]
+fn compact_document(
+    app: AppHandle,
+    state: State<AppState>,
+    doc_uuid: String,
+) -> Result<CompactionPreview, String> {
+    let progress = emit_progress(&app, &doc_uuid);
+    let options = ReconstructOptions {
+        progress: Some(&progress),
+        ..Default::default()
+    };
+    let report = compact(&mut state.write_db(), &state.cache, &doc_uuid, &options)?;
+
+    emit_document_changed(&app, &doc_uuid, DocumentChangeKind::Compacted, None);
+    Ok(report)
+}
+
+fn compact(
+    db: &mut Connection,
+    cache: &ContentCache,
+    doc_uuid: &str,
+    options: &ReconstructOptions,
+) -> Result<CompactionPreview, String> {
+    let versions = reconstruct_versions_with(db, cache, doc_uuid, i64::MAX, options)?;
+    let strategy = get_base_strategy(db, doc_uuid)?;
+    let max_chain_depth = read_max_chain_depth(db, doc_uuid)?;
+
+    let (rewritten, report) = plan_compaction(&versions, strategy, max_chain_depth);
+
+    let tx = db.transaction().map_err(|e| e.to_string())?;
+    for (patch_uuid, delta) in &rewritten {
+        update_patch_delta(&tx, patch_uuid, delta)?;
+    }
+    tx.commit().map_err(|e| e.to_string())?;
+
+    invalidate_document_cache(cache, doc_uuid);
+    Ok(report)
+}
+
+/// `compact_document` for the single patch at `timestamp`: re-encodes it against
+/// the best base its document's strategy allows and keeps the result only if it
+/// is smaller and decodes back to the same content. Returns the bytes saved.
+/// Later patches depend on this version's content, not its delta, so they need
+/// no repair; a snapshot the strategy requires is left alone, and with a
+/// `max_chain_depth` set no base that deepens the chain is taken.
+#[tauri::command]
+fn reoptimize_patch(
+    app: AppHandle,
+    state: State<AppState>,
+    doc_uuid: String,
+    timestamp: i64,
+) -> Result<i64, String> {
+    let saved = reoptimize_version(&state, &doc_uuid, timestamp)?;
+    if saved > 0 {
+        emit_document_changed(&app, &doc_uuid, DocumentChangeKind::Compacted, None);
+    }
+    Ok(saved)
+}
+
+fn reoptimize_version(state: &AppState, doc_uuid: &str, timestamp: i64) -> Result<i64, String> {
+    let (patch_uuid, old_delta, pos, strategy, max_chain_depth) = {
+        let db = state.read_db();
+        let (patch_uuid, old_delta, pos): (String, Option<Vec<u8>>, i64) = db
+            .query_row(
+                "SELECT uuid, delta,
+                        (SELECT COUNT(*) FROM patches WHERE document_uuid = ?1 AND timestamp < ?2)
+                 FROM patches
+                 WHERE document_uuid = ?1 AND timestamp = ?2",
+                params![doc_uuid, timestamp],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .optional()
+            .map_err(|e| e.to_string())?
+            .ok_or_else(|| "Version not found".to_string())?;
+        let strategy = get_base_strategy(&db, doc_uuid)?;
+        let max_chain_depth = read_max_chain_depth(&db, doc_uuid)?;
+        (patch_uuid, old_delta.unwrap_or_default(), pos as usize, strategy, max_chain_depth)
+    };
+    let Some(depth) = strategy_depth(strategy, pos) else {
+        return Ok(0);
+    };
+
+    let content = content_at(&state.read_db(), &state.cache, doc_uuid, timestamp)?
+        .unwrap_or_default();
+    let (tag, delta) = find_optimal_base(state, doc_uuid, timestamp, &content, depth, 0, None)?;
+    if delta.len() >= old_delta.len() {
+        return Ok(0);
+    }
+    if max_chain_depth.is_some() {
+        // Later versions built on this one would get deeper too, so no deeper at all
+        let db = state.read_db();
+        let current_depth = chain_steps(&db, doc_uuid, timestamp)?.len();
+        if chain_depth_with_tag(&db, doc_uuid, timestamp, tag)? > current_depth {
+            return Ok(0);
+        }
+    }
+    let base = encoded_base(state, doc_uuid, timestamp, tag)?;
+    verify_delta(&delta, &base, &content)?;
+
+    let db = state.write_db();
+    // Another writer may have rewritten or removed the patch meanwhile
+    let current: Option<Option<Vec<u8>>> = db
+        .query_row(
+            "SELECT delta FROM patches WHERE uuid = ?",
+            params![&patch_uuid],
+            |row| row.get(0),
+        )
+        .optional()
+        .map_err(|e| e.to_string())?;
+    if current.flatten().unwrap_or_default() != old_delta {
+        return Err("Patch changed while it was being re-optimized".to_string());
+    }
+    update_patch_delta(&db, &patch_uuid, &delta)?;
+
+    Ok((old_delta.len() - delta.len()) as i64)
+}
+
+/// Permanently drops every version after `timestamp`, returning how many were
+/// removed. Tags only reach backwards, so the surviving chain needs no re-encoding.
+#[tauri::command]
+fn truncate_after(
+    app: AppHandle,
+    state: State<AppState>,
+    doc_uuid: String,
+    timestamp: i64,
+) -> Result<usize, String> {
+    let removed = truncate_history(&mut state.write_db(), &state.cache, &doc_uuid, timestamp)?;
+    emit_document_changed(&app, &doc_uuid, DocumentChangeKind::HistoryRewritten, None);
+    Ok(removed)
+}
+
+fn truncate_history(
+    db: &mut Connection,
+    cache: &ContentCache,
+    doc_uuid: &str,
+    timestamp: i64,
+) -> Result<usize, String> {
+    let tx = db.transaction().map_err(|e| e.to_string())?;
+    let removed = tx
+        .execute(
+            "DELETE FROM patches WHERE document_uuid = ? AND timestamp > ?",
+            params![doc_uuid, timestamp],
+        )
+        .map_err(|e| e.to_string())?;
+
+    // Only a suffix was removed, so every survivor keeps its position and base
+    if cfg!(debug_assertions) {
+        let options = ReconstructOptions {
+            skip_cache: true,
+            ..Default::default()
+        };
+        let survivors = reconstruct_versions_with(&tx, cache, doc_uuid, i64::MAX, &options);
+        debug_assert!(
+            survivors.is_ok(),
+            "truncation broke the chain: {:?}",
+            survivors.err()
+        );
+    }
+
+    refresh_head(&tx, doc_uuid)?;
+    tx.commit().map_err(|e| e.to_string())?;
+
+    invalidate_document_cache(cache, doc_uuid);
+    Ok(removed)
+}
+
+/// Makes sure the earliest patch is stored as a plain tag-0 delta against empty
+/// content, re-encoding it if needed. Returns whether a patch was rewritten;
+/// its content is unchanged, so cached versions stay valid.
+#[tauri::command]
+fn ensure_snapshot_head(
+    app: AppHandle,
+    state: State<AppState>,
+    doc_uuid: String,
+) -> Result<bool, String> {
+    let rewritten = snapshot_first_patch(&state.write_db(), &state.cache, &doc_uuid)?;
+    if rewritten {
+        emit_document_changed(&app, &doc_uuid, DocumentChangeKind::Compacted, None);
+    }
+    Ok(rewritten)
+}
+
+fn snapshot_first_patch(
+    db: &Connection,
+    cache: &ContentCache,
+    doc_uuid: &str,
+) -> Result<bool, String> {
+    let first: Option<(String, i64, Option<Vec<u8>>)> = db
+        .query_row(
+            "SELECT uuid, timestamp, delta FROM patches
+             WHERE document_uuid = ?
+             ORDER BY timestamp ASC
+             LIMIT 1",
+            params![doc_uuid],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )
+        .optional()
+        .map_err(|e| e.to_string())?;
+    let Some((patch_uuid, timestamp, delta)) = first else {
+        return Ok(false);
+    };
+
+    if delta.is_some_and(|delta| xpatch::get_tag(&delta).is_ok_and(|tag| tag == 0)) {
+        return Ok(false);
+    }
+
+    let content = content_at(db, cache, doc_uuid, timestamp)?.unwrap_or_default();
+    let delta = xpatch::encode(0, &[], &content, ENABLE_ZSTD);
+    update_patch_delta(db, &patch_uuid, &delta)?;
+    Ok(true)
+}
+
+// Newest versions a dictionary is trained on, and the most it may grow to
+const MAX_DICTIONARY_SAMPLES: usize = 256;
+const MAX_DICTIONARY_BYTES: usize = 16 * 1024;
+// zstd level for dictionary-compressed delta bodies, which are tiny
+const DICTIONARY_ZSTD_LEVEL: i32 = 19;
+
+/// Trains a zstd dictionary on the document's newest versions and uses it for
+/// the delta bodies of every save from now on, whenever that makes them
+/// smaller. Existing patches keep the dictionary they were written with.
+/// Returns the dictionary's size in bytes.
+#[tauri::command]
+fn train_dictionary(
+    app: AppHandle,
+    state: State<AppState>,
+    doc_uuid: String,
+) -> Result<usize, String> {
+    let dictionary_bytes = train_document_dictionary(&state, &doc_uuid)?;
+    emit_document_changed(&app, &doc_uuid, DocumentChangeKind::SettingsChanged, None);
+    Ok(dictionary_bytes)
+}
+
+fn train_document_dictionary(state: &AppState, doc_uuid: &str) -> Result<usize, String> {
+    let versions = {
+        let db = state.read_db();
+        let options = ReconstructOptions {
+            skip_cache: true,
+            ..Default::default()
+        };
+        reconstruct_versions_with(&db, &state.cache, doc_uuid, i64::MAX, &options)?
+    };
+    let skip = versions.len().saturating_sub(MAX_DICTIONARY_SAMPLES);
+    let samples: Vec<&[u8]> = versions[skip..]
+        .iter()
+        .map(|(_, content)| content.as_slice())
+        .filter(|content| !content.is_empty())
+        .collect();
+
+    let dictionary = zstd::dict::from_samples(&samples, MAX_DICTIONARY_BYTES)
+        .map_err(|e| format!("Not enough history to train a dictionary: {}", e))?;
+
+    let mut db = state.write_db();
+    let tx = db.transaction().map_err(|e| e.to_string())?;
+    tx.execute(
+        "INSERT INTO zstd_dictionaries (content, created_at) VALUES (?, ?)",
+        params![&dictionary, chrono::Utc::now().timestamp_millis()],
+    )
+    .map_err(|e| e.to_string())?;
+    let updated = tx
+        .execute(
+            "UPDATE documents SET dictionary_id = ? WHERE uuid = ?",
+            params![tx.last_insert_rowid(), doc_uuid],
+        )
+        .map_err(|e| e.to_string())?;
+    if updated == 0 {
+        return Err("Document not found".to_string());
+    }
+    delete_unused_dictionaries(&tx)?;
+    tx.commit().map_err(|e| e.to_string())?;
+
+    Ok(dictionary.len())
+}
+
+// Dictionaries no document saves with and no patch was compressed with
+fn delete_unused_dictionaries(db: &Connection) -> Result<(), String> {
+    db.execute(
+        "DELETE FROM zstd_dictionaries
+         WHERE id NOT IN (SELECT dictionary_id FROM documents WHERE dictionary_id IS NOT NULL)
+           AND id NOT IN (SELECT dictionary_id FROM patches WHERE dictionary_id IS NOT NULL)",
+        [],
+    )
+    .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+/// Deletes patches older than `keep_after`, keeping every `keep_every_nth` one of
+/// them as a sparse trail (0 keeps none). Survivors that depended on a removed
+/// patch are re-encoded so every remaining version reconstructs unchanged.
+#[tauri::command]
+fn prune_old_patches(
+    app: AppHandle,
+    state: State<AppState>,
+    doc_uuid: String,
+    keep_after: i64,
+    keep_every_nth: usize,
+) -> Result<PruneReport, String> {
+    let progress = emit_progress(&app, &doc_uuid);
+    let options = ReconstructOptions {
+        progress: Some(&progress),
+        ..Default::default()
+    };
+    let report = prune_versions(
+        &mut state.write_db(),
+        &state.cache,
+        &doc_uuid,
+        keep_after,
+        keep_every_nth,
+        &options,
+    )?;
+
+    emit_document_changed(&app, &doc_uuid, DocumentChangeKind::HistoryRewritten, None);
+    Ok(report)
+}
+
+/// The retention policy behind `prune_old_patches`.
+fn prune_versions(
+    db: &mut Connection,
+    cache: &ContentCache,
+    doc_uuid: &str,
+    keep_after: i64,
+    keep_every_nth: usize,
+    options: &ReconstructOptions,
+) -> Result<PruneReport, String> {
+    let versions = reconstruct_versions_with(db, cache, doc_uuid, i64::MAX, options)?;
+
+    let mut old_index = 0;
+    remove_versions(db, cache, doc_uuid, versions, |_, patch| {
+        if patch.timestamp >= keep_after {
+            return false;
+        }
+        let keep = keep_every_nth > 0 && old_index % keep_every_nth == 0;
+        old_index += 1;
+        !keep
+    })
+}
+
+/// Deletes every version `remove` picks (called with each position and patch in
+/// timestamp order) and re-encodes the survivors that depended on one, so every
+/// remaining version reconstructs unchanged.
+fn remove_versions(
+    db: &mut Connection,
+    cache: &ContentCache,
+    doc_uuid: &str,
+    versions: Vec<(Patch, Vec<u8>)>,
+    mut remove: impl FnMut(usize, &Patch) -> bool,
+) -> Result<PruneReport, String> {
+    let bytes_before: usize = versions
+        .iter()
+        .map(|(p, _)| p.delta.as_ref().map_or(0, |d| d.len()))
+        .sum();
+
+    // Split into survivors and patches to remove
+    let mut survivors: Vec<(Patch, Vec<u8>)> = Vec::with_capacity(versions.len());
+    let mut removed: Vec<String> = Vec::new();
+    let mut first_changed: Option<usize> = None;
+
+    for (pos, (patch, content)) in versions.into_iter().enumerate() {
+        if remove(pos, &patch) {
+            first_changed.get_or_insert(survivors.len());
+            removed.push(patch.uuid);
+            continue;
+        }
+        survivors.push((patch, content));
+    }
+
+    let Some(first_changed) = first_changed else {
+        return Ok(PruneReport {
+            removed_patches: 0,
+            bytes_reclaimed: 0,
+        });
+    };
+
+    // Every survivor after the first removal may have lost its base
+    let strategy = get_base_strategy(db, doc_uuid)?;
+    let max_chain_depth = read_max_chain_depth(db, doc_uuid)?;
+    let rewritten = reencode_chain(&survivors, first_changed, strategy, max_chain_depth);
+    let bytes_after: usize = survivors[..first_changed]
+        .iter()
+        .map(|(p, _)| p.delta.as_ref().map_or(0, |d| d.len()))
+        .sum::<usize>()
+        + rewritten.iter().map(|(_, d)| d.len()).sum::<usize>();
+
+    let tx = db.transaction().map_err(|e| e.to_string())?;
+    for patch_uuid in &removed {
+        tx.execute("DELETE FROM patches WHERE uuid = ?", params![patch_uuid])
+            .map_err(|e| e.to_string())?;
+    }
+    for (patch_uuid, delta) in &rewritten {
+        update_patch_delta(&tx, patch_uuid, delta)?;
+    }
+    refresh_head(&tx, doc_uuid)?;
+    tx.commit().map_err(|e| e.to_string())?;
+
+    invalidate_document_cache(cache, doc_uuid);
+
+    Ok(PruneReport {
+        removed_patches: removed.len() as i64,
+        bytes_reclaimed: bytes_before as i64 - bytes_after as i64,
+    })
+}
+
+/// Permanently removes a document and everything stored for it, returning
+/// whether the document existed.
+fn purge_document(db: &Connection, doc_uuid: &str) -> Result<bool, String> {
+    db.execute("DELETE FROM bookmarks WHERE doc_uuid = ?", params![doc_uuid])
+        .map_err(|e| e.to_string())?;
+    db.execute("DELETE FROM document_metadata WHERE doc_uuid = ?", params![doc_uuid])
+        .map_err(|e| e.to_string())?;
+    db.execute("DELETE FROM attachments WHERE document_uuid = ?", params![doc_uuid])
+        .map_err(|e| e.to_string())?;
+    db.execute("DELETE FROM patches WHERE document_uuid = ?", params![doc_uuid])
+        .map_err(|e| e.to_string())?;
+    let deleted = db
+        .execute("DELETE FROM documents WHERE uuid = ?", params![doc_uuid])
+        .map_err(|e| e.to_string())?;
+    delete_unused_dictionaries(db)?;
+    Ok(deleted > 0)
+}
+
+/// Permanently deletes the listed documents, trashed or not, with everything
+/// stored for them, in one transaction. Unknown uuids are skipped rather than
+/// failing the batch, since a multi-select may include something another
+/// window already deleted. Returns how many documents were removed.
+#[tauri::command]
+fn delete_documents(
+    app: AppHandle,
+    state: State<AppState>,
+    doc_uuids: Vec<String>,
+) -> Result<usize, String> {
+    let deleted = purge_documents(&mut state.write_db(), &state.cache, &doc_uuids)?;
+    for doc_uuid in &deleted {
+        emit_document_changed(&app, doc_uuid, DocumentChangeKind::Deleted, None);
+    }
+    Ok(deleted.len())
+}
+
+/// Purges the documents that exist among `doc_uuids`, returning those.
+fn purge_documents<'a>(
+    db: &mut Connection,
+    cache: &ContentCache,
+    doc_uuids: &'a [String],
+) -> Result<Vec<&'a String>, String> {
+    let tx = db.transaction().map_err(|e| e.to_string())?;
+    let mut deleted = Vec::new();
+    for doc_uuid in doc_uuids {
+        if purge_document(&tx, doc_uuid)? {
+            deleted.push(doc_uuid);
+        }
+    }
+    tx.commit().map_err(|e| e.to_string())?;
+
+    for doc_uuid in doc_uuids {
+        invalidate_document_cache(cache, doc_uuid);
+    }
+    Ok(deleted)
+}
+
+/// Moves a document to the trash; it stays recoverable until `empty_trash`.
+#[tauri::command]
+fn delete_document(app: AppHandle, state: State<AppState>, doc_uuid: String) -> Result<(), String> {
+    let deleted_at = chrono::Utc::now().timestamp_millis();
+    trash_document(&state.write_db(), &doc_uuid, deleted_at)?;
+    emit_document_changed(&app, &doc_uuid, DocumentChangeKind::Trashed, None);
+    Ok(())
+}
+
+fn trash_document(db: &Connection, doc_uuid: &str, deleted_at: i64) -> Result<(), String> {
+    let updated = db
+        .execute(
+            "UPDATE documents SET deleted_at = ? WHERE uuid = ? AND deleted_at IS NULL",
+            params![deleted_at, doc_uuid],
+        )
+        .map_err(|e| e.to_string())?;
+
+    if updated == 0 {
+        return Err("Document not found".to_string());
+    }
+    Ok(())
+}
+
+#[tauri::command]
+fn list_trashed(state: State<AppState>) -> Result<Vec<Document>, String> {
+    trashed_documents(&state.read_db())
+}
+
+fn trashed_documents(db: &Connection) -> Result<Vec<Document>, String> {
+    let mut stmt = db
+        .prepare(&format!(
+            "SELECT {} FROM documents WHERE deleted_at IS NOT NULL ORDER BY deleted_at DESC",
+            DOCUMENT_COLUMNS
+        ))
+        .map_err(|e| e.to_string())?;
+
+    let docs = stmt
+        .query_map([], document_from_row)
+        .map_err(|e| e.to_string())?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| e.to_string())?;
+
+    Ok(docs)
+}
+
+#[tauri::command]
+fn restore_document(
+    app: AppHandle,
+    state: State<AppState>,
+    doc_uuid: String,
+) -> Result<(), String> {
+    untrash_document(&state.write_db(), &doc_uuid)?;
+    emit_document_changed(&app, &doc_uuid, DocumentChangeKind::Restored, None);
+    Ok(())
+}
+
+fn untrash_document(db: &Connection, doc_uuid: &str) -> Result<(), String> {
+    let updated = db
+        .execute(
+            "UPDATE documents SET deleted_at = NULL WHERE uuid = ? AND deleted_at IS NOT NULL",
+            params![doc_uuid],
+        )
+        .map_err(|e| e.to_string())?;
+
+    if updated == 0 {
+        return Err("Document is not in the trash".to_string());
+    }
+    Ok(())
+}
+
+/// Permanently deletes every trashed document, returning how many were removed.
+#[tauri::command]
+fn empty_trash(app: AppHandle, state: State<AppState>) -> Result<usize, String> {
+    let trashed = purge_trash(&mut state.write_db(), &state.cache)?;
+    for doc_uuid in &trashed {
+        emit_document_changed(&app, doc_uuid, DocumentChangeKind::Deleted, None);
+    }
+    Ok(trashed.len())
+}
+
+/// Purges every trashed document, returning their uuids.
+fn purge_trash(db: &mut Connection, cache: &ContentCache) -> Result<Vec<String>, String> {
+    let tx = db.transaction().map_err(|e| e.to_string())?;
+    let trashed: Vec<String> = {
+        let mut stmt = tx
+            .prepare("SELECT uuid FROM documents WHERE deleted_at IS NOT NULL")
+            .map_err(|e| e.to_string())?;
+        let trashed = stmt
+            .query_map([], |row| row.get(0))
+            .map_err(|e| e.to_string())?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| e.to_string())?;
+        trashed
+    };
+    for doc_uuid in &trashed {
+        purge_document(&tx, doc_uuid)?;
+    }
+    tx.commit().map_err(|e| e.to_string())?;
+
+    for doc_uuid in &trashed {
+        invalidate_document_cache(cache, doc_uuid);
+    }
+    Ok(trashed)
+}
+
+/// Groups of documents outside the trash whose latest versions are identical,
+/// oldest document first in each group. Only documents whose head predates
+/// stored hashes are reconstructed.
+#[tauri::command]
+fn find_duplicate_documents(state: State<AppState>) -> Result<Vec<Vec<String>>, String> {
+    duplicate_groups(&state.read_db(), &state.cache)
+}
+
+fn duplicate_groups(db: &Connection, cache: &ContentCache) -> Result<Vec<Vec<String>>, String> {
+    let mut stmt = db
+        .prepare(
+            "SELECT uuid, head_timestamp, head_hash FROM documents
+             WHERE deleted_at IS NULL
+             ORDER BY created_at ASC",
+        )
+        .map_err(|e| e.to_string())?;
+    let heads = stmt
+        .query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, Option<i64>>(1)?,
+                row.get::<_, Option<String>>(2)?,
+            ))
+        })
+        .map_err(|e| e.to_string())?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| e.to_string())?;
+    drop(stmt);
+
+    let mut by_hash: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for (doc_uuid, head_timestamp, head_hash) in heads {
+        let hash = match (head_timestamp, head_hash) {
+            (_, Some(hash)) => hash,
+            (None, None) => content_hash(&[]),
+            (Some(_), None) => {
+                let versions = reconstruct_versions(db, cache, &doc_uuid, i64::MAX)?;
+                let content = versions.last().map(|(_, content)| content.as_slice());
+                content_hash(content.unwrap_or_default())
+            }
+        };
+        by_hash.entry(hash).or_default().push(doc_uuid);
+    }
+
+    Ok(by_hash.into_values().filter(|group| group.len() > 1).collect())
+}
+
+// Patches whose document row no longer exists
+const ORPHAN_PATCHES: &str = "FROM patches
+     WHERE document_uuid NOT IN (SELECT uuid FROM documents)";
+
+/// Lists patches pointing at a document that does not exist.
+#[tauri::command]
+fn find_orphan_patches(state: State<AppState>) -> Result<Vec<String>, String> {
+    orphan_patches(&state.read_db())
+}
+
+fn orphan_patches(db: &Connection) -> Result<Vec<String>, String> {
+    let mut stmt = db
+        .prepare(&format!("SELECT uuid {}", ORPHAN_PATCHES))
+        .map_err(|e| e.to_string())?;
+
+    let orphans = stmt
+        .query_map([], |row| row.get(0))
+        .map_err(|e| e.to_string())?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| e.to_string())?;
+
+    Ok(orphans)
+}
+
+/// Deletes every orphaned patch, returning how many were removed.
+#[tauri::command]
+fn delete_orphan_patches(state: State<AppState>) -> Result<usize, String> {
+    remove_orphan_patches(&mut state.write_db(), &state.cache)
+}
+
+fn remove_orphan_patches(db: &mut Connection, cache: &ContentCache) -> Result<usize, String> {
+    let tx = db.transaction().map_err(|e| e.to_string())?;
+    let orphan_docs: Vec<String> = {
+        let mut stmt = tx
+            .prepare(&format!("SELECT DISTINCT document_uuid {}", ORPHAN_PATCHES))
+            .map_err(|e| e.to_string())?;
+        let orphan_docs = stmt
+            .query_map([], |row| row.get(0))
+            .map_err(|e| e.to_string())?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| e.to_string())?;
+        orphan_docs
+    };
+    let removed = tx
+        .execute(&format!("DELETE {}", ORPHAN_PATCHES), [])
+        .map_err(|e| e.to_string())?;
+    tx.commit().map_err(|e| e.to_string())?;
+
+    for doc_uuid in &orphan_docs {
+        invalidate_document_cache(cache, doc_uuid);
+    }
+
+    Ok(removed)
+}
+
+/// Recreates a placeholder row for every document that only survives in its
+/// patches, e.g. after the documents table was lost, so the history can be
+/// loaded again. Returns the recovered uuids.
+#[tauri::command]
+fn recover_documents(app: AppHandle, state: State<AppState>) -> Result<Vec<String>, String> {
+    let recovered = recover_orphaned_documents(&mut state.write_db(), &state.cache)?;
+    for doc_uuid in &recovered {
+        emit_document_changed(&app, doc_uuid, DocumentChangeKind::Created, None);
+    }
+    Ok(recovered)
+}
+
+fn recover_orphaned_documents(
+    db: &mut Connection,
+    cache: &ContentCache,
+) -> Result<Vec<String>, String> {
+    let tx = db.transaction().map_err(|e| e.to_string())?;
+    let recovered: Vec<(String, i64)> = {
+        let mut stmt = tx
+            .prepare(&format!(
+                "SELECT document_uuid, MIN(timestamp) {} GROUP BY document_uuid",
+                ORPHAN_PATCHES
+            ))
+            .map_err(|e| e.to_string())?;
+        let recovered = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .map_err(|e| e.to_string())?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| e.to_string())?;
+        recovered
+    };
+    for (doc_uuid, created_at) in &recovered {
+        tx.execute(
+            "INSERT INTO documents (uuid, name, created_at) VALUES (?, ?, ?)",
+            params![doc_uuid, format!("Recovered {}", doc_uuid), created_at],
+        )
+        .map_err(|e| e.to_string())?;
+        refresh_head(&tx, doc_uuid)?;
+    }
+    tx.commit().map_err(|e| e.to_string())?;
+
+    for (doc_uuid, _) in &recovered {
+        invalidate_document_cache(cache, doc_uuid);
+    }
+
+    Ok(recovered.into_iter().map(|(doc_uuid, _)| doc_uuid).collect())
+}
+
+/// Gives the listed patches corrected timestamps, e.g. after clock skew, and
+/// re-encodes every version whose position in the chain changed so each keeps
+/// its content. The resulting timestamps must all be distinct.
+#[tauri::command]
+fn resequence_patches(
+    app: AppHandle,
+    state: State<AppState>,
+    doc_uuid: String,
+    remap: Vec<(String, i64)>,
+) -> Result<(), String> {
+    resequence(&mut state.write_db(), &state.cache, &doc_uuid, remap)?;
+    emit_document_changed(&app, &doc_uuid, DocumentChangeKind::HistoryRewritten, None);
+    Ok(())
+}
+
+fn resequence(
+    db: &mut Connection,
+    cache: &ContentCache,
+    doc_uuid: &str,
+    remap: Vec<(String, i64)>,
+) -> Result<(), String> {
+    // Content is resolved under the current order, before anything moves
+    let mut versions = reconstruct_versions(db, cache, doc_uuid, i64::MAX)?;
+    let old_order: Vec<String> = versions.iter().map(|(patch, _)| patch.uuid.clone()).collect();
+
+    let mut new_timestamps: HashMap<String, i64> = HashMap::new();
+    for (patch_uuid, timestamp) in remap {
+        if !old_order.contains(&patch_uuid) {
+            return Err(format!("Patch not found: {}", patch_uuid));
+        }
+        new_timestamps.insert(patch_uuid, timestamp);
+    }
+
+    for (patch, _) in &mut versions {
+        if let Some(&timestamp) = new_timestamps.get(&patch.uuid) {
+            patch.timestamp = timestamp;
+        }
+    }
+    versions.sort_by_key(|(patch, _)| patch.timestamp);
+
+    let duplicate = versions
+        .windows(2)
+        .find(|pair| pair[0].0.timestamp == pair[1].0.timestamp);
+    if let Some(pair) = duplicate {
+        return Err(format!("Timestamps are not strictly increasing at {}", pair[0].0.timestamp));
+    }
+
+    // Versions before the first one that moved keep their position and bases
+    let first_moved = old_order
+        .iter()
+        .zip(&versions)
+        .position(|(old_uuid, (patch, _))| *old_uuid != patch.uuid)
+        .unwrap_or(versions.len());
+    let strategy = get_base_strategy(db, doc_uuid)?;
+    let max_chain_depth = read_max_chain_depth(db, doc_uuid)?;
+    let rewritten = reencode_chain(&versions, first_moved, strategy, max_chain_depth);
+
+    let tx = db.transaction().map_err(|e| e.to_string())?;
+    for (patch_uuid, timestamp) in &new_timestamps {
+        tx.execute(
+            "UPDATE patches SET timestamp = ? WHERE uuid = ?",
+            params![timestamp, patch_uuid],
+        )
+        .map_err(|e| e.to_string())?;
+    }
+    for (patch_uuid, delta) in &rewritten {
+        update_patch_delta(&tx, patch_uuid, delta)?;
+    }
+    refresh_head(&tx, doc_uuid)?;
+    tx.commit().map_err(|e| e.to_string())?;
+
+    invalidate_document_cache(cache, doc_uuid);
+    Ok(())
+}
+
+/// Moves the versions at or after `split_timestamp` into a new document named
+/// `new_name`, re-encoded as a self-contained chain, and returns its uuid. The
+/// new document copies the original's settings (content type, base strategy,
+/// size, version and chain depth caps, line endings and dictionary) and takes
+/// the bookmarks in its range; the original keeps everything earlier unchanged.
+#[tauri::command]
+fn split_document(
+    app: AppHandle,
+    state: State<AppState>,
+    doc_uuid: String,
+    split_timestamp: i64,
+    new_name: String,
+) -> Result<String, String> {
+    let progress = emit_progress(&app, &doc_uuid);
+    let options = ReconstructOptions {
+        progress: Some(&progress),
+        ..Default::default()
+    };
+    let new_uuid = split_off(
+        &mut state.write_db(),
+        &state.cache,
+        &doc_uuid,
+        split_timestamp,
+        &new_name,
+        &options,
+    )?;
+
+    emit_document_changed(&app, &doc_uuid, DocumentChangeKind::HistoryRewritten, None);
+    emit_document_changed(&app, &new_uuid, DocumentChangeKind::Created, None);
+    Ok(new_uuid)
+}
+
+fn split_off(
+    db: &mut Connection,
+    cache: &ContentCache,
+    doc_uuid: &str,
+    split_timestamp: i64,
+    new_name: &str,
+    options: &ReconstructOptions,
+) -> Result<String, String> {
+    let exists: bool = db
+        .query_row(
+            "SELECT EXISTS(SELECT 1 FROM documents WHERE uuid = ?)",
+            params![doc_uuid],
+            |row| row.get(0),
+        )
+        .map_err(|e| e.to_string())?;
+    if !exists {
+        return Err(format!("Document not found: {}", doc_uuid));
+    }
+
+    let versions = reconstruct_versions_with(db, cache, doc_uuid, i64::MAX, options)?;
+
+    let split_at = versions
+        .iter()
+        .position(|(patch, _)| patch.timestamp >= split_timestamp)
+        .ok_or_else(|| format!("No versions at or after {}", split_timestamp))?;
+
+    let new_uuid = Uuid::new_v4().to_string();
+    let moved: Vec<(Patch, Vec<u8>)> = versions
+        .into_iter()
+        .skip(split_at)
+        .map(|(patch, content)| {
+            let patch = Patch {
+                uuid: Uuid::new_v4().to_string(),
+                document_uuid: new_uuid.clone(),
+                timestamp: patch.timestamp,
+                delta: None,
+            };
+            (patch, content)
+        })
+        .collect();
+
+    // Starting from position 0, the first moved version is encoded against empty
+    // The new document copies both settings, so they are read from this one
+    let strategy = get_base_strategy(db, doc_uuid)?;
+    let max_chain_depth = read_max_chain_depth(db, doc_uuid)?;
+    let rewritten = reencode_chain(&moved, 0, strategy, max_chain_depth);
+
+    let tx = db.transaction().map_err(|e| e.to_string())?;
+    tx.execute(
+        "INSERT INTO documents
+            (uuid, name, created_at, content_type, base_strategy, max_versions,
+             max_content_bytes, eol_style, dictionary_id, max_chain_depth)
+         SELECT ?, ?, ?, content_type, base_strategy, max_versions,
+                max_content_bytes, eol_style, dictionary_id, max_chain_depth
+         FROM documents WHERE uuid = ?",
+        params![&new_uuid, new_name, chrono::Utc::now().timestamp_millis(), doc_uuid],
+    )
+    .map_err(|e| e.to_string())?;
+    for ((patch, content), (patch_uuid, delta)) in moved.iter().zip(&rewritten) {
+        let hash = content_hash(content);
+        insert_patch(&tx, patch_uuid, &new_uuid, patch.timestamp, delta, &hash)?;
+    }
+    tx.execute(
+        "DELETE FROM patches WHERE document_uuid = ? AND timestamp >= ?",
+        params![doc_uuid, split_timestamp],
+    )
+    .map_err(|e| e.to_string())?;
+    tx.execute(
+        "UPDATE bookmarks SET doc_uuid = ? WHERE doc_uuid = ? AND timestamp >= ?",
+        params![&new_uuid, doc_uuid, split_timestamp],
+    )
+    .map_err(|e| e.to_string())?;
+    refresh_head(&tx, doc_uuid)?;
+    refresh_head(&tx, &new_uuid)?;
+    tx.commit().map_err(|e| e.to_string())?;
+
+    invalidate_document_cache(cache, doc_uuid);
+    Ok(new_uuid)
+}
+
+/// Replays every version of `source_uuid` onto `target_uuid`, interleaved with the
+/// target's own history by timestamp, then deletes the source document. Colliding
+/// source timestamps are bumped by 1ms until unique. Returns the number of
+/// versions merged in.
+#[tauri::command]
+fn merge_documents(
+    app: AppHandle,
+    state: State<AppState>,
+    target_uuid: String,
+    source_uuid: String,
+) -> Result<usize, String> {
+    let target_progress = emit_progress(&app, &target_uuid);
+    let target_options = ReconstructOptions {
+        progress: Some(&target_progress),
+        ..Default::default()
+    };
+    let source_progress = emit_progress(&app, &source_uuid);
+    let source_options = ReconstructOptions {
+        progress: Some(&source_progress),
+        ..Default::default()
+    };
+    let merged_count = merge_into(
+        &mut state.write_db(),
+        &state.cache,
+        &target_uuid,
+        &source_uuid,
+        &target_options,
+        &source_options,
+    )?;
+
+    emit_document_changed(&app, &target_uuid, DocumentChangeKind::HistoryRewritten, None);
+    emit_document_changed(&app, &source_uuid, DocumentChangeKind::Deleted, None);
+    Ok(merged_count)
+}
+
+/// Moves every version of `source_uuid` onto `target_uuid`'s timeline and deletes
+/// the source, returning how many versions moved.
+fn merge_into(
+    db: &mut Connection,
+    cache: &ContentCache,
+    target_uuid: &str,
+    source_uuid: &str,
+    target_options: &ReconstructOptions,
+    source_options: &ReconstructOptions,
+) -> Result<usize, String> {
+    if target_uuid == source_uuid {
+        return Err("Cannot merge a document into itself".to_string());
+    }
+
+    for doc_uuid in [target_uuid, source_uuid] {
+        let exists: bool = db
+            .query_row(
+                "SELECT EXISTS(SELECT 1 FROM documents WHERE uuid = ?)",
+                params![doc_uuid],
+                |row| row.get(0),
+            )
+            .map_err(|e| e.to_string())?;
+        if !exists {
+            return Err(format!("Document not found: {}", doc_uuid));
+        }
+    }
+
+    let target_versions =
+        reconstruct_versions_with(db, cache, target_uuid, i64::MAX, target_options)?;
+    let source_versions =
+        reconstruct_versions_with(db, cache, source_uuid, i64::MAX, source_options)?;
+    let merged_count = source_versions.len();
+
+    let mut used: HashSet<i64> =
+        target_versions.iter().map(|(p, _)| p.timestamp).collect();
+    let mut new_uuids: HashSet<String> = HashSet::new();
+    let mut merged = target_versions;
+
+    for (patch, content) in source_versions {
+        let mut timestamp = patch.timestamp;
+        while !used.insert(timestamp) {
+            timestamp += 1;
+        }
+
+        let patch_uuid = Uuid::new_v4().to_string();
+        new_uuids.insert(patch_uuid.clone());
+        merged.push((
+            Patch {
+                uuid: patch_uuid,
+                document_uuid: target_uuid.to_string(),
+                timestamp,
+                delta: None,
+            },
+            content,
+        ));
+    }
+
+    merged.sort_by_key(|(p, _)| p.timestamp);
+
+    // Everything from the first merged-in version onward has a new set of bases
+    let first_changed = merged
+        .iter()
+        .position(|(p, _)| new_uuids.contains(&p.uuid))
+        .unwrap_or(merged.len());
+    let strategy = get_base_strategy(db, target_uuid)?;
+    let max_chain_depth = read_max_chain_depth(db, target_uuid)?;
+    let rewritten = reencode_chain(&merged, first_changed, strategy, max_chain_depth);
+
+    let tx = db.transaction().map_err(|e| e.to_string())?;
+    for ((patch, content), (patch_uuid, delta)) in merged[first_changed..].iter().zip(&rewritten) {
+        if new_uuids.contains(patch_uuid) {
+            let hash = content_hash(content);
+            insert_patch(&tx, patch_uuid, target_uuid, patch.timestamp, delta, &hash)?;
+        } else {
+            update_patch_delta(&tx, patch_uuid, delta)?;
+        }
+    }
+    purge_document(&tx, source_uuid)?;
+    refresh_head(&tx, target_uuid)?;
+    tx.commit().map_err(|e| e.to_string())?;
+
+    invalidate_document_cache(cache, target_uuid);
+    invalidate_document_cache(cache, source_uuid);
+    Ok(merged_count)
+}
+
+#[cfg_attr(mobile, tauri::mobile_entry_point)]
+pub fn run() {
+    tauri::Builder::default()
+        .plugin(tauri_plugin_opener::init())
+        .plugin(tauri_plugin_log::Builder::new().build())
+        .setup(|app| {
+            let (db, db_location) = init_database(app)?;
+            let mut state = AppState::new(db, db_location);
+            state.default_max_content_bytes = default_max_content_bytes()?;
+            state.verify_encodes = verify_encodes()?;
+            state.created_at_tolerance_ms = created_at_tolerance_ms()?;
+            app.manage(state);
+            Ok(())
+        })
+        .invoke_handler(tauri::generate_handler![
+            load_document_at_timestamp,
+            load_with_timeout,
+            load_document_at_date,
+            load_next_version,
+            load_previous_version,
+            load_latest,
+            load_relative_to_head,
+            load_with_cost,
+            load_with_changes,
+            load_nearest,
+            load_all_latest,
+            profile_reconstruction,
+            export_version,
+            export_all_versions,
+            export_sync_bundle,
+            import_sync_bundle,
+            version_metrics,
+            version_as_data_url,
+            version_is_utf8,
+            version_previews,
+            version_hash,
+            is_document_blank,
+            load_version_range,
+            open_version_stream,
+            read_version_chunk,
+            close_version_stream,
+            create_patch,
+            create_patch_returning_content,
+            get_eol_style,
+            create_patch_with_base,
+            estimate_patch_size,
+            explain_optimal_base,
+            create_document,
+            create_documents,
+            create_document_with_content,
+            import_file_as_document,
+            suggest_document_name,
+            set_base_strategy,
+            set_content_type,
+            set_created_at,
+            set_max_versions,
+            set_max_chain_depth,
+            set_max_content_bytes,
+            get_documents,
+            get_documents_paged,
+            get_documents_by_activity,
+            documents_active_between,
+            get_documents_with_stats,
+            get_patch_timestamps,
+            get_patch_timestamps_multi,
+            get_latest_timestamp,
+            cancel_reconstruction,
+            clear_cache,
+            clear_document_cache,
+            set_cache_compression,
+            cache_stats,
+            warm_cache,
+            prefetch_document,
+            open_database,
+            database_location,
+            storage_report,
+            library_counts,
+            library_compression_ratio,
+            document_time_bounds,
+            vacuum_database,
+            schema_status,
+            run_migrations,
+            get_document_stats,
+            export_stats_csv,
+            delta_between,
+            delta_from_client_base,
+            apply_delta_stream,
+            diff_versions,
+            diff_against_head,
+            diff_documents,
+            pending_diff,
+            similarity_matrix,
+            stable_regions,
+            inspect_patch,
+            dependency_chain,
+            chain_depth,
+            tag_histogram,
+            storage_timeline,
+            significant_edits,
+            edit_histogram,
+            add_bookmark,
+            list_bookmarks,
+            delete_bookmark,
+            load_bookmark,
+            set_metadata,
+            get_metadata,
+            list_metadata,
+            add_attachment,
+            get_attachment,
+            list_attachments,
+            delete_attachment,
+            blame_line,
+            offset_history,
+            compaction_preview,
+            compaction_candidates,
+            ensure_snapshot_head,
+            train_dictionary,
+            compact_document,
+            reoptimize_patch,
+            truncate_after,
+            prune_old_patches,
+            delete_document,
+            delete_documents,
+            list_trashed,
+            restore_document,
+            empty_trash,
+            find_duplicate_documents,
+            find_orphan_patches,
+            delete_orphan_patches,
+            recover_documents,
+            merge_documents,
+            resequence_patches,
+            split_document
+        ])
+        .run(tauri::generate_context!())
+        .expect("error while running tauri application");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_db() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        init_schema(&conn).unwrap();
+        conn
+    }
+
+    fn test_state() -> AppState {
+        let location = DatabaseLocation {
+            tier: DatabaseTier::InMemory,
+            path: None,
+        };
+        AppState::new(DbPool::single(test_db()), location)
+    }
+
+    fn add_document(db: &Connection, name: &str) -> String {
+        let doc_uuid = Uuid::new_v4().to_string();
+        db.execute(
+            "INSERT INTO documents (uuid, name, created_at) VALUES (?, ?, ?)",
+            params![&doc_uuid, name, 1],
+        )
+        .unwrap();
+        doc_uuid
+    }
+
+    // Stores `content` as a new latest version encoded against the previous one
+    fn add_version(db: &Connection, doc_uuid: &str, timestamp: i64, content: &str) {
+        let cache = ContentCache::default();
+        let previous = content_at(db, &cache, doc_uuid, i64::MAX)
+            .unwrap()
+            .unwrap_or_default();
+        let delta = xpatch::encode(0, &previous, content.as_bytes(), ENABLE_ZSTD);
+        let patch_uuid = Uuid::new_v4().to_string();
+        let hash = content_hash(content.as_bytes());
+        insert_patch(db, &patch_uuid, doc_uuid, timestamp, &delta, &hash).unwrap();
+        refresh_head(db, doc_uuid).unwrap();
+    }
+
+    // Every version with its content, replayed without a cache
+    fn history(db: &Connection, doc_uuid: &str) -> Vec<(i64, String)> {
+        let cache = ContentCache::default();
+        let options = ReconstructOptions::default();
+        reconstruct_versions_with(db, &cache, doc_uuid, i64::MAX, &options)
+            .unwrap()
+            .into_iter()
+            .map(|(patch, content)| (patch.timestamp, String::from_utf8(content).unwrap()))
+            .collect()
+    }
+
+    // A fresh directory under the system temp dir, for file-backed databases
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("xpatch-test-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // A multi-line text whose lines change a little with `i`
+    fn text(i: usize) -> String {
+        (0..=i)
+            .map(|line| format!("line {} of version {}\n", line, i % 3))
+            .collect()
+    }
+
+    #[test]
+    fn prune_keeps_every_nth_old_version_and_everything_after() {
+        let mut db = test_db();
+        let doc = add_document(&db, "notes");
+        for i in 0..20 {
+            add_version(&db, &doc, 100 + i as i64, &text(i));
+        }
+
+        let cache = ContentCache::default();
+        let options = ReconstructOptions::default();
+        let report = prune_versions(&mut db, &cache, &doc, 110, 4, &options).unwrap();
+
+        // Of the ten older versions, positions 0, 4 and 8 survive
+        assert_eq!(report.removed_patches, 7);
+        assert!(report.bytes_reclaimed > 0);
+        let expected: Vec<(i64, String)> = [0, 4, 8]
+            .into_iter()
+            .chain(10..20)
+            .map(|i| (100 + i as i64, text(i)))
+            .collect();
+        assert_eq!(history(&db, &doc), expected);
+    }
+
+    #[test]
+    fn prune_with_zero_keeps_nothing_before_the_cutoff() {
+        let mut db = test_db();
+        let doc = add_document(&db, "notes");
+        for i in 0..6 {
+            add_version(&db, &doc, 100 + i as i64, &text(i));
+        }
+
+        let cache = ContentCache::default();
+        let options = ReconstructOptions::default();
+        let report = prune_versions(&mut db, &cache, &doc, 103, 0, &options).unwrap();
+
+        assert_eq!(report.removed_patches, 3);
+        let timestamps: Vec<i64> = history(&db, &doc).iter().map(|(ts, _)| *ts).collect();
+        assert_eq!(timestamps, vec![103, 104, 105]);
+        assert_eq!(history(&db, &doc)[0].1, text(3));
+    }
+
+    #[test]
+    fn prune_with_nothing_old_is_a_no_op() {
+        let mut db = test_db();
+        let doc = add_document(&db, "notes");
+        add_version(&db, &doc, 100, "a\n");
+
+        let cache = ContentCache::default();
+        let options = ReconstructOptions::default();
+        let report = prune_versions(&mut db, &cache, &doc, 50, 0, &options).unwrap();
+
+        assert_eq!(report.removed_patches, 0);
+        assert_eq!(report.bytes_reclaimed, 0);
+        assert_eq!(history(&db, &doc).len(), 1);
+    }
+
+    #[test]
+    fn stats_see_one_snapshot_while_versions_are_written() {
+        let dir = temp_dir();
+        let pool = open_file(&dir.join("xpatch.db")).unwrap();
+        let doc = add_document(&pool.write(), "notes");
+        add_version(&pool.write(), &doc, 1, &text(0));
+
+        let options = ReconstructOptions {
+            skip_cache: true,
+            ..Default::default()
+        };
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                for i in 1..40 {
+                    add_version(&pool.write(), &doc, 1 + i as i64, &text(i));
+                }
+            });
+            for _ in 0..40 {
+                let cache = ContentCache::default();
+                let stats = document_stats(&pool.read(), &cache, &doc, &options).unwrap();
+                // The versions counted are exactly the ones whose bytes were summed
+                let expected: usize = (0..stats.total_patches as usize)
+                    .map(|i| text(i).len())
+                    .sum();
+                assert_eq!(stats.total_uncompressed_bytes, expected as i64);
+            }
+        });
+
+        drop(pool);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn read_transaction_passes_errors_through() {
+        let db = test_db();
+        let result: Result<(), String> = with_read_transaction(&db, |_| Err("boom".to_string()));
+        assert_eq!(result, Err("boom".to_string()));
+        // The failed transaction was rolled back, so another can start
+        assert_eq!(with_read_transaction(&db, |_| Ok(7)), Ok(7));
+    }
+
+    fn patch_count(db: &Connection, doc_uuid: &str) -> i64 {
+        db.query_row(
+            "SELECT COUNT(*) FROM patches WHERE document_uuid = ?",
+            params![doc_uuid],
+            |row| row.get(0),
+        )
+        .unwrap()
+    }
+
+    fn stored_delta(db: &Connection, patch_uuid: &str) -> Vec<u8> {
+        db.query_row(
+            "SELECT delta FROM patches WHERE uuid = ?",
+            params![patch_uuid],
+            |row| row.get(0),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn estimate_matches_the_delta_a_save_stores() {
+        let state = test_state();
+        let doc = add_document(&state.write_db(), "notes");
+        for i in 0..5 {
+            save_version(&state, &doc, text(i), 10 + i as i64, None).unwrap();
+        }
+
+        let content = text(7);
+        let hash = content_hash(content.as_bytes());
+        let (_tag, delta) =
+            encode_with_strategy(&state, &doc, 20, content.as_bytes(), &hash, None).unwrap();
+        // Estimating stores nothing
+        assert_eq!(patch_count(&state.read_db(), &doc), 5);
+        assert_eq!(latest_timestamp(&state.read_db(), &doc).unwrap(), Some(14));
+
+        let patch_uuid = save_version(&state, &doc, content, 20, None).unwrap();
+        assert_eq!(
+            stored_delta(&state.read_db(), &patch_uuid).len(),
+            delta.len()
+        );
+    }
+
+    fn tags(db: &Connection, doc_uuid: &str) -> Vec<usize> {
+        let mut stmt = db
+            .prepare("SELECT delta FROM patches WHERE document_uuid = ? ORDER BY timestamp")
+            .unwrap();
+        stmt.query_map(params![doc_uuid], |row| row.get::<_, Vec<u8>>(0))
+            .unwrap()
+            .map(|delta| xpatch::get_tag(&delta.unwrap()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn previous_only_never_stores_a_tag_past_zero() {
+        let state = test_state();
+        let doc = add_document(&state.write_db(), "log");
+        write_base_strategy(&state.write_db(), &doc, BaseStrategy::PreviousOnly).unwrap();
+        // Cycling through a few contents would make other strategies reach back
+        for i in 0..20 {
+            save_version(&state, &doc, text(i % 4), 10 + i as i64, None).unwrap();
+        }
+
+        assert!(tags(&state.read_db(), &doc).iter().all(|&tag| tag == 0));
+        let contents: Vec<String> = history(&state.read_db(), &doc)
+            .into_iter()
+            .map(|(_, c)| c)
+            .collect();
+        assert_eq!(contents, (0..20).map(|i| text(i % 4)).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn optimal_depth_compares_at_most_that_many_bases() {
+        let state = test_state();
+        let doc = add_document(&state.write_db(), "prose");
+        write_base_strategy(&state.write_db(), &doc, BaseStrategy::OptimalDepth(4)).unwrap();
+        for i in 0..12 {
+            save_version(&state, &doc, text(i), 10 + i as i64, None).unwrap();
+        }
+
+        let content = format!("{}rewritten\n", text(3));
+        let hash = content_hash(content.as_bytes());
+        let mut trace = Vec::new();
+        encode_with_strategy(
+            &state,
+            &doc,
+            30,
+            content.as_bytes(),
+            &hash,
+            Some(&mut trace),
+        )
+        .unwrap();
+        assert!(!trace.is_empty() && trace.len() <= 4);
+        assert!(tags(&state.read_db(), &doc).iter().all(|&tag| tag < 4));
+    }
+
+    #[test]
+    fn snapshot_every_stores_standalone_versions_on_schedule() {
+        let state = test_state();
+        let doc = add_document(&state.write_db(), "notes");
+        write_base_strategy(&state.write_db(), &doc, BaseStrategy::SnapshotEvery(3)).unwrap();
+        for i in 0..8 {
+            save_version(&state, &doc, text(i), 10 + i as i64, None).unwrap();
+        }
+
+        let tags = tags(&state.read_db(), &doc);
+        for pos in [0, 3, 6] {
+            assert_eq!(tags[pos], SNAPSHOT_TAG);
+        }
+        assert!(tags[1] != SNAPSHOT_TAG && tags[7] != SNAPSHOT_TAG);
+        assert_eq!(history(&state.read_db(), &doc)[7].1, text(7));
+    }
+
+    #[test]
+    fn base_strategy_round_trips_and_rejects_zero() {
+        let db = test_db();
+        let doc = add_document(&db, "notes");
+        assert_eq!(
+            get_base_strategy(&db, &doc).unwrap(),
+            BaseStrategy::default()
+        );
+
+        write_base_strategy(&db, &doc, BaseStrategy::SnapshotEvery(5)).unwrap();
+        assert_eq!(
+            get_base_strategy(&db, &doc).unwrap(),
+            BaseStrategy::SnapshotEvery(5)
+        );
+
+        assert!(write_base_strategy(&db, &doc, BaseStrategy::OptimalDepth(0)).is_err());
+        let missing = write_base_strategy(&db, "missing", BaseStrategy::PreviousOnly);
+        assert_eq!(missing, Err("Document not found".to_string()));
+    }
+
+    fn document_exists(db: &Connection, doc_uuid: &str) -> bool {
+        db.query_row(
+            "SELECT EXISTS(SELECT 1 FROM documents WHERE uuid = ?)",
+            params![doc_uuid],
+            |row| row.get(0),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn merge_interleaves_both_histories_and_bumps_collisions() {
+        let mut db = test_db();
+        let target = add_document(&db, "target");
+        let source = add_document(&db, "source");
+        // The source's third version collides with the target's at 120
+        let source_timestamps = [105, 115, 120, 135, 145];
+        for (i, &timestamp) in source_timestamps.iter().enumerate() {
+            add_version(&db, &target, 100 + 10 * i as i64, &text(i));
+            add_version(&db, &source, timestamp, &format!("source\n{}", text(i)));
+        }
+
+        let cache = ContentCache::default();
+        let options = ReconstructOptions::default();
+        let merged = merge_into(&mut db, &cache, &target, &source, &options, &options).unwrap();
+
+        assert_eq!(merged, 5);
+        assert!(!document_exists(&db, &source));
+        let history = history(&db, &target);
+        assert_eq!(history.len(), 10);
+        for (i, &timestamp) in source_timestamps.iter().enumerate() {
+            assert!(history.contains(&(100 + 10 * i as i64, text(i))));
+            let timestamp = if i == 2 { 121 } else { timestamp };
+            assert!(history.contains(&(timestamp, format!("source\n{}", text(i)))));
+        }
+    }
+
+    #[test]
+    fn merge_rejects_itself_and_unknown_documents() {
+        let mut db = test_db();
+        let doc = add_document(&db, "notes");
+        let cache = ContentCache::default();
+        let options = ReconstructOptions::default();
+
+        assert!(merge_into(&mut db, &cache, &doc, &doc, &options, &options).is_err());
+        let missing = merge_into(&mut db, &cache, &doc, "missing", &options, &options);
+        assert_eq!(missing, Err("Document not found: missing".to_string()));
+        assert!(document_exists(&db, &doc));
+    }
+
+    // Stores `delta` as is, for crafting chains save_version would never produce
+    fn insert_delta(db: &Connection, doc_uuid: &str, timestamp: i64, delta: &[u8]) -> String {
+        let patch_uuid = Uuid::new_v4().to_string();
+        insert_patch(db, &patch_uuid, doc_uuid, timestamp, delta, "").unwrap();
+        refresh_head(db, doc_uuid).unwrap();
+        patch_uuid
+    }
+
+    #[test]
+    fn tag_histogram_counts_patches_and_bytes_per_tag() {
+        let db = test_db();
+        let doc = add_document(&db, "notes");
+        let deltas: Vec<Vec<u8>> = [0, 2, 0, SNAPSHOT_TAG, 1, 0]
+            .iter()
+            .map(|&tag| xpatch::encode(tag, b"base", b"new content", ENABLE_ZSTD))
+            .collect();
+        for (i, delta) in deltas.iter().enumerate() {
+            insert_delta(&db, &doc, 10 + i as i64, delta);
+        }
+
+        let bytes = |positions: &[usize]| positions.iter().map(|&i| deltas[i].len() as i64).sum();
+        let expected = vec![
+            (0, 3, bytes(&[0, 2, 5])),
+            (1, 1, bytes(&[4])),
+            (2, 1, bytes(&[1])),
+            (SNAPSHOT_TAG, 1, bytes(&[3])),
+        ];
+        assert_eq!(tag_counts(&db, &doc).unwrap(), expected);
+        assert!(tag_counts(&db, "missing").unwrap().is_empty());
+    }
+
+    #[test]
+    fn poisoned_locks_are_recovered() {
+        let state = test_state();
+        let doc = add_document(&state.write_db(), "notes");
+        save_version(&state, &doc, text(1), 10, None).unwrap();
+
+        // Panic while holding both the connection and the cache
+        let panicked = std::thread::scope(|scope| {
+            scope
+                .spawn(|| {
+                    let _db = state.write_db();
+                    let _cache = state.lock_cache();
+                    panic!("command failed mid-write");
+                })
+                .join()
+                .is_err()
+        });
+        assert!(panicked);
+        assert!(state.db.writer.is_poisoned());
+
+        save_version(&state, &doc, text(2), 20, None).unwrap();
+        assert_eq!(latest_timestamp(&state.read_db(), &doc).unwrap(), Some(20));
+        let options = ReconstructOptions::default();
+        let content = load_content(&state.read_db(), &state.cache, &doc, 20, &options).unwrap();
+        assert_eq!(content, text(2));
+    }
+
+    #[test]
+    fn latest_timestamp_is_none_until_the_first_patch() {
+        let db = test_db();
+        let doc = add_document(&db, "notes");
+        assert_eq!(latest_timestamp(&db, &doc).unwrap(), None);
+
+        add_version(&db, &doc, 10, "a\n");
+        add_version(&db, &doc, 25, "b\n");
+        assert_eq!(latest_timestamp(&db, &doc).unwrap(), Some(25));
+        assert_eq!(latest_timestamp(&db, "missing").unwrap(), None);
+    }
+
+    #[test]
+    fn reverting_to_an_old_version_stores_a_minimal_delta() {
+        let state = test_state();
+        let doc = add_document(&state.write_db(), "notes");
+        let original = text(40);
+        save_version(&state, &doc, original.clone(), 10, None).unwrap();
+        for i in 0..3 {
+            let edit = format!("{}edit {}\n", text(20 + i), i);
+            save_version(&state, &doc, edit, 20 + i as i64, None).unwrap();
+        }
+
+        let patch_uuid = save_version(&state, &doc, original.clone(), 30, None).unwrap();
+        let delta = stored_delta(&state.read_db(), &patch_uuid);
+        // Three versions sit between the revert and the one it restores
+        assert_eq!(xpatch::get_tag(&delta).unwrap(), 3);
+        assert!(delta.len() < 16, "revert delta is {} bytes", delta.len());
+
+        let history = history(&state.read_db(), &doc);
+        assert_eq!(history.last().unwrap(), &(30, original));
+    }
+
+    #[test]
+    fn saving_the_head_again_is_rejected() {
+        let state = test_state();
+        let doc = add_document(&state.write_db(), "notes");
+        save_version(&state, &doc, text(3), 10, None).unwrap();
+
+        let result = save_version(&state, &doc, text(3), 20, None);
+        let expected = "Content identical to last version - patch not created";
+        assert_eq!(result, Err(expected.to_string()));
+        assert_eq!(patch_count(&state.read_db(), &doc), 1);
+    }
+
+    #[test]
+    fn batch_create_inserts_every_document_in_order() {
+        let mut db = test_db();
+        let names: Vec<String> = (0..1000).map(|i| format!("note {}", i)).collect();
+
+        let doc_uuids = insert_documents(&mut db, &names, 5).unwrap();
+
+        assert_eq!(doc_uuids.len(), 1000);
+        let documents = list_documents(&db).unwrap();
+        assert_eq!(documents.len(), 1000);
+        let name_of: HashMap<&str, &str> = documents
+            .iter()
+            .map(|doc| (doc.uuid.as_str(), doc.name.as_str()))
+            .collect();
+        for (doc_uuid, name) in doc_uuids.iter().zip(&names) {
+            assert_eq!(name_of[doc_uuid.as_str()], name);
+        }
+    }
+
+    #[test]
+    fn batch_create_rolls_back_when_any_row_fails() {
+        let mut db = test_db();
+        db.execute_batch(
+            "CREATE TRIGGER reject_bad BEFORE INSERT ON documents WHEN NEW.name = 'bad'
+             BEGIN SELECT RAISE(ABORT, 'rejected'); END",
+        )
+        .unwrap();
+        let names = vec!["one".to_string(), "bad".to_string(), "three".to_string()];
+
+        assert!(insert_documents(&mut db, &names, 5).is_err());
+        assert!(list_documents(&db).unwrap().is_empty());
+    }
+
+    #[test]
+    fn batch_create_beats_one_transaction_per_document() {
+        // File-backed, so every commit pays for a sync
+        let dir = temp_dir();
+        let pool = open_file(&dir.join("xpatch.db")).unwrap();
+        let names: Vec<String> = (0..200).map(|i| format!("note {}", i)).collect();
+
+        let started = Instant::now();
+        for name in &names {
+            insert_documents(&mut pool.write(), std::slice::from_ref(name), 5).unwrap();
+        }
+        let one_by_one = started.elapsed();
+
+        let started = Instant::now();
+        insert_documents(&mut pool.write(), &names, 5).unwrap();
+        let batched = started.elapsed();
+
+        assert!(batched < one_by_one, "{:?} vs {:?}", batched, one_by_one);
+        assert_eq!(list_documents(&pool.read()).unwrap().len(), 400);
+        drop(pool);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn load_latest_matches_a_load_at_the_head_timestamp() {
+        let db = test_db();
+        let doc = add_document(&db, "notes");
+        let cache = ContentCache::default();
+        assert_eq!(head_version(&db, &cache, &doc).unwrap(), (0, String::new()));
+
+        for i in 0..4 {
+            add_version(&db, &doc, 10 + i as i64, &text(i));
+        }
+        let options = ReconstructOptions::default();
+        let at_head = load_content(&db, &cache, &doc, 13, &options).unwrap();
+        assert_eq!(head_version(&db, &cache, &doc).unwrap(), (13, at_head));
+        // Once cached, the head is read without walking the chain
+        assert_eq!(head_version(&db, &cache, &doc).unwrap(), (13, text(3)));
+    }
+
+    #[test]
+    fn base_search_stops_at_a_good_enough_delta() {
+        let bases: Vec<String> = (0..8).map(|i| text(30 + i)).collect();
+        let new_content = format!("{}one more line\n", text(37));
+        let candidates = |good_enough_bytes| {
+            let mut tried = 0;
+            let bases = bases.iter().rev().map(|base| Ok(base.as_bytes()));
+            let (tag, delta) = select_base(
+                bases,
+                new_content.as_bytes(),
+                ENABLE_ZSTD,
+                good_enough_bytes,
+                |_, _| tried += 1,
+            )
+            .unwrap();
+            (tried, tag, delta)
+        };
+
+        let (exhaustive, _, _) = candidates(0);
+        let (early, tag, delta) = candidates(64);
+        assert_eq!(exhaustive, 8);
+        assert_eq!(early, 1);
+        let base = &bases[bases.len() - 1 - tag];
+        assert_eq!(
+            safe_decode(base.as_bytes(), &delta).unwrap(),
+            new_content.as_bytes()
+        );
+    }
+
+    #[test]
+    fn document_summaries_match_per_document_stats() {
+        let db = test_db();
+        let cache = ContentCache::default();
+        let options = ReconstructOptions::default();
+        let docs: Vec<String> = (0..3)
+            .map(|i| add_document(&db, &format!("doc {}", i)))
+            .collect();
+        for (n, doc) in docs.iter().enumerate() {
+            for i in 0..n * 3 {
+                add_version(&db, doc, 10 + i as i64, &text(i + n));
+            }
+        }
+
+        let summaries = document_summaries(&db).unwrap();
+        assert_eq!(summaries.len(), 3);
+        for summary in summaries {
+            let stats = document_stats(&db, &cache, &summary.document.uuid, &options).unwrap();
+            assert_eq!(summary.total_patches, stats.total_patches);
+            assert_eq!(summary.total_delta_bytes, stats.total_delta_bytes);
+        }
+    }
+
+    // A database as an older build left it: base tables plus the first `version`
+    // migrations
+    fn db_at_schema_version(version: usize) -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE documents (uuid TEXT PRIMARY KEY, name TEXT NOT NULL,
+                                     created_at INTEGER NOT NULL);
+             CREATE TABLE patches (uuid TEXT PRIMARY KEY, document_uuid TEXT NOT NULL,
+                                   timestamp INTEGER NOT NULL, delta BLOB);
+             CREATE TABLE schema_version (version INTEGER NOT NULL);",
+        )
+        .unwrap();
+        for migration in &MIGRATIONS[..version] {
+            conn.execute_batch(migration).unwrap();
+        }
+        conn.execute(
+            "INSERT INTO schema_version (version) VALUES (?)",
+            params![version as i64],
+        )
+        .unwrap();
+        conn
+    }
+
+    #[test]
+    fn content_type_persists_and_defaults_to_plain_text() {
+        let db = test_db();
+        let markdown = insert_document(
+            &db,
+            "readme",
+            Some("text/markdown".into()),
+            None,
+            DEFAULT_CREATED_AT_TOLERANCE_MS,
+        )
+        .unwrap();
+        let plain =
+            insert_document(&db, "notes", None, None, DEFAULT_CREATED_AT_TOLERANCE_MS).unwrap();
+        assert_eq!(
+            document_content_type(&db, &markdown).unwrap(),
+            "text/markdown"
+        );
+        assert_eq!(
+            document_content_type(&db, &plain).unwrap(),
+            DEFAULT_CONTENT_TYPE
+        );
+
+        write_content_type(&db, &plain, "image/png").unwrap();
+        assert_eq!(document_content_type(&db, &plain).unwrap(), "image/png");
+        assert!(write_content_type(&db, "missing", "image/png").is_err());
+    }
+
+    #[test]
+    fn documents_from_before_the_content_type_migration_are_plain_text() {
+        let db = db_at_schema_version(2);
+        db.execute(
+            "INSERT INTO documents (uuid, name, created_at) VALUES ('old', 'old', 1)",
+            [],
+        )
+        .unwrap();
+
+        init_schema(&db).unwrap();
+
+        assert_eq!(
+            document_content_type(&db, "old").unwrap(),
+            DEFAULT_CONTENT_TYPE
+        );
+    }
+
+    #[test]
+    fn cancelling_stops_a_reconstruction_between_patches() {
+        let db = test_db();
+        let doc = add_document(&db, "long");
+        for i in 0..200 {
+            add_version(&db, &doc, 10 + i as i64, &format!("version {}\n", i));
+        }
+
+        let cancel = AtomicBool::new(false);
+        // Cancel from another thread as soon as the first progress report arrives
+        let progress = |_done: usize, _total: usize| {
+            std::thread::scope(|scope| {
+                scope.spawn(|| cancel.store(true, Ordering::Relaxed));
+            });
+        };
+        let options = ReconstructOptions {
+            cancel: Some(&cancel),
+            progress: Some(&progress),
+            ..Default::default()
+        };
+        let cache = ContentCache::default();
+        let result = reconstruct_versions_with(&db, &cache, &doc, i64::MAX, &options);
+
+        assert_eq!(result.err(), Some("Reconstruction cancelled".to_string()));
+        assert_eq!(cache.entries().len(), PROGRESS_EVERY);
+    }
+
+    #[test]
+    fn a_flipped_delta_byte_fails_the_read_unless_repairing() {
+        let db = test_db();
+        let intact = add_document(&db, "intact");
+        let damaged = add_document(&db, "damaged");
+        add_version(&db, &intact, 10, &text(5));
+        add_version(&db, &damaged, 10, &text(5));
+        let patch_uuid: String = db
+            .query_row(
+                "SELECT uuid FROM patches WHERE document_uuid = ?",
+                params![&damaged],
+                |row| row.get(0),
+            )
+            .unwrap();
+        let mut delta = stored_delta(&db, &patch_uuid);
+        let last = delta.len() - 1;
+        delta[last] ^= 0x01;
+        db.execute(
+            "UPDATE patches SET delta = ? WHERE uuid = ?",
+            params![delta, &patch_uuid],
+        )
+        .unwrap();
+
+        let cache = ContentCache::default();
+        let options = ReconstructOptions::default();
+        let result = load_content(&db, &cache, &damaged, 10, &options);
+        assert_eq!(
+            result,
+            Err(format!("Corrupt delta in patch {}", patch_uuid))
+        );
+        assert_eq!(
+            load_content(&db, &cache, &intact, 10, &options).unwrap(),
+            text(5)
+        );
+
+        // Repair mode decodes whatever the bytes say instead of refusing
+        let repair = ReconstructOptions {
+            repair_mode: true,
+            ..Default::default()
+        };
+        let repaired = load_content(&db, &cache, &damaged, 10, &repair);
+        assert_ne!(
+            repaired,
+            Err(format!("Corrupt delta in patch {}", patch_uuid))
+        );
+    }
+
+    #[test]
+    fn progress_is_reported_in_steps_until_done_reaches_total() {
+        let mut db = test_db();
+        let doc = add_document(&db, "long");
+        for i in 0..150 {
+            add_version(&db, &doc, 10 + i as i64, &format!("version {}\n", i));
+        }
+
+        let reports = RefCell::new(Vec::new());
+        let progress = |done, total| reports.borrow_mut().push((done, total));
+        let options = ReconstructOptions {
+            progress: Some(&progress),
+            ..Default::default()
+        };
+        let cache = ContentCache::default();
+        reconstruct_versions_with(&db, &cache, &doc, i64::MAX, &options).unwrap();
+        assert_eq!(reports.take(), vec![(64, 150), (128, 150), (150, 150)]);
+
+        compact(&mut db, &cache, &doc, &options).unwrap();
+        assert_eq!(reports.take().last(), Some(&(150, 150)));
+    }
+
+    #[test]
+    fn progress_events_carry_the_document_and_counts() {
+        let event = ReconstructionProgress {
+            doc_uuid: "doc",
+            done: 64,
+            total: 150,
+        };
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({"doc_uuid": "doc", "done": 64, "total": 150})
+        );
+    }
+
+    #[test]
+    fn delta_between_decodes_from_one_version_to_the_other() {
+        let db = test_db();
+        let cache = ContentCache::default();
+        let doc = add_document(&db, "notes");
+        for i in 0..5 {
+            add_version(&db, &doc, 10 + i as i64, &text(i * 3));
+        }
+
+        for enable_zstd in [false, true] {
+            let delta = version_delta(&db, &cache, &doc, 11, 14, enable_zstd).unwrap();
+            assert_eq!(
+                safe_decode(text(3).as_bytes(), &delta).unwrap(),
+                text(12).as_bytes()
+            );
+            // Backwards works just as well
+            let delta = version_delta(&db, &cache, &doc, 14, 10, enable_zstd).unwrap();
+            assert_eq!(
+                safe_decode(text(12).as_bytes(), &delta).unwrap(),
+                text(0).as_bytes()
+            );
+        }
+
+        let same = version_delta(&db, &cache, &doc, 12, 12, false).unwrap();
+        assert_eq!(
+            safe_decode(text(6).as_bytes(), &same).unwrap(),
+            text(6).as_bytes()
+        );
+        let missing = version_delta(&db, &cache, &doc, 5, 12, false);
+        assert_eq!(missing, Err("Version not found".to_string()));
+    }
+
+    #[test]
+    fn startup_falls_back_to_temp_when_app_data_is_unavailable() {
+        let dir = temp_dir();
+
+        let (_db, location) =
+            open_with_fallback(Err("no app dir".into()), dir.join("temp")).unwrap();
+        assert_eq!(location.tier, DatabaseTier::Temp);
+        let expected = dir.join("temp").join("xpatch.db");
+        assert_eq!(location.path, Some(expected.to_string_lossy().into_owned()));
+
+        // A regular file where the app data directory should be cannot hold the database
+        let blocked = dir.join("blocked");
+        std::fs::write(&blocked, "").unwrap();
+        let (_db, location) = open_with_fallback(Ok(blocked), dir.join("temp")).unwrap();
+        assert_eq!(location.tier, DatabaseTier::Temp);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn startup_runs_in_memory_when_no_directory_works() {
+        let dir = temp_dir();
+        let blocked = dir.join("blocked");
+        std::fs::write(&blocked, "").unwrap();
+
+        let (db, location) = open_with_fallback(Ok(blocked.clone()), blocked).unwrap();
+        assert_eq!(location.tier, DatabaseTier::InMemory);
+        assert_eq!(location.path, None);
+        // The fallback is a working, migrated database
+        let doc = add_document(&db.write(), "notes");
+        assert_eq!(latest_timestamp(&db.read(), &doc).unwrap(), None);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn uuids(documents: Vec<Document>) -> Vec<String> {
+        documents.into_iter().map(|doc| doc.uuid).collect()
+    }
+
+    #[test]
+    fn trashed_documents_can_be_listed_and_restored() {
+        let db = test_db();
+        let kept = add_document(&db, "kept");
+        let doc = add_document(&db, "notes");
+        add_version(&db, &doc, 10, "a\n");
+
+        trash_document(&db, &doc, 1000).unwrap();
+        assert_eq!(uuids(list_documents(&db).unwrap()), vec![kept.clone()]);
+        assert_eq!(uuids(trashed_documents(&db).unwrap()), vec![doc.clone()]);
+        assert_eq!(
+            trash_document(&db, &doc, 1001),
+            Err("Document not found".to_string())
+        );
+
+        untrash_document(&db, &doc).unwrap();
+        assert!(trashed_documents(&db).unwrap().is_empty());
+        assert_eq!(list_documents(&db).unwrap().len(), 2);
+        assert_eq!(history(&db, &doc), vec![(10, "a\n".to_string())]);
+        let error = Err("Document is not in the trash".to_string());
+        assert_eq!(untrash_document(&db, &doc), error);
+        assert_eq!(untrash_document(&db, &kept), error);
+    }
+
+    #[test]
+    fn emptying_the_trash_deletes_only_trashed_documents() {
+        let mut db = test_db();
+        let cache = ContentCache::default();
+        let kept = add_document(&db, "kept");
+        let doc = add_document(&db, "notes");
+        add_version(&db, &kept, 10, "kept\n");
+        add_version(&db, &doc, 10, "gone\n");
+        trash_document(&db, &doc, 1000).unwrap();
+
+        assert_eq!(purge_trash(&mut db, &cache).unwrap(), vec![doc.clone()]);
+        assert!(!document_exists(&db, &doc));
+        assert_eq!(patch_count(&db, &doc), 0);
+        assert_eq!(history(&db, &kept), vec![(10, "kept\n".to_string())]);
+        assert!(purge_trash(&mut db, &cache).unwrap().is_empty());
+    }
+
+    fn total_delta_bytes(db: &Connection, doc_uuid: &str) -> i64 {
+        db.query_row(
+            "SELECT COALESCE(SUM(LENGTH(delta)), 0) FROM patches WHERE document_uuid = ?",
+            params![doc_uuid],
+            |row| row.get(0),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn compaction_preview_predicts_the_real_compaction() {
+        let mut db = test_db();
+        let cache = ContentCache::default();
+        let doc = add_document(&db, "notes");
+        // Every delta is against the previous version, though older ones match better
+        for i in 0..24 {
+            add_version(&db, &doc, 10 + i as i64, &text(20 + i % 4 * 7));
+        }
+        write_base_strategy(&db, &doc, BaseStrategy::SnapshotEvery(10)).unwrap();
+        let before = total_delta_bytes(&db, &doc);
+
+        let versions = reconstruct_versions(&db, &cache, &doc, i64::MAX).unwrap();
+        let strategy = BaseStrategy::SnapshotEvery(10);
+        let (_rewritten, preview) = plan_compaction(&versions, strategy, None);
+        assert_eq!(total_delta_bytes(&db, &doc), before);
+        assert_eq!(preview.delta_bytes_before, before);
+        assert_eq!(preview.snapshots_added, 2);
+        assert!(preview.delta_bytes_after < before);
+
+        let report = compact(&mut db, &cache, &doc, &ReconstructOptions::default()).unwrap();
+        assert_eq!(report.delta_bytes_after, preview.delta_bytes_after);
+        assert_eq!(total_delta_bytes(&db, &doc), preview.delta_bytes_after);
+        let tags = tags(&db, &doc);
+        assert!(tags[10] == SNAPSHOT_TAG && tags[20] == SNAPSHOT_TAG);
+        let contents: Vec<String> = history(&db, &doc).into_iter().map(|(_, c)| c).collect();
+        assert_eq!(
+            contents,
+            (0..24).map(|i| text(20 + i % 4 * 7)).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn reads_proceed_while_the_writer_is_busy() {
+        let dir = temp_dir();
+        let pool = open_file(&dir.join("xpatch.db")).unwrap();
+        let read_doc = add_document(&pool.write(), "read");
+        let write_doc = add_document(&pool.write(), "write");
+        add_version(&pool.write(), &read_doc, 10, &text(50));
+
+        let (done, finished) = std::sync::mpsc::channel();
+        std::thread::scope(|scope| {
+            // An open write transaction on the other document
+            let mut writer = pool.write();
+            let tx = writer.transaction().unwrap();
+            add_version(&tx, &write_doc, 10, "draft\n");
+
+            scope.spawn(|| {
+                let cache = ContentCache::default();
+                let options = ReconstructOptions::default();
+                let content = load_content(&pool.read(), &cache, &read_doc, 10, &options);
+                done.send(content).unwrap();
+            });
+            let content = finished.recv_timeout(Duration::from_secs(10)).unwrap();
+            assert_eq!(content.unwrap(), text(50));
+
+            tx.commit().unwrap();
+        });
+
+        assert_eq!(
+            history(&pool.read(), &write_doc),
+            vec![(10, "draft\n".to_string())]
+        );
+        drop(pool);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn blame_reports_every_time_a_line_comes_and_goes() {
+        let db = test_db();
+        let cache = ContentCache::default();
+        let doc = add_document(&db, "notes");
+        let versions = [
+            "intro\n",
+            "intro\nthe line\n",
+            "intro\nthe line\nmore\n",
+            "intro\nthe line\nmore\n",
+            "intro\nmore\n",
+            "intro\nmore\nthe line\n",
+            // Only whole lines count
+            "intro\nmore\nthe line, edited\n",
+        ];
+        for (i, content) in versions.iter().enumerate() {
+            add_version(&db, &doc, 1 + i as i64, content);
+        }
+
+        let kinds: Vec<(i64, LineEventKind)> = line_events(&db, &cache, &doc, "the line")
+            .unwrap()
+            .into_iter()
+            .map(|event| (event.timestamp, event.kind))
+            .collect();
+        let expected = vec![
+            (2, LineEventKind::Added),
+            (5, LineEventKind::Removed),
+            (6, LineEventKind::Added),
+            (7, LineEventKind::Removed),
+        ];
+        assert_eq!(kinds, expected);
+        assert!(line_events(&db, &cache, &doc, "never there")
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn skip_cache_reads_the_cache_without_filling_it() {
+        let db = test_db();
+        let cache = ContentCache::default();
+        let doc = add_document(&db, "notes");
+        for i in 0..10 {
+            add_version(&db, &doc, 10 + i as i64, &text(i));
+        }
+        let skip_cache = ReconstructOptions {
+            skip_cache: true,
+            ..Default::default()
+        };
+
+        let versions = reconstruct_versions_with(&db, &cache, &doc, i64::MAX, &skip_cache).unwrap();
+        assert_eq!(versions.len(), 10);
+        assert_eq!(cache.entries().len(), 0);
+
+        // The default path still caches, and a later uncached pass uses those entries
+        reconstruct_versions(&db, &cache, &doc, 14).unwrap();
+        assert_eq!(cache.entries().len(), 5);
+        let hits = cache.hits.load(Ordering::Relaxed);
+        let versions = reconstruct_versions_with(&db, &cache, &doc, i64::MAX, &skip_cache).unwrap();
+        assert_eq!(cache.hits.load(Ordering::Relaxed) - hits, 5);
+        assert_eq!(cache.entries().len(), 5);
+        assert_eq!(String::from_utf8(versions[9].1.clone()).unwrap(), text(9));
+    }
+
+    #[test]
+    fn orphan_patches_are_reported_and_removed() {
+        let mut db = test_db();
+        let kept = add_document(&db, "kept");
+        add_version(&db, &kept, 10, "a");
+        let doc = add_document(&db, "doc");
+        add_version(&db, &doc, 10, "a");
+        add_version(&db, &doc, 20, "ab");
+
+        db.pragma_update(None, "foreign_keys", "OFF").unwrap();
+        db.execute("DELETE FROM documents WHERE uuid = ?", params![&doc])
+            .unwrap();
+        init_schema(&db).unwrap();
+
+        assert_eq!(orphan_patches(&db).unwrap().len(), 2);
+        let cache = ContentCache::default();
+        assert_eq!(remove_orphan_patches(&mut db, &cache).unwrap(), 2);
+        assert!(orphan_patches(&db).unwrap().is_empty());
+        assert_eq!(patch_count(&db, &kept), 1);
+    }
+
+    #[test]
+    fn foreign_keys_reject_new_orphans() {
+        let db = test_db();
+        let result = insert_patch(&db, "p", "missing", 10, b"", "");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn bookmarks_resolve_to_the_version_current_at_their_timestamp() {
+        let db = test_db();
+        let cache = ContentCache::default();
+        let doc = add_document(&db, "doc");
+        add_version(&db, &doc, 10, "one");
+        add_version(&db, &doc, 20, "two");
+        add_version(&db, &doc, 30, "three");
+
+        insert_bookmark(&db, &doc, "release", 25).unwrap();
+        insert_bookmark(&db, &doc, "start", 10).unwrap();
+        let names: Vec<String> = bookmarks(&db, &doc)
+            .unwrap()
+            .into_iter()
+            .map(|b| b.name)
+            .collect();
+        assert_eq!(names, ["start", "release"]);
+        assert_eq!(
+            bookmark_content(&db, &cache, &doc, "release").unwrap(),
+            "two"
+        );
+
+        remove_bookmark(&db, &doc, "release").unwrap();
+        assert_eq!(bookmarks(&db, &doc).unwrap().len(), 1);
+        assert!(bookmark_content(&db, &cache, &doc, "release").is_err());
+        assert!(remove_bookmark(&db, &doc, "release").is_err());
+    }
+
+    #[test]
+    fn bookmark_names_are_unique_per_document() {
+        let db = test_db();
+        let doc = add_document(&db, "doc");
+        let other = add_document(&db, "other");
+        insert_bookmark(&db, &doc, "v1", 10).unwrap();
+
+        let err = insert_bookmark(&db, &doc, "v1", 20).unwrap_err();
+        assert_eq!(err, "Bookmark already exists: v1");
+        insert_bookmark(&db, &other, "v1", 20).unwrap();
+        assert!(insert_bookmark(&db, "missing", "v1", 20).is_err());
+    }
+
+    #[test]
+    fn version_stream_hands_out_bounded_chunks() {
+        let state = test_state();
+        let content: String = (0..1 << 20)
+            .map(|i| (b'a' + (i % 26) as u8) as char)
+            .collect();
+        let doc = {
+            let db = state.write_db();
+            let doc = add_document(&db, "big");
+            add_version(&db, &doc, 10, "small");
+            add_version(&db, &doc, 20, &content);
+            doc
+        };
+
+        let handle = open_stream(&state, &doc, 20).unwrap();
+        let mut reassembled = Vec::new();
+        while let Some(chunk) = next_chunk(&state, &handle, 64 * 1024).unwrap() {
+            assert!(chunk.len() <= 64 * 1024);
+            reassembled.extend(chunk);
+        }
+        let expected = content_at(&state.read_db(), &state.cache, &doc, 20).unwrap();
+        assert_eq!(Some(reassembled), expected);
+
+        assert!(state.lock_streams().is_empty());
+        assert_eq!(
+            next_chunk(&state, &handle, 1).unwrap_err(),
+            "Stream not found"
+        );
+    }
+
+    #[test]
+    fn version_stream_rejects_empty_chunks() {
+        let state = test_state();
+        let handle = open_stream(&state, "missing", 10).unwrap();
+        assert!(next_chunk(&state, &handle, 0).is_err());
+        assert_eq!(next_chunk(&state, &handle, 8).unwrap(), None);
+    }
+
+    #[test]
+    fn max_versions_prunes_the_oldest_on_save() {
+        let state = test_state();
+        let doc = add_document(&state.write_db(), "log");
+        write_max_versions(&state.write_db(), &doc, Some(5)).unwrap();
+
+        for i in 1..=10 {
+            save_version(&state, &doc, text(i), i as i64 * 10, None).unwrap();
+        }
+
+        let expected: Vec<(i64, String)> = (6..=10).map(|i| (i as i64 * 10, text(i))).collect();
+        assert_eq!(history(&state.read_db(), &doc), expected);
+    }
+
+    #[test]
+    fn max_versions_validates_its_input() {
+        let db = test_db();
+        let doc = add_document(&db, "doc");
+        assert!(write_max_versions(&db, &doc, Some(0)).is_err());
+        assert!(write_max_versions(&db, "missing", Some(3)).is_err());
+        write_max_versions(&db, &doc, None).unwrap();
+    }
+
+    #[test]
+    fn warmed_versions_load_without_decoding() {
+        let db = test_db();
+        let cache = ContentCache::default();
+        let doc = add_document(&db, "doc");
+        for i in 1..=6 {
+            add_version(&db, &doc, i as i64 * 10, &text(i));
+        }
+
+        let warmup = warm_document_cache(&db, &cache, &doc).unwrap();
+        assert_eq!(warmup.versions_warmed, 6);
+        assert_eq!(
+            warmup.bytes_cached,
+            (1..=6).map(|i| text(i).len()).sum::<usize>()
+        );
+
+        let misses = cache.misses.load(Ordering::Relaxed);
+        for i in 1..=6 {
+            let options = ReconstructOptions::default();
+            let content = load_content(&db, &cache, &doc, i as i64 * 10, &options).unwrap();
+            assert_eq!(content, text(i));
+        }
+        assert_eq!(cache.misses.load(Ordering::Relaxed), misses);
+    }
+
+    #[test]
+    fn dates_honour_their_offset() {
+        let utc = date_to_timestamp("2024-05-01T07:30:00Z").unwrap();
+        let paris = date_to_timestamp("2024-05-01T09:30:00+02:00").unwrap();
+        assert_eq!(utc, 1_714_548_600_000);
+        assert_eq!(paris, utc);
+        assert_eq!(
+            date_to_timestamp("2024-05-01T07:30:00.250Z").unwrap(),
+            utc + 250
+        );
+    }
+
+    #[test]
+    fn unparseable_dates_are_rejected() {
+        for date in ["", "2024-05-01", "yesterday", "2024-13-01T00:00:00Z"] {
+            let err = date_to_timestamp(date).unwrap_err();
+            assert!(
+                err.starts_with(&format!("Invalid date '{}'", date)),
+                "{}",
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn dates_before_the_first_patch_load_empty() {
+        let db = test_db();
+        let cache = ContentCache::default();
+        let doc = add_document(&db, "doc");
+        let first = date_to_timestamp("2024-05-01T00:00:00Z").unwrap();
+        add_version(&db, &doc, first, "hello");
+
+        let options = ReconstructOptions::default();
+        let before = date_to_timestamp("2024-04-30T23:59:59Z").unwrap();
+        assert_eq!(
+            load_content(&db, &cache, &doc, before, &options).unwrap(),
+            ""
+        );
+        let same_instant = date_to_timestamp("2024-05-01T02:00:00+02:00").unwrap();
+        assert_eq!(
+            load_content(&db, &cache, &doc, same_instant, &options).unwrap(),
+            "hello"
+        );
+    }
+
+    #[test]
+    fn exported_versions_round_trip_their_bytes() {
+        let state = test_state();
+        let bytes: Vec<u8> = (0..=255).chain(0..=255).collect();
+        let doc = {
+            let db = state.write_db();
+            let doc = add_document(&db, "image.bin");
+            insert_delta(&db, &doc, 10, &xpatch::encode(0, &[], &bytes, ENABLE_ZSTD));
+            doc
+        };
+        let dir = temp_dir();
+        let path = dir.join("nested/dir/export.bin");
+        let path = path.to_str().unwrap();
+
+        assert_eq!(write_version(&state, &doc, 10, path).unwrap(), bytes.len());
+        assert_eq!(std::fs::read(path).unwrap(), bytes);
+
+        assert_eq!(write_version(&state, &doc, 5, path).unwrap(), 0);
+        assert!(std::fs::read(path).unwrap().is_empty());
+
+        let blocked = dir.join("nested/dir/export.bin/child");
+        let err = write_version(&state, &doc, 10, blocked.to_str().unwrap()).unwrap_err();
+        assert!(err.starts_with("IO error writing"), "{}", err);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn vacuum_reports_the_space_freed_by_a_deletion() {
+        let dir = temp_dir();
+        let path = dir.join("xpatch.db");
+        let location = DatabaseLocation {
+            tier: DatabaseTier::Custom,
+            path: Some(path.to_string_lossy().into_owned()),
+        };
+        let state = AppState::new(open_file(&path).unwrap(), location);
+
+        // Pseudo-random bytes, so neither xpatch nor zstd can shrink them
+        let mut seed = 1u32;
+        let noise: Vec<u8> = (0..2 << 20)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                (seed >> 24) as u8
+            })
+            .collect();
+        {
+            let db = state.write_db();
+            let doc = add_document(&db, "large");
+            insert_delta(&db, &doc, 10, &xpatch::encode(0, &[], &noise, ENABLE_ZSTD));
+            db.execute("DELETE FROM patches WHERE document_uuid = ?", params![&doc])
+                .unwrap();
+            db.execute("DELETE FROM documents WHERE uuid = ?", params![&doc])
+                .unwrap();
+        }
+
+        let reclaimed = vacuum(&state).unwrap();
+        assert!(reclaimed > 1 << 20, "reclaimed {}", reclaimed);
+        assert_eq!(vacuum(&state).unwrap(), 0);
+        drop(state);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn vacuum_reclaims_nothing_in_memory() {
+        let state = test_state();
+        add_document(&state.write_db(), "doc");
+        assert_eq!(vacuum(&state).unwrap(), 0);
+    }
+
+    #[test]
+    fn documents_diff_against_their_copies() {
+        let db = test_db();
+        let cache = ContentCache::default();
+        let original = add_document(&db, "original");
+        let copy = add_document(&db, "copy");
+        let edited = add_document(&db, "edited");
+        add_version(&db, &original, 10, "a\nb\nc\n");
+        add_version(&db, &copy, 10, "a\nb\nc\n");
+        add_version(&db, &edited, 10, "a\nb\nc\n");
+        add_version(&db, &edited, 20, "a\nB\nc\n");
+
+        let same = document_diff(&db, &cache, &original, &copy).unwrap();
+        assert_eq!(same, [DiffOp::Equal("a\nb\nc\n".to_string())]);
+
+        let changed = document_diff(&db, &cache, &original, &edited).unwrap();
+        let expected = [
+            DiffOp::Equal("a\n".to_string()),
+            DiffOp::Insert("B\n".to_string()),
+            DiffOp::Delete("b\n".to_string()),
+            DiffOp::Equal("c\n".to_string()),
+        ];
+        assert_eq!(changed, expected);
+    }
+
+    #[test]
+    fn documents_without_patches_diff_as_empty() {
+        let db = test_db();
+        let cache = ContentCache::default();
+        let empty = add_document(&db, "empty");
+        let doc = add_document(&db, "doc");
+        add_version(&db, &doc, 10, "only line");
+
+        let diff = document_diff(&db, &cache, &empty, &doc).unwrap();
+        assert_eq!(diff, [DiffOp::Insert("only line".to_string())]);
+    }
+
+    #[test]
+    fn stepping_through_versions_matches_reconstruction() {
+        let db = test_db();
+        let doc = add_document(&db, "doc");
+        for i in 1..=8 {
+            add_version(&db, &doc, i as i64 * 10, &text(i));
+        }
+        let expected = history(&db, &doc);
+
+        let cache = ContentCache::default();
+        let mut forward = Vec::new();
+        let mut timestamp = i64::MIN;
+        while let Some((next, content)) = next_version(&db, &cache, &doc, timestamp).unwrap() {
+            forward.push((next, content));
+            timestamp = next;
+        }
+        assert_eq!(forward, expected);
+
+        let cache = ContentCache::default();
+        let mut backward = Vec::new();
+        let mut timestamp = i64::MAX;
+        while let Some((previous, content)) =
+            previous_version(&db, &cache, &doc, timestamp).unwrap()
+        {
+            backward.push((previous, content));
+            timestamp = previous;
+        }
+        backward.reverse();
+        assert_eq!(backward, expected);
+    }
+
+    #[test]
+    fn stepping_forward_decodes_one_delta_against_the_cached_version() {
+        let db = test_db();
+        let cache = ContentCache::default();
+        let doc = add_document(&db, "doc");
+        for i in 1..=4 {
+            add_version(&db, &doc, i as i64 * 10, &text(i));
+        }
+        load_content(&db, &cache, &doc, 20, &ReconstructOptions::default()).unwrap();
+        let cached = cache.entries().len();
+
+        let next = next_version(&db, &cache, &doc, 20).unwrap();
+        assert_eq!(next, Some((30, text(3))));
+        assert_eq!(cache.entries().len(), cached + 1);
+        assert_eq!(next_version(&db, &cache, &doc, 40).unwrap(), None);
+    }
+
+    #[test]
+    fn zstd_never_bloats_incompressible_deltas() {
+        let mut seed = 7u32;
+        let noise: Vec<u8> = (0..64 * 1024)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                (seed >> 24) as u8
+            })
+            .collect();
+        let varied = text(500).into_bytes();
+
+        for content in [&noise, &varied] {
+            let with_zstd = xpatch::encode(0, &[], content, ENABLE_ZSTD);
+            let without = xpatch::encode(0, &[], content, false);
+            assert!(with_zstd.len() <= without.len());
+            assert_eq!(&xpatch::decode(&[], &with_zstd).unwrap(), content);
+        }
+
+        let compressed = xpatch::encode(0, &[], &varied, ENABLE_ZSTD);
+        assert!(compressed.len() < xpatch::encode(0, &[], &varied, false).len());
+    }
+
+    #[test]
+    fn edit_histogram_counts_patches_per_bucket() {
+        let db = test_db();
+        let doc = add_document(&db, "doc");
+        for (i, timestamp) in [-5, 0, 999, 1000, 1500, 3999].into_iter().enumerate() {
+            add_version(&db, &doc, timestamp, &text(i));
+        }
+
+        let histogram = patch_histogram(&db, &doc, 1000).unwrap();
+        assert_eq!(histogram, [(-1000, 1), (0, 2), (1000, 2), (3000, 1)]);
+        assert!(patch_histogram(&db, "missing", 1000).unwrap().is_empty());
+    }
+
+    #[test]
+    fn edit_histogram_needs_a_positive_bucket() {
+        let db = test_db();
+        let doc = add_document(&db, "doc");
+        for bucket_ms in [0, -1] {
+            let err = patch_histogram(&db, &doc, bucket_ms).unwrap_err();
+            assert_eq!(err, "bucket_ms must be positive");
+        }
+    }
+
+    #[test]
+    fn lossy_loads_replace_invalid_utf8() {
+        let db = test_db();
+        let cache = ContentCache::default();
+        let doc = add_document(&db, "mixed");
+        let bytes = b"caf\xe9 latte".to_vec();
+        insert_delta(&db, &doc, 10, &xpatch::encode(0, &[], &bytes, ENABLE_ZSTD));
+
+        let strict = load_content(&db, &cache, &doc, 10, &ReconstructOptions::default());
+        assert!(strict.unwrap_err().starts_with("UTF-8 conversion error"));
+
+        let options = ReconstructOptions {
+            lossy: true,
+            ..Default::default()
+        };
+        let lossy = load_content(&db, &cache, &doc, 10, &options).unwrap();
+        assert_eq!(lossy, "caf\u{fffd} latte");
+    }
+
+    #[test]
+    fn version_metrics_count_scalars_lines_and_words() {
+        let db = test_db();
+        let cache = ContentCache::default();
+        let doc = add_document(&db, "doc");
+        add_version(&db, &doc, 10, "héllo wörld\n\ttwo  words\nlast");
+
+        let content = content_at(&db, &cache, &doc, 10).unwrap().unwrap();
+        let expected = VersionMetrics {
+            bytes: 30,
+            chars: 28,
+            lines: 3,
+            words: 5,
+        };
+        assert_eq!(metrics(&content), expected);
+
+        let empty = VersionMetrics {
+            bytes: 0,
+            chars: 0,
+            lines: 0,
+            words: 0,
+        };
+        assert_eq!(metrics(&[]), empty);
+    }
+
+    #[test]
+    fn head_columns_follow_saves_and_deletions() {
+        let state = test_state();
+        let doc = add_document(&state.write_db(), "doc");
+        let head = |db: &Connection| -> (Option<i64>, Option<String>) {
+            db.query_row(
+                "SELECT head_timestamp, head_hash FROM documents WHERE uuid = ?",
+                params![&doc],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap()
+        };
+        assert_eq!(head(&state.read_db()), (None, None));
+
+        for i in 1..=5 {
+            save_version(&state, &doc, text(i), i as i64 * 10, None).unwrap();
+            let expected = (Some(i as i64 * 10), Some(content_hash(text(i).as_bytes())));
+            assert_eq!(head(&state.read_db()), expected);
+            assert_eq!(
+                matches_head(&state.read_db(), &doc, i64::MAX, &expected.1.unwrap()),
+                Ok(Some(true))
+            );
+        }
+
+        let mut db = state.write_db();
+        let versions = reconstruct_versions(&db, &state.cache, &doc, i64::MAX).unwrap();
+        remove_versions(&mut db, &state.cache, &doc, versions, |pos, _| pos >= 3).unwrap();
+        let expected = (Some(30), Some(content_hash(text(3).as_bytes())));
+        assert_eq!(head(&db), expected);
+        // Before the head the columns don't know, so the caller reconstructs
+        assert_eq!(matches_head(&db, &doc, 20, "any"), Ok(None));
+    }
+
+    #[test]
+    fn inspect_patch_dumps_the_stored_row() {
+        let db = test_db();
+        let doc = add_document(&db, "doc");
+        let delta = xpatch::encode(3, b"base", b"new content", ENABLE_ZSTD);
+        let patch_uuid = insert_delta(&db, &doc, 42, &delta);
+
+        let inspection = patch_inspection(&db, &patch_uuid).unwrap();
+        assert_eq!(inspection.uuid, patch_uuid);
+        assert_eq!(inspection.document_uuid, doc);
+        assert_eq!(inspection.timestamp, 42);
+        assert_eq!(inspection.delta_len, delta.len());
+        assert_eq!(inspection.tag, Some(3));
+        let prefix = &delta[..delta.len().min(INSPECT_PREFIX_BYTES)];
+        let hex: String = prefix.iter().map(|byte| format!("{:02x}", byte)).collect();
+        assert_eq!(inspection.first_bytes_hex, hex);
+
+        let err = patch_inspection(&db, "missing").unwrap_err();
+        assert_eq!(err, "Patch not found: missing");
+    }
+
+    #[test]
+    fn compaction_leaves_no_stale_cache_entries() {
+        let mut db = test_db();
+        let cache = ContentCache::default();
+        let doc = add_document(&db, "doc");
+        let other = add_document(&db, "other");
+        for i in 1..=6 {
+            add_version(&db, &doc, i as i64 * 10, &text(i));
+            add_version(&db, &other, i as i64 * 10, &text(i + 1));
+        }
+        warm_document_cache(&db, &cache, &doc).unwrap();
+        warm_document_cache(&db, &cache, &other).unwrap();
+        let cached_for = |doc_uuid: &str| {
+            let entries = cache.entries();
+            entries
+                .keys()
+                .filter(|(cached, _)| cached == doc_uuid)
+                .count()
+        };
+
+        write_base_strategy(&db, &doc, BaseStrategy::SnapshotEvery(2)).unwrap();
+        compact(&mut db, &cache, &doc, &ReconstructOptions::default()).unwrap();
+        assert_eq!(cached_for(&doc), 0);
+        assert_eq!(cached_for(&other), 6);
+
+        for i in 1..=6 {
+            let options = ReconstructOptions::default();
+            let content = load_content(&db, &cache, &doc, i as i64 * 10, &options).unwrap();
+            assert_eq!(content, text(i));
+        }
+    }
+
+    #[test]
+    fn invalidation_only_drops_the_named_document() {
+        let cache = ContentCache::default();
+        cache.insert(("a".to_string(), "1".to_string()), b"one".to_vec());
+        cache.insert(("a".to_string(), "2".to_string()), b"two".to_vec());
+        cache.insert(("b".to_string(), "1".to_string()), b"one".to_vec());
+
+        assert_eq!(invalidate_document_cache(&cache, "a"), 2);
+        assert_eq!(invalidate_document_cache(&cache, "a"), 0);
+        assert_eq!(cache.entries().len(), 1);
+    }
+
+    #[test]
+    fn client_deltas_apply_to_unstored_content() {
+        let state = test_state();
+        let doc = add_document(&state.write_db(), "doc");
+        for i in 1..=4 {
+            add_version(&state.write_db(), &doc, i as i64 * 10, &text(i));
+        }
+        let client = format!("{}a local edit\n", text(2));
+
+        let delta = client_delta(&state, &doc, 30, client.as_bytes()).unwrap();
+        assert_eq!(
+            xpatch::decode(client.as_bytes(), &delta).unwrap(),
+            text(3).as_bytes()
+        );
+
+        let delta = client_delta(&state, &doc, 5, client.as_bytes()).unwrap();
+        assert!(xpatch::decode(client.as_bytes(), &delta)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn active_documents_are_those_edited_in_the_window() {
+        let db = test_db();
+        let early = add_document(&db, "early");
+        let late = add_document(&db, "late");
+        let both = add_document(&db, "both");
+        let trashed = add_document(&db, "trashed");
+        add_version(&db, &early, 100, "a");
+        add_version(&db, &late, 900, "a");
+        add_version(&db, &both, 150, "a");
+        add_version(&db, &both, 500, "b");
+        add_version(&db, &trashed, 300, "a");
+        trash_document(&db, &trashed, 400).unwrap();
+
+        assert_eq!(
+            uuids(active_documents(&db, 100, 500).unwrap()),
+            [both.clone(), early]
+        );
+        assert_eq!(uuids(active_documents(&db, 501, 900).unwrap()), [late]);
+        assert!(active_documents(&db, 600, 800).unwrap().is_empty());
+
+        let err = active_documents(&db, 10, 5).unwrap_err();
+        assert_eq!(err, "from_ms must not be after to_ms");
+    }
+
+    #[test]
+    fn truncating_drops_the_future() {
+        let mut db = test_db();
+        let cache = ContentCache::default();
+        let doc = add_document(&db, "doc");
+        for i in 1..=6 {
+            add_version(&db, &doc, i as i64 * 10, &text(i));
+        }
+        warm_document_cache(&db, &cache, &doc).unwrap();
+
+        assert_eq!(truncate_history(&mut db, &cache, &doc, 35).unwrap(), 3);
+        let expected: Vec<(i64, String)> = (1..=3).map(|i| (i as i64 * 10, text(i))).collect();
+        assert_eq!(history(&db, &doc), expected);
+        assert!(cache.entries().is_empty());
+        let options = ReconstructOptions::default();
+        assert_eq!(
+            load_content(&db, &cache, &doc, i64::MAX, &options).unwrap(),
+            text(3)
+        );
+
+        assert_eq!(truncate_history(&mut db, &cache, &doc, 35).unwrap(), 0);
+    }
+
+    #[test]
+    fn base_trace_flags_the_smallest_candidate() {
+        let state = test_state();
+        let doc = add_document(&state.write_db(), "doc");
+        let long_line = |word: &str| format!("{} ", word).repeat(400);
+        let original = long_line("alpha");
+        for (i, version) in [original.clone(), long_line("beta"), long_line("gamma")]
+            .iter()
+            .enumerate()
+        {
+            add_version(&state.write_db(), &doc, (i as i64 + 1) * 10, version);
+        }
+
+        let content = format!("{}and one more", original);
+        let trace = base_trace(&state, &doc, content, 40).unwrap();
+        let chosen: Vec<&BaseCandidate> = trace.iter().filter(|c| c.chosen).collect();
+        assert_eq!(chosen.len(), 1);
+        assert_eq!(chosen[0].base_timestamp, Some(10));
+        let smallest = trace.iter().map(|c| c.delta_bytes).min().unwrap();
+        assert_eq!(chosen[0].delta_bytes, smallest);
+        assert!(trace.len() >= 3);
+        assert_eq!(patch_count(&state.read_db(), &doc), 3);
+    }
+
+    #[test]
+    fn metadata_upserts_by_key() {
+        let db = test_db();
+        let doc = add_document(&db, "doc");
+        write_metadata(&db, &doc, "color", "red").unwrap();
+        write_metadata(&db, &doc, "folder", "work").unwrap();
+        write_metadata(&db, &doc, "color", "blue").unwrap();
+
+        assert_eq!(
+            metadata_value(&db, &doc, "color").unwrap().as_deref(),
+            Some("blue")
+        );
+        assert_eq!(metadata_value(&db, &doc, "missing").unwrap(), None);
+        let expected = HashMap::from([
+            ("color".to_string(), "blue".to_string()),
+            ("folder".to_string(), "work".to_string()),
+        ]);
+        assert_eq!(metadata_entries(&db, &doc).unwrap(), expected);
+
+        let err = write_metadata(&db, "missing", "color", "red").unwrap_err();
+        assert_eq!(err, "Document not found: missing");
+    }
+
+    #[test]
+    fn metadata_goes_with_its_document() {
+        let mut db = test_db();
+        let doc = add_document(&db, "doc");
+        let kept = add_document(&db, "kept");
+        write_metadata(&db, &doc, "color", "red").unwrap();
+        write_metadata(&db, &kept, "color", "green").unwrap();
+
+        trash_document(&db, &doc, 10).unwrap();
+        purge_trash(&mut db, &ContentCache::default()).unwrap();
+        assert!(metadata_entries(&db, &doc).unwrap().is_empty());
+        assert_eq!(metadata_entries(&db, &kept).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn saved_deltas_verify_against_their_base() {
+        let state = test_state();
+        let doc = add_document(&state.write_db(), "doc");
+        for i in 1..=4 {
+            save_version(&state, &doc, text(i), i as i64 * 10, None).unwrap();
+        }
+
+        let db = state.read_db();
+        let mut stmt = db
+            .prepare(
+                "SELECT timestamp, delta FROM patches WHERE document_uuid = ?
+                 ORDER BY timestamp",
+            )
+            .unwrap();
+        let rows: Vec<(i64, Vec<u8>)> = stmt
+            .query_map(params![&doc], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        drop(stmt);
+        drop(db);
+        for (i, (timestamp, delta)) in rows.into_iter().enumerate() {
+            let tag = xpatch::get_tag(&delta).unwrap();
+            let base = encoded_base(&state, &doc, timestamp, tag).unwrap();
+            verify_delta(&delta, &base, text(i + 1).as_bytes()).unwrap();
+        }
+    }
+
+    #[test]
+    fn mismatched_deltas_fail_verification() {
+        let delta = xpatch::encode(0, b"base", b"new content", ENABLE_ZSTD);
+        verify_delta(&delta, b"base", b"new content").unwrap();
+
+        let err = verify_delta(&delta, b"base", b"different").unwrap_err();
+        assert_eq!(
+            err,
+            "Encode verification failed: delta does not reproduce the content"
+        );
+    }
+
+    #[test]
+    fn all_latest_matches_each_head_without_caching() {
+        let db = test_db();
+        let cache = ContentCache::default();
+        let mut docs = Vec::new();
+        for d in 0..3 {
+            let doc = add_document(&db, &format!("doc {}", d));
+            for i in 1..=d + 2 {
+                add_version(&db, &doc, i as i64 * 10, &text(i + d));
+            }
+            docs.push(doc);
+        }
+        let empty = add_document(&db, "empty");
+
+        let latest: HashMap<String, String> =
+            latest_contents(&db, &cache).unwrap().into_iter().collect();
+        assert_eq!(latest.len(), 4);
+        for doc in &docs {
+            let (_, head) = history(&db, doc).pop().unwrap();
+            assert_eq!(latest[doc], head);
+        }
+        assert_eq!(latest[&empty], "");
+        assert!(cache.entries().is_empty());
+    }
+
+    #[test]
+    fn profile_counts_every_patch_as_a_hit_or_miss() {
+        let state = test_state();
+        let doc = add_document(&state.write_db(), "doc");
+        for i in 1..=6 {
+            add_version(&state.write_db(), &doc, i as i64 * 10, &text(i));
+        }
+
+        let cold = profile_load(&state, &doc, 40).unwrap();
+        assert_eq!(cold.patch_count, 4);
+        assert_eq!(cold.cache_hits + cold.cache_misses, cold.patch_count);
+        assert_eq!(cold.cache_misses, 4);
+
+        let warm = profile_load(&state, &doc, 60).unwrap();
+        assert_eq!(warm.patch_count, 6);
+        assert_eq!(warm.cache_hits + warm.cache_misses, warm.patch_count);
+        assert!(warm.cache_hits >= 1);
+        assert!(warm.cache_misses >= 2);
+    }
+
+    #[test]
+    fn changing_created_at_reorders_the_list() {
+        let db = test_db();
+        let older = insert_document(
+            &db,
+            "older",
+            None,
+            Some(1_000),
+            DEFAULT_CREATED_AT_TOLERANCE_MS,
+        )
+        .unwrap();
+        let newer = insert_document(
+            &db,
+            "newer",
+            None,
+            Some(2_000),
+            DEFAULT_CREATED_AT_TOLERANCE_MS,
+        )
+        .unwrap();
+        assert_eq!(
+            uuids(list_documents(&db).unwrap()),
+            [newer.clone(), older.clone()]
+        );
+
+        write_created_at(&db, &older, 3_000, DEFAULT_CREATED_AT_TOLERANCE_MS).unwrap();
+        assert_eq!(uuids(list_documents(&db).unwrap()), [older, newer]);
+        assert!(write_created_at(&db, "missing", 3_000, DEFAULT_CREATED_AT_TOLERANCE_MS).is_err());
+    }
+
+    #[test]
+    fn created_at_may_not_be_far_in_the_future() {
+        let db = test_db();
+        let now = chrono::Utc::now().timestamp_millis();
+        let tolerance = DEFAULT_CREATED_AT_TOLERANCE_MS;
+        let doc = insert_document(&db, "doc", None, None, tolerance).unwrap();
+
+        write_created_at(&db, &doc, now + tolerance / 2, tolerance).unwrap();
+        let too_late = now + 2 * tolerance;
+        let err = write_created_at(&db, &doc, too_late, tolerance).unwrap_err();
+        assert!(
+            err.starts_with("created_at is too far in the future"),
+            "{}",
+            err
+        );
+        assert!(insert_document(&db, "doc", None, Some(too_late), tolerance).is_err());
+
+        // A startup-configured tolerance replaces the default
+        let an_hour_ahead = now + 60 * 60 * 1000;
+        assert!(write_created_at(&db, &doc, an_hour_ahead, 0).is_err());
+        write_created_at(&db, &doc, too_late, 3 * tolerance).unwrap();
+    }
+
+    #[test]
+    fn garbage_deltas_fail_without_panicking() {
+        let mut seed = 42u32;
+        let mut next = || {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            (seed >> 16) as u8
+        };
+        for len in 0..2000 {
+            let delta: Vec<u8> = (0..len % 64).map(|_| next()).collect();
+            let base: Vec<u8> = (0..len % 17).map(|_| next()).collect();
+            if let Ok(decoded) = safe_decode(&base, &delta) {
+                assert!(decoded.len() <= MAX_DECODED_BYTES);
+            }
+        }
+    }
+
+    #[test]
+    fn oversized_repeats_are_rejected_before_decoding() {
+        let mut delta = xpatch::delta::encode_header(xpatch::Algorithm::RepeatChars, 0);
+        delta.extend(xpatch::varint::encode_varint(0));
+        delta.extend(xpatch::varint::encode_varint(1 << 40));
+        delta.extend(b"ab");
+
+        assert_eq!(safe_decode(b"", &delta), Err(DECODED_TOO_LARGE.to_string()));
+    }
+
+    #[test]
+    fn version_hashes_follow_content() {
+        let db = test_db();
+        let cache = ContentCache::default();
+        let doc = add_document(&db, "doc");
+        add_version(&db, &doc, 10, "same");
+        add_version(&db, &doc, 20, "different");
+        add_version(&db, &doc, 30, "same");
+
+        let first = content_hash_at(&db, &cache, &doc, 10).unwrap();
+        assert_eq!(first, content_hash(b"same"));
+        assert_eq!(content_hash_at(&db, &cache, &doc, 30).unwrap(), first);
+        assert_ne!(content_hash_at(&db, &cache, &doc, 25).unwrap(), first);
+        assert_eq!(
+            content_hash_at(&db, &cache, &doc, 5).unwrap(),
+            content_hash(&[])
+        );
+
+        // Rows from before hashes were stored are reconstructed instead
+        db.execute("UPDATE patches SET content_hash = NULL", [])
+            .unwrap();
+        assert_eq!(content_hash_at(&db, &cache, &doc, 30).unwrap(), first);
+        let different = content_hash_at(&db, &cache, &doc, 20).unwrap();
+        assert_eq!(different, content_hash(b"different"));
+    }
+
+    // `(timestamp, delta)` pairs turning each version of `doc` into the next, as
+    // `delta_between` would give them
+    fn delta_stream(db: &Connection, doc_uuid: &str) -> Vec<(i64, Vec<u8>)> {
+        let mut previous = String::new();
+        history(db, doc_uuid)
+            .into_iter()
+            .map(|(timestamp, content)| {
+                let delta = xpatch::encode(0, previous.as_bytes(), content.as_bytes(), true);
+                previous = content;
+                (timestamp, delta)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn delta_streams_replay_onto_a_fresh_document() {
+        let state = test_state();
+        let (source, target) = {
+            let db = state.write_db();
+            let source = add_document(&db, "source");
+            for i in 1..=6 {
+                add_version(&db, &source, i as i64 * 10, &text(i));
+            }
+            (source, add_document(&db, "target"))
+        };
+
+        let stream = delta_stream(&state.read_db(), &source);
+        let created = append_deltas(&state, &target, stream).unwrap();
+        assert_eq!(created.len(), 6);
+        let db = state.read_db();
+        assert_eq!(history(&db, &target), history(&db, &source));
+    }
+
+    #[test]
+    fn delta_streams_are_stored_per_the_base_strategy() {
+        let state = test_state();
+        let (source, target) = {
+            let db = state.write_db();
+            let source = add_document(&db, "source");
+            for i in 1..=6 {
+                add_version(&db, &source, i as i64 * 10, &text(i));
+            }
+            let target = add_document(&db, "target");
+            write_base_strategy(&db, &target, BaseStrategy::SnapshotEvery(2)).unwrap();
+            (source, target)
+        };
+
+        let stream = delta_stream(&state.read_db(), &source);
+        append_deltas(&state, &target, stream).unwrap();
+        let db = state.read_db();
+        let tags = tags(&db, &target);
+        for pos in [0, 2, 4] {
+            assert_eq!(tags[pos], SNAPSHOT_TAG);
+        }
+        assert_eq!(history(&db, &target), history(&db, &source));
+    }
+
+    #[test]
+    fn a_bad_delta_rolls_back_the_whole_stream() {
+        let state = test_state();
+        let doc = add_document(&state.write_db(), "doc");
+        let first = xpatch::encode(0, b"", b"one", ENABLE_ZSTD);
+        let second = xpatch::encode(0, b"one", b"two", ENABLE_ZSTD);
+
+        let garbage = vec![0xff; 8];
+        let stream = vec![(10, first.clone()), (20, second.clone()), (30, garbage)];
+        assert!(append_deltas(&state, &doc, stream).is_err());
+        assert_eq!(patch_count(&state.read_db(), &doc), 0);
+
+        let out_of_order = vec![(20, first), (20, second)];
+        assert!(append_deltas(&state, &doc, out_of_order).is_err());
+        assert_eq!(patch_count(&state.read_db(), &doc), 0);
+    }
+
+    #[test]
+    fn duplicates_are_grouped_by_head_content() {
+        let db = test_db();
+        let cache = ContentCache::default();
+        let a = add_document(&db, "a");
+        let b = add_document(&db, "b");
+        let c = add_document(&db, "c");
+        let empty_1 = add_document(&db, "empty 1");
+        let empty_2 = add_document(&db, "empty 2");
+        add_version(&db, &a, 10, "same");
+        add_version(&db, &b, 10, "draft");
+        add_version(&db, &b, 20, "same");
+        add_version(&db, &c, 10, "other");
+
+        let mut groups = duplicate_groups(&db, &cache).unwrap();
+        for group in &mut groups {
+            group.sort();
+        }
+        groups.sort();
+        let mut same = vec![a, b];
+        same.sort();
+        let mut empty = vec![empty_1, empty_2];
+        empty.sort();
+        let mut expected = vec![same, empty];
+        expected.sort();
+        assert_eq!(groups, expected);
+    }
+
+    #[test]
+    fn prefetched_documents_load_from_the_cache() {
+        let state = test_state();
+        let doc = add_document(&state.write_db(), "doc");
+        for i in 1..=5 {
+            add_version(&state.write_db(), &doc, i as i64 * 10, &text(i));
+        }
+
+        assert!(state.lock_prefetching().insert(doc.clone()));
+        std::thread::scope(|scope| {
+            scope.spawn(|| prefetch(&state, &doc));
+        });
+        assert!(state.lock_prefetching().is_empty());
+
+        let misses = state.cache.misses.load(Ordering::Relaxed);
+        let head = head_version(&state.read_db(), &state.cache, &doc).unwrap();
+        assert_eq!(head, (50, text(5)));
+        assert_eq!(state.cache.misses.load(Ordering::Relaxed), misses);
+    }
+
+    #[test]
+    fn nearest_versions_resolve_in_each_direction() {
+        let db = test_db();
+        let cache = ContentCache::default();
+        let doc = add_document(&db, "doc");
+        add_version(&db, &doc, 10, "ten");
+        add_version(&db, &doc, 20, "twenty");
+        let seek = |timestamp, direction| {
+            nearest_version(&db, &cache, &doc, timestamp, direction).map(|(ts, _)| ts)
+        };
+
+        // Exact matches win in every direction
+        for direction in [
+            SeekDirection::Before,
+            SeekDirection::After,
+            SeekDirection::Nearest,
+        ] {
+            assert_eq!(seek(20, direction), Ok(20));
+        }
+        assert_eq!(seek(14, SeekDirection::Before), Ok(10));
+        assert_eq!(seek(14, SeekDirection::After), Ok(20));
+        assert_eq!(seek(14, SeekDirection::Nearest), Ok(10));
+        assert_eq!(seek(16, SeekDirection::Nearest), Ok(20));
+        // Equidistant goes backwards
+        assert_eq!(seek(15, SeekDirection::Nearest), Ok(10));
+
+        // Past either end only one side exists
+        let not_found = Err("Version not found".to_string());
+        assert_eq!(seek(5, SeekDirection::Before), not_found);
+        assert_eq!(seek(25, SeekDirection::After), not_found);
+        assert_eq!(seek(5, SeekDirection::Nearest), Ok(10));
+        assert_eq!(seek(25, SeekDirection::Nearest), Ok(20));
+
+        let version = nearest_version(&db, &cache, &doc, 16, SeekDirection::Nearest);
+        assert_eq!(version, Ok((20, "twenty".to_string())));
+    }
+
+    #[test]
+    fn compressed_cache_reads_match_plain_ones() {
+        let db = test_db();
+        let doc = add_document(&db, "doc");
+        for i in 1..=5 {
+            add_version(&db, &doc, i as i64 * 10, &text(i * 40));
+        }
+        let plain = ContentCache::default();
+        let compressed = ContentCache::default();
+        compressed.compress.store(true, Ordering::Relaxed);
+
+        let options = ReconstructOptions::default();
+        for cache in [&plain, &compressed] {
+            warm_document_cache(&db, cache, &doc).unwrap();
+        }
+        for i in 1..=5 {
+            let timestamp = i as i64 * 10;
+            let from_plain = load_content(&db, &plain, &doc, timestamp, &options).unwrap();
+            let from_compressed = load_content(&db, &compressed, &doc, timestamp, &options);
+            assert_eq!(from_compressed.unwrap(), from_plain);
+        }
+
+        let plain_stats = cache_statistics(&plain);
+        assert_eq!(plain_stats.stored_bytes, plain_stats.logical_bytes);
+        let compressed_stats = cache_statistics(&compressed);
+        assert!(compressed_stats.compression_enabled);
+        assert_eq!(compressed_stats.logical_bytes, plain_stats.logical_bytes);
+        assert!(compressed_stats.stored_bytes < compressed_stats.logical_bytes / 2);
+    }
+
+    #[test]
+    fn incompressible_entries_stay_plain() {
+        let cache = ContentCache::default();
+        cache.compress.store(true, Ordering::Relaxed);
+        let key = ("doc".to_string(), "patch".to_string());
+        cache.insert(key.clone(), b"xy".to_vec());
+
+        assert!(matches!(cache.entries()[&key], CachedContent::Plain(_)));
+        assert_eq!(cache.get(&key).as_deref(), Some(&b"xy"[..]));
+    }
+
+    #[test]
+    fn split_documents_each_keep_their_range() {
+        let mut db = test_db();
+        let cache = ContentCache::default();
+        let doc = add_document(&db, "doc");
+        for i in 1..=6 {
+            add_version(&db, &doc, i as i64 * 10, &text(i));
+        }
+        insert_bookmark(&db, &doc, "early", 10).unwrap();
+        insert_bookmark(&db, &doc, "late", 50).unwrap();
+        warm_document_cache(&db, &cache, &doc).unwrap();
+
+        let options = ReconstructOptions::default();
+        let new_doc = split_off(&mut db, &cache, &doc, 30, "split", &options).unwrap();
+
+        let before: Vec<(i64, String)> = (1..=2).map(|i| (i as i64 * 10, text(i))).collect();
+        let after: Vec<(i64, String)> = (3..=6).map(|i| (i as i64 * 10, text(i))).collect();
+        assert_eq!(history(&db, &doc), before);
+        assert_eq!(history(&db, &new_doc), after);
+        assert!(is_snapshot(tags(&db, &new_doc)[0], 0));
+        assert_eq!(
+            bookmark_content(&db, &cache, &new_doc, "late").unwrap(),
+            text(5)
+        );
+        assert_eq!(bookmarks(&db, &doc).unwrap().len(), 1);
+        assert_eq!(head_version(&db, &cache, &doc).unwrap(), (20, text(2)));
+    }
+
+    #[test]
+    fn splitting_past_the_head_is_rejected() {
+        let mut db = test_db();
+        let cache = ContentCache::default();
+        let doc = add_document(&db, "doc");
+        add_version(&db, &doc, 10, "a");
+
+        let options = ReconstructOptions::default();
+        let err = split_off(&mut db, &cache, &doc, 20, "split", &options).unwrap_err();
+        assert_eq!(err, "No versions at or after 20");
+        assert!(split_off(&mut db, &cache, "missing", 0, "split", &options).is_err());
+        assert_eq!(list_documents(&db).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn storage_report_counts_rows_and_bytes() {
+        let state = test_state();
+        let docs: Vec<String> = {
+            let db = state.write_db();
+            let docs: Vec<String> = (0..3)
+                .map(|d| add_document(&db, &format!("doc {}", d)))
+                .collect();
+            for (d, doc) in docs.iter().enumerate() {
+                for i in 1..=d + 1 {
+                    add_version(&db, doc, i as i64 * 10, &text(i));
+                }
+            }
+            trash_document(&db, &docs[0], 100).unwrap();
+            docs
+        };
+
+        let report = storage_usage(&state).unwrap();
+        assert_eq!(report.file_bytes, None);
+        assert_eq!(report.document_count, 3);
+        assert_eq!(report.patch_count, 6);
+        let db = state.read_db();
+        let bytes: i64 = docs.iter().map(|doc| total_delta_bytes(&db, doc)).sum();
+        assert_eq!(report.total_delta_bytes, bytes);
+    }
+
+    #[test]
+    fn storage_report_measures_the_database_file() {
+        let dir = temp_dir();
+        let path = dir.join("xpatch.db");
+        let location = DatabaseLocation {
+            tier: DatabaseTier::Custom,
+            path: Some(path.to_string_lossy().into_owned()),
+        };
+        let state = AppState::new(open_file(&path).unwrap(), location);
+        add_document(&state.write_db(), "doc");
+
+        let file_bytes = storage_usage(&state).unwrap().file_bytes.unwrap();
+        assert!(file_bytes >= std::fs::metadata(&path).unwrap().len() as i64);
+        drop(state);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn patch_uuids(db: &Connection, doc_uuid: &str) -> Vec<String> {
+        let mut stmt = db
+            .prepare("SELECT uuid FROM patches WHERE document_uuid = ? ORDER BY timestamp")
+            .unwrap();
+        stmt.query_map(params![doc_uuid], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn resequencing_fixes_a_swapped_pair() {
+        let mut db = test_db();
+        let cache = ContentCache::default();
+        let doc = add_document(&db, "doc");
+        // Versions 2 and 3 were saved with each other's timestamps
+        for (timestamp, i) in [(10, 1), (20, 3), (30, 2), (40, 4)] {
+            add_version(&db, &doc, timestamp, &text(i));
+        }
+        let uuids = patch_uuids(&db, &doc);
+
+        let remap = vec![(uuids[1].clone(), 30), (uuids[2].clone(), 20)];
+        resequence(&mut db, &cache, &doc, remap).unwrap();
+
+        let expected: Vec<(i64, String)> = (1..=4).map(|i| (i as i64 * 10, text(i))).collect();
+        assert_eq!(history(&db, &doc), expected);
+        assert_eq!(patch_uuids(&db, &doc)[1], uuids[2]);
+    }
+
+    #[test]
+    fn resequencing_rejects_colliding_timestamps() {
+        let mut db = test_db();
+        let cache = ContentCache::default();
+        let doc = add_document(&db, "doc");
+        add_version(&db, &doc, 10, "a");
+        add_version(&db, &doc, 20, "b");
+        let uuids = patch_uuids(&db, &doc);
+
+        let err = resequence(&mut db, &cache, &doc, vec![(uuids[1].clone(), 10)]).unwrap_err();
+        assert_eq!(err, "Timestamps are not strictly increasing at 10");
+        let err = resequence(&mut db, &cache, &doc, vec![("missing".into(), 5)]).unwrap_err();
+        assert_eq!(err, "Patch not found: missing");
+        assert_eq!(
+            history(&db, &doc),
+            [(10, "a".to_string()), (20, "b".to_string())]
+        );
+    }
+
+    #[test]
+    fn dependency_chains_follow_the_stored_tags() {
+        let db = test_db();
+        let doc = add_document(&db, "doc");
+        let contents = ["a", "ab", "abc", "abcd", "abcde", "abcdef"];
+        // (tag, base position) for each version
+        let layout = [
+            (0, None),
+            (0, Some(0)),
+            (1, Some(0)),
+            (SNAPSHOT_TAG, None),
+            (0, Some(3)),
+        ];
+        for (pos, (tag, base)) in layout.iter().enumerate() {
+            let base = base.map_or("", |base| contents[base]);
+            let delta = xpatch::encode(*tag, base.as_bytes(), contents[pos].as_bytes(), true);
+            insert_delta(&db, &doc, (pos as i64 + 1) * 10, &delta);
+        }
+        let add_delta = xpatch::encode(2, contents[2].as_bytes(), contents[5].as_bytes(), true);
+        insert_delta(&db, &doc, 60, &add_delta);
+
+        let summary = |timestamp| -> Vec<(i64, usize, Option<i64>)> {
+            chain_steps(&db, &doc, timestamp)
+                .unwrap()
+                .into_iter()
+                .map(|step| (step.timestamp, step.tag, step.base_timestamp))
+                .collect()
+        };
+        assert_eq!(summary(30), [(10, 0, None), (30, 1, Some(10))]);
+        assert_eq!(summary(50), [(40, SNAPSHOT_TAG, None), (50, 0, Some(40))]);
+        assert_eq!(
+            summary(60),
+            [(10, 0, None), (30, 1, Some(10)), (60, 2, Some(30))]
+        );
+        assert!(summary(5).is_empty());
+
+        let cache = ContentCache::default();
+        let options = ReconstructOptions::default();
+        assert_eq!(
+            load_content(&db, &cache, &doc, 60, &options).unwrap(),
+            contents[5]
+        );
+    }
+
+    #[test]
+    fn two_database_files_hold_independent_data() {
+        let dir = temp_dir();
+        let first = open_file(&dir.join("first.db")).unwrap();
+        let second = open_file(&dir.join("second.db")).unwrap();
+        let doc = add_document(&first.write(), "notes");
+        add_version(&first.write(), &doc, 1, "only in first\n");
+
+        assert_eq!(
+            history(&first.read(), &doc),
+            vec![(1, "only in first\n".to_string())]
+        );
+        assert!(list_documents(&second.read()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn switching_databases_changes_location_and_clears_the_cache() {
+        let dir = temp_dir();
+        let path = dir.join("nested").join("other.db");
+        let state = test_state();
+        let doc = add_document(&state.write_db(), "notes");
+        add_version(&state.write_db(), &doc, 1, "hello\n");
+        state
+            .cache
+            .insert((doc.clone(), "p".to_string()), b"hello\n".to_vec());
+
+        let location = switch_database(&state, path.to_str().unwrap()).unwrap();
+
+        assert!(matches!(location.tier, DatabaseTier::Custom));
+        assert_eq!(state.db_location().path.as_deref(), path.to_str());
+        assert!(state.lock_cache().is_empty());
+        assert!(history(&state.read_db(), &doc).is_empty());
+        assert!(path.exists());
+    }
+
+    #[test]
+    fn switching_to_memory_uses_the_in_memory_tier() {
+        let state = test_state();
+        let location = switch_database(&state, ":memory:").unwrap();
+        assert!(matches!(location.tier, DatabaseTier::InMemory));
+        assert!(location.path.is_none());
+    }
+
+    #[test]
+    fn storage_timeline_ends_at_the_document_total() {
+        let db = test_db();
+        let doc = add_document(&db, "notes");
+        for i in 0..5 {
+            add_version(&db, &doc, 10 + i as i64, &text(i));
+        }
+
+        let timeline = delta_byte_timeline(&db, &doc).unwrap();
+        let timestamps: Vec<i64> = timeline.iter().map(|(ts, _)| *ts).collect();
+        assert_eq!(timestamps, vec![10, 11, 12, 13, 14]);
+        assert!(timeline.windows(2).all(|pair| pair[0].1 <= pair[1].1));
+
+        let cache = ContentCache::default();
+        let stats = document_stats(&db, &cache, &doc, &ReconstructOptions::default()).unwrap();
+        assert_eq!(timeline.last().unwrap().1, stats.total_delta_bytes);
+    }
+
+    #[test]
+    fn recovery_recreates_a_lost_document_row() {
+        let mut db = test_db();
+        let doc = add_document(&db, "notes");
+        add_version(&db, &doc, 40, "first\n");
+        add_version(&db, &doc, 50, "second\n");
+        let kept = add_document(&db, "kept");
+        // Simulate the lost row; with foreign keys on the delete would be refused
+        db.pragma_update(None, "foreign_keys", "OFF").unwrap();
+        db.execute("DELETE FROM documents WHERE uuid = ?", params![&doc])
+            .unwrap();
+        db.pragma_update(None, "foreign_keys", "ON").unwrap();
+
+        let cache = ContentCache::default();
+        assert_eq!(
+            recover_orphaned_documents(&mut db, &cache).unwrap(),
+            vec![doc.clone()]
+        );
+
+        let documents = list_documents(&db).unwrap();
+        let recovered = documents.iter().find(|d| d.uuid == doc).unwrap();
+        assert_eq!(recovered.name, format!("Recovered {}", doc));
+        assert_eq!(recovered.created_at, 40);
+        assert!(documents.iter().any(|d| d.uuid == kept));
+        assert_eq!(
+            content_at(&db, &cache, &doc, i64::MAX).unwrap().unwrap(),
+            b"second\n"
+        );
+
+        // Nothing is orphaned any more
+        assert!(recover_orphaned_documents(&mut db, &cache)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn a_forced_base_is_used_even_when_a_closer_one_fits_better() {
+        let state = test_state();
+        let doc = add_document(&state.write_db(), "notes");
+        add_version(&state.write_db(), &doc, 10, "the original draft\n");
+        add_version(&state.write_db(), &doc, 20, "a rewrite\n");
+        add_version(&state.write_db(), &doc, 30, "a rewrite, extended\n");
+
+        let content = "a rewrite, extended again\n".to_string();
+        save_with_base(&state, &doc, content.clone(), 40, 10).unwrap();
+
+        let db = state.read_db();
+        // Two versions sit between the base and the new patch
+        assert_eq!(tags(&db, &doc), vec![0, 0, 0, 2]);
+        assert_eq!(history(&db, &doc).last().unwrap(), &(40, content));
+    }
+
+    #[test]
+    fn a_forced_base_must_exist_and_be_older() {
+        let state = test_state();
+        let doc = add_document(&state.write_db(), "notes");
+        add_version(&state.write_db(), &doc, 10, "a\n");
+
+        let newer = save_with_base(&state, &doc, "b\n".to_string(), 20, 20);
+        assert_eq!(
+            newer.unwrap_err(),
+            "Base version must be older than the new patch"
+        );
+        let missing = save_with_base(&state, &doc, "b\n".to_string(), 20, 5);
+        assert_eq!(missing.unwrap_err(), "Version not found");
+        assert_eq!(patch_count(&state.read_db(), &doc), 1);
+    }
+
+    // Documents named `names`, each created one millisecond after the previous
+    fn add_documents(db: &Connection, names: &[&str]) -> Vec<String> {
+        names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| {
+                let doc = add_document(db, name);
+                write_created_at(db, &doc, 1 + i as i64, DEFAULT_CREATED_AT_TOLERANCE_MS).unwrap();
+                doc
+            })
+            .collect()
+    }
+
+    #[test]
+    fn pages_stop_at_the_end_of_the_library() {
+        let db = test_db();
+        let docs = add_documents(&db, &["a", "b", "c", "d", "e"]);
+        let newest_first: Vec<String> = docs.into_iter().rev().collect();
+
+        let page = document_page(&db, 0, 2, None).unwrap();
+        assert_eq!(page.total, 5);
+        assert_eq!(uuids(page.documents), newest_first[..2]);
+        let page = document_page(&db, 4, 2, None).unwrap();
+        assert_eq!(uuids(page.documents), newest_first[4..]);
+
+        let past_end = document_page(&db, 10, 2, None).unwrap();
+        assert!(past_end.documents.is_empty());
+        assert_eq!(past_end.total, 5);
+        let everything = document_page(&db, 0, 100, None).unwrap();
+        assert_eq!(uuids(everything.documents), newest_first);
+
+        assert!(document_page(&db, -1, 2, None).is_err());
+    }
+
+    #[test]
+    fn the_name_filter_counts_only_matches_and_escapes_wildcards() {
+        let db = test_db();
+        let docs = add_documents(
+            &db,
+            &["Meeting notes", "notes_2024", "recipes", "100% done"],
+        );
+
+        let page = document_page(&db, 0, 10, Some("NOTES")).unwrap();
+        assert_eq!(page.total, 2);
+        assert_eq!(
+            uuids(page.documents),
+            vec![docs[1].clone(), docs[0].clone()]
+        );
+
+        let page = document_page(&db, 0, 10, Some("%")).unwrap();
+        assert_eq!(uuids(page.documents), vec![docs[3].clone()]);
+        let page = document_page(&db, 0, 10, Some("s_2")).unwrap();
+        assert_eq!(uuids(page.documents), vec![docs[1].clone()]);
+    }
+
+    #[test]
+    fn pending_diff_shows_only_the_unsaved_lines_and_writes_nothing() {
+        let db = test_db();
+        let doc = add_document(&db, "notes");
+        add_version(&db, &doc, 10, "old\n");
+        add_version(&db, &doc, 20, "intro\nkeep\ndrop\nend\n");
+
+        let cache = ContentCache::default();
+        let ops = draft_diff(&db, &cache, &doc, "intro\nkeep\nadded\nend\nmore\n").unwrap();
+        assert_eq!(
+            ops,
+            [
+                DiffOp::Equal("intro\nkeep\n".to_string()),
+                DiffOp::Insert("added\n".to_string()),
+                DiffOp::Delete("drop\n".to_string()),
+                DiffOp::Equal("end\n".to_string()),
+                DiffOp::Insert("more\n".to_string()),
+            ]
+        );
+        assert_eq!(patch_count(&db, &doc), 2);
+
+        let unchanged = draft_diff(&db, &cache, &doc, "intro\nkeep\ndrop\nend\n").unwrap();
+        assert_eq!(
+            unchanged,
+            [DiffOp::Equal("intro\nkeep\ndrop\nend\n".to_string())]
+        );
+    }
+
+    #[test]
+    fn blank_means_never_edited_or_emptied_to_whitespace() {
+        let db = test_db();
+        let cache = ContentCache::default();
+
+        let never_edited = add_document(&db, "new");
+        assert!(document_is_blank(&db, &cache, &never_edited).unwrap());
+
+        let emptied = add_document(&db, "emptied");
+        add_version(&db, &emptied, 10, "some words\n");
+        add_version(&db, &emptied, 20, " \n\t\n");
+        assert!(document_is_blank(&db, &cache, &emptied).unwrap());
+
+        let written = add_document(&db, "written");
+        add_version(&db, &written, 10, "");
+        add_version(&db, &written, 20, "  words\n");
+        assert!(!document_is_blank(&db, &cache, &written).unwrap());
+    }
+
+    #[test]
+    fn base_search_decodes_each_version_once_and_picks_the_same_base() {
+        let state = test_state();
+        let doc = add_document(&state.write_db(), "notes");
+        for i in 0..20 {
+            add_version(&state.write_db(), &doc, 1 + i as i64, &text(i));
+        }
+        let new_content = text(17).into_bytes();
+
+        let chosen = find_optimal_base(&state, &doc, 21, &new_content, 16, 0, None).unwrap();
+
+        // Replaying the history once per candidate would decode over a hundred versions
+        assert_eq!(state.cache.misses.load(Ordering::Relaxed), 20);
+        let newest_first: Vec<Vec<u8>> = history(&state.read_db(), &doc)
+            .into_iter()
+            .rev()
+            .take(16)
+            .map(|(_, content)| content.into_bytes())
+            .collect();
+        let bases = newest_first.into_iter().map(Ok);
+        let expected = select_base(bases, &new_content, ENABLE_ZSTD, 0, |_, _| {}).unwrap();
+        assert_eq!(chosen, expected);
+        assert_eq!(chosen.0, 2);
+    }
+
+    #[test]
+    fn data_urls_decode_to_the_version_and_default_to_the_content_type() {
+        let db = test_db();
+        let doc = add_document(&db, "notes");
+        write_content_type(&db, &doc, "text/markdown").unwrap();
+        add_version(&db, &doc, 10, "# Title\n");
+        add_version(&db, &doc, 20, "# Title\n\nBody\n");
+
+        let cache = ContentCache::default();
+        let url = data_url(&db, &cache, &doc, 10, None).unwrap();
+        let encoded = url.strip_prefix("data:text/markdown;base64,").unwrap();
+        assert_eq!(BASE64_STANDARD.decode(encoded).unwrap(), b"# Title\n");
+
+        let url = data_url(&db, &cache, &doc, 20, Some("text/plain".to_string())).unwrap();
+        let encoded = url.strip_prefix("data:text/plain;base64,").unwrap();
+        assert_eq!(
+            BASE64_STANDARD.decode(encoded).unwrap(),
+            b"# Title\n\nBody\n"
+        );
+    }
+
+    #[test]
+    fn data_urls_refuse_versions_past_the_cap() {
+        let db = test_db();
+        let doc = add_document(&db, "big");
+        add_version(&db, &doc, 10, &"x".repeat(MAX_DATA_URL_BYTES + 1));
+
+        let cache = ContentCache::default();
+        let err = data_url(&db, &cache, &doc, 10, None).unwrap_err();
+        assert!(
+            err.starts_with("Version is too large for a data URL"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn content_at_the_size_limit_is_saved_and_over_it_is_refused() {
+        let state = test_state();
+        let doc = add_document(&state.write_db(), "notes");
+        write_max_content_bytes(&state.write_db(), &doc, Some(5)).unwrap();
+
+        save_version(&state, &doc, "12345".to_string(), 10, None).unwrap();
+        let err = save_version(&state, &doc, "123456".to_string(), 20, None).unwrap_err();
+        assert_eq!(err, "Content too large: 6 bytes (limit 5)");
+        assert_eq!(
+            history(&state.read_db(), &doc),
+            vec![(10, "12345".to_string())]
+        );
+
+        // Clearing the limit allows anything again
+        write_max_content_bytes(&state.write_db(), &doc, None).unwrap();
+        save_version(&state, &doc, "123456".to_string(), 20, None).unwrap();
+        assert!(write_max_content_bytes(&state.write_db(), &doc, Some(-1)).is_err());
+    }
+
+    #[test]
+    fn the_startup_default_applies_to_documents_without_a_limit() {
+        let mut state = test_state();
+        state.default_max_content_bytes = Some(3);
+        let limited = add_document(&state.write_db(), "limited");
+        let own_limit = add_document(&state.write_db(), "own limit");
+        write_max_content_bytes(&state.write_db(), &own_limit, Some(10)).unwrap();
+
+        assert!(save_version(&state, &limited, "abcd".to_string(), 10, None).is_err());
+        assert_eq!(patch_count(&state.read_db(), &limited), 0);
+        save_version(&state, &own_limit, "abcd".to_string(), 10, None).unwrap();
+    }
+
+    // Every file in the tar archive at `path`, by name
+    fn archive_files(path: &Path) -> Vec<(String, Vec<u8>)> {
+        let mut archive = tar::Archive::new(std::fs::File::open(path).unwrap());
+        archive
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let mut entry = entry.unwrap();
+                let name = entry.path().unwrap().to_string_lossy().into_owned();
+                let mut data = Vec::new();
+                std::io::Read::read_to_end(&mut entry, &mut data).unwrap();
+                (name, data)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn the_version_archive_holds_every_version_and_a_manifest() {
+        let state = test_state();
+        let doc = add_document(&state.write_db(), "notes");
+        for (i, ts) in [1_700_000_000_000, 1_700_000_001_000, 1_700_000_002_000]
+            .iter()
+            .enumerate()
+        {
+            add_version(&state.write_db(), &doc, *ts, &text(i));
+        }
+        let path = temp_dir().join("out").join("versions.tar");
+
+        assert_eq!(
+            write_version_archive(&state, &doc, path.to_str().unwrap()).unwrap(),
+            3
+        );
+
+        let mut files = archive_files(&path);
+        let (name, manifest) = files.pop().unwrap();
+        assert_eq!(name, "manifest.json");
+        let manifest: ArchiveManifest = serde_json::from_slice(&manifest).unwrap();
+        assert_eq!(manifest.doc_uuid, doc);
+        assert_eq!(manifest.content_type, "text/plain");
+
+        let versions = history(&state.read_db(), &doc);
+        assert_eq!(files.len(), versions.len());
+        for (index, ((name, data), (ts, content))) in files.iter().zip(&versions).enumerate() {
+            assert_eq!(name, &format!("v{:04}_{}.txt", index + 1, ts));
+            assert_eq!(data, content.as_bytes());
+            let entry = &manifest.versions[index];
+            assert_eq!(
+                (&entry.file, entry.timestamp, entry.bytes),
+                (name, *ts, data.len())
+            );
+        }
+    }
+
+    #[test]
+    fn binary_versions_take_the_content_type_extension() {
+        let state = test_state();
+        let doc = add_document(&state.write_db(), "logo");
+        write_content_type(&state.write_db(), &doc, "image/png").unwrap();
+        add_version(&state.write_db(), &doc, 5, "\u{89}PNG");
+        add_version(&state.write_db(), &doc, 6, "\u{89}PNG!");
+        let path = temp_dir().join("logo.tar");
+
+        write_version_archive(&state, &doc, path.to_str().unwrap()).unwrap();
+
+        let names: Vec<String> = archive_files(&path)
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(names, ["v0001_5.png", "v0002_6.png", "manifest.json"]);
+    }
+
+    fn cache_lookups(cache: &ContentCache) -> u64 {
+        cache.hits.load(Ordering::Relaxed) + cache.misses.load(Ordering::Relaxed)
+    }
+
+    #[test]
+    fn pure_appends_skip_the_base_search_and_reconstruct_nothing() {
+        let state = test_state();
+        let doc = add_document(&state.write_db(), "log");
+        let mut content = "log\n".to_string();
+        save_version(&state, &doc, content.clone(), 1, None).unwrap();
+
+        let searches = BASE_SEARCHES.with(std::cell::Cell::get);
+        for i in 0..20 {
+            content.push_str(&format!("entry {}\n", i));
+            let lookups = cache_lookups(&state.cache);
+            save_version(&state, &doc, content.clone(), 10 + i as i64, None).unwrap();
+            // The cached head is read to encode and to verify, whatever the length
+            assert_eq!(cache_lookups(&state.cache) - lookups, 2);
+        }
+        assert_eq!(BASE_SEARCHES.with(std::cell::Cell::get), searches);
+        assert!(tags(&state.read_db(), &doc).iter().all(|&tag| tag == 0));
+        assert_eq!(history(&state.read_db(), &doc).last().unwrap().1, content);
+
+        // Anything but an append searches as before
+        save_version(&state, &doc, "rewritten\n".to_string(), 100, None).unwrap();
+        assert_eq!(BASE_SEARCHES.with(std::cell::Cell::get), searches + 1);
+        assert_eq!(
+            history(&state.read_db(), &doc).last().unwrap().1,
+            "rewritten\n"
+        );
+    }
+
+    #[test]
+    fn the_append_base_is_the_version_before_the_timestamp() {
+        let state = test_state();
+        let doc = add_document(&state.write_db(), "log");
+        add_version(&state.write_db(), &doc, 10, "a\n");
+        add_version(&state.write_db(), &doc, 20, "a\nb\n");
+        add_version(&state.write_db(), &doc, 30, "something else\n");
+
+        // Explaining a save that predates the head must not use the head
+        let trace = base_trace(&state, &doc, "a\nb\nc\n".to_string(), 25).unwrap();
+        assert_eq!(trace.len(), 1);
+        assert_eq!((trace[0].tag, trace[0].base_timestamp), (0, Some(20)));
+    }
+
+    #[test]
+    fn only_edits_past_the_ratio_are_significant() {
+        let db = test_db();
+        let doc = add_document(&db, "notes");
+        add_version(&db, &doc, 10, "");
+        add_version(&db, &doc, 20, "0123456789");
+        add_version(&db, &doc, 30, "0123456789!");
+        add_version(&db, &doc, 40, &"x".repeat(30));
+        add_version(&db, &doc, 50, &"x".repeat(29));
+        add_version(&db, &doc, 60, "short");
+
+        let cache = ContentCache::default();
+        // Growing from empty, tripling and shrinking to a sixth; not the one-byte edits
+        assert_eq!(
+            large_edits(&db, &cache, &doc, 0.5).unwrap(),
+            vec![20, 40, 60]
+        );
+        assert_eq!(
+            large_edits(&db, &cache, &doc, 0.0).unwrap(),
+            vec![20, 30, 40, 50, 60]
+        );
+        assert!(large_edits(&db, &cache, &doc, f64::NAN).is_err());
+        assert!(large_edits(&db, &cache, &doc, -1.0).is_err());
+    }
+
+    #[test]
+    fn attachments_round_trip_compressed_or_not() {
+        let db = test_db();
+        let doc = add_document(&db, "notes");
+        let text_data = "figure caption\n".repeat(200).into_bytes();
+        let random_data: Vec<u8> = (0..64u32)
+            .map(|i| (i.wrapping_mul(2654435761) >> 24) as u8)
+            .collect();
+
+        let text_uuid = insert_attachment(&db, &doc, "caption.txt", text_data.clone()).unwrap();
+        let image_uuid = insert_attachment(&db, &doc, "image.png", random_data.clone()).unwrap();
+
+        let compressed = |uuid: &str| -> bool {
+            db.query_row(
+                "SELECT compressed FROM attachments WHERE uuid = ?",
+                params![uuid],
+                |row| row.get(0),
+            )
+            .unwrap()
+        };
+        assert!(compressed(&text_uuid));
+        assert!(!compressed(&image_uuid));
+        assert_eq!(attachment_content(&db, &text_uuid).unwrap(), text_data);
+        assert_eq!(attachment_content(&db, &image_uuid).unwrap(), random_data);
+        let listed = attachments(&db, &doc).unwrap();
+        let names: Vec<&str> = listed.iter().map(|a| a.name.as_str()).collect();
+        assert_eq!(names, ["caption.txt", "image.png"]);
+        assert_eq!(listed[0].size, text_data.len() as i64);
+
+        assert_eq!(remove_attachment(&db, &text_uuid).unwrap(), doc);
+        assert_eq!(
+            attachment_content(&db, &text_uuid).unwrap_err(),
+            "Attachment not found"
+        );
+        assert_eq!(attachments(&db, &doc).unwrap().len(), 1);
+        assert!(insert_attachment(&db, "missing", "x", vec![1]).is_err());
+    }
+
+    #[test]
+    fn purging_a_document_deletes_its_attachments() {
+        let db = test_db();
+        let doc = add_document(&db, "notes");
+        let kept = add_document(&db, "kept");
+        let attachment = insert_attachment(&db, &doc, "a.bin", vec![1, 2, 3]).unwrap();
+        insert_attachment(&db, &kept, "b.bin", vec![4]).unwrap();
+
+        assert!(purge_document(&db, &doc).unwrap());
+
+        assert!(attachments(&db, &doc).unwrap().is_empty());
+        assert!(attachment_content(&db, &attachment).is_err());
+        assert_eq!(attachments(&db, &kept).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn similarity_is_one_on_the_diagonal_and_symmetric() {
+        let db = test_db();
+        let doc = add_document(&db, "notes");
+        add_version(&db, &doc, 10, "a\nb\nc\nd\n");
+        add_version(&db, &doc, 20, "a\nb\nc\nd\n");
+        add_version(&db, &doc, 30, "a\nb\nx\ny\n");
+        add_version(&db, &doc, 40, "p\nq\nr\ns\n");
+
+        let cache = ContentCache::default();
+        let versions = reconstruct_versions(&db, &cache, &doc, i64::MAX).unwrap();
+        let matrix = version_similarities(&versions);
+
+        assert_eq!(matrix.len(), 4);
+        for (i, row) in matrix.iter().enumerate() {
+            assert_eq!(row[i], 1.0);
+            for (j, &score) in row.iter().enumerate() {
+                assert_eq!(score, matrix[j][i]);
+                assert!((0.0..=1.0).contains(&score));
+            }
+        }
+        // Duplicates, half shared, nothing shared
+        assert_eq!(matrix[0][1], 1.0);
+        assert_eq!(matrix[0][2], 0.5);
+        assert_eq!(matrix[0][3], 0.0);
+    }
+
+    #[test]
+    fn the_similarity_matrix_keeps_only_the_newest_versions() {
+        let db = test_db();
+        let doc = add_document(&db, "notes");
+        for i in 0..MAX_SIMILARITY_VERSIONS + 3 {
+            add_version(&db, &doc, 1 + i as i64, &format!("{}\n", i));
+        }
+
+        let cache = ContentCache::default();
+        let versions = reconstruct_versions(&db, &cache, &doc, i64::MAX).unwrap();
+        let matrix = version_similarities(&versions);
+        assert_eq!(matrix.len(), MAX_SIMILARITY_VERSIONS);
+        assert!(matrix
+            .iter()
+            .all(|row| row.len() == MAX_SIMILARITY_VERSIONS));
+    }
+
+    #[test]
+    fn saves_at_or_before_the_head_are_refused_and_leave_the_chain_alone() {
+        let state = test_state();
+        let doc = add_document(&state.write_db(), "notes");
+        save_version(&state, &doc, "first\n".to_string(), 10, None).unwrap();
+        save_version(&state, &doc, "second\n".to_string(), 20, None).unwrap();
+
+        let older = save_version(&state, &doc, "inserted\n".to_string(), 15, None);
+        assert_eq!(
+            older.unwrap_err(),
+            "Timestamp 15 is not after the latest version (20)"
+        );
+        let same = save_version(&state, &doc, "again\n".to_string(), 20, None);
+        assert_eq!(
+            same.unwrap_err(),
+            "Timestamp 20 is not after the latest version (20)"
+        );
+
+        let expected = vec![(10, "first\n".to_string()), (20, "second\n".to_string())];
+        assert_eq!(history(&state.read_db(), &doc), expected);
+        save_version(&state, &doc, "third\n".to_string(), 21, None).unwrap();
+    }
+
+    #[test]
+    fn reading_the_head_right_after_a_save_is_a_cache_hit() {
+        let state = test_state();
+        let doc = add_document(&state.write_db(), "notes");
+        save_version(&state, &doc, "first\n".to_string(), 10, None).unwrap();
+        let patch_uuid = save_version(&state, &doc, "second\n".to_string(), 20, None).unwrap();
+        assert!(state.cache.lookup(&(doc.clone(), patch_uuid)).is_some());
+
+        let misses = state.cache.misses.load(Ordering::Relaxed);
+        let hits = state.cache.hits.load(Ordering::Relaxed);
+        let head = head_version(&state.read_db(), &state.cache, &doc).unwrap();
+
+        assert_eq!(head, (20, "second\n".to_string()));
+        assert_eq!(state.cache.misses.load(Ordering::Relaxed), misses);
+        assert_eq!(state.cache.hits.load(Ordering::Relaxed), hits + 1);
+    }
+
+    #[test]
+    fn version_ranges_slice_the_full_version_and_clamp() {
+        let db = test_db();
+        let doc = add_document(&db, "notes");
+        add_version(&db, &doc, 10, &text(3));
+        add_version(&db, &doc, 20, &text(8));
+
+        let cache = ContentCache::default();
+        let full = text(3).into_bytes();
+        assert_eq!(
+            version_range(&db, &cache, &doc, 10, 5, 12).unwrap(),
+            full[5..17]
+        );
+        assert_eq!(
+            version_range(&db, &cache, &doc, 10, 0, full.len()).unwrap(),
+            full
+        );
+
+        // Past the end, or running over it, clamps instead of failing
+        assert_eq!(
+            version_range(&db, &cache, &doc, 10, 10, usize::MAX).unwrap(),
+            full[10..]
+        );
+        assert!(version_range(&db, &cache, &doc, 10, full.len() + 5, 3)
+            .unwrap()
+            .is_empty());
+        assert!(version_range(&db, &cache, &doc, 5, 0, 10)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn library_counts_leave_out_the_trash() {
+        let db = test_db();
+        let counts = |db: &Connection| {
+            let counts = count_library(db).unwrap();
+            (counts.documents, counts.patches)
+        };
+        assert_eq!(counts(&db), (0, 0));
+
+        let docs = add_documents(&db, &["a", "b", "c"]);
+        for (i, doc) in docs.iter().enumerate() {
+            for ts in 0..=i as i64 {
+                add_version(&db, doc, 10 + ts, &text(ts as usize));
+            }
+        }
+        assert_eq!(counts(&db), (3, 6));
+
+        trash_document(&db, &docs[2], 99).unwrap();
+        assert_eq!(counts(&db), (2, 3));
+    }
+
+    #[test]
+    fn versions_back_counts_from_the_head_and_clamps_to_the_first() {
+        let db = test_db();
+        let doc = add_document(&db, "notes");
+        let cache = ContentCache::default();
+        assert_eq!(
+            version_before_head(&db, &cache, &doc, 0).unwrap(),
+            (0, String::new())
+        );
+
+        for i in 0..6 {
+            add_version(&db, &doc, 10 * (1 + i as i64), &text(i));
+        }
+
+        assert_eq!(
+            version_before_head(&db, &cache, &doc, 0).unwrap(),
+            (60, text(5))
+        );
+        assert_eq!(
+            version_before_head(&db, &cache, &doc, 3).unwrap(),
+            (30, text(2))
+        );
+        assert_eq!(
+            version_before_head(&db, &cache, &doc, 5).unwrap(),
+            (10, text(0))
+        );
+        assert_eq!(
+            version_before_head(&db, &cache, &doc, 50).unwrap(),
+            (10, text(0))
+        );
+        assert_eq!(
+            version_before_head(&db, &cache, &doc, usize::MAX).unwrap(),
+            (10, text(0))
+        );
+    }
+
+    #[test]
+    fn name_suggestions_count_up_past_taken_names() {
+        let db = test_db();
+        assert_eq!(free_document_name(&db, "Notes").unwrap(), "Notes");
+
+        for _ in 0..3 {
+            let name = free_document_name(&db, "Notes").unwrap();
+            add_document(&db, &name);
+        }
+        assert_eq!(free_document_name(&db, "Notes").unwrap(), "Notes (4)");
+
+        // Gaps are reused, trashed documents still count, other names don't
+        db.execute("DELETE FROM documents WHERE name = 'Notes (2)'", [])
+            .unwrap();
+        assert_eq!(free_document_name(&db, "Notes").unwrap(), "Notes (2)");
+        let trashed = add_document(&db, "Notes (2)");
+        trash_document(&db, &trashed, 99).unwrap();
+        add_document(&db, "Notes extra");
+        assert_eq!(free_document_name(&db, "Notes").unwrap(), "Notes (4)");
+        assert_eq!(
+            free_document_name(&db, "Notes (2)").unwrap(),
+            "Notes (2) (2)"
+        );
+    }
+
+    #[test]
+    fn the_cost_counts_patches_back_to_the_last_snapshot() {
+        let db = test_db();
+        let doc = add_document(&db, "notes");
+        insert_delta(&db, &doc, 10, &xpatch::encode(0, b"", b"a", ENABLE_ZSTD));
+        insert_delta(&db, &doc, 20, &xpatch::encode(0, b"a", b"ab", ENABLE_ZSTD));
+        insert_delta(
+            &db,
+            &doc,
+            30,
+            &xpatch::encode(0, b"ab", b"abc", ENABLE_ZSTD),
+        );
+        insert_delta(
+            &db,
+            &doc,
+            40,
+            &xpatch::encode(SNAPSHOT_TAG, b"", b"abcd", ENABLE_ZSTD),
+        );
+        insert_delta(
+            &db,
+            &doc,
+            50,
+            &xpatch::encode(0, b"abcd", b"abcde", ENABLE_ZSTD),
+        );
+        // Skips the version at 50 and decodes against the snapshot
+        insert_delta(
+            &db,
+            &doc,
+            60,
+            &xpatch::encode(1, b"abcd", b"abcdX", ENABLE_ZSTD),
+        );
+
+        let cache = ContentCache::default();
+        let cost = |timestamp| {
+            let versions = reconstruct_versions(&db, &cache, &doc, timestamp).unwrap();
+            let cost = last_version_cost(versions).unwrap();
+            (
+                cost.content,
+                cost.patches_replayed,
+                cost.total_base_bytes_read,
+            )
+        };
+
+        assert_eq!(cost(10), ("a".to_string(), 1, 0));
+        assert_eq!(cost(30), ("abc".to_string(), 3, 2 + 1));
+        assert_eq!(cost(40), ("abcd".to_string(), 1, 0));
+        assert_eq!(cost(50), ("abcde".to_string(), 2, 4));
+        assert_eq!(cost(60), ("abcdX".to_string(), 2, 4));
+        assert_eq!(cost(5), (String::new(), 0, 0));
+    }
+
+    #[test]
+    fn normalized_saves_ignore_line_ending_churn() {
+        let state = test_state();
+        let crlf = |content: &str| content.replace('\n', "\r\n");
+        let edited = format!("{}one more line\n", text(20));
+
+        let normalized = add_document(&state.write_db(), "normalized");
+        let lf = Some(EolStyle::Lf);
+        save_version(&state, &normalized, text(20), 10, lf).unwrap();
+        // The same text from a CRLF editor is no change at all
+        let same = save_version(&state, &normalized, crlf(&text(20)), 20, lf);
+        assert_eq!(
+            same.unwrap_err(),
+            "Content identical to last version - patch not created"
+        );
+        let small = save_version(&state, &normalized, crlf(&edited), 20, lf).unwrap();
+
+        let raw = add_document(&state.write_db(), "raw");
+        save_version(&state, &raw, text(20), 10, None).unwrap();
+        let large = save_version(&state, &raw, crlf(&edited), 20, None).unwrap();
+
+        let db = state.read_db();
+        assert!(stored_delta(&db, &small).len() < stored_delta(&db, &large).len());
+        assert_eq!(history(&db, &normalized)[1].1, edited);
+        assert_eq!(eol_style(&db, &normalized).unwrap(), Some(EolStyle::Lf));
+        assert_eq!(eol_style(&db, &raw).unwrap(), None);
+    }
+
+    #[test]
+    fn line_endings_normalize_both_ways_and_keep_lone_returns() {
+        assert_eq!(
+            normalize_line_endings("a\r\nb\nc\r", EolStyle::Lf),
+            "a\nb\nc\r"
+        );
+        assert_eq!(
+            normalize_line_endings("a\r\nb\nc\r", EolStyle::Crlf),
+            "a\r\nb\r\nc\r"
+        );
+    }
+
+    #[test]
+    fn changed_ranges_cover_the_inserted_lines() {
+        let db = test_db();
+        let doc = add_document(&db, "notes");
+        add_version(&db, &doc, 10, "one\ntwo\n");
+        add_version(&db, &doc, 20, "zero\none\n1.5\ntwo\nthree\n");
+        add_version(&db, &doc, 30, "zero\ntwo\n");
+
+        let cache = ContentCache::default();
+        let changes = |timestamp| {
+            let versions = reconstruct_versions(&db, &cache, &doc, timestamp).unwrap();
+            last_version_changes(versions).unwrap()
+        };
+
+        // The first version is new in its entirety
+        assert_eq!(changes(10).changed_ranges, vec![(0, 8)]);
+
+        let second = changes(20);
+        let added: Vec<&str> = second
+            .changed_ranges
+            .iter()
+            .map(|&(start, end)| &second.content[start..end])
+            .collect();
+        assert_eq!(added, ["zero\n", "1.5\n", "three\n"]);
+
+        // Deleting lines adds nothing to highlight
+        assert!(changes(30).changed_ranges.is_empty());
+    }
+
+    #[test]
+    fn bulk_deletion_removes_everything_stored_and_skips_unknown_uuids() {
+        let mut db = test_db();
+        let cache = ContentCache::default();
+        let docs = add_documents(&db, &["a", "b", "c", "kept"]);
+        for doc in &docs {
+            add_version(&db, doc, 10, "content\n");
+            insert_bookmark(&db, doc, "start", 10).unwrap();
+            write_metadata(&db, doc, "author", "someone").unwrap();
+            insert_attachment(&db, doc, "file", vec![1, 2, 3]).unwrap();
+            warm_document_cache(&db, &cache, doc).unwrap();
+        }
+
+        let mut doomed = docs[..3].to_vec();
+        doomed.push("unknown".to_string());
+        let deleted = purge_documents(&mut db, &cache, &doomed).unwrap();
+        assert_eq!(deleted, docs[..3].iter().collect::<Vec<_>>());
+
+        // Rows still referring to any of the deleted documents
+        let count = |table: &str, column: &str| -> i64 {
+            db.query_row(
+                &format!(
+                    "SELECT COUNT(*) FROM {} WHERE {} IN (?, ?, ?)",
+                    table, column
+                ),
+                params![&docs[0], &docs[1], &docs[2]],
+                |row| row.get(0),
+            )
+            .unwrap()
+        };
+        for (table, column) in [
+            ("documents", "uuid"),
+            ("patches", "document_uuid"),
+            ("bookmarks", "doc_uuid"),
+            ("document_metadata", "doc_uuid"),
+            ("attachments", "document_uuid"),
+        ] {
+            assert_eq!(count(table, column), 0, "{}", table);
+        }
+        let cached: Vec<String> = cache.entries().keys().map(|(doc, _)| doc.clone()).collect();
+        assert_eq!(cached, [docs[3].clone()]);
+        assert_eq!(history(&db, &docs[3]).len(), 1);
+    }
+
+    #[test]
+    fn time_bounds_are_the_first_and_last_patch_or_the_creation_time() {
+        let db = test_db();
+        let doc = add_document(&db, "notes");
+        write_created_at(&db, &doc, 5, DEFAULT_CREATED_AT_TOLERANCE_MS).unwrap();
+        assert_eq!(time_bounds(&db, &doc).unwrap(), (5, 5));
+
+        add_version(&db, &doc, 100, "a\n");
+        assert_eq!(time_bounds(&db, &doc).unwrap(), (100, 100));
+        add_version(&db, &doc, 250, "b\n");
+        add_version(&db, &doc, 900, "c\n");
+        assert_eq!(time_bounds(&db, &doc).unwrap(), (100, 900));
+
+        assert_eq!(
+            time_bounds(&db, "missing").unwrap_err(),
+            "Document not found"
+        );
+    }
+
+    // `lines` lines of hex that zstd cannot shrink much
+    fn incompressible_text(lines: usize) -> String {
+        (0..lines)
+            .map(|i| format!("{}\n", content_hash(i.to_string().as_bytes())))
+            .collect()
+    }
+
+    #[test]
+    fn only_fragmented_documents_are_compaction_candidates() {
+        let db = test_db();
+        let fragmented = add_document(&db, "fragmented");
+        let tight = add_document(&db, "tight");
+        let tiny = add_document(&db, "tiny");
+        for i in 0..5 {
+            let content = incompressible_text(100 + i);
+            let snapshot = xpatch::encode(SNAPSHOT_TAG, &[], content.as_bytes(), ENABLE_ZSTD);
+            insert_delta(&db, &fragmented, 10 + i as i64, &snapshot);
+            add_version(&db, &tight, 10 + i as i64, &incompressible_text(200 + i));
+            let snapshot = xpatch::encode(SNAPSHOT_TAG, &[], text(i).as_bytes(), ENABLE_ZSTD);
+            insert_delta(&db, &tiny, 10 + i as i64, &snapshot);
+        }
+        assert!(total_delta_bytes(&db, &tight) >= COMPACTION_MIN_DELTA_BYTES);
+
+        let cache = ContentCache::default();
+        assert_eq!(
+            fragmented_documents(&db, &cache, 0.5).unwrap(),
+            vec![fragmented.clone()]
+        );
+        assert!(fragmented_documents(&db, &cache, 0.99).unwrap().is_empty());
+        assert!(fragmented_documents(&db, &cache, -0.1).is_err());
+    }
+
+    #[test]
+    fn the_first_patch_is_rewritten_as_a_tag_zero_snapshot_once() {
+        let db = test_db();
+        let doc = add_document(&db, "notes");
+        let first = xpatch::encode(SNAPSHOT_TAG, &[], b"first\n", ENABLE_ZSTD);
+        insert_delta(&db, &doc, 10, &first);
+        insert_delta(
+            &db,
+            &doc,
+            20,
+            &xpatch::encode(0, b"first\n", b"second\n", ENABLE_ZSTD),
+        );
+        let before = history(&db, &doc);
+
+        let cache = ContentCache::default();
+        assert!(snapshot_first_patch(&db, &cache, &doc).unwrap());
+
+        assert_eq!(tags(&db, &doc), vec![0, 0]);
+        assert_eq!(history(&db, &doc), before);
+        assert!(!snapshot_first_patch(&db, &cache, &doc).unwrap());
+
+        let empty = add_document(&db, "empty");
+        assert!(!snapshot_first_patch(&db, &cache, &empty).unwrap());
+    }
+
+    // Splits CSV text into records of fields, undoing `csv_field`'s quoting
+    fn parse_csv(csv: &str) -> Vec<Vec<String>> {
+        let mut records = Vec::new();
+        let (mut record, mut field) = (Vec::new(), String::new());
+        let (mut quoted, mut chars) = (false, csv.chars().peekable());
+        while let Some(c) = chars.next() {
+            match (c, quoted) {
+                ('"', true) if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                }
+                ('"', _) => quoted = !quoted,
+                (',', false) => record.push(std::mem::take(&mut field)),
+                ('\n', false) => {
+                    record.push(std::mem::take(&mut field));
+                    records.push(std::mem::take(&mut record));
+                }
+                _ => field.push(c),
+            }
+        }
+        records
+    }
+
+    #[test]
+    fn the_stats_csv_parses_back_to_each_documents_stats() {
+        let state = test_state();
+        let names = ["plain", "with, comma", "say \"hi\"\nnext line"];
+        let docs = add_documents(&state.write_db(), &names);
+        for (i, doc) in docs.iter().enumerate() {
+            for v in 0..=i {
+                add_version(&state.write_db(), doc, 10 + v as i64, &text(v));
+            }
+        }
+        let trashed = add_document(&state.write_db(), "trashed");
+        trash_document(&state.write_db(), &trashed, 99).unwrap();
+        let path = temp_dir().join("stats").join("library.csv");
+
+        assert_eq!(write_stats_csv(&state, path.to_str().unwrap()).unwrap(), 3);
+
+        let records = parse_csv(&std::fs::read_to_string(&path).unwrap());
+        assert_eq!(records[0][0], "uuid");
+        assert_eq!(records[0][6], "compression_ratio");
+        assert_eq!(records.len(), 4);
+        let cache = ContentCache::default();
+        for (i, record) in records[1..].iter().enumerate() {
+            let db = state.read_db();
+            let stats = document_stats(&db, &cache, &docs[i], &Default::default()).unwrap();
+            let expected = [
+                docs[i].clone(),
+                names[i].to_string(),
+                (1 + i).to_string(),
+                stats.total_patches.to_string(),
+                stats.total_delta_bytes.to_string(),
+                stats.total_uncompressed_bytes.to_string(),
+                stats.compression_ratio.to_string(),
+            ];
+            assert_eq!(record, &expected);
+        }
+    }
+
+    #[test]
+    fn clearing_one_documents_cache_keeps_the_others() {
+        let db = test_db();
+        let cache = ContentCache::default();
+        let docs = add_documents(&db, &["a", "b"]);
+        for doc in &docs {
+            for i in 0..3 {
+                add_version(&db, doc, 10 + i as i64, &text(i));
+            }
+            warm_document_cache(&db, &cache, doc).unwrap();
+        }
+
+        assert_eq!(invalidate_document_cache(&cache, &docs[0]), 3);
+
+        let cached: Vec<String> = cache.entries().keys().map(|(doc, _)| doc.clone()).collect();
+        assert_eq!(cached, vec![docs[1].clone(); 3]);
+        assert_eq!(invalidate_document_cache(&cache, &docs[0]), 0);
+        assert_eq!(invalidate_document_cache(&cache, "unknown"), 0);
+    }
+
+    #[test]
+    fn stable_regions_are_the_shared_header_and_footer() {
+        let db = test_db();
+        let cache = ContentCache::default();
+        let affixes =
+            |doc: &str| common_affixes(&reconstruct_versions(&db, &cache, doc, i64::MAX).unwrap());
+
+        let doc = add_document(&db, "licensed");
+        let (header, footer) = ("// License: MIT\n", "// end\n");
+        for (i, body) in ["first body", "second body, longer", "x"]
+            .iter()
+            .enumerate()
+        {
+            add_version(
+                &db,
+                &doc,
+                10 + i as i64,
+                &format!("{}{}{}", header, body, footer),
+            );
+        }
+        // The bodies happen to share nothing at either end
+        assert_eq!(affixes(&doc), (header.len(), footer.len()));
+
+        let single = add_document(&db, "single");
+        add_version(&db, &single, 10, "only\n");
+        assert_eq!(affixes(&single), (5, 5));
+
+        let emptied = add_document(&db, "emptied");
+        add_version(&db, &emptied, 10, "words\n");
+        add_version(&db, &emptied, 20, "");
+        assert_eq!(affixes(&emptied), (0, 0));
+        assert_eq!(affixes(&add_document(&db, "new")), (0, 0));
+    }
+
+    // The first `n` records of a repetitive JSON-lines corpus
+    fn json_corpus(n: usize) -> String {
+        (0..n)
+            .map(|i| {
+                format!(
+                    "{{\"id\": {}, \"name\": \"customer-{}\", \"status\": \"{}\"}},\n",
+                    i,
+                    i * 7919 % 1000,
+                    ["active", "pending", "closed"][i % 3]
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn a_trained_dictionary_shrinks_later_deltas_and_still_decodes() {
+        let state = test_state();
+        let plain = add_document(&state.write_db(), "plain");
+        let trained = add_document(&state.write_db(), "trained");
+        assert!(train_document_dictionary(&state, &trained).is_err());
+
+        for n in 1..=60 {
+            save_version(&state, &plain, json_corpus(n), n as i64, None).unwrap();
+            save_version(&state, &trained, json_corpus(n), n as i64, None).unwrap();
+        }
+        assert!(train_document_dictionary(&state, &trained).unwrap() > 0);
+        for n in 61..=120 {
+            save_version(&state, &plain, json_corpus(n), n as i64, None).unwrap();
+            save_version(&state, &trained, json_corpus(n), n as i64, None).unwrap();
+        }
+
+        let later_bytes = |doc: &str| -> i64 {
+            state
+                .read_db()
+                .query_row(
+                    "SELECT SUM(LENGTH(delta)) FROM patches
+                     WHERE document_uuid = ? AND timestamp > 60",
+                    params![doc],
+                    |row| row.get(0),
+                )
+                .unwrap()
+        };
+        assert!(later_bytes(&trained) < later_bytes(&plain));
+
+        let expected: Vec<(i64, String)> = (1..=120).map(|n| (n as i64, json_corpus(n))).collect();
+        assert_eq!(history(&state.read_db(), &trained), expected);
+    }
+
+    #[test]
+    fn utf8_checks_tell_text_from_binary_versions() {
+        let db = test_db();
+        let doc = add_document(&db, "mixed");
+        add_version(&db, &doc, 10, "héllo ✓\n");
+        let binary = [0xff, 0xfe, 0x00, 0x81];
+        insert_delta(
+            &db,
+            &doc,
+            20,
+            &xpatch::encode(0, "héllo ✓\n".as_bytes(), &binary, ENABLE_ZSTD),
+        );
+
+        let cache = ContentCache::default();
+        assert!(is_utf8_at(&db, &cache, &doc, 10).unwrap());
+        assert!(!is_utf8_at(&db, &cache, &doc, 20).unwrap());
+        // Before the first version there is only empty content
+        assert!(is_utf8_at(&db, &cache, &doc, 5).unwrap());
+    }
+
+    #[test]
+    fn load_with_a_tiny_budget_times_out_and_a_generous_one_succeeds() {
+        let db = test_db();
+        let doc = add_document(&db, "slow");
+        for i in 0..200 {
+            add_version(&db, &doc, i as i64, &text(i));
+        }
+        let budget = |ms| ReconstructOptions {
+            deadline: deadline_after(ms),
+            ..Default::default()
+        };
+
+        let timed_out = load_content(&db, &ContentCache::default(), &doc, 199, &budget(0));
+        assert_eq!(timed_out, Err(RECONSTRUCTION_TIMED_OUT.to_string()));
+
+        let loaded = load_content(&db, &ContentCache::default(), &doc, 199, &budget(60_000));
+        assert_eq!(loaded, Ok(text(199)));
+
+        // A budget too large for an `Instant` means no deadline rather than a panic
+        let unbounded = load_content(&db, &ContentCache::default(), &doc, 199, &budget(u64::MAX));
+        assert_eq!(unbounded, Ok(text(199)));
+    }
+
+    #[test]
+    fn offset_history_lists_only_the_versions_that_change_the_byte() {
+        let db = test_db();
+        let doc = add_document(&db, "forensics");
+        let contents = ["ab", "abc", "abX", "aYX", "aY", "aYZ", "aYZ!"];
+        for (i, content) in contents.iter().enumerate() {
+            add_version(&db, &doc, i as i64 + 1, content);
+        }
+        let cache = ContentCache::default();
+        let options = ReconstructOptions::default();
+        let versions = reconstruct_versions_with(&db, &cache, &doc, i64::MAX, &options).unwrap();
 
-// Commands
+        // The first version is too short, so its empty slot counts as unchanged
+        assert_eq!(offset_changes(&versions, 2), vec![2, 3, 5, 6]);
+        assert_eq!(offset_changes(&versions, 1), vec![1, 4]);
+        assert_eq!(offset_changes(&versions, 10), Vec::<i64>::new());
+    }
 
-#[tauri::command]
-fn load_document_at_timestamp(
-    state: State<AppState>,
-    doc_uuid: String,
-    timestamp: i64,
-) -> Result<String, String> {
-    let db = state.db.lock().unwrap();
-    let mut cache = state.cache.lock().unwrap();
+    #[test]
+    fn created_document_heads_reconstruct_to_the_supplied_content() {
+        let state = test_state();
+        let doc =
+            create_document_from_bytes(&state, "notes", DEFAULT_CONTENT_TYPE, b"hi\n".to_vec())
+                .unwrap();
+        let cache = ContentCache::default();
+        assert_eq!(
+            content_at(&state.read_db(), &cache, &doc, i64::MAX).unwrap(),
+            Some(b"hi\n".to_vec())
+        );
 
-    let mut stmt = db
-        .prepare(
-            "SELECT uuid, timestamp, delta
-             FROM patches
-             WHERE document_uuid = ? AND timestamp <= ?
-             ORDER BY timestamp ASC",
-        )
-        .map_err(|e| e.to_string())?;
+        let empty =
+            create_document_from_bytes(&state, "empty", DEFAULT_CONTENT_TYPE, Vec::new()).unwrap();
+        assert_eq!(patch_count(&state.read_db(), &empty), 0);
 
-    let patches: Vec<Patch> = stmt
-        .query_map(params![&doc_uuid, timestamp], |row| {
-            Ok(Patch {
-                uuid: row.get(0)?,
-                document_uuid: doc_uuid.clone(),
-                timestamp: row.get(1)?,
-                delta: row.get(2)?,
-            })
-        })
-        .map_err(|e| e.to_string())?
-        .collect::<Result<Vec<_>, _>>()
-        .map_err(|e| e.to_string())?;
+        let dir = temp_dir();
+        let image = dir.join("logo.png");
+        std::fs::write(&image, [0x89, b'P', b'N', b'G', 0xff]).unwrap();
+        let (content_type, content) = read_import_file(image.to_str().unwrap()).unwrap();
+        assert_eq!(content_type, "image/png");
+        let imported = create_document_from_bytes(&state, "logo", content_type, content).unwrap();
+        let head = content_at(&state.read_db(), &cache, &imported, i64::MAX).unwrap();
+        assert_eq!(head, Some(vec![0x89, b'P', b'N', b'G', 0xff]));
 
-    if patches.is_empty() {
-        return Ok("".to_string());
+        let binary = dir.join("blob");
+        std::fs::write(&binary, [0xff, 0xfe]).unwrap();
+        assert_eq!(
+            read_import_file(binary.to_str().unwrap()).unwrap().0,
+            "application/octet-stream"
+        );
+        std::fs::remove_dir_all(dir).unwrap();
     }
 
-    // Build a map of timestamps to patch content for quick lookup
-    let mut content_by_timestamp: HashMap<i64, Vec<u8>> = HashMap::new();
-    let mut sorted_timestamps: Vec<i64> = patches.iter().map(|p| p.timestamp).collect();
-    sorted_timestamps.sort_unstable();
+    #[test]
+    fn activity_order_follows_the_newest_edit_not_creation() {
+        let db = test_db();
+        let docs = add_documents(&db, &["a", "b", "c"]);
+        add_version(&db, &docs[2], 5, "c");
+        add_version(&db, &docs[0], 10, "a");
+
+        let order = |documents: Vec<Document>| -> Vec<String> {
+            documents.into_iter().map(|doc| doc.name).collect()
+        };
+        assert_eq!(order(list_documents(&db).unwrap()), ["c", "b", "a"]);
+        // b has no patches, so it sorts by its created_at of 2
+        assert_eq!(order(documents_by_activity(&db).unwrap()), ["a", "c", "b"]);
+    }
 
-    for patch in patches {
-        let cache_key = (doc_uuid.clone(), patch.uuid.clone());
+    #[test]
+    fn library_ratio_weights_each_document_by_its_delta_bytes() {
+        let db = test_db();
+        let cache = ContentCache::default();
+        assert_eq!(library_ratio(&db, &cache).unwrap(), 1.0);
 
-        // Check cache first
-        if let Some(cached_content) = cache.get(&cache_key) {
-            content_by_timestamp.insert(patch.timestamp, cached_content.clone());
-            continue;
+        let docs = add_documents(&db, &["repetitive", "varied"]);
+        for i in 0..20 {
+            add_version(&db, &docs[0], i as i64, &text(i));
+            add_version(
+                &db,
+                &docs[1],
+                i as i64,
+                &format!("{}{}", text(i), incompressible_text(i)),
+            );
         }
 
-        // Extract the tag from the delta to know which base to use
-        let tag = if let Some(ref delta) = patch.delta {
-            xpatch::get_tag(delta).unwrap_or(0)
-        } else {
-            0
-        };
-
-        // Find the base content based on the tag
-        let base_content = if tag == 0 {
-            // tag 0 means use previous version (N-1)
-            let pos = sorted_timestamps.iter().position(|&t| t == patch.timestamp).unwrap();
-            if pos > 0 {
-                content_by_timestamp
-                    .get(&sorted_timestamps[pos - 1])
-                    .cloned()
-                    .unwrap_or_default()
-            } else {
-                Vec::new()
-            }
-        } else {
-            // tag N means use version N steps back
-            let pos = sorted_timestamps.iter().position(|&t| t == patch.timestamp).unwrap();
-            if pos > tag {
-                content_by_timestamp
-                    .get(&sorted_timestamps[pos - tag - 1])
-                    .cloned()
-                    .unwrap_or_default()
-            } else {
-                Vec::new()
-            }
+        let options = ReconstructOptions::default();
+        let stats: Vec<DocumentStats> = docs
+            .iter()
+            .map(|doc| document_stats(&db, &cache, doc, &options).unwrap())
+            .collect();
+        let delta_bytes: i64 = stats.iter().map(|stats| stats.total_delta_bytes).sum();
+        let weighted: f64 = stats
+            .iter()
+            .map(|stats| stats.compression_ratio * stats.total_delta_bytes as f64)
+            .sum::<f64>()
+            / delta_bytes as f64;
+        assert!((library_ratio(&db, &cache).unwrap() - weighted).abs() < 1e-9);
+    }
+
+    #[test]
+    fn reoptimizing_a_snapshot_shrinks_it_and_keeps_every_version() {
+        let state = test_state();
+        let doc = add_document(&state.read_db(), "notes");
+        for i in 0..10 {
+            add_version(&state.read_db(), &doc, i as i64, &text(i));
+        }
+        // Store version 10 as a full snapshot although version 9 is a close base
+        let snapshot = xpatch::encode(SNAPSHOT_TAG, &[], text(10).as_bytes(), ENABLE_ZSTD);
+        let patch_uuid = insert_delta(&state.read_db(), &doc, 10, &snapshot);
+        for i in 11..14 {
+            add_version(&state.read_db(), &doc, i as i64, &text(i));
+        }
+        let before = history(&state.read_db(), &doc);
+
+        let saved = reoptimize_version(&state, &doc, 10).unwrap();
+        let delta = stored_delta(&state.read_db(), &patch_uuid);
+        assert!(saved > 0);
+        assert_eq!(delta.len() as i64, snapshot.len() as i64 - saved);
+        assert_eq!(history(&state.read_db(), &doc), before);
+
+        // Already optimal, so a second pass leaves it alone
+        assert_eq!(reoptimize_version(&state, &doc, 10).unwrap(), 0);
+        assert_eq!(stored_delta(&state.read_db(), &patch_uuid), delta);
+    }
+
+    #[test]
+    fn document_changed_events_carry_the_uuid_kind_and_timestamp() {
+        let doc = Uuid::new_v4().to_string();
+        // What create_patch emits after saving the version at 5
+        let edited = DocumentChanged {
+            doc_uuid: &doc,
+            kind: DocumentChangeKind::Edited,
+            timestamp: Some(5),
         };
+        assert_eq!(
+            serde_json::to_value(&edited).unwrap(),
+            serde_json::json!({"doc_uuid": doc, "kind": "Edited", "timestamp": 5})
+        );
 
-        // Decode the delta
-        let current_content = if let Some(delta) = patch.delta {
-            xpatch::decode(&base_content, &delta)
-                .map_err(|e| format!("Delta decode error: {:?}", e))?
-        } else {
-            base_content
+        let trashed = DocumentChanged {
+            doc_uuid: &doc,
+            kind: DocumentChangeKind::Trashed,
+            timestamp: None,
         };
+        assert_eq!(
+            serde_json::to_value(&trashed).unwrap(),
+            serde_json::json!({"doc_uuid": doc, "kind": "Trashed", "timestamp": null})
+        );
+    }
+
+    #[test]
+    fn head_diff_matches_diffing_against_the_latest_version() {
+        let db = test_db();
+        let cache = ContentCache::default();
+        let doc = add_document(&db, "notes");
+        for i in 0..5 {
+            add_version(&db, &doc, i as i64, &text(i));
+        }
 
-        content_by_timestamp.insert(patch.timestamp, current_content.clone());
-        cache.insert(cache_key, current_content);
+        for timestamp in 0..4 {
+            assert_eq!(
+                head_diff(&db, &cache, &doc, timestamp).unwrap(),
+                version_diff(&db, &cache, &doc, timestamp, i64::MAX).unwrap()
+            );
+        }
+        assert_eq!(
+            head_diff(&db, &cache, &doc, 4).unwrap(),
+            vec![DiffOp::Equal(text(4))]
+        );
     }
 
-    // Return the content at the requested timestamp
-    let final_content = content_by_timestamp
-        .get(sorted_timestamps.last().unwrap())
-        .ok_or("Failed to reconstruct content")?;
+    #[test]
+    fn batched_timestamps_group_each_documents_patches() {
+        let db = test_db();
+        let docs = add_documents(&db, &["a", "b", "c"]);
+        for (doc, timestamps) in docs.iter().zip([&[3, 1, 2][..], &[5], &[]]) {
+            for &timestamp in timestamps {
+                add_version(&db, doc, timestamp, &timestamp.to_string());
+            }
+        }
+        // Never created, but still asked for
+        let missing = Uuid::new_v4().to_string();
 
-    String::from_utf8(final_content.clone())
-        .map_err(|e| format!("UTF-8 conversion error: {}", e))
-}
+        let mut requested = docs.clone();
+        requested.push(missing.clone());
+        let timestamps = timestamps_by_document(&db, &requested).unwrap();
+        assert_eq!(timestamps.len(), 4);
+        assert_eq!(timestamps[&docs[0]], [1, 2, 3]);
+        assert_eq!(timestamps[&docs[1]], [5]);
+        assert!(timestamps[&docs[2]].is_empty());
+        assert!(timestamps[&missing].is_empty());
 
-fn find_optimal_base(
-    state: &State<AppState>,
-    doc_uuid: &str,
-    current_timestamp: i64,
-    new_content: &[u8],
-    max_depth: usize,
-    enable_zstd: bool,
-) -> Result<(usize, Vec<u8>), String> {
-    let db = state.db.lock().unwrap();
+        assert!(timestamps_by_document(&db, &[]).unwrap().is_empty());
+    }
 
-    // Get timestamps of previous versions
-    let mut stmt = db
-        .prepare(
-            "SELECT DISTINCT timestamp
-             FROM patches
-             WHERE document_uuid = ? AND timestamp < ?
-             ORDER BY timestamp DESC
-             LIMIT ?",
+    fn chain_depths(db: &Connection, doc_uuid: &str) -> Vec<usize> {
+        history(db, doc_uuid)
+            .iter()
+            .map(|(timestamp, _)| chain_steps(db, doc_uuid, *timestamp).unwrap().len())
+            .collect()
+    }
+
+    fn set_chain_limit(db: &Connection, doc_uuid: &str, max: i64) {
+        db.execute(
+            "UPDATE documents SET max_chain_depth = ? WHERE uuid = ?",
+            params![max, doc_uuid],
         )
-        .map_err(|e| e.to_string())?;
+        .unwrap();
+    }
 
-    let previous_timestamps: Vec<i64> = stmt
-        .query_map(params![doc_uuid, current_timestamp, max_depth], |row| {
-            row.get(0)
-        })
-        .map_err(|e| e.to_string())?
-        .collect::<Result<Vec<_>, _>>()
-        .map_err(|e| e.to_string())?;
+    #[test]
+    fn saves_past_the_max_chain_depth_are_stored_as_snapshots() {
+        let state = test_state();
+        let doc = add_document(&state.read_db(), "notes");
+        set_chain_limit(&state.read_db(), &doc, 3);
+        for i in 0..6 {
+            save_version(&state, &doc, text(i), i as i64 + 1, None).unwrap();
+        }
 
-    drop(stmt);
-    drop(db);
+        let db = state.read_db();
+        assert_eq!(chain_depths(&db, &doc), [1, 2, 3, 1, 2, 3]);
+        assert_eq!(tags(&db, &doc)[3], SNAPSHOT_TAG);
+        assert_eq!(history(&db, &doc).last().unwrap().1, text(5));
+    }
 
-    if previous_timestamps.is_empty() {
-        // No previous versions, encode against empty
-        let delta = xpatch::encode(0, &[], new_content, enable_zstd);
-        return Ok((0, delta));
+    #[test]
+    fn pinned_bases_past_the_max_chain_depth_are_refused() {
+        let state = test_state();
+        let doc = add_document(&state.read_db(), "notes");
+        set_chain_limit(&state.read_db(), &doc, 2);
+        save_version(&state, &doc, text(1), 1, None).unwrap();
+        save_version(&state, &doc, text(2), 2, None).unwrap();
+
+        // Version 1 is one delta deep, so a patch on it stays within the limit
+        save_with_base(&state, &doc, text(3), 3, 1).unwrap();
+        // Versions 3 and 4 would chain to a depth of 3
+        let err = save_with_base(&state, &doc, text(4), 4, 3).unwrap_err();
+        assert_eq!(err, "Base chain exceeds max_chain_depth");
+
+        let db = state.read_db();
+        assert_eq!(chain_depths(&db, &doc), [1, 2, 2]);
+        assert_eq!(tags(&db, &doc)[2], 1);
     }
 
-    // Try encoding against each previous version and find the smallest
-    let mut best_tag = 0;
-    let mut best_delta: Option<Vec<u8>> = None;
-    let mut best_size = usize::MAX;
+    #[test]
+    fn binary_versions_can_be_pinned_as_bases() {
+        let state = test_state();
+        let binary = vec![0xff, 0x00, 0xfe, b'\n'];
+        let doc = create_document_from_bytes(&state, "blob", "image/png", binary).unwrap();
+        let base_timestamp = latest_timestamp(&state.read_db(), &doc).unwrap().unwrap();
 
-    for (tag, &timestamp) in previous_timestamps.iter().enumerate() {
-        // Load the base version
-        let base_content = load_document_at_timestamp(
-            state.clone(),
-            doc_uuid.to_string(),
-            timestamp,
-        )?;
-        let base_bytes = base_content.as_bytes();
+        save_with_base(
+            &state,
+            &doc,
+            "text\n".to_string(),
+            base_timestamp + 1,
+            base_timestamp,
+        )
+        .unwrap();
+        let content = content_at(&state.read_db(), &state.cache, &doc, base_timestamp + 1);
+        assert_eq!(content.unwrap(), Some(b"text\n".to_vec()));
+    }
+
+    #[test]
+    fn delta_streams_past_the_max_chain_depth_insert_snapshots() {
+        let state = test_state();
+        let (source, target) = {
+            let db = state.write_db();
+            let source = add_document(&db, "source");
+            for i in 1..=5 {
+                add_version(&db, &source, i as i64 * 10, &text(i));
+            }
+            (source, add_document(&db, "target"))
+        };
+        set_chain_limit(&state.read_db(), &target, 2);
 
-        // Encode against this base
-        let delta = xpatch::encode(tag, base_bytes, new_content, enable_zstd);
+        let stream = delta_stream(&state.read_db(), &source);
+        append_deltas(&state, &target, stream).unwrap();
 
-        // Check if this is the best so far
-        if delta.len() < best_size {
-            best_size = delta.len();
-            best_delta = Some(delta);
-            best_tag = tag;
+        let db = state.read_db();
+        assert_eq!(chain_depths(&db, &target), [1, 2, 1, 2, 1]);
+        assert_eq!(history(&db, &target), history(&db, &source));
+    }
+
+    #[test]
+    fn preview_pages_hold_the_start_of_each_version() {
+        let db = test_db();
+        let cache = ContentCache::default();
+        let doc = add_document(&db, "notes");
+        for i in 1..=7 {
+            add_version(&db, &doc, i as i64, &text(i));
         }
+        let expected: Vec<VersionPreview> = history(&db, &doc)
+            .iter()
+            .map(|(timestamp, content)| VersionPreview {
+                timestamp: *timestamp,
+                preview: content[..12].to_string(),
+            })
+            .collect();
+        let binary = xpatch::encode(SNAPSHOT_TAG, &[], &[0xff, 0x00, 0xab], ENABLE_ZSTD);
+        insert_delta(&db, &doc, 8, &binary);
+
+        assert_eq!(
+            preview_page(&db, &cache, &doc, 0, 3, 12).unwrap(),
+            expected[..3]
+        );
+        assert_eq!(
+            preview_page(&db, &cache, &doc, 3, 3, 12).unwrap(),
+            expected[3..6]
+        );
+
+        // The short last page runs to the newest version, shown as hex
+        let last_page = preview_page(&db, &cache, &doc, 6, 3, 2).unwrap();
+        assert_eq!(
+            last_page
+                .iter()
+                .map(|page| &page.preview[..])
+                .collect::<Vec<_>>(),
+            ["li", "ff00"]
+        );
+        assert!(preview_page(&db, &cache, &doc, 8, 3, 12)
+            .unwrap()
+            .is_empty());
+        assert!(preview_page(&db, &cache, &doc, 0, 0, 12)
+            .unwrap()
+            .is_empty());
     }
 
-    Ok((best_tag, best_delta.unwrap()))
-}
+    #[test]
+    fn non_positive_timestamps_are_rejected_on_save_but_load_as_empty() {
+        let state = test_state();
+        let doc = add_document(&state.read_db(), "notes");
+        save_version(&state, &doc, "first".to_string(), 10, None).unwrap();
 
-#[tauri::command]
-fn create_patch(
-    state: State<AppState>,
-    doc_uuid: String,
-    current_content: String,
-    timestamp: i64,
-) -> Result<String, String> {
-    let new_content = current_content.as_bytes().to_vec();
+        let rejected =
+            |result: Result<String, String>| result.unwrap_err().starts_with("Invalid timestamp");
+        assert!(rejected(save_version(
+            &state,
+            &doc,
+            "a".to_string(),
+            -5,
+            None
+        )));
+        assert!(rejected(save_version(
+            &state,
+            &doc,
+            "a".to_string(),
+            0,
+            None
+        )));
+        let stream = vec![(-5, xpatch::encode(0, b"first", b"a", ENABLE_ZSTD))];
+        assert!(append_deltas(&state, &doc, stream)
+            .unwrap_err()
+            .starts_with("Invalid timestamp"));
+        assert_eq!(patch_count(&state.read_db(), &doc), 1);
 
-    // Load the last content for comparison
-    let last_content = load_document_at_timestamp(
-        state.clone(),
-        doc_uuid.clone(),
-        timestamp,
-    )?;
+        // No version exists that early, so the document was still empty
+        let cache = ContentCache::default();
+        let options = ReconstructOptions::default();
+        let db = state.read_db();
+        assert_eq!(
+            load_content(&db, &cache, &doc, -86_400_000, &options).unwrap(),
+            ""
+        );
+        assert_eq!(
+            load_content(&db, &cache, &doc, 10, &options).unwrap(),
+            "first"
+        );
+    }
 
-    // If content is identical, return early without creating a patch
-    if last_content.as_bytes() == new_content {
-        return Err("Content identical to last version - patch not created".to_string());
+    fn pack_sync_bundle(bundle: &SyncBundle) -> Vec<u8> {
+        let json = serde_json::to_vec(bundle).unwrap();
+        zstd::bulk::compress(&json, SYNC_BUNDLE_ZSTD_LEVEL).unwrap()
     }
 
-    // Find the optimal base version to encode against
-    // Try up to 16 previous versions (you can adjust this)
-    let max_depth = 16;
-    let enable_zstd = true;
+    fn document_settings(db: &Connection, doc_uuid: &str) -> (Option<i64>, Option<i64>) {
+        db.query_row(
+            "SELECT max_versions, max_chain_depth FROM documents WHERE uuid = ?",
+            params![doc_uuid],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .unwrap()
+    }
 
-    let (_best_tag, delta) = find_optimal_base(
-        &state,
-        &doc_uuid,
-        timestamp,
-        &new_content,
-        max_depth,
-        enable_zstd,
-    )?;
+    #[test]
+    fn sync_bundles_restore_every_version_and_setting_on_a_fresh_database() {
+        let source = test_state();
+        let doc = {
+            let db = source.write_db();
+            let doc = add_document(&db, "notes");
+            write_base_strategy(&db, &doc, BaseStrategy::PreviousOnly).unwrap();
+            write_max_versions(&db, &doc, Some(10)).unwrap();
+            write_max_chain_depth(&db, &doc, Some(3)).unwrap();
+            write_max_content_bytes(&db, &doc, Some(4096)).unwrap();
+            db.execute(
+                "INSERT INTO document_metadata (doc_uuid, key, value) VALUES (?, 'lang', 'en')",
+                params![&doc],
+            )
+            .unwrap();
+            doc
+        };
+        for i in 1..=6 {
+            save_version(&source, &doc, text(i), i as i64, Some(EolStyle::Lf)).unwrap();
+        }
 
-    let db = state.db.lock().unwrap();
-    let mut cache = state.cache.lock().unwrap();
+        let bundle = sync_bundle(&source, &doc).unwrap();
+        let peer = test_state();
+        assert_eq!(restore_sync_bundle(&peer, &bundle).unwrap(), doc);
 
-    let patch_uuid = Uuid::new_v4().to_string();
+        let (source_db, peer_db) = (source.read_db(), peer.read_db());
+        assert_eq!(history(&peer_db, &doc), history(&source_db, &doc));
+        assert_eq!(
+            get_base_strategy(&peer_db, &doc).unwrap(),
+            BaseStrategy::PreviousOnly
+        );
+        assert_eq!(document_settings(&peer_db, &doc), (Some(10), Some(3)));
+        assert_eq!(content_limit(&peer_db, &doc, None).unwrap(), Some(4096));
+        assert_eq!(eol_style(&peer_db, &doc).unwrap(), Some(EolStyle::Lf));
+        assert_eq!(chain_depths(&peer_db, &doc), [1, 2, 3, 1, 2, 3]);
+        let lang: String = peer_db
+            .query_row(
+                "SELECT value FROM document_metadata WHERE key = 'lang'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(lang, "en");
+    }
 
-    db.execute(
-        "INSERT INTO patches (uuid, document_uuid, timestamp, delta) VALUES (?, ?, ?, ?)",
-        params![&patch_uuid, &doc_uuid, timestamp, delta.as_slice()],
-    )
-        .map_err(|e| e.to_string())?;
+    #[test]
+    fn a_sync_bundle_that_fails_to_replay_leaves_nothing_behind() {
+        let state = test_state();
+        let mut bundle = SyncBundle {
+            format_version: SYNC_BUNDLE_VERSION,
+            document: Document {
+                uuid: Uuid::new_v4().to_string(),
+                name: "notes".to_string(),
+                created_at: 1,
+                content_type: DEFAULT_CONTENT_TYPE.to_string(),
+            },
+            base_strategy: BaseStrategy::default(),
+            max_versions: None,
+            max_chain_depth: None,
+            max_content_bytes: None,
+            eol_style: None,
+            metadata: BTreeMap::from([("lang".to_string(), "en".to_string())]),
+            patches: vec![
+                SyncPatch {
+                    timestamp: 1,
+                    delta: BASE64_STANDARD.encode(xpatch::encode(0, b"", b"a\n", ENABLE_ZSTD)),
+                },
+                SyncPatch {
+                    timestamp: 2,
+                    delta: BASE64_STANDARD.encode(b"not a delta"),
+                },
+            ],
+        };
+        assert!(restore_sync_bundle(&state, &pack_sync_bundle(&bundle)).is_err());
+        let db = state.read_db();
+        let count = |table: &str| -> i64 {
+            db.query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |row| {
+                row.get(0)
+            })
+            .unwrap()
+        };
+        assert_eq!(
+            (
+                count("documents"),
+                count("patches"),
+                count("document_metadata")
+            ),
+            (0, 0, 0)
+        );
+        drop(db);
 
-    cache.insert((doc_uuid.clone(), patch_uuid.clone()), new_content);
+        // Once it applies, a second import of the same uuid is refused
+        bundle.patches.pop();
+        let packed = pack_sync_bundle(&bundle);
+        restore_sync_bundle(&state, &packed).unwrap();
+        let err = restore_sync_bundle(&state, &packed).unwrap_err();
+        assert!(err.starts_with("Document already exists"));
+        assert_eq!(patch_count(&state.read_db(), &bundle.document.uuid), 1);
+    }
 
-    Ok(patch_uuid)
-}
+    #[test]
+    fn version_one_sync_bundles_import_with_default_settings() {
+        let state = test_state();
+        let doc = Uuid::new_v4().to_string();
+        let delta = BASE64_STANDARD.encode(xpatch::encode(0, b"", b"a\n", ENABLE_ZSTD));
+        let document = serde_json::json!({
+            "uuid": doc, "name": "notes", "created_at": 1, "content_type": "text/plain"
+        });
+        let json = serde_json::json!({
+            "format_version": 1,
+            "document": document,
+            "metadata": {},
+            "patches": [{"timestamp": 1, "tag": 0, "delta": delta}],
+        });
+        let packed = zstd::bulk::compress(&serde_json::to_vec(&json).unwrap(), 3).unwrap();
 
-#[tauri::command]
-fn create_document(state: State<AppState>, name: String) -> Result<String, String> {
-    let db = state.db.lock().unwrap();
-    let doc_uuid = Uuid::new_v4().to_string();
-    let created_at = chrono::Utc::now().timestamp_millis();
+        restore_sync_bundle(&state, &packed).unwrap();
+        let db = state.read_db();
+        assert_eq!(history(&db, &doc), [(1, "a\n".to_string())]);
+        assert_eq!(
+            get_base_strategy(&db, &doc).unwrap(),
+            BaseStrategy::default()
+        );
+        assert_eq!(document_settings(&db, &doc), (None, None));
+        assert_eq!(content_limit(&db, &doc, None).unwrap(), None);
+        assert_eq!(eol_style(&db, &doc).unwrap(), None);
+    }
 
-    db.execute(
-        "INSERT INTO documents (uuid, name, created_at) VALUES (?, ?, ?)",
-        params![&doc_uuid, &name, created_at],
-    )
-    .map_err(|e| e.to_string())?;
+    #[test]
+    fn schema_status_reports_the_gap_until_migrations_run() {
+        let latest_version = MIGRATIONS.len() as i64;
+        let db = db_at_schema_version(2);
+        assert_eq!(
+            read_schema_status(&db).unwrap(),
+            SchemaStatus {
+                current_version: 2,
+                latest_version,
+                up_to_date: false,
+            }
+        );
 
-    Ok(doc_uuid)
-}
+        assert_eq!(apply_migrations(&db).unwrap(), latest_version);
+        assert_eq!(
+            read_schema_status(&db).unwrap(),
+            SchemaStatus {
+                current_version: latest_version,
+                latest_version,
+                up_to_date: true,
+            }
+        );
+        // Nothing is pending the second time
+        assert_eq!(apply_migrations(&db).unwrap(), latest_version);
+        assert!(read_schema_status(&test_db()).unwrap().up_to_date);
+    }
 
-#[tauri::command]
-fn get_documents(state: State<AppState>) -> Result<Vec<Document>, String> {
-    let db = state.db.lock().unwrap();
-    let mut stmt = db
-        .prepare("SELECT uuid, name, created_at FROM documents ORDER BY created_at DESC")
-        .map_err(|e| e.to_string())?;
+    #[test]
+    fn reverts_respect_the_base_strategy() {
+        let state = test_state();
+        let doc = add_document(&state.read_db(), "notes");
+        write_base_strategy(&state.read_db(), &doc, BaseStrategy::SnapshotEvery(3)).unwrap();
+        for (i, content) in ["a\n", "b\n", "c\n", "b\n", "e\n", "f\n"]
+            .iter()
+            .enumerate()
+        {
+            save_version(&state, &doc, content.to_string(), i as i64 + 1, None).unwrap();
+        }
+        // Position 3 reverts to "b" but is due a snapshot
+        let snapshots = [SNAPSHOT_TAG, 0, 0, SNAPSHOT_TAG, 0, 0];
+        assert_eq!(tags(&state.read_db(), &doc), snapshots);
 
-    let docs = stmt
-        .query_map([], |row| {
-            Ok(Document {
-                uuid: row.get(0)?,
-                name: row.get(1)?,
-                created_at: row.get(2)?,
-            })
-        })
-        .map_err(|e| e.to_string())?
-        .collect::<Result<Vec<_>, _>>()
-        .map_err(|e| e.to_string())?;
+        let doc = add_document(&state.read_db(), "shallow");
+        write_base_strategy(&state.read_db(), &doc, BaseStrategy::OptimalDepth(2)).unwrap();
+        for (i, content) in ["a\n", "b\n", "c\n", "a\n"].iter().enumerate() {
+            save_version(&state, &doc, content.to_string(), i as i64 + 1, None).unwrap();
+        }
+        // The match three versions back is past the search depth
+        assert!(tags(&state.read_db(), &doc)[3] < 2);
+        assert_eq!(history(&state.read_db(), &doc)[3].1, "a\n");
+    }
 
-    Ok(docs)
-}
+    #[test]
+    fn a_reused_request_id_stays_cancellable_after_the_first_load_ends() {
+        let state = test_state();
+        let first = register_reconstruction(&state, "load");
+        let second = register_reconstruction(&state, "load");
 
-#[tauri::command]
-fn get_patch_timestamps(
-    state: State<AppState>,
-    doc_uuid: String,
-) -> Result<Vec<i64>, String> {
-    let db = state.db.lock().unwrap();
-    let mut stmt = db
-        .prepare("SELECT timestamp FROM patches WHERE document_uuid = ? ORDER BY timestamp ASC")
-        .map_err(|e| e.to_string())?;
+        // The first load finishing must not drop the second one's token
+        unregister_reconstruction(&state, "load", &first);
+        assert!(request_cancellation(&state, "load"));
+        assert!(second.load(Ordering::Relaxed));
+        assert!(!first.load(Ordering::Relaxed));
 
-    let timestamps = stmt
-        .query_map(params![&doc_uuid], |row| row.get(0))
-        .map_err(|e| e.to_string())?
-        .collect::<Result<Vec<_>, _>>()
-        .map_err(|e| e.to_string())?;
+        unregister_reconstruction(&state, "load", &second);
+        assert!(!request_cancellation(&state, "load"));
+    }
 
-    Ok(timestamps)
-}
+    #[test]
+    fn diffs_too_large_to_align_replace_the_middle_wholesale() {
+        let lines =
+            |tag: &str| -> String { (0..5000).map(|i| format!("{} {}\n", tag, i)).collect() };
+        let old = format!("head\n{}a\ntail\n", lines("same"));
+        let new = format!("head\nb\n{}tail\n", lines("same"));
 
-#[tauri::command]
-fn clear_cache(state: State<AppState>) -> Result<(), String> {
-    let mut cache = state.cache.lock().unwrap();
-    cache.clear();
-    Ok(())
-}
+        // 5002 x 5002 cells is past MAX_DIFF_CELLS
+        let middle_old = format!("{}a\n", lines("same"));
+        let middle_new = format!("b\n{}", lines("same"));
+        assert_eq!(
+            diff_lines(&old, &new),
+            vec![
+                DiffOp::Equal("head\n".to_string()),
+                DiffOp::Insert(middle_new),
+                DiffOp::Delete(middle_old),
+                DiffOp::Equal("tail\n".to_string()),
+            ]
+        );
+    }
 
-#[tauri::command]
-fn get_document_stats(
-    state: State<AppState>,
-    doc_uuid: String,
-) -> Result<DocumentStats, String> {
-    let db = state.db.lock().unwrap();
+    #[test]
+    fn encode_verification_can_be_switched_off() {
+        for verify_encodes in [true, false] {
+            let mut state = test_state();
+            state.verify_encodes = verify_encodes;
+            let doc = add_document(&state.write_db(), "log");
+            save_version(&state, &doc, "log\n".to_string(), 1, None).unwrap();
 
-    // Get total patches and delta size
-    let (total_patches, total_delta_bytes): (i64, i64) = db
-        .query_row(
-            "SELECT COUNT(*), COALESCE(SUM(LENGTH(delta)), 0)
-             FROM patches
-             WHERE document_uuid = ?",
-            params![&doc_uuid],
-            |row| Ok((row.get(0)?, row.get(1)?))
+            let lookups = cache_lookups(&state.cache);
+            save_version(&state, &doc, "log\nentry\n".to_string(), 2, None).unwrap();
+            // Verifying reads the base a second time
+            let expected = if verify_encodes { 2 } else { 1 };
+            assert_eq!(cache_lookups(&state.cache) - lookups, expected);
+            assert_eq!(history(&state.read_db(), &doc)[1].1, "log\nentry\n");
+        }
+    }
+
+    #[test]
+    fn split_documents_copy_every_setting() {
+        let mut db = test_db();
+        let cache = ContentCache::default();
+        let doc = add_document(&db, "doc");
+        for i in 1..=3 {
+            add_version(&db, &doc, i as i64 * 10, &text(i));
+        }
+        db.execute(
+            "INSERT INTO zstd_dictionaries (id, content, created_at) VALUES (7, x'00', 1)",
+            [],
         )
-        .map_err(|e| e.to_string())?;
+        .unwrap();
+        db.execute(
+            "UPDATE documents
+             SET content_type = 'text/markdown', base_strategy = '\"PreviousOnly\"',
+                 max_versions = 9, max_content_bytes = 4096, eol_style = '\"Crlf\"',
+                 dictionary_id = 7, max_chain_depth = 5
+             WHERE uuid = ?",
+            params![&doc],
+        )
+        .unwrap();
 
-    // Get all timestamps
-    let mut stmt = db
-        .prepare("SELECT timestamp FROM patches WHERE document_uuid = ? ORDER BY timestamp ASC")
-        .map_err(|e| e.to_string())?;
+        let options = ReconstructOptions::default();
+        let new_doc = split_off(&mut db, &cache, &doc, 20, "split", &options).unwrap();
 
-    let timestamps: Vec<i64> = stmt
-        .query_map(params![&doc_uuid], |row| row.get(0))
-        .map_err(|e| e.to_string())?
-        .collect::<Result<Vec<_>, _>>()
-        .map_err(|e| e.to_string())?;
+        let settings = |doc_uuid: &str| -> Vec<Option<String>> {
+            db.query_row(
+                "SELECT content_type, base_strategy, CAST(max_versions AS TEXT),
+                        CAST(max_content_bytes AS TEXT), eol_style,
+                        CAST(dictionary_id AS TEXT), CAST(max_chain_depth AS TEXT)
+                 FROM documents WHERE uuid = ?",
+                params![doc_uuid],
+                |row| (0..7).map(|i| row.get(i)).collect(),
+            )
+            .unwrap()
+        };
+        assert_eq!(settings(&new_doc), settings(&doc));
+        assert!(settings(&new_doc).iter().all(Option::is_some));
+    }
 
-    drop(stmt);
-    drop(db);
+    #[test]
+    fn identical_saves_still_record_the_line_ending_style() {
+        let state = test_state();
+        let doc = add_document(&state.write_db(), "notes");
+        save_version(&state, &doc, text(1), 1, None).unwrap();
 
-    // Calculate actual uncompressed size by reconstructing each version
-    let mut total_uncompressed_bytes = 0i64;
+        let lf = Some(EolStyle::Lf);
+        let same = save_version(&state, &doc, text(1).replace('\n', "\r\n"), 2, lf);
+        assert!(same.is_err());
+        assert_eq!(
+            eol_style(&state.read_db(), &doc).unwrap(),
+            Some(EolStyle::Lf)
+        );
 
-    for timestamp in timestamps {
-        let content = load_document_at_timestamp(
-            state.clone(),
-            doc_uuid.clone(),
-            timestamp,
-        )?;
-        total_uncompressed_bytes += content.len() as i64;
+        // A save refused before it is stored leaves the style alone
+        let late = save_version(&state, &doc, text(2), -1, Some(EolStyle::Crlf));
+        assert!(late.is_err());
+        assert_eq!(
+            eol_style(&state.read_db(), &doc).unwrap(),
+            Some(EolStyle::Lf)
+        );
     }
 
-    // Calculate true compression ratio
-    let compression_ratio = if total_delta_bytes > 0 {
-        total_uncompressed_bytes as f64 / total_delta_bytes as f64
-    } else {
-        1.0
-    };
+    #[test]
+    fn dictionary_deltas_are_checked_against_their_base() {
+        let dictionary =
+            zstd::dict::from_samples(&(0..60).map(json_corpus).collect::<Vec<_>>(), 4096).unwrap();
+        let base = json_corpus(1).into_bytes();
+        let content = json_corpus(2).into_bytes();
+        let plain = xpatch::encode(0, &base, &content, false);
+        let compressed = compress_delta(&plain, &dictionary).unwrap().unwrap();
 
-    Ok(DocumentStats {
-        total_patches,
-        total_delta_bytes,
-        total_uncompressed_bytes,
-        compression_ratio,
-    })
-}
+        assert!(expands_to(&compressed, &dictionary, &base, &content));
+        assert!(!expands_to(&compressed, &dictionary, &content, &content));
+        assert!(!expands_to(
+            &compressed,
+            b"not the dictionary",
+            &base,
+            &content
+        ));
+    }
 
-#[cfg_attr(mobile, tauri::mobile_entry_point)]
-pub fn run() {
-    tauri::Builder::default()
-        .plugin(tauri_plugin_opener::init())
-        .setup(|app| {
-            let conn = init_database(app)?;
-            app.manage(AppState {
-                db: Mutex::new(conn),
-                cache: Mutex::new(HashMap::new()),
-            });
-            Ok(())
-        })
-        .invoke_handler(tauri::generate_handler![
-            load_document_at_timestamp,
-            create_patch,
-            create_document,
-            get_documents,
-            get_patch_timestamps,
-            clear_cache,
-            get_document_stats
-        ])
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+    #[test]
+    fn compacting_and_pruning_keep_within_the_max_chain_depth() {
+        let state = test_state();
+        let doc = add_document(&state.read_db(), "notes");
+        for i in 0..8 {
+            save_version(&state, &doc, text(i), i as i64 + 1, None).unwrap();
+        }
+        let expected = history(&state.read_db(), &doc);
+        assert!(chain_depths(&state.read_db(), &doc)
+            .iter()
+            .any(|&depth| depth > 3));
+        // Set after the fact, so compaction has to shorten the existing chain
+        set_chain_limit(&state.read_db(), &doc, 3);
+        let options = ReconstructOptions::default();
+
+        compact(&mut state.write_db(), &state.cache, &doc, &options).unwrap();
+        assert!(chain_depths(&state.read_db(), &doc)
+            .iter()
+            .all(|&depth| depth <= 3));
+        assert_eq!(history(&state.read_db(), &doc), expected);
+
+        prune_versions(&mut state.write_db(), &state.cache, &doc, 5, 2, &options).unwrap();
+        let db = state.read_db();
+        assert!(chain_depths(&db, &doc).iter().all(|&depth| depth <= 3));
+        assert_eq!(
+            history(&db, &doc),
+            [&expected[..1], &expected[2..3], &expected[4..]].concat()
+        );
+    }
+
+    #[test]
+    fn reoptimizing_never_deepens_a_capped_chain() {
+        let state = test_state();
+        let doc = add_document(&state.read_db(), "notes");
+        set_chain_limit(&state.read_db(), &doc, 3);
+        for i in 0..6 {
+            save_version(&state, &doc, text(i), i as i64 + 1, None).unwrap();
+        }
+        let depths = chain_depths(&state.read_db(), &doc);
+        assert_eq!(depths, [1, 2, 3, 1, 2, 3]);
+
+        // The fourth version's snapshot would be smaller as a delta on the third
+        assert_eq!(reoptimize_version(&state, &doc, 4).unwrap(), 0);
+        assert_eq!(chain_depths(&state.read_db(), &doc), depths);
+    }
 }
@@ -1,9 +1,11 @@
 import { invoke } from '@tauri-apps/api/core';
+import { listen, type UnlistenFn } from '@tauri-apps/api/event';
 
 export interface Document {
     uuid: string;
     name: string;
     created_at: number;
+    content_type: string;
 }
 
 export interface DocumentStats {
@@ -13,40 +15,912 @@ export interface DocumentStats {
     compression_ratio: number;
 }
 
-export async function createDocument(name: string): Promise<string> {
-    return await invoke('create_document', { name });
+export interface DocumentSummary {
+    document: Document;
+    total_patches: number;
+    total_delta_bytes: number;
+}
+
+export interface VersionWithChanges {
+    content: string;
+    // [start, end) byte offsets of lines added since the previous version
+    changed_ranges: [number, number][];
+}
+
+export interface VersionWithCost {
+    content: string;
+    // Deltas decoded from the nearest snapshot (or first patch) up to this version
+    patches_replayed: number;
+    total_base_bytes_read: number;
+}
+
+export interface SavedVersion {
+    patch_uuid: string;
+    timestamp: number;
+    content: string;
+}
+
+export interface Attachment {
+    uuid: string;
+    document_uuid: string;
+    name: string;
+    size: number;
+    created_at: number;
+}
+
+export interface DocumentPage {
+    documents: Document[];
+    total: number;
+}
+
+export interface DatabaseLocation {
+    tier: 'AppData' | 'Temp' | 'InMemory' | 'Custom';
+    path: string | null;
+}
+
+export interface StorageReport {
+    file_bytes: number | null;
+    document_count: number;
+    patch_count: number;
+    total_delta_bytes: number;
+}
+
+export interface SchemaStatus {
+    current_version: number;
+    latest_version: number;
+    up_to_date: boolean;
+}
+
+export interface LibraryCounts {
+    documents: number;
+    patches: number;
+}
+
+export interface ReconstructionProgress {
+    doc_uuid: string;
+    done: number;
+    total: number;
+}
+
+export type DocumentChangeKind =
+    | 'Created'
+    | 'Edited'
+    | 'HistoryRewritten'
+    | 'Compacted'
+    | 'SettingsChanged'
+    | 'AnnotationsChanged'
+    | 'Trashed'
+    | 'Restored'
+    | 'Deleted';
+
+export interface DocumentChanged {
+    doc_uuid: string;
+    kind: DocumentChangeKind;
+    // Newest saved version for 'Edited', otherwise null
+    timestamp: number | null;
+}
+
+export type BaseStrategy =
+    | 'PreviousOnly'
+    | { OptimalDepth: number }
+    | { SnapshotEvery: number };
+
+export interface PruneReport {
+    removed_patches: number;
+    bytes_reclaimed: number;
+}
+
+export interface Bookmark {
+    name: string;
+    timestamp: number;
+}
+
+export interface LineEvent {
+    timestamp: number;
+    kind: 'Added' | 'Removed';
+}
+
+// Runs of whole lines; Equal + Delete rebuild the old text, Equal + Insert the new
+export type DiffOp = { Equal: string } | { Insert: string } | { Delete: string };
+
+// Timings are in microseconds
+export interface ProfileReport {
+    query_us: number;
+    decode_us: number;
+    utf8_us: number;
+    patch_count: number;
+    cache_hits: number;
+    cache_misses: number;
+}
+
+export type SeekDirection = 'Nearest' | 'Before' | 'After';
+
+export type EolStyle = 'Lf' | 'Crlf';
+
+export interface BaseCandidate {
+    tag: number;
+    base_timestamp: number | null;
+    delta_bytes: number;
+    chosen: boolean;
+}
+
+export interface ChainStep {
+    patch_uuid: string;
+    timestamp: number;
+    tag: number;
+    base_timestamp: number | null;
+}
+
+export interface PatchInspection {
+    uuid: string;
+    document_uuid: string;
+    timestamp: number;
+    delta_len: number;
+    tag: number | null;
+    first_bytes_hex: string;
+}
+
+export interface VersionPreview {
+    timestamp: number;
+    // First previewLen characters, or the first previewLen bytes in hex if not UTF-8
+    preview: string;
+}
+
+export interface VersionMetrics {
+    bytes: number;
+    chars: number;
+    lines: number;
+    words: number;
+}
+
+export interface CacheWarmup {
+    versions_warmed: number;
+    bytes_cached: number;
+}
+
+// logical_bytes of content held in stored_bytes of memory
+export interface CacheStats {
+    entries: number;
+    logical_bytes: number;
+    stored_bytes: number;
+    compression_enabled: boolean;
+    // Lookups since startup
+    hits: number;
+    misses: number;
+}
+
+export interface CompactionPreview {
+    delta_bytes_before: number;
+    delta_bytes_after: number;
+    snapshots_added: number;
+}
+
+// Emitted during full-history operations (stats, compaction, pruning, merging)
+export async function onReconstructionProgress(
+    handler: (progress: ReconstructionProgress) => void
+): Promise<UnlistenFn> {
+    return await listen<ReconstructionProgress>('reconstruction-progress', (event) =>
+        handler(event.payload)
+    );
+}
+
+// Fired after every successful mutation so open views can refresh
+export async function onDocumentChanged(
+    handler: (change: DocumentChanged) => void
+): Promise<UnlistenFn> {
+    return await listen<DocumentChanged>('document-changed', (event) => handler(event.payload));
+}
+
+// createdAt (ms since epoch) defaults to now; importers pass the original date
+export async function createDocument(
+    name: string,
+    contentType?: string,
+    createdAt?: number
+): Promise<string> {
+    return await invoke('create_document', { name, contentType, createdAt });
+}
+
+export async function createDocuments(names: string[]): Promise<string[]> {
+    return await invoke('create_documents', { names });
+}
+
+// Creates the document and its first version in one call; resolves to the uuid
+export async function createDocumentWithContent(name: string, content: string): Promise<string> {
+    return await invoke('create_document_with_content', { name, content });
+}
+
+// The content type follows the file extension (text/plain or octet-stream otherwise)
+export async function importFileAsDocument(name: string, path: string): Promise<string> {
+    return await invoke('import_file_as_document', { name, path });
+}
+
+// desired, or desired with the first free " (n)" suffix if the name is taken
+export async function suggestDocumentName(desired: string): Promise<string> {
+    return await invoke('suggest_document_name', { desired });
+}
+
+export async function setBaseStrategy(
+    docUuid: string,
+    strategy: BaseStrategy
+): Promise<void> {
+    return await invoke('set_base_strategy', { docUuid, strategy });
+}
+
+export async function setContentType(docUuid: string, contentType: string): Promise<void> {
+    return await invoke('set_content_type', { docUuid, contentType });
+}
+
+export async function setCreatedAt(docUuid: string, createdAt: number): Promise<void> {
+    return await invoke('set_created_at', { docUuid, createdAt });
+}
+
+// null removes the cap; the oldest extra versions go on the next createPatch
+export async function setMaxVersions(docUuid: string, max: number | null): Promise<void> {
+    return await invoke('set_max_versions', { docUuid, max });
+}
+
+// null removes the cap; a createPatch that would go deeper saves a snapshot instead
+export async function setMaxChainDepth(docUuid: string, max: number | null): Promise<void> {
+    return await invoke('set_max_chain_depth', { docUuid, max });
+}
+
+// null falls back to the startup default; larger versions are rejected by createPatch
+export async function setMaxContentBytes(docUuid: string, max: number | null): Promise<void> {
+    return await invoke('set_max_content_bytes', { docUuid, max });
 }
 
 export async function getDocuments(): Promise<Document[]> {
     return await invoke('get_documents');
 }
 
+// Most recently edited first; documents without versions rank by createdAt
+export async function getDocumentsByActivity(): Promise<Document[]> {
+    return await invoke('get_documents_by_activity');
+}
+
+// search keeps only documents whose name contains it
+export async function getDocumentsPaged(
+    offset: number,
+    limit: number,
+    search?: string
+): Promise<DocumentPage> {
+    return await invoke('get_documents_paged', { offset, limit, search });
+}
+
+// Moves the document to the trash; restoreDocument undoes it until emptyTrash
+export async function deleteDocument(docUuid: string): Promise<void> {
+    return await invoke('delete_document', { docUuid });
+}
+
+// Permanent, unlike deleteDocument; unknown uuids are skipped. Resolves to the count removed.
+export async function deleteDocuments(docUuids: string[]): Promise<number> {
+    return await invoke('delete_documents', { docUuids });
+}
+
+export async function listTrashed(): Promise<Document[]> {
+    return await invoke('list_trashed');
+}
+
+export async function restoreDocument(docUuid: string): Promise<void> {
+    return await invoke('restore_document', { docUuid });
+}
+
+export async function emptyTrash(): Promise<number> {
+    return await invoke('empty_trash');
+}
+
+// Groups of document uuids with identical latest content
+export async function findDuplicateDocuments(): Promise<string[][]> {
+    return await invoke('find_duplicate_documents');
+}
+
+export async function findOrphanPatches(): Promise<string[]> {
+    return await invoke('find_orphan_patches');
+}
+
+export async function deleteOrphanPatches(): Promise<number> {
+    return await invoke('delete_orphan_patches');
+}
+
+// Recreates placeholder documents for orphaned patches, returning their uuids
+export async function recoverDocuments(): Promise<string[]> {
+    return await invoke('recover_documents');
+}
+
+export async function documentsActiveBetween(
+    fromMs: number,
+    toMs: number
+): Promise<Document[]> {
+    return await invoke('documents_active_between', { fromMs, toMs });
+}
+
+export async function getDocumentsWithStats(): Promise<DocumentSummary[]> {
+    return await invoke('get_documents_with_stats');
+}
+
 export async function getDocumentStats(docUuid: string): Promise<DocumentStats> {
     return await invoke('get_document_stats', { docUuid });
 }
 
+// One CSV row of stats per document outside the trash; resolves to the row count
+export async function exportStatsCsv(path: string): Promise<number> {
+    return await invoke('export_stats_csv', { path });
+}
+
+// Raw xpatch delta from one version to another, as a byte array
+export async function deltaBetween(
+    docUuid: string,
+    fromTimestamp: number,
+    toTimestamp: number,
+    enableZstd: boolean = true
+): Promise<number[]> {
+    return await invoke('delta_between', {
+        docUuid,
+        fromTimestamp,
+        toTimestamp,
+        enableZstd
+    });
+}
+
+export async function deltaFromClientBase(
+    docUuid: string,
+    targetTimestamp: number,
+    clientContent: number[]
+): Promise<number[]> {
+    return await invoke('delta_from_client_base', {
+        docUuid,
+        targetTimestamp,
+        clientContent
+    });
+}
+
+// Each [timestamp, delta] applies to the version before it; all or nothing
+export async function applyDeltaStream(
+    docUuid: string,
+    deltas: [number, number[]][]
+): Promise<string[]> {
+    return await invoke('apply_delta_stream', { docUuid, deltas });
+}
+
+export async function diffVersions(
+    docUuid: string,
+    fromTimestamp: number,
+    toTimestamp: number
+): Promise<DiffOp[]> {
+    return await invoke('diff_versions', { docUuid, fromTimestamp, toTimestamp });
+}
+
+// Same as diffVersions(timestamp, head) without looking up the head first
+export async function diffAgainstHead(docUuid: string, timestamp: number): Promise<DiffOp[]> {
+    return await invoke('diff_against_head', { docUuid, timestamp });
+}
+
+export async function diffDocuments(docA: string, docB: string): Promise<DiffOp[]> {
+    return await invoke('diff_documents', { docA, docB });
+}
+
+// What saving draftContent would change relative to the stored latest version
+export async function pendingDiff(docUuid: string, draftContent: string): Promise<DiffOp[]> {
+    return await invoke('pending_diff', { docUuid, draftContent });
+}
+
+// Pairwise similarity in [0, 1] of the newest versions (at most 64), oldest first
+export async function similarityMatrix(docUuid: string): Promise<number[][]> {
+    return await invoke('similarity_matrix', { docUuid });
+}
+
+// [prefix, suffix] byte lengths unchanged across every version; they may overlap
+export async function stableRegions(docUuid: string): Promise<[number, number]> {
+    return await invoke('stable_regions', { docUuid });
+}
+
+export async function inspectPatch(patchUuid: string): Promise<PatchInspection> {
+    return await invoke('inspect_patch', { patchUuid });
+}
+
+// [tag, patch_count, total_delta_bytes], sorted by tag
+// Deltas reconstruction applies to build the version, first decoded first
+export async function dependencyChain(docUuid: string, timestamp: number): Promise<ChainStep[]> {
+    return await invoke('dependency_chain', { docUuid, timestamp });
+}
+
+// Deltas decoded to rebuild the version, i.e. dependencyChain(...).length
+export async function chainDepth(docUuid: string, timestamp: number): Promise<number> {
+    return await invoke('chain_depth', { docUuid, timestamp });
+}
+
+export async function tagHistogram(
+    docUuid: string
+): Promise<[number, number, number][]> {
+    return await invoke('tag_histogram', { docUuid });
+}
+
+// [timestamp, cumulativeDeltaBytes] per patch, oldest first
+export async function storageTimeline(docUuid: string): Promise<[number, number][]> {
+    return await invoke('storage_timeline', { docUuid });
+}
+
+// Timestamps where the size changed by more than minDeltaRatio of the previous size
+export async function significantEdits(docUuid: string, minDeltaRatio: number): Promise<number[]> {
+    return await invoke('significant_edits', { docUuid, minDeltaRatio });
+}
+
+// [bucketStart, patchCount] for each non-empty bucket
+export async function editHistogram(
+    docUuid: string,
+    bucketMs: number
+): Promise<[number, number][]> {
+    return await invoke('edit_histogram', { docUuid, bucketMs });
+}
+
+// Pass a requestId to be able to abort the load with cancelReconstruction.
+// repairMode skips delta checksum verification for emergency recovery.
+// lossy shows invalid UTF-8 as U+FFFD instead of rejecting the load.
 export async function loadDocumentAtTimestamp(
     docUuid: string,
-    timestamp: number
+    timestamp: number,
+    requestId?: string,
+    repairMode?: boolean,
+    lossy?: boolean
 ): Promise<string> {
     return await invoke('load_document_at_timestamp', {
         docUuid,
-        timestamp
+        timestamp,
+        requestId,
+        repairMode,
+        lossy
     });
 }
 
+// Rejects with 'Reconstruction timed out' once budgetMs passes; a retry resumes from the cache
+export async function loadWithTimeout(
+    docUuid: string,
+    timestamp: number,
+    budgetMs: number
+): Promise<string> {
+    return await invoke('load_with_timeout', { docUuid, timestamp, budgetMs });
+}
+
+// Accepts any RFC 3339 string, such as new Date().toISOString()
+export async function loadDocumentAtDate(docUuid: string, rfc3339: string): Promise<string> {
+    return await invoke('load_document_at_date', { docUuid, rfc3339 });
+}
+
+export async function cancelReconstruction(requestId: string): Promise<boolean> {
+    return await invoke('cancel_reconstruction', { requestId });
+}
+
+// Both resolve to null when there is no version in that direction
+export async function loadNextVersion(
+    docUuid: string,
+    currentTimestamp: number
+): Promise<[number, string] | null> {
+    return await invoke('load_next_version', { docUuid, currentTimestamp });
+}
+
+export async function loadPreviousVersion(
+    docUuid: string,
+    currentTimestamp: number
+): Promise<[number, string] | null> {
+    return await invoke('load_previous_version', { docUuid, currentTimestamp });
+}
+
+// Resolves to [0, ''] for a document without patches
+export async function loadLatest(docUuid: string): Promise<[number, string]> {
+    return await invoke('load_latest', { docUuid });
+}
+
+// [timestamp, content] of the version versionsBack saves before the head
+export async function loadRelativeToHead(
+    docUuid: string,
+    versionsBack: number
+): Promise<[number, string]> {
+    return await invoke('load_relative_to_head', { docUuid, versionsBack });
+}
+
+export async function loadWithCost(docUuid: string, timestamp: number): Promise<VersionWithCost> {
+    return await invoke('load_with_cost', { docUuid, timestamp });
+}
+
+export async function loadWithChanges(
+    docUuid: string,
+    timestamp: number
+): Promise<VersionWithChanges> {
+    return await invoke('load_with_changes', { docUuid, timestamp });
+}
+
+export async function profileReconstruction(
+    docUuid: string,
+    timestamp: number
+): Promise<ProfileReport> {
+    return await invoke('profile_reconstruction', { docUuid, timestamp });
+}
+
+// [docUuid, latestContent] for every document outside the trash
+export async function loadAllLatest(): Promise<[string, string][]> {
+    return await invoke('load_all_latest');
+}
+
+// Rejects with 'Version not found' when nothing lies in that direction
+export async function loadNearest(
+    docUuid: string,
+    timestamp: number,
+    direction: SeekDirection
+): Promise<[number, string]> {
+    return await invoke('load_nearest', { docUuid, timestamp, direction });
+}
+
+// Preloads every version so timeline scrubbing never waits on reconstruction
+export async function warmCache(docUuid: string): Promise<CacheWarmup> {
+    return await invoke('warm_cache', { docUuid });
+}
+
+// Resolves to how many cached versions of the document were dropped
+export async function clearDocumentCache(docUuid: string): Promise<number> {
+    return await invoke('clear_document_cache', { docUuid });
+}
+
+// Applies to entries cached from now on
+export async function setCacheCompression(enabled: boolean): Promise<void> {
+    return await invoke('set_cache_compression', { enabled });
+}
+
+export async function cacheStats(): Promise<CacheStats> {
+    return await invoke('cache_stats');
+}
+
+// Fire-and-forget warm-up on hover/selection; false if one is already running
+export async function prefetchDocument(docUuid: string): Promise<boolean> {
+    return await invoke('prefetch_document', { docUuid });
+}
+
+export async function exportVersion(
+    docUuid: string,
+    timestamp: number,
+    path: string
+): Promise<number> {
+    return await invoke('export_version', { docUuid, timestamp, path });
+}
+
+// Tar archive of every version plus manifest.json; resolves to the version count
+export async function exportAllVersions(docUuid: string, path: string): Promise<number> {
+    return await invoke('export_all_versions', { docUuid, path });
+}
+
+// Zstd-compressed bundle of the whole history for a peer; see importSyncBundle
+export async function exportSyncBundle(docUuid: string): Promise<number[]> {
+    return await invoke('export_sync_bundle', { docUuid });
+}
+
+// Recreates the document under its original uuid and settings; fails if that uuid exists
+export async function importSyncBundle(bundle: number[]): Promise<string> {
+    return await invoke('import_sync_bundle', { bundle });
+}
+
+// Hex SHA-256 of the version's bytes; equal hashes mean equal content
+export async function versionHash(docUuid: string, timestamp: number): Promise<string> {
+    return await invoke('version_hash', { docUuid, timestamp });
+}
+
+// True when the document has no patches or only whitespace in its latest version
+export async function isDocumentBlank(docUuid: string): Promise<boolean> {
+    return await invoke('is_document_blank', { docUuid });
+}
+
+export async function versionMetrics(
+    docUuid: string,
+    timestamp: number
+): Promise<VersionMetrics> {
+    return await invoke('version_metrics', { docUuid, timestamp });
+}
+
+// false means binary content, better shown in a hex viewer
+export async function versionIsUtf8(docUuid: string, timestamp: number): Promise<boolean> {
+    return await invoke('version_is_utf8', { docUuid, timestamp });
+}
+
+// Oldest first; a page past the last version is empty
+export async function versionPreviews(
+    docUuid: string,
+    offset: number,
+    limit: number,
+    previewLen: number
+): Promise<VersionPreview[]> {
+    return await invoke('version_previews', { docUuid, offset, limit, previewLen });
+}
+
+// mime defaults to the document's content type
+export async function versionAsDataUrl(
+    docUuid: string,
+    timestamp: number,
+    mime?: string
+): Promise<string> {
+    return await invoke('version_as_data_url', { docUuid, timestamp, mime });
+}
+
+// Clamped to the version's length, so out-of-range requests return fewer bytes
+export async function loadVersionRange(
+    docUuid: string,
+    timestamp: number,
+    start: number,
+    len: number
+): Promise<number[]> {
+    return await invoke('load_version_range', { docUuid, timestamp, start, len });
+}
+
+export async function openVersionStream(
+    docUuid: string,
+    timestamp: number
+): Promise<string> {
+    return await invoke('open_version_stream', { docUuid, timestamp });
+}
+
+// Resolves to null once the stream is exhausted; the handle is closed then
+export async function readVersionChunk(
+    handle: string,
+    maxBytes: number
+): Promise<number[] | null> {
+    return await invoke('read_version_chunk', { handle, maxBytes });
+}
+
+export async function closeVersionStream(handle: string): Promise<void> {
+    return await invoke('close_version_stream', { handle });
+}
+
+// timestamp must be positive epoch ms and after the latest version; others are rejected.
+// normalizeEol converts line endings before saving and records the style.
 export async function createPatch(
     docUuid: string,
     currentContent: string,
-    timestamp: number = Date.now()
+    timestamp: number = Date.now(),
+    normalizeEol?: EolStyle
 ): Promise<string> {
     return await invoke('create_patch', {
         docUuid,
         currentContent,
+        timestamp,
+        normalizeEol
+    });
+}
+
+// Style of the latest normalized save, or null if none was normalized
+export async function getEolStyle(docUuid: string): Promise<EolStyle | null> {
+    return await invoke('get_eol_style', { docUuid });
+}
+
+// Saves and returns the new head, so the editor needs no follow-up loadLatest
+export async function createPatchReturningContent(
+    docUuid: string,
+    currentContent: string,
+    timestamp: number = Date.now()
+): Promise<SavedVersion> {
+    return await invoke('create_patch_returning_content', {
+        docUuid,
+        currentContent,
+        timestamp
+    });
+}
+
+// Encodes against the version at baseTimestamp instead of the automatic choice
+export async function createPatchWithBase(
+    docUuid: string,
+    currentContent: string,
+    baseTimestamp: number,
+    timestamp: number = Date.now()
+): Promise<string> {
+    return await invoke('create_patch_with_base', {
+        docUuid,
+        currentContent,
+        timestamp,
+        baseTimestamp
+    });
+}
+
+export async function estimatePatchSize(
+    docUuid: string,
+    content: string,
+    timestamp: number = Date.now()
+): Promise<number> {
+    return await invoke('estimate_patch_size', {
+        docUuid,
+        content,
         timestamp
     });
 }
 
+export async function explainOptimalBase(
+    docUuid: string,
+    content: string,
+    timestamp: number
+): Promise<BaseCandidate[]> {
+    return await invoke('explain_optimal_base', { docUuid, content, timestamp });
+}
+
 export async function getPatchTimestamps(docUuid: string): Promise<number[]> {
     return await invoke('get_patch_timestamps', { docUuid });
+}
+
+// Keyed by uuid; documents without patches map to []
+export async function getPatchTimestampsMulti(
+    docUuids: string[]
+): Promise<Record<string, number[]>> {
+    return await invoke('get_patch_timestamps_multi', { docUuids });
+}
+
+export async function getLatestTimestamp(docUuid: string): Promise<number | null> {
+    return await invoke('get_latest_timestamp', { docUuid });
+}
+
+export async function addBookmark(
+    docUuid: string,
+    name: string,
+    timestamp: number
+): Promise<void> {
+    return await invoke('add_bookmark', { docUuid, name, timestamp });
+}
+
+export async function listBookmarks(docUuid: string): Promise<Bookmark[]> {
+    return await invoke('list_bookmarks', { docUuid });
+}
+
+export async function deleteBookmark(docUuid: string, name: string): Promise<void> {
+    return await invoke('delete_bookmark', { docUuid, name });
+}
+
+export async function loadBookmark(docUuid: string, name: string): Promise<string> {
+    return await invoke('load_bookmark', { docUuid, name });
+}
+
+export async function setMetadata(docUuid: string, key: string, value: string): Promise<void> {
+    return await invoke('set_metadata', { docUuid, key, value });
+}
+
+export async function getMetadata(docUuid: string, key: string): Promise<string | null> {
+    return await invoke('get_metadata', { docUuid, key });
+}
+
+export async function listMetadata(docUuid: string): Promise<Record<string, string>> {
+    return await invoke('list_metadata', { docUuid });
+}
+
+export async function addAttachment(
+    docUuid: string,
+    name: string,
+    data: number[]
+): Promise<string> {
+    return await invoke('add_attachment', { docUuid, name, data });
+}
+
+export async function getAttachment(attachmentUuid: string): Promise<number[]> {
+    return await invoke('get_attachment', { attachmentUuid });
+}
+
+export async function listAttachments(docUuid: string): Promise<Attachment[]> {
+    return await invoke('list_attachments', { docUuid });
+}
+
+export async function deleteAttachment(attachmentUuid: string): Promise<void> {
+    return await invoke('delete_attachment', { attachmentUuid });
+}
+
+export async function blameLine(
+    docUuid: string,
+    lineText: string
+): Promise<LineEvent[]> {
+    return await invoke('blame_line', { docUuid, lineText });
+}
+
+// Versions where the byte at offset changed, appeared or disappeared
+export async function offsetHistory(docUuid: string, offset: number): Promise<number[]> {
+    return await invoke('offset_history', { docUuid, offset });
+}
+
+export async function compactionPreview(docUuid: string): Promise<CompactionPreview> {
+    return await invoke('compaction_preview', { docUuid });
+}
+
+// Uuids worth compacting, largest first; tiny and single-patch documents are never listed
+export async function compactionCandidates(minSavingsRatio: number): Promise<string[]> {
+    return await invoke('compaction_candidates', { minSavingsRatio });
+}
+
+export async function compactDocument(docUuid: string): Promise<CompactionPreview> {
+    return await invoke('compact_document', { docUuid });
+}
+
+// compactDocument for one version; resolves to the bytes saved (0 if already optimal)
+export async function reoptimizePatch(docUuid: string, timestamp: number): Promise<number> {
+    return await invoke('reoptimize_patch', { docUuid, timestamp });
+}
+
+// Irreversibly drops every version after timestamp; resolves to how many went
+export async function truncateAfter(docUuid: string, timestamp: number): Promise<number> {
+    return await invoke('truncate_after', { docUuid, timestamp });
+}
+
+// Resolves to whether the earliest patch had to be re-encoded as a tag-0 snapshot
+export async function ensureSnapshotHead(docUuid: string): Promise<boolean> {
+    return await invoke('ensure_snapshot_head', { docUuid });
+}
+
+// Later saves compress their deltas with it; resolves to the dictionary's size in bytes
+export async function trainDictionary(docUuid: string): Promise<number> {
+    return await invoke('train_dictionary', { docUuid });
+}
+
+export async function pruneOldPatches(
+    docUuid: string,
+    keepAfter: number,
+    keepEveryNth: number
+): Promise<PruneReport> {
+    return await invoke('prune_old_patches', {
+        docUuid,
+        keepAfter,
+        keepEveryNth
+    });
+}
+
+export async function mergeDocuments(
+    targetUuid: string,
+    sourceUuid: string
+): Promise<number> {
+    return await invoke('merge_documents', { targetUuid, sourceUuid });
+}
+
+// [patchUuid, correctedTimestamp] pairs; unlisted patches keep theirs
+export async function resequencePatches(
+    docUuid: string,
+    remap: [string, number][]
+): Promise<void> {
+    return await invoke('resequence_patches', { docUuid, remap });
+}
+
+// Versions at/after splitTimestamp move to a new document; resolves to its uuid
+export async function splitDocument(
+    docUuid: string,
+    splitTimestamp: number,
+    newName: string
+): Promise<string> {
+    return await invoke('split_document', { docUuid, splitTimestamp, newName });
+}
+
+// Switches the app to another database file; ':memory:' for a throwaway one
+export async function openDatabase(path: string): Promise<DatabaseLocation> {
+    return await invoke('open_database', { path });
+}
+
+export async function databaseLocation(): Promise<DatabaseLocation> {
+    return await invoke('database_location');
+}
+
+export async function storageReport(): Promise<StorageReport> {
+    return await invoke('storage_report');
+}
+
+// Excludes trashed documents and their patches
+export async function libraryCounts(): Promise<LibraryCounts> {
+    return await invoke('library_counts');
+}
+
+// Uncompressed over stored bytes across every document, trash included; 1 when empty
+export async function libraryCompressionRatio(): Promise<number> {
+    return await invoke('library_compression_ratio');
+}
+
+// [first, last] edit timestamps; both are createdAt for a document with no patches
+export async function documentTimeBounds(docUuid: string): Promise<[number, number]> {
+    return await invoke('document_time_bounds', { docUuid });
+}
+
+export async function vacuumDatabase(): Promise<number> {
+    return await invoke('vacuum_database');
+}
+
+export async function schemaStatus(): Promise<SchemaStatus> {
+    return await invoke('schema_status');
+}
+
+// Resolves to the schema version after applying whatever was pending
+export async function runMigrations(): Promise<number> {
+    return await invoke('run_migrations');
 }
\ No newline at end of file